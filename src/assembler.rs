@@ -0,0 +1,1371 @@
+﻿use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use elf::abi::{
+    ELFCLASS64, ELFDATA2LSB, EM_RISCV, ET_EXEC, EV_CURRENT, PF_R, PF_W, PF_X, PT_LOAD, SHF_ALLOC, SHF_EXECINSTR,
+    SHF_WRITE, SHT_PROGBITS, SHT_STRTAB, SHT_SYMTAB, STB_GLOBAL, STT_NOTYPE,
+};
+
+use crate::disassembler::{csr_name, ROUNDING_MODES};
+use crate::instruction::{parse_float_register, parse_register};
+use crate::mmu::PAGE_SIZE;
+use crate::opcodes::*;
+
+const NOP: u32 = 0x0000_0013;
+/// `csrrw zero, cycle, zero`, which traps because `cycle` is read-only.
+const UNIMP: u32 = 0xC000_1073;
+const FENCE_TSO: u32 = 0x8330_000F;
+const FENCE_I: u32 = 0x0000_100F;
+
+const RA: u32 = 1;
+const T1: u32 = 6;
+
+/// Where `.text` starts unless told otherwise, so the result boots on the bare machine.
+pub const DEFAULT_BASE: u64 = crate::bus::DRAM_BASE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+}
+
+enum Item {
+    Instruction { mnemonic: String, operands: Vec<String> },
+    /// `.byte`, `.half`, `.word` or `.dword` values, which may refer to labels.
+    Values { size: usize, expressions: Vec<String> },
+    Bytes(Vec<u8>),
+}
+
+struct Statement {
+    line: usize,
+    section: Section,
+    offset: u64,
+    item: Item,
+}
+
+/// An assembled program: the contents of `.text` and `.data`, with `.data` starting on the page after `.text`.
+#[derive(Debug)]
+pub struct Program {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub text_address: u64,
+    pub data_address: u64,
+    /// `_start` if the program defines it, otherwise the start of `.text`.
+    pub entry_point: u64,
+    /// Every label except the `.L` temporaries, in definition order.
+    pub symbols: Vec<(String, u64)>,
+}
+
+/// Assembles RV64GC assembly in the GNU `as` syntax, placing `.text` at `base`.
+///
+/// Instructions are never compressed and calls are never relaxed, so each one assembles to a fixed size.
+pub fn assemble(source: &str, base: u64) -> Result<Program> {
+    let mut assembler = Assembler {
+        statements: Vec::new(),
+        labels: Vec::new(),
+        constants: HashMap::new(),
+        addresses: HashMap::new(),
+        pcrel_high: HashMap::new(),
+        local_labels: HashMap::new(),
+        section: Section::Text,
+        sizes: [0, 0],
+        line: 0,
+    };
+    for (index, line) in source.lines().enumerate() {
+        assembler.line = index + 1;
+        assembler.parse_line(line).with_context(|| format!("line {}", index + 1))?;
+    }
+    assembler.finish(base)
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    labels: Vec<(String, Section, u64)>,
+    /// Symbols defined with `.equ` or `.set`.
+    constants: HashMap<String, i64>,
+    /// Label addresses, known once all the statements have been laid out.
+    addresses: HashMap<String, u64>,
+    /// The offsets `%pcrel_hi` computed for the `auipc` at each address, for the matching `%pcrel_lo`.
+    pcrel_high: HashMap<u64, i64>,
+    /// How many times each numeric local label like `1:` has been defined so far.
+    local_labels: HashMap<String, usize>,
+    section: Section,
+    sizes: [u64; 2],
+    line: usize,
+}
+
+impl Assembler {
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        let mut rest = strip_comment(line).trim();
+        while let Some((label, after)) = rest.split_once(':').filter(|(label, _)| is_label(label.trim())) {
+            let label = match label.trim() {
+                // every definition of a numeric label is a separate one, referred to as `1b` or `1f`
+                number if number.bytes().all(|c| c.is_ascii_digit()) => {
+                    let count = self.local_labels.entry(number.to_string()).or_default();
+                    *count += 1;
+                    local_label_name(number, *count - 1)
+                }
+                label => label.to_string(),
+            };
+            if self.labels.iter().any(|(name, _, _)| *name == label) || self.constants.contains_key(&label) {
+                bail!("{} is defined twice", label);
+            }
+            self.labels.push((label, self.section, self.offset()));
+            rest = after.trim();
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let (mnemonic, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let mnemonic = mnemonic.to_lowercase();
+        let operands: Vec<String> = split_operands(operands).iter().map(|operand| self.rename_local_labels(operand)).collect();
+        if mnemonic.starts_with('.') {
+            return self.directive(&mnemonic, operands);
+        }
+        if self.section != Section::Text {
+            bail!("instructions must be in .text");
+        }
+        let size = match mnemonic.as_str() {
+            "la" | "lla" | "call" | "tail" => 8,
+            "li" => {
+                expect_operands(&operands, 2)?;
+                4 * load_immediate(0, self.constant(&operands[1])?).len() as u64
+            }
+            _ => 4,
+        };
+        self.push(size, Item::Instruction { mnemonic, operands });
+        Ok(())
+    }
+
+    fn directive(&mut self, name: &str, operands: Vec<String>) -> Result<()> {
+        let size = match name {
+            ".text" => {
+                self.section = Section::Text;
+                return Ok(());
+            }
+            ".data" | ".rodata" | ".bss" => {
+                self.section = Section::Data;
+                return Ok(());
+            }
+            ".section" => {
+                let section = operands.first().ok_or_else(|| anyhow!(".section needs a name"))?;
+                self.section = if section.starts_with(".text") { Section::Text } else { Section::Data };
+                return Ok(());
+            }
+            // symbol visibility, types and assembler options don't matter for a statically placed image
+            ".globl" | ".global" | ".local" | ".type" | ".size" | ".option" | ".file" | ".ident" | ".attribute" => return Ok(()),
+            ".equ" | ".set" => {
+                expect_operands(&operands, 2)?;
+                let name = &operands[0];
+                if !is_identifier(name) || self.labels.iter().any(|(label, _, _)| label == name) {
+                    bail!("can't define {} as a constant", name);
+                }
+                let value = self.constant(&operands[1])?;
+                self.constants.insert(name.clone(), value);
+                return Ok(());
+            }
+            ".byte" => 1,
+            ".half" | ".short" | ".2byte" => 2,
+            ".word" | ".long" | ".4byte" => 4,
+            ".dword" | ".quad" | ".8byte" => 8,
+            ".string" | ".asciz" | ".ascii" => {
+                let mut bytes = Vec::new();
+                for operand in &operands {
+                    bytes.extend(parse_string(operand)?);
+                    if name != ".ascii" {
+                        bytes.push(0);
+                    }
+                }
+                self.push(bytes.len() as u64, Item::Bytes(bytes));
+                return Ok(());
+            }
+            ".zero" | ".space" | ".skip" => {
+                let (count, fill) = match operands.as_slice() {
+                    [count] => (self.constant(count)?, 0),
+                    [count, fill] => (self.constant(count)?, self.constant(fill)?),
+                    _ => bail!("{} takes a size and an optional fill byte", name),
+                };
+                let count = u64::try_from(count).map_err(|_| anyhow!("negative size {}", count))?;
+                self.push(count, Item::Bytes(vec![fill as u8; count as usize]));
+                return Ok(());
+            }
+            ".align" | ".p2align" | ".balign" => {
+                expect_operands(&operands, 1)?;
+                let value = self.constant(&operands[0])?;
+                let alignment = match name {
+                    ".balign" if value > 0 && (value as u64).is_power_of_two() => value as u64,
+                    ".balign" => bail!("alignment {} isn't a power of two", value),
+                    // on RISC-V, .align counts bits like .p2align
+                    _ if (0..16).contains(&value) => 1 << value,
+                    _ => bail!("alignment 2^{} is too large", value),
+                };
+                let offset = self.offset();
+                let padding = offset.next_multiple_of(alignment) - offset;
+                // pad code with nops so execution can fall through the gap
+                let bytes = if self.section == Section::Text && offset.is_multiple_of(4) {
+                    let mut bytes: Vec<u8> = NOP.to_le_bytes().repeat(padding as usize / 4);
+                    bytes.resize(padding as usize, 0);
+                    bytes
+                } else {
+                    vec![0; padding as usize]
+                };
+                self.push(padding, Item::Bytes(bytes));
+                return Ok(());
+            }
+            _ => bail!("unknown directive {}", name),
+        };
+        if operands.is_empty() {
+            bail!("{} needs at least one value", name);
+        }
+        self.push(size as u64 * operands.len() as u64, Item::Values { size, expressions: operands });
+        Ok(())
+    }
+
+    /// Replaces references like `1b` and `1f` with the unique names of the numeric labels they refer to.
+    fn rename_local_labels(&self, operand: &str) -> String {
+        if operand.starts_with('"') {
+            return operand.to_string();
+        }
+        let mut renamed = String::new();
+        let mut rest = operand;
+        while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+            let (before, from) = rest.split_at(start);
+            let length = from.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(from.len());
+            let (token, after) = from.split_at(length);
+            renamed.push_str(before);
+            // only whole tokens, so that hex digits and names like `a1b` are left alone
+            let standalone = !before.ends_with(|c: char| c.is_ascii_alphanumeric() || "_.$".contains(c));
+            match token.split_at(token.len() - 1) {
+                (number, direction @ ("b" | "f")) if standalone && !number.is_empty() && number.bytes().all(|c| c.is_ascii_digit()) => {
+                    let defined = self.local_labels.get(number).copied().unwrap_or(0);
+                    let index = if direction == "b" { defined.wrapping_sub(1) } else { defined };
+                    renamed.push_str(&local_label_name(number, index));
+                }
+                _ => renamed.push_str(token),
+            }
+            rest = after;
+        }
+        renamed.push_str(rest);
+        renamed
+    }
+
+    fn offset(&self) -> u64 {
+        self.sizes[self.section as usize]
+    }
+
+    fn push(&mut self, size: u64, item: Item) {
+        self.statements.push(Statement { line: self.line, section: self.section, offset: self.offset(), item });
+        self.sizes[self.section as usize] += size;
+    }
+
+    /// Evaluates an expression that may only use `.equ` constants, because its value decides the program's layout.
+    fn constant(&self, expression: &str) -> Result<i64> {
+        evaluate(expression, &|name| self.constants.get(name).copied())
+    }
+
+    fn finish(mut self, base: u64) -> Result<Program> {
+        let text_size = self.sizes[Section::Text as usize];
+        let data_address = (base + text_size).next_multiple_of(PAGE_SIZE);
+        let address = |section: Section, offset: u64| match section {
+            Section::Text => base + offset,
+            Section::Data => data_address + offset,
+        };
+        self.addresses = self.labels.iter().map(|(name, section, offset)| (name.clone(), address(*section, *offset))).collect();
+
+        let mut text = vec![0; text_size as usize];
+        let mut data = vec![0; self.sizes[Section::Data as usize] as usize];
+        for statement in std::mem::take(&mut self.statements) {
+            let pc = address(statement.section, statement.offset);
+            let bytes = self.encode_statement(statement.item, pc).with_context(|| format!("line {}", statement.line))?;
+            let output = if statement.section == Section::Text { &mut text } else { &mut data };
+            output[statement.offset as usize..][..bytes.len()].copy_from_slice(&bytes);
+        }
+
+        let symbols: Vec<(String, u64)> = self.labels.iter()
+            .filter(|(name, _, _)| !name.starts_with(".L"))
+            .map(|(name, _, _)| (name.clone(), self.addresses[name]))
+            .collect();
+        Ok(Program {
+            entry_point: self.addresses.get("_start").copied().unwrap_or(base),
+            text,
+            data,
+            text_address: base,
+            data_address,
+            symbols,
+        })
+    }
+
+    fn encode_statement(&mut self, item: Item, pc: u64) -> Result<Vec<u8>> {
+        match item {
+            Item::Bytes(bytes) => Ok(bytes),
+            Item::Values { size, expressions } => {
+                let mut bytes = Vec::new();
+                for expression in expressions {
+                    let value = self.value(&expression, pc)?;
+                    let bits = size as u32 * 8;
+                    if bits < 64 && (value < -(1 << (bits - 1)) || value >= 1 << bits) {
+                        bail!("{} doesn't fit in {} bytes", value, size);
+                    }
+                    bytes.extend_from_slice(&value.to_le_bytes()[..size]);
+                }
+                Ok(bytes)
+            }
+            Item::Instruction { mnemonic, operands } => {
+                let words = self.encode(&mnemonic, &operands, pc)
+                    .with_context(|| format!("{} {}", mnemonic, operands.join(", ")))?;
+                Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+            }
+        }
+    }
+
+    fn encode(&mut self, mnemonic: &str, operands: &[String], pc: u64) -> Result<Vec<u32>> {
+        let count = |expected: usize| expect_operands(operands, expected);
+        let x = |index: usize| register(&operands[index]);
+        let f = |index: usize| float_register(&operands[index]);
+
+        let word = match mnemonic {
+            "nop" => {
+                count(0)?;
+                NOP
+            }
+            "li" => {
+                count(2)?;
+                return Ok(load_immediate(x(0)?, self.constant(&operands[1])?));
+            }
+            "la" | "lla" | "call" | "tail" => {
+                let (rd, base, link, target) = match mnemonic {
+                    "call" if operands.len() == 1 => (RA, RA, RA, &operands[0]),
+                    "call" => {
+                        count(2)?;
+                        (x(0)?, x(0)?, x(0)?, &operands[1])
+                    }
+                    "tail" => {
+                        count(1)?;
+                        (T1, T1, 0, &operands[0])
+                    }
+                    _ => {
+                        count(2)?;
+                        (x(0)?, x(0)?, x(0)?, &operands[1])
+                    }
+                };
+                let (high, low) = split_offset(self.value(target, pc)?.wrapping_sub(pc as i64))?;
+                let second = match mnemonic {
+                    "la" | "lla" => i_type(OPCODE_OP_IMM, F3_ADD, link, base, low),
+                    _ => i_type(OPCODE_JALR, 0, link, base, low),
+                };
+                return Ok(vec![u_type(OPCODE_AUIPC, rd, high), second]);
+            }
+            "mv" | "not" | "neg" | "negw" | "sext.w" | "seqz" | "snez" | "sltz" | "sgtz" => {
+                count(2)?;
+                let (rd, rs) = (x(0)?, x(1)?);
+                match mnemonic {
+                    "mv" => i_type(OPCODE_OP_IMM, F3_ADD, rd, rs, 0),
+                    "not" => i_type(OPCODE_OP_IMM, F3_XOR, rd, rs, -1),
+                    "neg" => r_type(OPCODE_OP, F3_SUB, F7_SUB, rd, 0, rs),
+                    "negw" => r_type(OPCODE_OP_32, F3_SUB, F7_SUB, rd, 0, rs),
+                    "sext.w" => i_type(OPCODE_OP_IMM_32, F3_ADD, rd, rs, 0),
+                    "seqz" => i_type(OPCODE_OP_IMM, F3_SLTU, rd, rs, 1),
+                    "snez" => r_type(OPCODE_OP, F3_SLTU, F7_SLTU, rd, 0, rs),
+                    "sltz" => r_type(OPCODE_OP, F3_SLT, F7_SLT, rd, rs, 0),
+                    _ => r_type(OPCODE_OP, F3_SLT, F7_SLT, rd, 0, rs),
+                }
+            }
+            "beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz" => {
+                count(2)?;
+                let (rs, offset) = (x(0)?, self.target(&operands[1], pc)?);
+                match mnemonic {
+                    "beqz" => b_type(F3_BEQ, rs, 0, offset)?,
+                    "bnez" => b_type(F3_BNE, rs, 0, offset)?,
+                    "blez" => b_type(F3_BGE, 0, rs, offset)?,
+                    "bgez" => b_type(F3_BGE, rs, 0, offset)?,
+                    "bltz" => b_type(F3_BLT, rs, 0, offset)?,
+                    _ => b_type(F3_BLT, 0, rs, offset)?,
+                }
+            }
+            "bgt" | "ble" | "bgtu" | "bleu" => {
+                count(3)?;
+                let funct3 = match mnemonic {
+                    "bgt" => F3_BLT,
+                    "ble" => F3_BGE,
+                    "bgtu" => F3_BLTU,
+                    _ => F3_BGEU,
+                };
+                b_type(funct3, x(1)?, x(0)?, self.target(&operands[2], pc)?)?
+            }
+            "j" => {
+                count(1)?;
+                j_type(0, self.target(&operands[0], pc)?)?
+            }
+            "jal" if operands.len() == 1 => j_type(RA, self.target(&operands[0], pc)?)?,
+            "jal" => {
+                count(2)?;
+                j_type(x(0)?, self.target(&operands[1], pc)?)?
+            }
+            "ret" => {
+                count(0)?;
+                i_type(OPCODE_JALR, 0, 0, RA, 0)
+            }
+            "jr" => {
+                count(1)?;
+                i_type(OPCODE_JALR, 0, 0, x(0)?, 0)
+            }
+            "jalr" => match operands.len() {
+                1 if operands[0].ends_with(')') => {
+                    let (offset, base) = self.memory(&operands[0], pc)?;
+                    i_type(OPCODE_JALR, 0, RA, base, immediate(offset, 12)?)
+                }
+                1 => i_type(OPCODE_JALR, 0, RA, x(0)?, 0),
+                2 => {
+                    let (offset, base) = self.memory(&operands[1], pc)?;
+                    i_type(OPCODE_JALR, 0, x(0)?, base, immediate(offset, 12)?)
+                }
+                _ => {
+                    count(3)?;
+                    i_type(OPCODE_JALR, 0, x(0)?, x(1)?, immediate(self.value(&operands[2], pc)?, 12)?)
+                }
+            },
+            "lui" | "auipc" => {
+                count(2)?;
+                let value = self.value(&operands[1], pc)?;
+                if !(0..1 << 20).contains(&value) {
+                    bail!("immediate {} is out of range", value);
+                }
+                let opcode = if mnemonic == "lui" { OPCODE_LUI } else { OPCODE_AUIPC };
+                u_type(opcode, x(0)?, value)
+            }
+
+            "ecall" | "ebreak" | "mret" | "sret" | "wfi" | "unimp" | "fence.i" | "fence.tso" => {
+                count(0)?;
+                match mnemonic {
+                    "ecall" => i_type(OPCODE_SYSTEM, F3_ECALL_EBREAK, 0, 0, IMM_ECALL as i64),
+                    "ebreak" => i_type(OPCODE_SYSTEM, F3_ECALL_EBREAK, 0, 0, IMM_EBREAK as i64),
+                    "mret" => r_type(OPCODE_SYSTEM, F3_ECALL_EBREAK, F7_MRET, 0, 0, RS2_XRET as u32),
+                    "sret" => r_type(OPCODE_SYSTEM, F3_ECALL_EBREAK, F7_SRET, 0, 0, RS2_XRET as u32),
+                    "wfi" => r_type(OPCODE_SYSTEM, F3_ECALL_EBREAK, F7_WFI, 0, 0, RS2_WFI as u32),
+                    "unimp" => UNIMP,
+                    "fence.i" => FENCE_I,
+                    _ => FENCE_TSO,
+                }
+            }
+            "sfence.vma" => match operands.len() {
+                0 => r_type(OPCODE_SYSTEM, F3_ECALL_EBREAK, F7_SFENCE_VMA, 0, 0, 0),
+                1 => r_type(OPCODE_SYSTEM, F3_ECALL_EBREAK, F7_SFENCE_VMA, 0, x(0)?, 0),
+                _ => {
+                    count(2)?;
+                    r_type(OPCODE_SYSTEM, F3_ECALL_EBREAK, F7_SFENCE_VMA, 0, x(0)?, x(1)?)
+                }
+            },
+            "fence" => {
+                let (predecessor, successor) = match operands {
+                    [] => (0xF, 0xF),
+                    [predecessor, successor] => (fence_set(predecessor)?, fence_set(successor)?),
+                    _ => bail!("fence takes no operands or two"),
+                };
+                i_type(OPCODE_MISC_MEM, 0, 0, 0, (predecessor << 4 | successor) as i64)
+            }
+
+            "csrr" | "rdcycle" | "rdtime" | "rdinstret" | "frflags" | "frrm" | "frcsr" => {
+                let csr = match mnemonic {
+                    "csrr" => {
+                        count(2)?;
+                        csr(&operands[1])?
+                    }
+                    _ => {
+                        count(1)?;
+                        match mnemonic {
+                            "rdcycle" => CSR_CYCLE,
+                            "rdtime" => CSR_TIME,
+                            "rdinstret" => CSR_INSTRET,
+                            "frflags" => CSR_FFLAGS,
+                            "frrm" => CSR_FRM,
+                            _ => CSR_FCSR,
+                        }
+                    }
+                };
+                csr_type(F3_CSRRS, x(0)?, csr, 0)
+            }
+            "fsflags" | "fsrm" | "fscsr" => {
+                let csr = match mnemonic {
+                    "fsflags" => CSR_FFLAGS,
+                    "fsrm" => CSR_FRM,
+                    _ => CSR_FCSR,
+                };
+                match operands.len() {
+                    1 => csr_type(F3_CSRRW, 0, csr, x(0)?),
+                    _ => {
+                        count(2)?;
+                        csr_type(F3_CSRRW, x(0)?, csr, x(1)?)
+                    }
+                }
+            }
+            "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => {
+                count(2)?;
+                let funct3 = ["csrw", "csrs", "csrc", "", "csrwi", "csrsi", "csrci"].iter().position(|&name| name == mnemonic).unwrap() as i32 + 1;
+                let source = if funct3 >= F3_CSRRWI { csr_immediate(self.value(&operands[1], pc)?)? } else { x(1)? };
+                csr_type(funct3, 0, csr(&operands[0])?, source)
+            }
+            "csrrw" | "csrrs" | "csrrc" | "csrrwi" | "csrrsi" | "csrrci" => {
+                count(3)?;
+                let funct3 = ["csrrw", "csrrs", "csrrc", "", "csrrwi", "csrrsi", "csrrci"].iter().position(|&name| name == mnemonic).unwrap() as i32 + 1;
+                let source = if funct3 >= F3_CSRRWI { csr_immediate(self.value(&operands[2], pc)?)? } else { x(2)? };
+                csr_type(funct3, x(0)?, csr(&operands[1])?, source)
+            }
+
+            _ if LOADS.contains(&mnemonic) || FLOAT_LOADS.contains(&mnemonic) => {
+                count(2)?;
+                let (offset, base) = self.memory(&operands[1], pc)?;
+                let (opcode, funct3, rd) = match LOADS.iter().position(|&name| name == mnemonic) {
+                    Some(funct3) => (OPCODE_LOAD, funct3 as i32, x(0)?),
+                    None if mnemonic == "flw" => (OPCODE_LOAD_FP, F3_FLW, f(0)?),
+                    None => (OPCODE_LOAD_FP, F3_FLD, f(0)?),
+                };
+                i_type(opcode, funct3, rd, base, immediate(offset, 12)?)
+            }
+            _ if STORES.contains(&mnemonic) || FLOAT_STORES.contains(&mnemonic) => {
+                count(2)?;
+                let (offset, base) = self.memory(&operands[1], pc)?;
+                let (opcode, funct3, source) = match STORES.iter().position(|&name| name == mnemonic) {
+                    Some(funct3) => (OPCODE_STORE, funct3 as i32, x(0)?),
+                    None if mnemonic == "fsw" => (OPCODE_STORE_FP, F3_FSW, f(0)?),
+                    None => (OPCODE_STORE_FP, F3_FSD, f(0)?),
+                };
+                s_type(opcode, funct3, base, source, immediate(offset, 12)?)
+            }
+            _ if BRANCHES.contains(&mnemonic) => {
+                count(3)?;
+                let funct3 = BRANCHES.iter().position(|&name| name == mnemonic).unwrap() as i32;
+                b_type(funct3, x(0)?, x(1)?, self.target(&operands[2], pc)?)?
+            }
+            _ if mnemonic.starts_with("amo") || mnemonic.starts_with("lr.") || mnemonic.starts_with("sc.") => {
+                return Ok(vec![self.encode_atomic(mnemonic, operands)?]);
+            }
+            _ if mnemonic.starts_with('f') => return Ok(vec![encode_float(mnemonic, operands)?]),
+            _ => {
+                // the W forms operate on the low 32 bits
+                let (word, name) = match mnemonic.strip_suffix('w') {
+                    Some(name) if ["add", "sub", "sll", "srl", "sra", "mul", "div", "divu", "rem", "remu", "addi", "slli", "srli", "srai"].contains(&name) => (true, name),
+                    _ => (false, mnemonic),
+                };
+                let (operation_opcode, immediate_opcode) = if word { (OPCODE_OP_32, OPCODE_OP_IMM_32) } else { (OPCODE_OP, OPCODE_OP_IMM) };
+                let operation = |funct3: i32, funct7: i32| -> Result<u32> {
+                    count(3)?;
+                    Ok(r_type(operation_opcode, funct3, funct7, x(0)?, x(1)?, x(2)?))
+                };
+                if let Some(funct3) = OPERATIONS.iter().position(|&operation| operation == name) {
+                    operation(funct3 as i32, 0)?
+                } else if let Some(funct3) = MULTIPLY_DIVIDE.iter().position(|&operation| operation == name) {
+                    operation(funct3 as i32, F7_MULDIV)?
+                } else if name == "sub" {
+                    operation(F3_SUB, F7_SUB)?
+                } else if name == "sra" {
+                    operation(F3_SRA, F7_SRA)?
+                } else if name == "slli" || name == "srli" || name == "srai" {
+                    count(3)?;
+                    let shift = self.value(&operands[2], pc)?;
+                    if !(0..if word { 32 } else { 64 }).contains(&shift) {
+                        bail!("shift amount {} is out of range", shift);
+                    }
+                    let funct3 = if name == "slli" { F3_SLL } else { F3_SRL };
+                    let arithmetic = if name == "srai" { (F7_SRA as i64) << 5 } else { 0 };
+                    i_type(immediate_opcode, funct3, x(0)?, x(1)?, arithmetic | shift)
+                } else if let Some(funct3) = IMMEDIATE_OPERATIONS.iter().position(|&operation| operation == name) {
+                    count(3)?;
+                    i_type(immediate_opcode, funct3 as i32, x(0)?, x(1)?, immediate(self.value(&operands[2], pc)?, 12)?)
+                } else {
+                    bail!("unknown instruction {}", mnemonic);
+                }
+            }
+        };
+        Ok(vec![word])
+    }
+
+    fn encode_atomic(&self, mnemonic: &str, operands: &[String]) -> Result<u32> {
+        let mut parts = mnemonic.split('.');
+        let (name, width) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let ordering = match parts.next() {
+            None => 0,
+            Some("rl") => 1,
+            Some("aq") => 2,
+            Some("aqrl") => 3,
+            Some(_) => bail!("unknown instruction {}", mnemonic),
+        };
+        let funct5 = match name {
+            "lr" => F5_LR,
+            "sc" => F5_SC,
+            "amoswap" => F5_AMOSWAP,
+            "amoadd" => F5_AMOADD,
+            "amoxor" => F5_AMOXOR,
+            "amoand" => F5_AMOAND,
+            "amoor" => F5_AMOOR,
+            "amomin" => F5_AMOMIN,
+            "amomax" => F5_AMOMAX,
+            "amominu" => F5_AMOMINU,
+            "amomaxu" => F5_AMOMAXU,
+            _ => bail!("unknown instruction {}", mnemonic),
+        };
+        let funct3 = match width {
+            "w" => F3_AMO_W,
+            "d" => F3_AMO_D,
+            _ => bail!("unknown instruction {}", mnemonic),
+        };
+        let (rs2, address) = if funct5 == F5_LR {
+            expect_operands(operands, 2)?;
+            (0, &operands[1])
+        } else {
+            expect_operands(operands, 3)?;
+            (register(&operands[1])?, &operands[2])
+        };
+        let base = address.strip_prefix('(').and_then(|address| address.strip_suffix(')'))
+            .ok_or_else(|| anyhow!("expected (register), found {}", address))?;
+        Ok(r_type(OPCODE_AMO, funct3, funct5 << 2 | ordering, register(&operands[0])?, register(base.trim())?, rs2))
+    }
+
+    /// Evaluates an immediate operand, which may be a `%hi`, `%lo`, `%pcrel_hi` or `%pcrel_lo` relocation.
+    fn value(&mut self, operand: &str, pc: u64) -> Result<i64> {
+        // `.` is the address of the current instruction
+        let lookup = |name: &str| if name == "." { Some(pc as i64) } else { self.symbol(name) };
+        let Some(relocation) = operand.strip_prefix('%') else {
+            return evaluate(operand, &lookup);
+        };
+        let (function, argument) = relocation.split_once('(')
+            .and_then(|(function, argument)| Some((function.trim(), argument.strip_suffix(')')?)))
+            .ok_or_else(|| anyhow!("malformed relocation {}", operand))?;
+        let value = evaluate(argument, &lookup)?;
+        match function {
+            "hi" => Ok(split_offset(value)?.0),
+            "lo" => Ok(split_offset(value)?.1),
+            "pcrel_hi" => {
+                let offset = value.wrapping_sub(pc as i64);
+                self.pcrel_high.insert(pc, offset);
+                Ok(split_offset(offset)?.0)
+            }
+            // refers to the label on the auipc, whose offset this completes
+            "pcrel_lo" => match self.pcrel_high.get(&(value as u64)) {
+                Some(&offset) => Ok(split_offset(offset)?.1),
+                None => bail!("no %pcrel_hi at {}", argument),
+            },
+            _ => bail!("unknown relocation %{}", function),
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Option<i64> {
+        self.addresses.get(name).map(|&address| address as i64).or_else(|| self.constants.get(name).copied())
+    }
+
+    /// A branch or jump target: a label expression, or a number that's already the offset from `pc`.
+    fn target(&mut self, operand: &str, pc: u64) -> Result<i64> {
+        let value = self.value(operand, pc)?;
+        Ok(if operand.starts_with(|c: char| c.is_ascii_digit() || c == '-') { value } else { value.wrapping_sub(pc as i64) })
+    }
+
+    /// Parses `offset(register)`, where the offset is optional.
+    fn memory(&mut self, operand: &str, pc: u64) -> Result<(i64, u32)> {
+        let (offset, base) = operand.strip_suffix(')').and_then(|operand| operand.rsplit_once('('))
+            .ok_or_else(|| anyhow!("expected offset(register), found {}", operand))?;
+        let offset = if offset.trim().is_empty() { 0 } else { self.value(offset.trim(), pc)? };
+        Ok((offset, register(base.trim())?))
+    }
+}
+
+const LOADS: [&str; 7] = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"];
+const STORES: [&str; 4] = ["sb", "sh", "sw", "sd"];
+const FLOAT_LOADS: [&str; 2] = ["flw", "fld"];
+const FLOAT_STORES: [&str; 2] = ["fsw", "fsd"];
+const BRANCHES: [&str; 8] = ["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"];
+/// Indexed by funct3, like the rest of these tables.
+const OPERATIONS: [&str; 8] = ["add", "sll", "slt", "sltu", "xor", "srl", "or", "and"];
+const IMMEDIATE_OPERATIONS: [&str; 8] = ["addi", "", "slti", "sltiu", "xori", "", "ori", "andi"];
+const MULTIPLY_DIVIDE: [&str; 8] = ["mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu"];
+const INTEGER_TYPES: [&str; 4] = ["w", "wu", "l", "lu"];
+
+fn encode_float(mnemonic: &str, operands: &[String]) -> Result<u32> {
+    let x = |index: usize| register(&operands[index]);
+    let f = |index: usize| float_register(&operands[index]);
+    let unknown = || anyhow!("unknown instruction {}", mnemonic);
+    // a trailing rounding mode operand is optional
+    let with_rounding = |count: usize, default: i32| -> Result<i32> {
+        if operands.len() == count + 1 {
+            let mode = operands[count].as_str();
+            match ROUNDING_MODES.iter().position(|&name| name == mode) {
+                Some(index) => Ok(index as i32),
+                None if mode == "dyn" => Ok(F3_RM_DYNAMIC),
+                None => bail!("unknown rounding mode {}", mode),
+            }
+        } else {
+            expect_operands(operands, count)?;
+            Ok(default)
+        }
+    };
+
+    let parts: Vec<&str> = mnemonic.split('.').collect();
+    if parts[0] == "fcvt" && parts.len() == 3 {
+        let format = |name: &str| match name {
+            "s" => Some(FMT_S),
+            "d" => Some(FMT_D),
+            _ => None,
+        };
+        let integer = |name: &str| INTEGER_TYPES.iter().position(|&integer| integer == name).map(|index| index as i32);
+        return match (format(parts[1]), format(parts[2]), integer(parts[1]), integer(parts[2])) {
+            // widening is exact, so the rounding mode field is left at zero
+            (Some(FMT_D), Some(FMT_S), _, _) => Ok(float_type(F5_FCVT_FMT_FMT, FMT_D, with_rounding(2, 0)?, f(0)?, f(1)?, FMT_S as u32)),
+            (Some(FMT_S), Some(FMT_D), _, _) => Ok(float_type(F5_FCVT_FMT_FMT, FMT_S, with_rounding(2, F3_RM_DYNAMIC)?, f(0)?, f(1)?, FMT_D as u32)),
+            (None, Some(fmt), Some(integer), None) => Ok(float_type(F5_FCVT_INT_FMT, fmt, with_rounding(2, F3_RM_DYNAMIC)?, x(0)?, f(1)?, integer as u32)),
+            (Some(fmt), None, None, Some(integer)) => {
+                let default = if fmt == FMT_D && integer <= RS2_FCVT_WU { 0 } else { F3_RM_DYNAMIC };
+                Ok(float_type(F5_FCVT_FMT_INT, fmt, with_rounding(2, default)?, f(0)?, x(1)?, integer as u32))
+            }
+            _ => Err(unknown()),
+        };
+    }
+    match parts.as_slice() {
+        ["fmv", "x", "w"] | ["fmv", "x", "d"] => {
+            expect_operands(operands, 2)?;
+            let fmt = if parts[2] == "w" { FMT_S } else { FMT_D };
+            return Ok(float_type(F5_FMV_X_FCLASS, fmt, F3_FMV_X, x(0)?, f(1)?, 0));
+        }
+        ["fmv", "w", "x"] | ["fmv", "d", "x"] => {
+            expect_operands(operands, 2)?;
+            let fmt = if parts[1] == "w" { FMT_S } else { FMT_D };
+            return Ok(float_type(F5_FMV_FMT_X, fmt, 0, f(0)?, x(1)?, 0));
+        }
+        _ => {}
+    }
+
+    let [name, suffix] = parts.as_slice() else { return Err(unknown()) };
+    let fmt = match *suffix {
+        "s" => FMT_S,
+        "d" => FMT_D,
+        _ => return Err(unknown()),
+    };
+    let three = |funct5: i32, funct3: i32| -> Result<u32> {
+        expect_operands(operands, 3)?;
+        Ok(float_type(funct5, fmt, funct3, f(0)?, f(1)?, f(2)?))
+    };
+    let two = |funct5: i32, funct3: i32| -> Result<u32> {
+        expect_operands(operands, 2)?;
+        Ok(float_type(funct5, fmt, funct3, f(0)?, f(1)?, f(1)?))
+    };
+    match *name {
+        "fadd" | "fsub" | "fmul" | "fdiv" => {
+            let funct5 = ["fadd", "fsub", "fmul", "fdiv"].iter().position(|operation| operation == name).unwrap() as i32;
+            let rounding = with_rounding(3, F3_RM_DYNAMIC)?;
+            Ok(float_type(funct5, fmt, rounding, f(0)?, f(1)?, f(2)?))
+        }
+        "fsqrt" => {
+            let rounding = with_rounding(2, F3_RM_DYNAMIC)?;
+            Ok(float_type(F5_FSQRT, fmt, rounding, f(0)?, f(1)?, 0))
+        }
+        "fmadd" | "fmsub" | "fnmsub" | "fnmadd" => {
+            let opcode = match *name {
+                "fmadd" => OPCODE_MADD,
+                "fmsub" => OPCODE_MSUB,
+                "fnmsub" => OPCODE_NMSUB,
+                _ => OPCODE_NMADD,
+            };
+            let rounding = with_rounding(4, F3_RM_DYNAMIC)?;
+            Ok(r_type(opcode, rounding, fmt, f(0)?, f(1)?, f(2)?) | f(3)? << 27)
+        }
+        "fsgnj" => three(F5_FSGNJ, F3_FSGNJ),
+        "fsgnjn" => three(F5_FSGNJ, F3_FSGNJN),
+        "fsgnjx" => three(F5_FSGNJ, F3_FSGNJX),
+        "fmv" => two(F5_FSGNJ, F3_FSGNJ),
+        "fneg" => two(F5_FSGNJ, F3_FSGNJN),
+        "fabs" => two(F5_FSGNJ, F3_FSGNJX),
+        "fmin" => three(F5_FMINMAX, F3_FMIN),
+        "fmax" => three(F5_FMINMAX, F3_FMAX),
+        "feq" | "flt" | "fle" => {
+            expect_operands(operands, 3)?;
+            let funct3 = ["fle", "flt", "feq"].iter().position(|comparison| comparison == name).unwrap() as i32;
+            Ok(float_type(F5_FCMP, fmt, funct3, x(0)?, f(1)?, f(2)?))
+        }
+        "fclass" => {
+            expect_operands(operands, 2)?;
+            Ok(float_type(F5_FMV_X_FCLASS, fmt, F3_FCLASS, x(0)?, f(1)?, 0))
+        }
+        _ => Err(unknown()),
+    }
+}
+
+/// The instructions that put `value` in `rd`, the same sequence LLVM picks: `lui` and `addiw` for 32-bit values,
+/// and for wider ones the upper bits loaded recursively, shifted into place and the low 12 bits added. Positive
+/// values may instead be built shifted left and moved back into place with a final `srli`, if that's shorter.
+fn load_immediate(rd: u32, value: i64) -> Vec<u32> {
+    let mut sequence = shifted_load_immediate(rd, value);
+    if value > 0 && sequence.len() > 2 {
+        let leading_zeros = value.leading_zeros();
+        let shifted = value << leading_zeros;
+        // the bits shifted back in can be ones, which suits masks, or zeros
+        for candidate in [shifted | ((1 << leading_zeros) - 1), shifted] {
+            let mut alternative = shifted_load_immediate(rd, candidate);
+            alternative.push(i_type(OPCODE_OP_IMM, F3_SRL, rd, rd, leading_zeros as i64));
+            if alternative.len() < sequence.len() {
+                sequence = alternative;
+            }
+        }
+    }
+    sequence
+}
+
+fn shifted_load_immediate(rd: u32, value: i64) -> Vec<u32> {
+    let low = value << 52 >> 52;
+    if value == value as i32 as i64 {
+        let high = (value - low) >> 12 & 0xFFFFF;
+        return match (high, low) {
+            (0, _) => vec![i_type(OPCODE_OP_IMM, F3_ADD, rd, 0, low)],
+            (_, 0) => vec![u_type(OPCODE_LUI, rd, high)],
+            _ => vec![u_type(OPCODE_LUI, rd, high), i_type(OPCODE_OP_IMM_32, F3_ADD, rd, rd, low)],
+        };
+    }
+    let high = value.wrapping_sub(low) >> 12;
+    let mut shift = high.trailing_zeros() + 12;
+    let mut high = high >> (shift - 12);
+    // shifting 12 bits less lets lui supply the upper bits, if they don't fit addi
+    if shift > 12 && high != high << 52 >> 52 && high << 12 == (high << 12) as i32 as i64 {
+        shift -= 12;
+        high <<= 12;
+    }
+    let mut sequence = shifted_load_immediate(rd, high);
+    sequence.push(i_type(OPCODE_OP_IMM, F3_SLL, rd, rd, shift as i64));
+    if low != 0 {
+        sequence.push(i_type(OPCODE_OP_IMM, F3_ADD, rd, rd, low));
+    }
+    sequence
+}
+
+/// Splits a 32-bit offset into the `auipc`/`lui` immediate and the sign-extended 12 bits added to it.
+fn split_offset(offset: i64) -> Result<(i64, i64)> {
+    if offset != offset as i32 as i64 {
+        bail!("offset {:#x} is out of range", offset);
+    }
+    let low = offset << 52 >> 52;
+    Ok(((offset - low) >> 12 & 0xFFFFF, low))
+}
+
+fn immediate(value: i64, bits: u32) -> Result<i64> {
+    if value < -(1 << (bits - 1)) || value >= 1 << (bits - 1) {
+        bail!("immediate {} is out of range", value);
+    }
+    Ok(value)
+}
+
+fn csr_immediate(value: i64) -> Result<u32> {
+    if !(0..32).contains(&value) {
+        bail!("immediate {} is out of range", value);
+    }
+    Ok(value as u32)
+}
+
+fn r_type(opcode: i32, funct3: i32, funct7: i32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    (funct7 as u32) << 25 | rs2 << 20 | rs1 << 15 | (funct3 as u32) << 12 | rd << 7 | opcode as u32
+}
+
+fn i_type(opcode: i32, funct3: i32, rd: u32, rs1: u32, immediate: i64) -> u32 {
+    (immediate as u32) << 20 | rs1 << 15 | (funct3 as u32) << 12 | rd << 7 | opcode as u32
+}
+
+fn s_type(opcode: i32, funct3: i32, rs1: u32, rs2: u32, immediate: i64) -> u32 {
+    let immediate = immediate as u32;
+    (immediate >> 5 & 0x7F) << 25 | rs2 << 20 | rs1 << 15 | (funct3 as u32) << 12 | (immediate & 0x1F) << 7 | opcode as u32
+}
+
+fn b_type(funct3: i32, rs1: u32, rs2: u32, offset: i64) -> Result<u32> {
+    if offset % 2 != 0 || immediate(offset, 13).is_err() {
+        bail!("branch offset {} is out of range", offset);
+    }
+    let offset = offset as u32;
+    let immediate = (offset >> 12 & 1) << 31 | (offset >> 5 & 0x3F) << 25 | (offset >> 1 & 0xF) << 8 | (offset >> 11 & 1) << 7;
+    Ok(immediate | rs2 << 20 | rs1 << 15 | (funct3 as u32) << 12 | OPCODE_BRANCH as u32)
+}
+
+fn u_type(opcode: i32, rd: u32, immediate: i64) -> u32 {
+    (immediate as u32) << 12 | rd << 7 | opcode as u32
+}
+
+fn j_type(rd: u32, offset: i64) -> Result<u32> {
+    if offset % 2 != 0 || immediate(offset, 21).is_err() {
+        bail!("jump offset {} is out of range", offset);
+    }
+    let offset = offset as u32;
+    let immediate = (offset >> 20 & 1) << 31 | (offset >> 1 & 0x3FF) << 21 | (offset >> 11 & 1) << 20 | (offset >> 12 & 0xFF) << 12;
+    Ok(immediate | rd << 7 | OPCODE_JAL as u32)
+}
+
+fn csr_type(funct3: i32, rd: u32, csr: u64, source: u32) -> u32 {
+    (csr as u32) << 20 | source << 15 | (funct3 as u32) << 12 | rd << 7 | OPCODE_SYSTEM as u32
+}
+
+fn float_type(funct5: i32, fmt: i32, funct3: i32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r_type(OPCODE_OP_FP, funct3, funct5 << 2 | fmt, rd, rs1, rs2)
+}
+
+fn register(operand: &str) -> Result<u32> {
+    parse_register(operand).map(|index| index as u32).ok_or_else(|| anyhow!("expected a register, found {}", operand))
+}
+
+fn float_register(operand: &str) -> Result<u32> {
+    parse_float_register(operand).map(|index| index as u32)
+        .ok_or_else(|| anyhow!("expected a floating-point register, found {}", operand))
+}
+
+/// A CSR by name or number.
+fn csr(operand: &str) -> Result<u64> {
+    if let Some(id) = (0..4096).find(|&id| csr_name(id).as_deref() == Some(operand)) {
+        return Ok(id);
+    }
+    let id = evaluate(operand, &|_| None).map_err(|_| anyhow!("unknown CSR {}", operand))?;
+    u64::try_from(id).ok().filter(|&id| id < 4096).ok_or_else(|| anyhow!("CSR number {} is out of range", id))
+}
+
+/// Parses the `iorw` device input, device output, memory read and memory write set of a fence.
+fn fence_set(operand: &str) -> Result<u32> {
+    if operand == "0" {
+        return Ok(0);
+    }
+    operand.chars().try_fold(0, |set, c| match "wroi".find(c) {
+        Some(bit) if set & 1 << bit == 0 => Ok(set | 1 << bit),
+        _ => Err(anyhow!("invalid fence operand {}", operand)),
+    })
+}
+
+fn expect_operands(operands: &[String], count: usize) -> Result<()> {
+    if operands.len() != count {
+        bail!("expected {} operands, found {}", count, operands.len());
+    }
+    Ok(())
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits operands on the commas outside parentheses and string literals.
+fn split_operands(text: &str) -> Vec<String> {
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let (mut depth, mut quoted, mut escaped) = (0, false, false);
+    for c in text.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() || !operands.is_empty() {
+        operands.push(current.trim().to_string());
+    }
+    operands
+}
+
+fn is_label(text: &str) -> bool {
+    is_identifier(text) || !text.is_empty() && text.bytes().all(|c| c.is_ascii_digit())
+}
+
+/// An assembler temporary name for the `index`th definition of the numeric label `number`.
+fn local_label_name(number: &str, index: usize) -> String {
+    format!(".L{}${}", number, index)
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+}
+
+fn parse_string(operand: &str) -> Result<Vec<u8>> {
+    let text = operand.strip_prefix('"').and_then(|text| text.strip_suffix('"'))
+        .ok_or_else(|| anyhow!("expected a string, found {}", operand))?;
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('x') => {
+                let digits: String = chars.clone().take_while(char::is_ascii_hexdigit).take(2).collect();
+                chars.nth(digits.len().saturating_sub(1));
+                u8::from_str_radix(&digits, 16).map_err(|_| anyhow!("invalid escape in {}", operand))?
+            }
+            _ => bail!("invalid escape in {}", operand),
+        });
+    }
+    Ok(bytes)
+}
+
+/// Binary operators from the loosest binding to the tightest.
+const BINARY_OPERATORS: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+/// Evaluates an integer expression of numbers, character literals and symbols with C operators and parentheses.
+fn evaluate(text: &str, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<i64> {
+    let mut parser = ExpressionParser { text: text.as_bytes(), position: 0, lookup };
+    let value = parser.binary(0)?;
+    parser.skip_whitespace();
+    if parser.position != text.len() {
+        bail!("unexpected {:?} in {:?}", &text[parser.position..], text);
+    }
+    Ok(value)
+}
+
+struct ExpressionParser<'a> {
+    text: &'a [u8],
+    position: usize,
+    lookup: &'a dyn Fn(&str) -> Option<i64>,
+}
+
+impl ExpressionParser<'_> {
+    fn binary(&mut self, level: usize) -> Result<i64> {
+        if level == BINARY_OPERATORS.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1)?;
+        loop {
+            self.skip_whitespace();
+            let rest = &self.text[self.position..];
+            let Some(&operator) = BINARY_OPERATORS[level].iter().find(|operator| rest.starts_with(operator.as_bytes())) else {
+                return Ok(value);
+            };
+            self.position += operator.len();
+            let right = self.binary(level + 1)?;
+            value = match operator {
+                "|" => value | right,
+                "^" => value ^ right,
+                "&" => value & right,
+                "<<" => value.wrapping_shl(right as u32),
+                ">>" => value.wrapping_shr(right as u32),
+                "+" => value.wrapping_add(right),
+                "-" => value.wrapping_sub(right),
+                "*" => value.wrapping_mul(right),
+                _ if right == 0 => bail!("division by zero"),
+                "/" => value.wrapping_div(right),
+                _ => value.wrapping_rem(right),
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64> {
+        self.skip_whitespace();
+        let start = self.position;
+        let Some(&next) = self.text.get(start) else { bail!("expression ends early") };
+        self.position += 1;
+        match next {
+            b'-' => Ok(self.unary()?.wrapping_neg()),
+            b'~' => Ok(!self.unary()?),
+            b'+' => self.unary(),
+            b'(' => {
+                let value = self.binary(0)?;
+                self.skip_whitespace();
+                if self.text.get(self.position) != Some(&b')') {
+                    bail!("missing )");
+                }
+                self.position += 1;
+                Ok(value)
+            }
+            b'\'' => {
+                let (value, length) = match self.text[self.position..] {
+                    [b'\\', b'n', b'\'', ..] => (b'\n', 3),
+                    [b'\\', b't', b'\'', ..] => (b'\t', 3),
+                    [b'\\', b'0', b'\'', ..] => (0, 3),
+                    [b'\\', c, b'\'', ..] => (c, 3),
+                    [c, b'\'', ..] => (c, 2),
+                    _ => bail!("malformed character literal"),
+                };
+                self.position += length;
+                Ok(value as i64)
+            }
+            _ => {
+                while self.text.get(self.position).is_some_and(|&c| c.is_ascii_alphanumeric() || b"_.$".contains(&c)) {
+                    self.position += 1;
+                }
+                let token = std::str::from_utf8(&self.text[start..self.position]).unwrap_or_default();
+                if next.is_ascii_digit() {
+                    parse_integer(token)
+                } else if token.is_empty() || !is_identifier(token) {
+                    bail!("unexpected {:?}", next as char)
+                } else {
+                    (self.lookup)(token).ok_or_else(|| anyhow!("unknown symbol {}", token))
+                }
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.position).is_some_and(u8::is_ascii_whitespace) {
+            self.position += 1;
+        }
+    }
+}
+
+/// Decimal, `0x` hexadecimal or `0b` binary, up to 64 bits unsigned.
+fn parse_integer(token: &str) -> Result<i64> {
+    let lowercase = token.to_lowercase();
+    let parsed = if let Some(hex) = lowercase.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(binary) = lowercase.strip_prefix("0b") {
+        u64::from_str_radix(binary, 2)
+    } else {
+        lowercase.parse()
+    };
+    parsed.map(|value| value as i64).map_err(|_| anyhow!("invalid number {}", token))
+}
+
+impl Program {
+    /// The program as it's laid out in memory from `text_address`, for `--raw-binary`. Execution has to start at
+    /// the beginning of `.text`.
+    pub fn flat_image(&self) -> Vec<u8> {
+        let mut image = self.text.clone();
+        if !self.data.is_empty() {
+            image.resize((self.data_address - self.text_address) as usize, 0);
+            image.extend_from_slice(&self.data);
+        }
+        image
+    }
+
+    /// A statically linked executable with a segment each for `.text` and `.data`, and a symbol table.
+    pub fn elf(&self) -> Vec<u8> {
+        const HEADER_SIZE: u64 = 64;
+        const PROGRAM_HEADER_SIZE: u64 = 56;
+        const SECTION_HEADER_SIZE: u64 = 64;
+        const SYMBOL_SIZE: u64 = 24;
+
+        let has_data = !self.data.is_empty();
+        // file offsets match the addresses modulo the page size, as loaders expect
+        let text_offset = PAGE_SIZE;
+        let data_offset = (text_offset + self.text.len() as u64).next_multiple_of(PAGE_SIZE);
+        let mut file = vec![0u8; text_offset as usize];
+        file.extend_from_slice(&self.text);
+        if has_data {
+            file.resize(data_offset as usize, 0);
+            file.extend_from_slice(&self.data);
+        }
+
+        let data_section = if has_data { 2 } else { 1 };
+        let mut strings = vec![0u8];
+        let mut symbol_table = vec![0u8; SYMBOL_SIZE as usize];
+        for (name, address) in &self.symbols {
+            let section: u16 = if has_data && *address >= self.data_address { data_section } else { 1 };
+            symbol_table.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+            symbol_table.push(STB_GLOBAL << 4 | STT_NOTYPE);
+            symbol_table.push(0);
+            symbol_table.extend_from_slice(&section.to_le_bytes());
+            symbol_table.extend_from_slice(&address.to_le_bytes());
+            symbol_table.extend_from_slice(&0u64.to_le_bytes());
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+        let section_names = b"\0.text\0.data\0.symtab\0.strtab\0.shstrtab\0";
+
+        let symbol_table_offset = (file.len() as u64).next_multiple_of(8);
+        file.resize(symbol_table_offset as usize, 0);
+        file.extend_from_slice(&symbol_table);
+        let strings_offset = file.len() as u64;
+        file.extend_from_slice(&strings);
+        let section_names_offset = file.len() as u64;
+        file.extend_from_slice(section_names);
+        let section_headers_offset = (file.len() as u64).next_multiple_of(8);
+        file.resize(section_headers_offset as usize, 0);
+
+        // name, type, flags, address, offset, size, link, info, alignment, entry size
+        let mut sections = vec![
+            [0; 10],
+            [1, SHT_PROGBITS as u64, (SHF_ALLOC | SHF_EXECINSTR) as u64, self.text_address, text_offset, self.text.len() as u64, 0, 0, 4, 0],
+        ];
+        if has_data {
+            sections.push([7, SHT_PROGBITS as u64, (SHF_ALLOC | SHF_WRITE) as u64, self.data_address, data_offset, self.data.len() as u64, 0, 0, 8, 0]);
+        }
+        let strings_index = sections.len() as u64 + 1;
+        sections.push([13, SHT_SYMTAB as u64, 0, 0, symbol_table_offset, symbol_table.len() as u64, strings_index, 1, 8, SYMBOL_SIZE]);
+        sections.push([21, SHT_STRTAB as u64, 0, 0, strings_offset, strings.len() as u64, 0, 0, 1, 0]);
+        sections.push([29, SHT_STRTAB as u64, 0, 0, section_names_offset, section_names.len() as u64, 0, 0, 1, 0]);
+        for [name, kind, flags, address, offset, size, link, info, alignment, entry_size] in &sections {
+            file.extend_from_slice(&(*name as u32).to_le_bytes());
+            file.extend_from_slice(&(*kind as u32).to_le_bytes());
+            for value in [flags, address, offset, size] {
+                file.extend_from_slice(&value.to_le_bytes());
+            }
+            file.extend_from_slice(&(*link as u32).to_le_bytes());
+            file.extend_from_slice(&(*info as u32).to_le_bytes());
+            file.extend_from_slice(&alignment.to_le_bytes());
+            file.extend_from_slice(&entry_size.to_le_bytes());
+        }
+
+        let mut segments = vec![(PF_R | PF_X, text_offset, self.text_address, self.text.len() as u64)];
+        if has_data {
+            segments.push((PF_R | PF_W, data_offset, self.data_address, self.data.len() as u64));
+        }
+        let mut header = Vec::new();
+        header.extend_from_slice(&[0x7F, b'E', b'L', b'F', ELFCLASS64, ELFDATA2LSB, EV_CURRENT, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        header.extend_from_slice(&ET_EXEC.to_le_bytes());
+        header.extend_from_slice(&EM_RISCV.to_le_bytes());
+        header.extend_from_slice(&(EV_CURRENT as u32).to_le_bytes());
+        for value in [self.entry_point, HEADER_SIZE, section_headers_offset] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header.extend_from_slice(&0u32.to_le_bytes());
+        let section_names_index = sections.len() as u16 - 1;
+        for half in [HEADER_SIZE as u16, PROGRAM_HEADER_SIZE as u16, segments.len() as u16, SECTION_HEADER_SIZE as u16, sections.len() as u16, section_names_index] {
+            header.extend_from_slice(&half.to_le_bytes());
+        }
+        for (flags, offset, address, size) in segments {
+            header.extend_from_slice(&PT_LOAD.to_le_bytes());
+            header.extend_from_slice(&flags.to_le_bytes());
+            for value in [offset, address, address, size, size, PAGE_SIZE] {
+                header.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        file[..header.len()].copy_from_slice(&header);
+        file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, Ram, DRAM_BASE};
+    use crate::loader;
+    use crate::machine::{Cpu, Machine};
+
+    fn words(source: &str) -> Vec<u32> {
+        let program = assemble(source, DRAM_BASE).unwrap();
+        program.text.chunks(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect()
+    }
+
+    fn error(source: &str) -> String {
+        format!("{:#}", assemble(source, DRAM_BASE).unwrap_err())
+    }
+
+    #[test]
+    fn test_encodings() {
+        let cases: [(&str, &[u32]); 21] = [
+            ("addi x1, x0, 3", &[0x00300093]),
+            ("li a0, 0x123456789abcdef0", &[0x00247537, 0x8ad5051b, 0x00e51513, 0xc4d50513, 0x00c51513, 0x5e750513, 0x00d51513, 0xef050513]),
+            ("li t0, -1", &[0xfff00293]),
+            ("srai a0, a1, 63", &[0x43f5d513]),
+            ("addiw sp, sp, -16", &[0xff01011b]),
+            ("sd ra, 8(sp)", &[0x00113423]),
+            ("lw a0, -4(s0)", &[0xffc42503]),
+            ("amoswap.d.aq a0, a1, (a2)", &[0x0cb6352f]),
+            ("lr.w t0, (a0)", &[0x100522af]),
+            ("fmadd.d fa0, fa1, fa2, fa3, rtz", &[0x6ac59543]),
+            ("fcvt.d.w fa0, a0", &[0xd2050553]),
+            ("fcvt.l.s a0, fa0, rup", &[0xc0253553]),
+            ("fmv.x.d a0, fa0", &[0xe2050553]),
+            ("csrrwi a0, mtvec, 31", &[0x305fd573]),
+            ("csrw satp, a0", &[0x18051073]),
+            ("fence r, rw", &[0x0230000f]),
+            ("sfence.vma a0", &[0x12050073]),
+            ("lui a0, %hi(0x12345fff)\naddi a0, a0, %lo(0x12345fff)", &[0x12346537, 0xfff50513]),
+            ("loop: bnez a0, loop\nj loop", &[0x00051063, 0xffdff06f]),
+            ("1: j 1f\n1: j 1b\nj 1b", &[0x0040006f, 0x0000006f, 0xffdff06f]),
+            (".equ SIZE, 4 * 8\naddi sp, sp, -SIZE # frame", &[0xfe010113]),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(words(source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_run_program() {
+        let source = "
+            .text
+            .globl _start
+        _start:
+            la a0, message
+            lbu a1, 1(a0)
+            call twice
+        .Lvalue:
+            auipc t0, %pcrel_hi(value)
+            ld t1, %pcrel_lo(.Lvalue)(t0)
+            j .
+        twice:
+            slli a1, a1, 1
+            ret
+
+            .data
+        message:
+            .string \"hi\\n\"
+            .align 3
+        value:
+            .dword 0x1122334455667788
+        table:
+            .word _start, twice
+        ";
+        let program = assemble(source, DRAM_BASE).unwrap();
+        assert_eq!(program.data_address, DRAM_BASE + PAGE_SIZE);
+        assert_eq!(program.symbols[0], ("_start".to_string(), DRAM_BASE));
+        assert!(!program.symbols.iter().any(|(name, _)| name == ".Lvalue"));
+        assert_eq!(&program.data[..4], b"hi\n\0");
+        assert_eq!(program.data[16..24], (DRAM_BASE as u32).to_le_bytes().into_iter().chain((DRAM_BASE as u32 + 0x20).to_le_bytes()).collect::<Vec<_>>()[..]);
+        assert_eq!(program.flat_image().len() as u64, PAGE_SIZE + 24);
+
+        let machine = Machine::new();
+        let mut bus = Bus::new();
+        bus.map(DRAM_BASE, 0x4000, Ram::new(0x4000));
+        let elf = program.elf();
+        let loaded = loader::load_elf_file(&mut bus, &elf).unwrap();
+        assert_eq!(loader::find_symbol(&elf, "value"), Some(DRAM_BASE + PAGE_SIZE + 8));
+        let mut cpu = Cpu::new(&machine);
+        cpu.pc = loaded.entry_point.virtual_address();
+        for _ in 0..10 {
+            cpu.step(&mut bus).unwrap();
+        }
+        assert_eq!(cpu.pc, DRAM_BASE + 0x1c);
+        assert_eq!(cpu.registers[10], DRAM_BASE + PAGE_SIZE);
+        assert_eq!(cpu.registers[11], 2 * b'i' as u64);
+        assert_eq!(cpu.registers[6], 0x1122334455667788);
+    }
+
+    #[test]
+    fn test_foo() {
+        let program = assemble(include_str!("../foo.s"), DRAM_BASE).unwrap();
+        let machine = Machine::new();
+        let mut bus = Bus::new();
+        bus.map(DRAM_BASE, 0x2000, Ram::new(0x2000));
+        bus.store_bytes(DRAM_BASE, &program.flat_image()).unwrap();
+        let mut cpu = Cpu::new(&machine);
+        cpu.pc = DRAM_BASE;
+        for _ in 0..7 {
+            cpu.step(&mut bus).unwrap();
+        }
+        assert_eq!(cpu.registers[1..8], [3, u64::MAX, 666, 2, 4, -667i64 as u64, 666 >> 3]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(error("nop\nfrob a0").contains("line 2: frob a0: unknown instruction frob"));
+        assert!(error("add a0, a1, q7").contains("expected a register, found q7"));
+        assert!(error("add a0, a1").contains("expected 3 operands, found 2"));
+        assert!(error("beqz a0, far\n.zero 4096\nfar:").contains("branch offset 4100 is out of range"));
+        assert!(error("x:\nx:").contains("line 2: x is defined twice"));
+        assert!(error("j nowhere").contains("unknown symbol nowhere"));
+        assert!(error("li a0, label\nlabel:").contains("line 1: unknown symbol label"));
+        assert!(error(".data\nnop").contains("instructions must be in .text"));
+        assert!(error(".byte 256").contains("256 doesn't fit in 1 bytes"));
+    }
+}
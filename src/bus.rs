@@ -1,62 +1,432 @@
-﻿#[derive(Debug)]
+﻿use std::collections::HashSet;
+use std::ops::Range;
+
+use anyhow::{bail, Context, Result};
+
+use crate::mmu::PAGE_SIZE;
+use crate::snapshot::{Reader, Writer};
+
+/// Where RAM starts on the machines we emulate, matching QEMU's virt board and spike.
+pub const DRAM_BASE: u64 = 0x8000_0000;
+
+/// Returned when nothing is mapped at an address, or the device there refuses the access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessFault {
+    pub address: u64,
+}
+
+/// Interrupt wires between devices and the hart, updated as devices tick.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InterruptLines {
+    /// Platform timer value. The bus sets it from the machine clock and the CLINT adjusts it to its `mtime`.
+    pub mtime: u64,
+    pub msip: bool,
+    pub mtip: bool,
+    pub meip: bool,
+    pub seip: bool,
+    /// Level of every external interrupt source, indexed by its interrupt controller source number.
+    pub sources: u64,
+}
+
+impl InterruptLines {
+    pub fn set_source(&mut self, source: u32, level: bool) {
+        if level {
+            self.sources |= 1 << source;
+        } else {
+            self.sources &= !(1 << source);
+        }
+    }
+}
+
+/// A physical address range a debugger wants to hear about accesses to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: Range<u64>,
+    pub loads: bool,
+    pub stores: bool,
+}
+
+/// Anything that can be mapped into the physical address space.
+///
+/// Accesses are 1, 2, 4 or 8 bytes wide and the offset is relative to the start of the mapping.
+/// Returning `None` makes the access fault.
+pub trait Device {
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64>;
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Option<()>;
+
+    /// Advances the device by one step, letting it update the interrupt lines it drives.
+    fn tick(&mut self, _interrupts: &mut InterruptLines) {}
+
+    /// Set once the guest has asked to exit through this device.
+    fn exit_code(&self) -> Option<i32> {
+        None
+    }
+
+    /// Backing storage for memory-like devices, used for bulk copies.
+    fn memory(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    /// Appends the device's registers to a snapshot. Memory contents are saved by the bus instead.
+    fn save(&self, _snapshot: &mut Writer) {}
+
+    /// Reads back what `save` wrote.
+    fn restore(&mut self, _snapshot: &mut Reader) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Ram {
+    data: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Ram {
+        Ram { data: vec![0; size] }
+    }
+}
+
+impl Device for Ram {
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        let index = offset as usize;
+        let bytes = self.data.get(index..index + size as usize)?;
+        let mut buffer = [0; 8];
+        buffer[..bytes.len()].copy_from_slice(bytes);
+        Some(u64::from_le_bytes(buffer))
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Option<()> {
+        let index = offset as usize;
+        let bytes = self.data.get_mut(index..index + size as usize)?;
+        bytes.copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        Some(())
+    }
+
+    fn memory(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.data)
+    }
+}
+
+struct Region {
+    base: u64,
+    size: u64,
+    device: Box<dyn Device>,
+}
+
+impl Region {
+    fn contains(&self, addr: u64, size: u64) -> bool {
+        addr >= self.base && addr - self.base < self.size && size <= self.size - (addr - self.base)
+    }
+}
+
+/// The physical address space: a list of devices, each mapped at its own address range.
+#[derive(Default)]
 pub struct Bus {
-    pub memory: Vec<u8>,
+    regions: Vec<Region>,
+    pub interrupts: InterruptLines,
+    pub watchpoints: Vec<Watchpoint>,
+    /// Index into `watchpoints` of the first one hit since the debugger last took it.
+    pub watch_hit: Option<usize>,
+    /// Exit code a device received from the guest.
+    pub exit_code: Option<i32>,
+    /// Pages the decode cache holds instructions from.
+    pub code_pages: HashSet<u64>,
+    /// Pages removed from `code_pages` by stores, for the decode cache to drop.
+    pub code_written: Vec<u64>,
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus::default()
+    }
+
+    /// Maps `device` at `base..base + size`. Later mappings shadow earlier ones where they overlap.
+    pub fn map(&mut self, base: u64, size: u64, device: impl Device + 'static) {
+        self.regions.push(Region { base, size, device: Box::new(device) });
+    }
+
+    pub fn tick(&mut self, time: u64) {
+        self.interrupts.mtime = time;
+        for region in &mut self.regions {
+            region.device.tick(&mut self.interrupts);
+        }
+    }
+
+    fn region(&mut self, addr: u64, size: u64) -> Result<&mut Region, AccessFault> {
+        self.regions.iter_mut().rev()
+            .find(|region| region.contains(addr, size))
+            .ok_or(AccessFault { address: addr })
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, AccessFault> {
+        let region = self.region(addr, size)?;
+        region.device.read(addr - region.base, size).ok_or(AccessFault { address: addr })
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), AccessFault> {
+        let region = self.region(addr, size)?;
+        region.device.write(addr - region.base, size, value).ok_or(AccessFault { address: addr })?;
+        if let Some(exit_code) = region.device.exit_code() {
+            self.exit_code = Some(exit_code);
+        }
+        self.check_code_pages(addr, size);
+        self.check_watchpoints(addr, size, false);
+        Ok(())
+    }
+
+    /// Whether `addr..addr + size` is backed by plain memory, so reading it has no side effects.
+    pub fn is_memory(&mut self, addr: u64, size: u64) -> bool {
+        self.region(addr, size).is_ok_and(|region| region.device.memory().is_some())
+    }
+
+    pub fn load_bytes(&mut self, addr: u64, bytes: &mut [u8]) -> Result<(), AccessFault> {
+        let size = bytes.len() as u64;
+        if let Ok(region) = self.region(addr, size) {
+            let offset = (addr - region.base) as usize;
+            if let Some(memory) = region.device.memory().and_then(|memory| memory.get(offset..offset + bytes.len())) {
+                bytes.copy_from_slice(memory);
+                return Ok(());
+            }
+        }
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.load(addr.wrapping_add(i as u64), 1)? as u8;
+        }
+        Ok(())
+    }
+
+    pub fn store_bytes(&mut self, addr: u64, bytes: &[u8]) -> Result<(), AccessFault> {
+        let size = bytes.len() as u64;
+        if let Ok(region) = self.region(addr, size) {
+            let offset = (addr - region.base) as usize;
+            if let Some(memory) = region.device.memory().and_then(|memory| memory.get_mut(offset..offset + bytes.len())) {
+                memory.copy_from_slice(bytes);
+                self.check_code_pages(addr, size);
+                self.check_watchpoints(addr, size, false);
+                return Ok(());
+            }
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            self.store8(addr.wrapping_add(i as u64), *byte as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the interrupt lines and every mapping to a snapshot, with the non-zero pages of memory.
+    pub fn save(&mut self, snapshot: &mut Writer) {
+        let lines = self.interrupts;
+        snapshot.u64(lines.mtime);
+        for line in [lines.msip, lines.mtip, lines.meip, lines.seip] {
+            snapshot.bool(line);
+        }
+        snapshot.u64(lines.sources);
+        snapshot.bool(self.exit_code.is_some());
+        snapshot.u32(self.exit_code.unwrap_or_default() as u32);
+
+        snapshot.u32(self.regions.len() as u32);
+        for region in &mut self.regions {
+            snapshot.u64(region.base);
+            snapshot.u64(region.size);
+            region.device.save(snapshot);
+            let memory = region.device.memory();
+            snapshot.bool(memory.is_some());
+            for (index, page) in memory.into_iter().flat_map(|memory| memory.chunks(PAGE_SIZE as usize).enumerate()) {
+                if page.iter().any(|&byte| byte != 0) {
+                    snapshot.u64(index as u64);
+                    snapshot.bytes(page);
+                }
+            }
+            snapshot.u64(u64::MAX);
+        }
+    }
+
+    /// Reads back what `save` wrote. The same devices have to be mapped at the same addresses.
+    pub fn restore(&mut self, snapshot: &mut Reader) -> Result<()> {
+        self.interrupts.mtime = snapshot.u64()?;
+        self.interrupts.msip = snapshot.bool()?;
+        self.interrupts.mtip = snapshot.bool()?;
+        self.interrupts.meip = snapshot.bool()?;
+        self.interrupts.seip = snapshot.bool()?;
+        self.interrupts.sources = snapshot.u64()?;
+        let exited = snapshot.bool()?;
+        let exit_code = snapshot.u32()? as i32;
+        self.exit_code = exited.then_some(exit_code);
+
+        if snapshot.u32()? as usize != self.regions.len() {
+            bail!("the snapshot is of a machine with different devices");
+        }
+        for region in &mut self.regions {
+            let (base, size) = (snapshot.u64()?, snapshot.u64()?);
+            if (base, size) != (region.base, region.size) {
+                bail!("the snapshot has {:#x} bytes mapped at {:#x} where this machine has {:#x} at {:#x}",
+                    size, base, region.size, region.base);
+            }
+            region.device.restore(snapshot).with_context(|| format!("can't restore the device at {:#x}", base))?;
+            let memory = region.device.memory();
+            if snapshot.bool()? != memory.is_some() {
+                bail!("the snapshot has a different device at {:#x}", base);
+            }
+            let memory = memory.unwrap_or_default();
+            memory.fill(0);
+            loop {
+                let index = snapshot.u64()?;
+                if index == u64::MAX {
+                    break;
+                }
+                let size = memory.len();
+                let start = index.checked_mul(PAGE_SIZE).filter(|&start| start < size as u64)
+                    .with_context(|| format!("the snapshot has page {} outside the memory at {:#x}", index, base))? as usize;
+                let page = &mut memory[start..(start + PAGE_SIZE as usize).min(size)];
+                page.copy_from_slice(snapshot.bytes(page.len())?);
+            }
+        }
+        // every page may hold different code now
+        self.flush_code_pages();
+        self.watch_hit = None;
+        Ok(())
+    }
+
+    /// Reports a data load to the watchpoints. Loads through `load` aren't watched, as they also fetch
+    /// instructions and walk page tables.
+    pub fn watch_load(&mut self, addr: u64, size: u64) {
+        self.check_watchpoints(addr, size, true);
+    }
+
+    /// Treats every page with cached code as written, for FENCE.I.
+    pub fn flush_code_pages(&mut self) {
+        self.code_written.extend(self.code_pages.drain());
+    }
+
+    fn check_code_pages(&mut self, addr: u64, size: u64) {
+        if self.code_pages.is_empty() || size == 0 {
+            return;
+        }
+        for page in addr / PAGE_SIZE..=(addr + (size - 1)) / PAGE_SIZE {
+            if self.code_pages.remove(&page) {
+                self.code_written.push(page);
+            }
+        }
+    }
+
+    fn check_watchpoints(&mut self, addr: u64, size: u64, load: bool) {
+        if self.watch_hit.is_none() && !self.watchpoints.is_empty() {
+            self.watch_hit = self.watchpoints.iter().position(|watchpoint| {
+                let overlaps = addr < watchpoint.range.end && watchpoint.range.start < addr.saturating_add(size);
+                overlaps && if load { watchpoint.loads } else { watchpoint.stores }
+            });
+        }
+    }
 }
 
+// fixed-width shorthands for `load`/`store`
+#[allow(dead_code)]
 impl Bus {
-    pub fn new(memory_size: usize) -> Bus {
-        let mut bus = Bus {
-            memory: Vec::new()
-        };
-        bus.memory.resize(memory_size, 0);
-        bus
+    pub fn load8(&mut self, addr: u64) -> Result<u64, AccessFault> {
+        self.load(addr, 1)
+    }
+
+    pub fn load16(&mut self, addr: u64) -> Result<u64, AccessFault> {
+        self.load(addr, 2)
+    }
+
+    pub fn load32(&mut self, addr: u64) -> Result<u64, AccessFault> {
+        self.load(addr, 4)
     }
 
-    pub fn load8(&self, addr: u64) -> u64 {
-        let index = addr as usize;
-        self.memory[index] as u64
+    pub fn load64(&mut self, addr: u64) -> Result<u64, AccessFault> {
+        self.load(addr, 8)
     }
 
-    pub fn load16(&self, addr: u64) -> u64 {
-        let index = addr as usize;
-        let bytes = &self.memory[index..index + 2];
-        u16::from_le_bytes(bytes.try_into().unwrap()) as u64
+    pub fn store8(&mut self, addr: u64, value: u64) -> Result<(), AccessFault> {
+        self.store(addr, 1, value)
     }
 
-    pub fn load32(&self, addr: u64) -> u64 {
-        let index = addr as usize;
-        let bytes = &self.memory[index..index + 4];
-        u32::from_le_bytes(bytes.try_into().unwrap()) as u64
+    pub fn store16(&mut self, addr: u64, value: u64) -> Result<(), AccessFault> {
+        self.store(addr, 2, value)
     }
 
-    pub fn load64(&self, addr: u64) -> u64 {
-        let index = addr as usize;
-        let bytes = &self.memory[index..index + 8];
-        u64::from_le_bytes(bytes.try_into().unwrap())
+    pub fn store32(&mut self, addr: u64, value: u64) -> Result<(), AccessFault> {
+        self.store(addr, 4, value)
     }
 
-    pub fn store_bytes(&mut self, addr: u64, bytes: &[u8]) {
-        let index = addr as usize;
-        self.memory[index..index + bytes.len()].copy_from_slice(bytes);
+    pub fn store64(&mut self, addr: u64, value: u64) -> Result<(), AccessFault> {
+        self.store(addr, 8, value)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn store8(&mut self, addr: u64, value: u64) {
-        let index = addr as usize;
-        self.memory[index] = value as u8;
+    /// Reads back the offset and ignores writes, rejecting 8-byte accesses.
+    struct Register;
+
+    impl Device for Register {
+        fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+            (size < 8).then_some(offset)
+        }
+
+        fn write(&mut self, _offset: u64, size: u64, _value: u64) -> Option<()> {
+            (size < 8).then_some(())
+        }
     }
 
-    pub fn store16(&mut self, addr: u64, value: u64) {
-        let index = addr as usize;
-        self.memory[index..index + 2].copy_from_slice(&(value as u16).to_le_bytes());
+    #[test]
+    fn test_ram() {
+        let mut bus = Bus::new();
+        bus.map(DRAM_BASE, 0x1000, Ram::new(0x1000));
+        bus.store32(DRAM_BASE + 0x11, 0xDEAD_BEEF).unwrap();
+        assert_eq!(bus.load16(DRAM_BASE + 0x13), Ok(0xDEAD));
+        assert_eq!(bus.load64(DRAM_BASE + 0x10), Ok(0xDE_ADBE_EF00));
+
+        assert_eq!(bus.store_bytes(DRAM_BASE + 0xFFF, &[1, 2]), Err(AccessFault { address: DRAM_BASE + 0x1000 }));
+        bus.store_bytes(DRAM_BASE + 0xFFE, &[1, 2]).unwrap();
+        assert_eq!(bus.load16(DRAM_BASE + 0xFFE), Ok(0x0201));
     }
 
-    pub fn store32(&mut self, addr: u64, value: u64) {
-        let index = addr as usize;
-        self.memory[index..index + 4].copy_from_slice(&(value as u32).to_le_bytes());
+    #[test]
+    fn test_access_faults() {
+        let mut bus = Bus::new();
+        bus.map(DRAM_BASE, 0x1000, Ram::new(0x1000));
+        bus.map(0x1000_0000, 0x100, Register);
+
+        assert_eq!(bus.load8(0), Err(AccessFault { address: 0 }));
+        assert_eq!(bus.store8(DRAM_BASE - 1, 0), Err(AccessFault { address: DRAM_BASE - 1 }));
+        // accesses straddling the end of a mapping fault as a whole
+        assert_eq!(bus.load32(DRAM_BASE + 0xFFE), Err(AccessFault { address: DRAM_BASE + 0xFFE }));
+        assert_eq!(bus.load64(u64::MAX - 3), Err(AccessFault { address: u64::MAX - 3 }));
+
+        assert_eq!(bus.load32(0x1000_0010), Ok(0x10));
+        assert_eq!(bus.load64(0x1000_0010), Err(AccessFault { address: 0x1000_0010 }));
+        assert_eq!(bus.store16(0x1000_0004, 0x1234), Ok(()));
     }
 
-    pub fn store64(&mut self, addr: u64, value: u64) {
-        let index = addr as usize;
-        self.memory[index..index + 8].copy_from_slice(&value.to_le_bytes());
+    #[test]
+    fn test_overlapping_mappings() {
+        let mut bus = Bus::new();
+        bus.map(0, 0x1000, Ram::new(0x1000));
+        bus.map(0x800, 0x10, Register);
+        bus.store32(0x7FC, 7).unwrap();
+        assert_eq!(bus.load32(0x7FC), Ok(7));
+        assert_eq!(bus.load32(0x804), Ok(4));
+        assert_eq!(bus.load32(0x810), Ok(0));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_watchpoints() {
+        let mut bus = Bus::new();
+        bus.map(0, 0x1000, Ram::new(0x1000));
+        bus.watchpoints = vec![
+            Watchpoint { range: 0x100..0x108, loads: false, stores: true },
+            Watchpoint { range: 0x200..0x201, loads: true, stores: true },
+        ];
+        bus.store32(0xF8, 1).unwrap();
+        bus.watch_load(0x100, 8);
+        assert_eq!(bus.watch_hit, None);
+        bus.watch_load(0x1FC, 8);
+        assert_eq!(bus.watch_hit.take(), Some(1));
+        bus.store_bytes(0x1FE, &[1, 2, 3]).unwrap();
+        assert_eq!(bus.watch_hit.take(), Some(1));
+        bus.store16(0x107, 1).unwrap();
+        assert_eq!(bus.watch_hit, Some(0));
+    }
+}
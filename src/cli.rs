@@ -0,0 +1,354 @@
+﻿use std::ops::Range;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::assembler;
+
+pub const USAGE: &str = "\
+usage: untitled1 [options] <program> [guest arguments...]
+       untitled1 disasm <program>
+       untitled1 asm [-o <output>] [--base <address>] [--flat] <source.s>
+
+Runs a RISC-V ELF executable. Linux user-mode programs get their syscalls emulated,
+anything else boots on a bare machine with RAM at 0x80000000.
+The disasm command lists the code in an ELF file instead. The asm command assembles
+a source file into an ELF executable, or with --flat into an image for --raw-binary,
+with .text at the base address (default 0x80000000).
+
+options:
+  --memory <size>            RAM size, with an optional K, M or G suffix (default 64M)
+  --max-instructions <n>     stop after n instructions with exit code 124
+  --trace                    print every executed instruction to stderr
+  --log-commits              log every instruction with the registers and memory it wrote,
+                             in the format of Spike's -l --log-commits, to stderr
+  --log <file>               write the commit log to a file instead, implies --log-commits
+  --log-range <start>:<end>  only log instructions at addresses from start up to end,
+                             implies --log-commits
+  --entry <address>          start executing at address instead of the ELF entry point
+  --raw-binary <address>     load the program as a flat binary image at address
+  --debug                    run under a debugger that reads commands from stdin and can
+                             go backwards, the guest console gets no input
+  --gdb <address>            wait for GDB to connect to a TCP port, host:port or Unix
+                             socket path before running
+  --lockstep <trace>         compare every instruction against a commit log recorded with
+                             Spike's --log-commits and stop at the first difference
+  --no-decode-cache          fetch and decode every instruction again instead of caching
+                             decoded basic blocks
+  --no-jit                   interpret every instruction, in builds with the jit feature
+  --stop-on-exception        stop with an error at the first exception instead of trapping,
+                             for bare-metal programs without a trap handler
+  --stats                    print the instruction count and speed to stderr at the end
+  --save-snapshot <file>     save the whole machine to a file when the program stops, for
+                             example at --max-instructions
+  --restore <file>           resume from a snapshot taken with the same program and options
+  --deterministic            make every run with the same input the same: time counts
+                             instructions, randomness is seeded and stdin is read up front
+  --clock-rate <n>           instructions per second of virtual time (default 100000000),
+                             implies --deterministic
+  --seed <n>                 seed for the guest's randomness (default 0), implies
+                             --deterministic
+  --record <file>            record the input the program gets from the host, its console,
+                             clock and syscalls, to a file at the end
+  --replay <file>            play back input recorded with --record instead of asking the
+                             host, then carry on live where the recording ends
+  -h, --help                 show this message";
+
+/// Speed the hart seems to run at in deterministic mode, in instructions per second.
+pub const DEFAULT_CLOCK_RATE: u64 = 100_000_000;
+
+/// Exit code when `--max-instructions` runs out, the same one `timeout` uses.
+pub const INSTRUCTION_LIMIT_EXIT_CODE: i32 = 124;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Box<Options>),
+    Disassemble(String),
+    Assemble(AssembleOptions),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AssembleOptions {
+    pub source: String,
+    pub output: String,
+    pub base: u64,
+    pub flat: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub program: String,
+    /// Guest argv, starting with the program path.
+    pub args: Vec<String>,
+    pub memory_size: u64,
+    pub max_instructions: Option<u64>,
+    pub trace: bool,
+    pub log_commits: bool,
+    pub log_file: Option<String>,
+    pub log_range: Option<Range<u64>>,
+    pub entry: Option<u64>,
+    pub raw_binary: Option<u64>,
+    pub debug: bool,
+    pub gdb: Option<String>,
+    pub lockstep: Option<String>,
+    pub decode_cache: bool,
+    /// Translate hot code to machine code, on by default in builds with the `jit` feature.
+    pub jit: bool,
+    pub stop_on_exception: bool,
+    pub stats: bool,
+    pub save_snapshot: Option<String>,
+    pub restore: Option<String>,
+    pub deterministic: bool,
+    pub clock_rate: u64,
+    pub seed: u64,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+}
+
+/// Parses the arguments after the binary name. Returns `None` when help was requested.
+pub fn parse_command(args: impl IntoIterator<Item = String>) -> Result<Option<Command>> {
+    let mut args = args.into_iter().peekable();
+    if args.peek().is_some_and(|arg| arg == "disasm") {
+        args.next();
+        return match (args.next(), args.next()) {
+            (Some(help), _) if help == "-h" || help == "--help" => Ok(None),
+            (Some(program), None) => Ok(Some(Command::Disassemble(program))),
+            _ => bail!("disasm takes exactly one program\n\n{}", USAGE),
+        };
+    }
+    if args.peek().is_some_and(|arg| arg == "asm") {
+        args.next();
+        return Ok(parse_assemble_args(args)?.map(Command::Assemble));
+    }
+    Ok(parse_args(args)?.map(|options| Command::Run(Box::new(options))))
+}
+
+fn parse_assemble_args(mut args: impl Iterator<Item = String>) -> Result<Option<AssembleOptions>> {
+    let mut options = AssembleOptions { source: String::new(), output: String::new(), base: assembler::DEFAULT_BASE, flat: false };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" => options.output = value()?,
+            "--base" => options.base = parse_number(&value()?)?,
+            "--flat" => options.flat = true,
+            _ if arg.starts_with('-') => bail!("unknown option {}\n\n{}", arg, USAGE),
+            _ if !options.source.is_empty() => bail!("asm takes exactly one source file\n\n{}", USAGE),
+            _ => options.source = arg,
+        }
+    }
+    if options.source.is_empty() {
+        bail!("no source file given\n\n{}", USAGE);
+    }
+    if options.output.is_empty() {
+        // foo.s becomes foo, or foo.bin for a flat image
+        let stem = options.source.strip_suffix(".s").or_else(|| options.source.strip_suffix(".S")).unwrap_or(&options.source);
+        options.output = if options.flat { format!("{}.bin", stem) } else if stem == options.source { format!("{}.elf", stem) } else { stem.to_string() };
+    }
+    Ok(Some(options))
+}
+
+/// Parses the options for running a program. Returns `None` when help was requested.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>> {
+    let mut args = args.into_iter();
+    let mut options = Options {
+        program: String::new(),
+        args: Vec::new(),
+        memory_size: 64 * 1024 * 1024,
+        max_instructions: None,
+        trace: false,
+        log_commits: false,
+        log_file: None,
+        log_range: None,
+        entry: None,
+        raw_binary: None,
+        debug: false,
+        gdb: None,
+        lockstep: None,
+        decode_cache: true,
+        jit: cfg!(feature = "jit"),
+        stop_on_exception: false,
+        stats: false,
+        save_snapshot: None,
+        restore: None,
+        deterministic: false,
+        clock_rate: DEFAULT_CLOCK_RATE,
+        seed: 0,
+        record: None,
+        replay: None,
+    };
+
+    let value = |args: &mut dyn Iterator<Item = String>, option: &str| {
+        args.next().ok_or_else(|| anyhow!("{} needs a value", option))
+    };
+    loop {
+        let Some(arg) = args.next() else { bail!("no program given\n\n{}", USAGE) };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--memory" => options.memory_size = parse_size(&value(&mut args, &arg)?)?,
+            "--max-instructions" => options.max_instructions = Some(parse_number(&value(&mut args, &arg)?)?),
+            "--trace" => options.trace = true,
+            "--log-commits" => options.log_commits = true,
+            "--log" => {
+                options.log_file = Some(value(&mut args, &arg)?);
+                options.log_commits = true;
+            }
+            "--log-range" => {
+                options.log_range = Some(parse_range(&value(&mut args, &arg)?)?);
+                options.log_commits = true;
+            }
+            "--entry" => options.entry = Some(parse_number(&value(&mut args, &arg)?)?),
+            "--raw-binary" => options.raw_binary = Some(parse_number(&value(&mut args, &arg)?)?),
+            "--debug" => options.debug = true,
+            "--gdb" => options.gdb = Some(value(&mut args, &arg)?),
+            "--lockstep" => options.lockstep = Some(value(&mut args, &arg)?),
+            "--no-decode-cache" => options.decode_cache = false,
+            "--no-jit" => options.jit = false,
+            "--stop-on-exception" => options.stop_on_exception = true,
+            "--stats" => options.stats = true,
+            "--save-snapshot" => options.save_snapshot = Some(value(&mut args, &arg)?),
+            "--restore" => options.restore = Some(value(&mut args, &arg)?),
+            "--deterministic" => options.deterministic = true,
+            "--clock-rate" => {
+                options.clock_rate = parse_number(&value(&mut args, &arg)?)?;
+                if options.clock_rate == 0 {
+                    bail!("the clock rate has to be at least 1");
+                }
+                options.deterministic = true;
+            }
+            "--seed" => {
+                options.seed = parse_number(&value(&mut args, &arg)?)?;
+                options.deterministic = true;
+            }
+            "--record" => options.record = Some(value(&mut args, &arg)?),
+            "--replay" => options.replay = Some(value(&mut args, &arg)?),
+            _ if arg.starts_with('-') => bail!("unknown option {}\n\n{}", arg, USAGE),
+            _ if options.debug && options.gdb.is_some() => bail!("--debug and --gdb can't be combined"),
+            _ if options.lockstep.is_some() && (options.debug || options.gdb.is_some()) => bail!("--lockstep can't be combined with a debugger"),
+            _ => {
+                options.program = arg.clone();
+                options.args = std::iter::once(arg).chain(args).collect();
+                return Ok(Some(options));
+            }
+        }
+    }
+}
+
+/// Decimal or `0x`-prefixed hexadecimal.
+pub fn parse_number(text: &str) -> Result<u64> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => text.replace('_', "").parse(),
+    };
+    parsed.with_context(|| format!("invalid number {:?}", text))
+}
+
+/// `start:end`, with the end excluded.
+fn parse_range(text: &str) -> Result<Range<u64>> {
+    let (start, end) = text.split_once(':').with_context(|| format!("range {:?} isn't start:end", text))?;
+    Ok(parse_number(start)?..parse_number(end)?)
+}
+
+fn parse_size(text: &str) -> Result<u64> {
+    let (number, multiplier) = match text.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&text[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&text[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&text[..i], 1 << 30),
+        _ => (text, 1),
+    };
+    parse_number(number)?.checked_mul(multiplier).with_context(|| format!("size {} is too large", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse(&["--memory", "128M", "--trace", "--entry", "0x8000_0000", "--gdb", "1234", "prog", "--trace", "x"]).unwrap().unwrap();
+        assert_eq!(options.program, "prog");
+        assert_eq!(options.args, ["prog", "--trace", "x"]);
+        assert_eq!(options.memory_size, 128 << 20);
+        assert!(options.trace);
+        assert_eq!(options.entry, Some(0x8000_0000));
+        assert_eq!(options.max_instructions, None);
+        assert_eq!(options.gdb.as_deref(), Some("1234"));
+
+        let options = parse(&["--max-instructions", "1000", "--raw-binary", "0x80000000", "--debug", "image.bin"]).unwrap().unwrap();
+        assert_eq!(options.max_instructions, Some(1000));
+        assert_eq!(options.raw_binary, Some(0x8000_0000));
+        assert!(options.debug);
+        assert_eq!(options.memory_size, 64 << 20);
+        assert!(!options.log_commits);
+        assert_eq!(options.lockstep, None);
+        assert!(options.decode_cache);
+        assert!(!options.stats);
+
+        let options = parse(&["--log", "commits.log", "--log-range", "0x80000000:0x80001000", "--lockstep", "spike.log", "prog"]).unwrap().unwrap();
+        assert!(options.decode_cache);
+        assert_eq!(options.lockstep.as_deref(), Some("spike.log"));
+        assert!(options.log_commits);
+        assert_eq!(options.log_file.as_deref(), Some("commits.log"));
+        assert_eq!(options.log_range, Some(0x8000_0000..0x8000_1000));
+        assert!(parse(&["--log-range", "0x80000000", "prog"]).is_err());
+
+        let arguments = ["--no-decode-cache", "--no-jit", "--stop-on-exception", "--stats", "prog"];
+        let options = parse(&arguments).unwrap().unwrap();
+        assert!(!options.decode_cache);
+        assert!(!options.jit);
+        assert!(options.stop_on_exception);
+        assert!(options.stats);
+        assert_eq!(options.save_snapshot, None);
+
+        let options = parse(&["--save-snapshot", "boot.snap", "--restore", "init.snap", "prog"]).unwrap().unwrap();
+        assert_eq!(options.save_snapshot.as_deref(), Some("boot.snap"));
+        assert_eq!(options.restore.as_deref(), Some("init.snap"));
+        assert!(!options.deterministic);
+
+        let options = parse(&["--seed", "42", "prog"]).unwrap().unwrap();
+        assert!(options.deterministic);
+        assert_eq!((options.seed, options.clock_rate), (42, DEFAULT_CLOCK_RATE));
+        assert_eq!(options.record, None);
+
+        let options = parse(&["--record", "run.journal", "--replay", "old.journal", "prog"]).unwrap().unwrap();
+        assert_eq!(options.record.as_deref(), Some("run.journal"));
+        assert_eq!(options.replay.as_deref(), Some("old.journal"));
+        assert!(parse(&["--clock-rate", "0", "prog"]).is_err());
+
+        assert_eq!(parse(&["--help", "prog"]).unwrap(), None);
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--memory"]).is_err());
+        assert!(parse(&["--memory", "lots", "prog"]).is_err());
+        assert!(parse(&["--frobnicate", "prog"]).is_err());
+        assert!(parse(&["--debug", "--gdb", "1234", "prog"]).is_err());
+        assert!(parse(&["--lockstep", "spike.log", "--debug", "prog"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let command = |args: &[&str]| parse_command(args.iter().map(|arg| arg.to_string()));
+        assert_eq!(command(&["disasm", "prog"]).unwrap(), Some(Command::Disassemble("prog".to_string())));
+        assert!(command(&["disasm"]).is_err());
+        assert!(matches!(command(&["--trace", "disasm"]).unwrap(), Some(Command::Run(options)) if options.program == "disasm"));
+
+        let expected = AssembleOptions { source: "foo.s".to_string(), output: "foo".to_string(), base: 0x8000_0000, flat: false };
+        assert_eq!(command(&["asm", "foo.s"]).unwrap(), Some(Command::Assemble(expected)));
+        let expected = AssembleOptions { source: "foo.s".to_string(), output: "foo.bin".to_string(), base: 0x1000, flat: true };
+        assert_eq!(command(&["asm", "--flat", "--base", "0x1000", "foo.s"]).unwrap(), Some(Command::Assemble(expected)));
+        assert!(matches!(command(&["asm", "-o", "out", "foo.s"]).unwrap(), Some(Command::Assemble(options)) if options.output == "out"));
+        assert!(command(&["asm"]).is_err());
+        assert!(command(&["asm", "a.s", "b.s"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("0x1000").unwrap(), 4096);
+        assert_eq!(parse_size("2k").unwrap(), 2048);
+        assert_eq!(parse_size("1G").unwrap(), 1 << 30);
+        assert!(parse_size("99999999999G").is_err());
+    }
+}
@@ -0,0 +1,149 @@
+﻿use anyhow::Result;
+
+use crate::bus::{Device, InterruptLines};
+use crate::replay::SharedJournal;
+use crate::snapshot::{Reader, Writer};
+
+pub const CLINT_BASE: u64 = 0x0200_0000;
+pub const CLINT_SIZE: u64 = 0x1_0000;
+
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xBFF8;
+
+/// SiFive-style core local interruptor for a single hart: the machine timer and software interrupt.
+pub struct Clint {
+    msip: bool,
+    mtimecmp: u64,
+    /// Latest value of the platform clock, as seen on the last tick.
+    clock: u64,
+    /// Difference between `mtime` and the platform clock, set when software writes `mtime`.
+    offset: u64,
+    /// Told when software reads or writes `mtime`, when the run is recorded.
+    journal: Option<SharedJournal>,
+}
+
+impl Clint {
+    pub fn new(journal: Option<SharedJournal>) -> Clint {
+        Clint {
+            msip: false,
+            // no timer interrupt until software programs a deadline
+            mtimecmp: u64::MAX,
+            clock: 0,
+            offset: 0,
+            journal,
+        }
+    }
+
+    fn mtime(&self) -> u64 {
+        self.clock.wrapping_add(self.offset)
+    }
+
+    fn keep_time(&self) {
+        if let Some(journal) = &self.journal {
+            journal.borrow_mut().keep_time();
+        }
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        let (register, value) = match offset {
+            MSIP..=0x3 => (MSIP, self.msip as u64),
+            MTIMECMP..=0x4007 => (MTIMECMP, self.mtimecmp),
+            MTIME..=0xBFFF => {
+                self.keep_time();
+                (MTIME, self.mtime())
+            }
+            _ => return Some(0),
+        };
+        Some(part(value, offset - register, size))
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Option<()> {
+        match offset {
+            MSIP..=0x3 => self.msip = value & 1 != 0,
+            MTIMECMP..=0x4007 => self.mtimecmp = merge(self.mtimecmp, offset - MTIMECMP, size, value),
+            MTIME..=0xBFFF => {
+                self.keep_time();
+                let mtime = merge(self.mtime(), offset - MTIME, size, value);
+                self.offset = mtime.wrapping_sub(self.clock);
+            }
+            _ => (),
+        }
+        Some(())
+    }
+
+    fn tick(&mut self, interrupts: &mut InterruptLines) {
+        self.clock = interrupts.mtime;
+        interrupts.mtime = self.mtime();
+        interrupts.msip = self.msip;
+        interrupts.mtip = interrupts.mtime >= self.mtimecmp;
+    }
+
+    fn save(&self, snapshot: &mut Writer) {
+        snapshot.bool(self.msip);
+        snapshot.u64(self.mtimecmp);
+        snapshot.u64(self.mtime());
+    }
+
+    fn restore(&mut self, snapshot: &mut Reader) -> Result<()> {
+        self.msip = snapshot.bool()?;
+        self.mtimecmp = snapshot.u64()?;
+        // mtime carries on from the saved value, whatever the clock says now
+        self.offset = snapshot.u64()?.wrapping_sub(self.clock);
+        Ok(())
+    }
+}
+
+/// Bytes `offset..offset + size` of a 64-bit register, for firmware that accesses it in halves.
+fn part(register: u64, offset: u64, size: u64) -> u64 {
+    let value = register >> (8 * offset);
+    if size >= 8 { value } else { value & ((1 << (8 * size)) - 1) }
+}
+
+fn merge(register: u64, offset: u64, size: u64, value: u64) -> u64 {
+    let mask = if size >= 8 { u64::MAX } else { ((1 << (8 * size)) - 1) << (8 * offset) };
+    (register & !mask) | ((value << (8 * offset)) & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(clint: &mut Clint, clock: u64) -> InterruptLines {
+        let mut interrupts = InterruptLines { mtime: clock, ..Default::default() };
+        clint.tick(&mut interrupts);
+        interrupts
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut clint = Clint::new(None);
+        assert!(!tick(&mut clint, 100).mtip);
+        assert_eq!(clint.read(MTIME, 8), Some(100));
+
+        clint.write(MTIMECMP, 4, 150);
+        clint.write(MTIMECMP + 4, 4, 0);
+        assert_eq!(clint.read(MTIMECMP, 8), Some(150));
+        assert!(!tick(&mut clint, 149).mtip);
+        assert!(tick(&mut clint, 150).mtip);
+
+        // moving mtime back keeps counting from the new value
+        clint.write(MTIME, 8, 10);
+        let interrupts = tick(&mut clint, 160);
+        assert_eq!(interrupts.mtime, 20);
+        assert!(!interrupts.mtip);
+        assert_eq!(clint.read(MTIME + 4, 4), Some(0));
+    }
+
+    #[test]
+    fn test_software_interrupt() {
+        let mut clint = Clint::new(None);
+        clint.write(MSIP, 4, 1);
+        assert_eq!(clint.read(MSIP, 4), Some(1));
+        assert!(tick(&mut clint, 0).msip);
+        clint.write(MSIP, 4, 0);
+        assert!(!tick(&mut clint, 0).msip);
+    }
+}
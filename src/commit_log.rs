@@ -0,0 +1,374 @@
+﻿//! Execution trace in the format of Spike's `-l --log-commits`, for diffing against reference simulators.
+//!
+//! Every instruction gets a line with its disassembly before it executes, then one listing the
+//! registers, CSRs and memory it wrote once it retires. Instructions that trap get an exception line
+//! instead:
+//!
+//! ```text
+//! core   0: 0x0000000080000000 (0x00000297) auipc t0, 0
+//! core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+//! core   0: 0x0000000080000004 (0x0452b023) sd t0, 64(t0)
+//! core   0: 3 0x0000000080000004 (0x0452b023) mem 0x0000000080000040 0x0000000080000000
+//! ```
+//!
+//! Only the commit lines, the ones with the privilege level, follow Spike character for character;
+//! the disassembly is this emulator's own. Implicit CSR updates such as `mstatus.FS` becoming dirty
+//! aren't logged, accrued floating point flags are.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::disassembler::csr_name;
+use crate::instruction::Instruction;
+use crate::trap::{Exception, Privilege};
+
+/// Something a retired instruction wrote or read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Entry {
+    Register(u64, u64),
+    FloatRegister(u64, u64),
+    ControlRegister(u64, u64),
+    Load(u64),
+    Store { address: u64, size: u64, value: u64 },
+}
+
+/// One retired instruction, the contents of a commit line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub privilege: u64,
+    pub pc: u64,
+    pub encoding: u32,
+    /// Instruction length in bytes, 2 for compressed instructions.
+    pub size: u64,
+    pub entries: Vec<Entry>,
+}
+
+impl Commit {
+    /// Parses a commit line, returning `None` for any other line of a Spike log. Entries this
+    /// emulator doesn't know about, like vector registers, are skipped.
+    pub fn parse(line: &str) -> Option<Commit> {
+        let rest = line.strip_prefix("core")?.trim_start();
+        let (_, rest) = rest.split_once(':')?;
+        let mut words = rest.split_whitespace().peekable();
+        let privilege = words.next()?.parse().ok().filter(|&privilege| privilege <= 3)?;
+        let pc = hex(words.next()?)?;
+        let encoding_text = words.next()?.strip_prefix('(')?.strip_suffix(')')?;
+        let encoding = hex(encoding_text)? as u32;
+        let size = (encoding_text.len() as u64 - 2) / 2;
+
+        let mut entries = Vec::new();
+        while let Some(word) = words.next() {
+            let entry = match word.as_bytes()[0] {
+                b'm' if word == "mem" => {
+                    let address = words.next().and_then(hex)?;
+                    match words.next_if(|word| word.starts_with("0x")) {
+                        Some(value) => Entry::Store { address, size: (value.len() as u64 - 2) / 2, value: hex(value)? },
+                        None => Entry::Load(address),
+                    }
+                }
+                b'x' => Entry::Register(word[1..].parse().ok()?, words.next().and_then(hex)?),
+                b'f' => Entry::FloatRegister(word[1..].parse().ok()?, words.next().and_then(hex)?),
+                b'c' => {
+                    let id = word[1..].split('_').next()?.parse().ok()?;
+                    Entry::ControlRegister(id, words.next().and_then(hex)?)
+                }
+                _ => {
+                    words.next_if(|word| word.starts_with("0x"));
+                    continue;
+                }
+            };
+            entries.push(entry);
+        }
+        Some(Commit { privilege, pc, encoding, size, entries })
+    }
+
+    /// Integer and floating point register writes, in a canonical order since Spike doesn't keep one.
+    pub fn register_writes(&self) -> Vec<Entry> {
+        let mut writes: Vec<Entry> = self.entries.iter().copied()
+            .filter(|entry| matches!(entry, Entry::Register(..) | Entry::FloatRegister(..)))
+            .collect();
+        writes.sort();
+        writes
+    }
+
+    pub fn stores(&self) -> Vec<Entry> {
+        self.entries.iter().copied().filter(|entry| matches!(entry, Entry::Store { .. })).collect()
+    }
+}
+
+impl fmt::Display for Commit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "core   0: {} 0x{:016x} ({})", self.privilege, self.pc, encoding(self.encoding, self.size))?;
+        // Spike lists register writes first, then memory reads, then memory writes
+        for entry in &self.entries {
+            match *entry {
+                Entry::Register(index, value) => write!(f, " x{:<2} 0x{:016x}", index, value)?,
+                Entry::FloatRegister(index, value) => write!(f, " f{:<2} 0x{:016x}", index, value)?,
+                Entry::ControlRegister(id, value) => {
+                    let name = csr_name(id).unwrap_or_else(|| format!("0x{:03x}", id));
+                    write!(f, " c{}_{} 0x{:016x}", id, name, value)?;
+                }
+                _ => {}
+            }
+        }
+        for entry in &self.entries {
+            if let Entry::Load(address) = *entry {
+                write!(f, " mem 0x{:016x}", address)?;
+            }
+        }
+        for entry in &self.entries {
+            if let Entry::Store { address, size, value } = *entry {
+                write!(f, " mem 0x{:016x} 0x{:0width$x}", address, value & mask(size), width = 2 * size as usize)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct CommitLog {
+    /// Where the log goes, nothing gets written without one and only the last commit is kept.
+    output: Option<Box<dyn Write>>,
+    /// Instructions outside this range of addresses aren't logged.
+    range: Option<Range<u64>>,
+    /// The instruction being executed, if it gets logged.
+    current: Option<Commit>,
+    last: Option<Commit>,
+}
+
+impl fmt::Debug for CommitLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitLog").field("range", &self.range).field("current", &self.current).finish_non_exhaustive()
+    }
+}
+
+impl CommitLog {
+    pub fn new(output: Box<dyn Write>, range: Option<Range<u64>>) -> CommitLog {
+        CommitLog { output: Some(output), range, current: None, last: None }
+    }
+
+    /// A log that writes nothing and only records the last commit, see [`CommitLog::take_last`].
+    pub fn recorder() -> CommitLog {
+        CommitLog { output: None, range: None, current: None, last: None }
+    }
+
+    /// Starts logging an instruction that is about to execute at `pc`.
+    pub fn begin(&mut self, pc: u64, instruction: &Instruction, privilege: Privilege) {
+        self.current = None;
+        if !self.in_range(pc) {
+            return;
+        }
+        if self.output.is_some() {
+            let line = format!("core   0: 0x{:016x} ({}) {}", pc, encoding(instruction.encoding as u32, instruction.size), instruction.disassemble(pc));
+            self.write_line(&line);
+        }
+        self.current = Some(Commit {
+            privilege: privilege as u64,
+            pc,
+            encoding: instruction.encoding as u32,
+            size: instruction.size,
+            entries: Vec::new(),
+        });
+    }
+
+    pub fn register(&mut self, index: i32, value: u64) {
+        if index != 0 {
+            self.record(Entry::Register(index as u64, value));
+        }
+    }
+
+    pub fn float_register(&mut self, index: i32, value: u64) {
+        self.record(Entry::FloatRegister(index as u64, value));
+    }
+
+    /// A CSR write, with the value the register reads back as afterwards.
+    pub fn control_register(&mut self, id: u64, value: u64) {
+        self.record(Entry::ControlRegister(id, value));
+    }
+
+    pub fn load(&mut self, address: u64) {
+        self.record(Entry::Load(address));
+    }
+
+    pub fn store(&mut self, address: u64, size: u64, value: u64) {
+        self.record(Entry::Store { address, size, value });
+    }
+
+    /// Writes the commit line for the instruction that has just retired.
+    pub fn commit(&mut self) {
+        let Some(commit) = self.current.take() else { return };
+        if self.output.is_some() {
+            self.write_line(&commit.to_string());
+        }
+        self.last = Some(commit);
+    }
+
+    /// Logs an exception raised by the instruction at `pc`.
+    pub fn exception(&mut self, pc: u64, exception: &Exception) {
+        self.current = None;
+        if self.output.is_none() || !self.in_range(pc) {
+            return;
+        }
+        let line = format!("core   0: exception {}, epc 0x{:016x}", exception_name(exception), pc);
+        self.write_line(&line);
+        if !matches!(exception, Exception::EnvironmentCallFromUMode | Exception::EnvironmentCallFromSMode | Exception::EnvironmentCallFromMMode) {
+            let line = format!("core   0:           tval 0x{:016x}", exception.value());
+            self.write_line(&line);
+        }
+    }
+
+    /// The last instruction that retired, if it hasn't been taken yet.
+    pub fn take_last(&mut self) -> Option<Commit> {
+        self.last.take()
+    }
+
+    fn in_range(&self, pc: u64) -> bool {
+        self.range.as_ref().is_none_or(|range| range.contains(&pc))
+    }
+
+    fn record(&mut self, entry: Entry) {
+        if let Some(commit) = &mut self.current {
+            commit.entries.push(entry);
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        let Some(output) = &mut self.output else { return };
+        if let Err(error) = writeln!(output, "{}", line) {
+            // a full disk shouldn't bring the guest down, but the log stops being useful
+            eprintln!("can't write the commit log: {}", error);
+            self.output = Some(Box::new(io::sink()));
+        }
+    }
+}
+
+fn encoding(encoding: u32, size: u64) -> String {
+    if size == 2 {
+        format!("0x{:04x}", encoding as u16)
+    } else {
+        format!("0x{:08x}", encoding)
+    }
+}
+
+fn hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text.strip_prefix("0x")?, 16).ok()
+}
+
+fn mask(size: u64) -> u64 {
+    if size >= 8 { u64::MAX } else { (1 << (8 * size)) - 1 }
+}
+
+fn exception_name(exception: &Exception) -> &'static str {
+    match exception {
+        Exception::InstructionAccessFault(_) => "trap_instruction_access_fault",
+        Exception::IllegalInstruction(_) => "trap_illegal_instruction",
+        Exception::Breakpoint(_) => "trap_breakpoint",
+        Exception::LoadAddressMisaligned(_) => "trap_load_address_misaligned",
+        Exception::LoadAccessFault(_) => "trap_load_access_fault",
+        Exception::StoreAddressMisaligned(_) => "trap_store_address_misaligned",
+        Exception::StoreAccessFault(_) => "trap_store_access_fault",
+        Exception::EnvironmentCallFromUMode => "trap_user_ecall",
+        Exception::EnvironmentCallFromSMode => "trap_supervisor_ecall",
+        Exception::EnvironmentCallFromMMode => "trap_machine_ecall",
+        Exception::InstructionPageFault(_) => "trap_instruction_page_fault",
+        Exception::LoadPageFault(_) => "trap_load_page_fault",
+        Exception::StorePageFault(_) => "trap_store_page_fault",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::assembler::assemble;
+    use crate::bus::{Bus, Ram, DRAM_BASE};
+    use crate::machine::{Cpu, Machine};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log(range: Option<Range<u64>>) -> String {
+        let source = "
+            auipc t0, 0
+            sd t0, 64(t0)
+            lb t1, 64(t0)
+            .half 0x4515 # c.li a0, 5
+            csrw mscratch, a0
+            addi t2, t0, 64
+            amoadd.w a1, a0, (t2)
+            ecall
+        ";
+        let program = assemble(source, DRAM_BASE).unwrap();
+        let machine = Machine::new();
+        let mut bus = Bus::new();
+        bus.map(DRAM_BASE, 0x1000, Ram::new(0x1000));
+        bus.store_bytes(DRAM_BASE, &program.flat_image()).unwrap();
+        let mut cpu = Cpu::new(&machine);
+        cpu.pc = DRAM_BASE;
+        cpu.stop_on_exception = true;
+        let buffer = SharedBuffer::default();
+        cpu.commit_log = Some(CommitLog::new(Box::new(buffer.clone()), range));
+        for _ in 0..7 {
+            cpu.step(&mut bus).unwrap();
+        }
+        // there is no trap handler for the ecall
+        assert!(cpu.step(&mut bus).is_err());
+        let text = buffer.0.borrow().clone();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn test_commit_log() {
+        assert_eq!(log(None), "\
+core   0: 0x0000000080000000 (0x00000297) auipc t0, 0
+core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+core   0: 0x0000000080000004 (0x0452b023) sd t0, 64(t0)
+core   0: 3 0x0000000080000004 (0x0452b023) mem 0x0000000080000040 0x0000000080000000
+core   0: 0x0000000080000008 (0x04028303) lb t1, 64(t0)
+core   0: 3 0x0000000080000008 (0x04028303) x6  0x0000000000000000 mem 0x0000000080000040
+core   0: 0x000000008000000c (0x4515) li a0, 5
+core   0: 3 0x000000008000000c (0x4515) x10 0x0000000000000005
+core   0: 0x000000008000000e (0x34051073) csrw mscratch, a0
+core   0: 3 0x000000008000000e (0x34051073) c832_mscratch 0x0000000000000005
+core   0: 0x0000000080000012 (0x04028393) addi t2, t0, 64
+core   0: 3 0x0000000080000012 (0x04028393) x7  0x0000000080000040
+core   0: 0x0000000080000016 (0x00a3a5af) amoadd.w a1, a0, (t2)
+core   0: 3 0x0000000080000016 (0x00a3a5af) x11 0xffffffff80000000 mem 0x0000000080000040 mem 0x0000000080000040 0x80000005
+core   0: 0x000000008000001a (0x00000073) ecall
+core   0: exception trap_machine_ecall, epc 0x000000008000001a
+");
+
+        let filtered = log(Some(DRAM_BASE + 0xc..DRAM_BASE + 0x12));
+        assert_eq!(filtered.lines().count(), 4);
+        assert!(filtered.starts_with("core   0: 0x000000008000000c (0x4515)"));
+    }
+
+    #[test]
+    fn test_parse() {
+        // commit lines round trip, everything else in a Spike log is skipped
+        for line in log(None).lines() {
+            match Commit::parse(line) {
+                Some(commit) => assert_eq!(commit.to_string(), line),
+                None => assert!(!line.starts_with("core   0: 3 ")),
+            }
+        }
+
+        let commit = Commit::parse("core   0: 1 0x0000000080000010 (0x0000b503) x10 0x0000000000000001 c1_fflags 0x0000000000000001 e8 m1 l4 v2  0x0 mem 0x0000000000001000").unwrap();
+        assert_eq!(commit.privilege, 1);
+        assert_eq!(commit.entries, [Entry::Register(10, 1), Entry::ControlRegister(1, 1), Entry::Load(0x1000)]);
+        assert_eq!(Commit::parse("core   0: 0x0000000080000000 (0x00000297) auipc   t0, 0x0"), None);
+        assert_eq!(Commit::parse("core   0: exception trap_machine_ecall, epc 0x0000000080000016"), None);
+    }
+}
@@ -0,0 +1,236 @@
+﻿use crate::opcodes::*;
+
+const REG_ZERO: i32 = 0;
+const REG_RA: i32 = 1;
+const REG_SP: i32 = 2;
+
+/// Expands a 16-bit RVC instruction into its 32-bit equivalent.
+/// Returns `None` for reserved and illegal encodings.
+pub fn expand(data: u16) -> Option<i32> {
+    let c = data as i32;
+    let funct3 = (c >> 13) & 0x7;
+    let rd = (c >> 7) & 0x1F;
+    let rs2 = (c >> 2) & 0x1F;
+    let rd_prime = ((c >> 2) & 0x7) + 8;
+    let rs1_prime = ((c >> 7) & 0x7) + 8;
+
+    match (c & 3, funct3) {
+        // C.ADDI4SPN
+        (0, 0b000) => {
+            let imm = bits(c, 12, 11, 4) | bits(c, 10, 7, 6) | bits(c, 6, 6, 2) | bits(c, 5, 5, 3);
+            if imm == 0 { return None; }
+            Some(i_type(imm, REG_SP, F3_ADD, rd_prime, OPCODE_OP_IMM))
+        }
+        // C.FLD
+        (0, 0b001) => Some(i_type(offset_d(c), rs1_prime, F3_FLD, rd_prime, OPCODE_LOAD_FP)),
+        // C.LW
+        (0, 0b010) => Some(i_type(offset_w(c), rs1_prime, F3_LW, rd_prime, OPCODE_LOAD)),
+        // C.LD
+        (0, 0b011) => Some(i_type(offset_d(c), rs1_prime, F3_LD, rd_prime, OPCODE_LOAD)),
+        // C.FSD
+        (0, 0b101) => Some(s_type(offset_d(c), rd_prime, rs1_prime, F3_FSD, OPCODE_STORE_FP)),
+        // C.SW
+        (0, 0b110) => Some(s_type(offset_w(c), rd_prime, rs1_prime, F3_SW, OPCODE_STORE)),
+        // C.SD
+        (0, 0b111) => Some(s_type(offset_d(c), rd_prime, rs1_prime, F3_SD, OPCODE_STORE)),
+
+        // C.ADDI, C.NOP
+        (1, 0b000) => Some(i_type(imm6(c), rd, F3_ADD, rd, OPCODE_OP_IMM)),
+        // C.ADDIW
+        (1, 0b001) => {
+            if rd == REG_ZERO { return None; }
+            Some(i_type(imm6(c), rd, F3_ADD, rd, OPCODE_OP_IMM_32))
+        }
+        // C.LI
+        (1, 0b010) => Some(i_type(imm6(c), REG_ZERO, F3_ADD, rd, OPCODE_OP_IMM)),
+        // C.ADDI16SP
+        (1, 0b011) if rd == REG_SP => {
+            let imm = sign_extend(bits(c, 12, 12, 9) | bits(c, 6, 6, 4) | bits(c, 5, 5, 6) | bits(c, 4, 3, 7) | bits(c, 2, 2, 5), 10);
+            if imm == 0 { return None; }
+            Some(i_type(imm, REG_SP, F3_ADD, REG_SP, OPCODE_OP_IMM))
+        }
+        // C.LUI
+        (1, 0b011) => {
+            let imm = imm6(c);
+            if imm == 0 { return None; }
+            Some(u_type(imm << 12, rd, OPCODE_LUI))
+        }
+        (1, 0b100) => {
+            let shamt = bits(c, 12, 12, 5) | bits(c, 6, 2, 0);
+            match (c >> 10) & 0x3 {
+                // C.SRLI
+                0b00 => Some(i_type(shamt, rs1_prime, F3_SRL, rs1_prime, OPCODE_OP_IMM)),
+                // C.SRAI
+                0b01 => Some(i_type(shamt | (F7_SRA << 5), rs1_prime, F3_SRA, rs1_prime, OPCODE_OP_IMM)),
+                // C.ANDI
+                0b10 => Some(i_type(imm6(c), rs1_prime, F3_AND, rs1_prime, OPCODE_OP_IMM)),
+                _ => {
+                    let rs2_prime = rd_prime;
+                    let (funct7, funct3, opcode) = match (bits(c, 12, 12, 0), (c >> 5) & 0x3) {
+                        (0, 0b00) => (F7_SUB, F3_SUB, OPCODE_OP),
+                        (0, 0b01) => (F7_XOR, F3_XOR, OPCODE_OP),
+                        (0, 0b10) => (F7_OR, F3_OR, OPCODE_OP),
+                        (0, 0b11) => (F7_AND, F3_AND, OPCODE_OP),
+                        (1, 0b00) => (F7_SUB, F3_SUB, OPCODE_OP_32),
+                        (1, 0b01) => (F7_ADD, F3_ADD, OPCODE_OP_32),
+                        _ => return None,
+                    };
+                    Some(r_type(funct7, rs2_prime, rs1_prime, funct3, rs1_prime, opcode))
+                }
+            }
+        }
+        // C.J
+        (1, 0b101) => Some(j_type(offset_j(c), REG_ZERO)),
+        // C.BEQZ
+        (1, 0b110) => Some(b_type(offset_b(c), REG_ZERO, rs1_prime, F3_BEQ)),
+        // C.BNEZ
+        (1, 0b111) => Some(b_type(offset_b(c), REG_ZERO, rs1_prime, F3_BNE)),
+
+        // C.SLLI
+        (2, 0b000) => Some(i_type(bits(c, 12, 12, 5) | bits(c, 6, 2, 0), rd, F3_SLL, rd, OPCODE_OP_IMM)),
+        // C.FLDSP
+        (2, 0b001) => Some(i_type(offset_lsp_d(c), REG_SP, F3_FLD, rd, OPCODE_LOAD_FP)),
+        // C.LWSP
+        (2, 0b010) => {
+            if rd == REG_ZERO { return None; }
+            let imm = bits(c, 12, 12, 5) | bits(c, 6, 4, 2) | bits(c, 3, 2, 6);
+            Some(i_type(imm, REG_SP, F3_LW, rd, OPCODE_LOAD))
+        }
+        // C.LDSP
+        (2, 0b011) => {
+            if rd == REG_ZERO { return None; }
+            Some(i_type(offset_lsp_d(c), REG_SP, F3_LD, rd, OPCODE_LOAD))
+        }
+        (2, 0b100) => match (bits(c, 12, 12, 0), rd, rs2) {
+            // C.JR
+            (0, REG_ZERO, REG_ZERO) => None,
+            (0, rs1, REG_ZERO) => Some(i_type(0, rs1, 0, REG_ZERO, OPCODE_JALR)),
+            // C.MV
+            (0, rd, rs2) => Some(r_type(F7_ADD, rs2, REG_ZERO, F3_ADD, rd, OPCODE_OP)),
+            // C.EBREAK
+            (_, REG_ZERO, REG_ZERO) => Some(i_type(IMM_EBREAK, REG_ZERO, F3_ECALL_EBREAK, REG_ZERO, OPCODE_SYSTEM)),
+            // C.JALR
+            (_, rs1, REG_ZERO) => Some(i_type(0, rs1, 0, REG_RA, OPCODE_JALR)),
+            // C.ADD
+            (_, rd, rs2) => Some(r_type(F7_ADD, rs2, rd, F3_ADD, rd, OPCODE_OP)),
+        },
+        // C.FSDSP
+        (2, 0b101) => Some(s_type(offset_ssp_d(c), rs2, REG_SP, F3_FSD, OPCODE_STORE_FP)),
+        // C.SWSP
+        (2, 0b110) => Some(s_type(bits(c, 12, 9, 2) | bits(c, 8, 7, 6), rs2, REG_SP, F3_SW, OPCODE_STORE)),
+        // C.SDSP
+        (2, 0b111) => Some(s_type(offset_ssp_d(c), rs2, REG_SP, F3_SD, OPCODE_STORE)),
+
+        _ => None,
+    }
+}
+
+/// Extracts bits `high..=low` of `value` and places them starting at bit `to`.
+fn bits(value: i32, high: i32, low: i32, to: i32) -> i32 {
+    ((value >> low) & ((1 << (high - low + 1)) - 1)) << to
+}
+
+fn sign_extend(value: i32, width: i32) -> i32 {
+    (value << (32 - width)) >> (32 - width)
+}
+
+fn imm6(c: i32) -> i32 { sign_extend(bits(c, 12, 12, 5) | bits(c, 6, 2, 0), 6) }
+fn offset_w(c: i32) -> i32 { bits(c, 12, 10, 3) | bits(c, 6, 6, 2) | bits(c, 5, 5, 6) }
+fn offset_d(c: i32) -> i32 { bits(c, 12, 10, 3) | bits(c, 6, 5, 6) }
+fn offset_lsp_d(c: i32) -> i32 { bits(c, 12, 12, 5) | bits(c, 6, 5, 3) | bits(c, 4, 2, 6) }
+fn offset_ssp_d(c: i32) -> i32 { bits(c, 12, 10, 3) | bits(c, 9, 7, 6) }
+
+fn offset_j(c: i32) -> i32 {
+    sign_extend(bits(c, 12, 12, 11) | bits(c, 11, 11, 4) | bits(c, 10, 9, 8) | bits(c, 8, 8, 10)
+        | bits(c, 7, 7, 6) | bits(c, 6, 6, 7) | bits(c, 5, 3, 1) | bits(c, 2, 2, 5), 12)
+}
+
+fn offset_b(c: i32) -> i32 {
+    sign_extend(bits(c, 12, 12, 8) | bits(c, 11, 10, 3) | bits(c, 6, 5, 6) | bits(c, 4, 3, 1) | bits(c, 2, 2, 5), 9)
+}
+
+fn r_type(funct7: i32, rs2: i32, rs1: i32, funct3: i32, rd: i32, opcode: i32) -> i32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn i_type(imm: i32, rs1: i32, funct3: i32, rd: i32, opcode: i32) -> i32 {
+    (imm << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn s_type(imm: i32, rs2: i32, rs1: i32, funct3: i32, opcode: i32) -> i32 {
+    ((imm >> 5) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1F) << 7) | opcode
+}
+
+fn b_type(imm: i32, rs2: i32, rs1: i32, funct3: i32) -> i32 {
+    bits(imm, 12, 12, 31) | bits(imm, 10, 5, 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12)
+        | bits(imm, 4, 1, 8) | bits(imm, 11, 11, 7) | OPCODE_BRANCH
+}
+
+fn u_type(imm: i32, rd: i32, opcode: i32) -> i32 {
+    (imm & !0xFFF) | (rd << 7) | opcode
+}
+
+fn j_type(imm: i32, rd: i32) -> i32 {
+    bits(imm, 20, 20, 31) | bits(imm, 10, 1, 21) | bits(imm, 11, 11, 20) | bits(imm, 19, 12, 12) | (rd << 7) | OPCODE_JAL
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        // (compressed, expanded) pairs produced by llvm-mc
+        let cases: &[(u16, u32)] = &[
+            (0x0808, 0x01010513), // c.addi4spn a0, sp, 16
+            (0x2988, 0x0105b507), // c.fld fa0, 16(a1)
+            (0x41c8, 0x0045a503), // c.lw a0, 4(a1)
+            (0x7de8, 0x0f85b503), // c.ld a0, 248(a1)
+            (0xa988, 0x00a5b827), // c.fsd fa0, 16(a1)
+            (0xc1c8, 0x00a5a223), // c.sw a0, 4(a1)
+            (0xfde8, 0x0ea5bc23), // c.sd a0, 248(a1)
+            (0x0001, 0x00000013), // c.nop
+            (0x157d, 0xfff50513), // c.addi a0, -1
+            (0x2505, 0x0015051b), // c.addiw a0, 1
+            (0x557d, 0xfff00513), // c.li a0, -1
+            (0x7101, 0xe0010113), // c.addi16sp sp, -512
+            (0x7579, 0xffffe537), // c.lui a0, 0xffffe
+            (0x8185, 0x0015d593), // c.srli a1, 1
+            (0x9581, 0x4205d593), // c.srai a1, 32
+            (0x99f1, 0xffc5f593), // c.andi a1, -4
+            (0x8d91, 0x40c585b3), // c.sub a1, a2
+            (0x8db1, 0x00c5c5b3), // c.xor a1, a2
+            (0x8dd1, 0x00c5e5b3), // c.or a1, a2
+            (0x8df1, 0x00c5f5b3), // c.and a1, a2
+            (0x9d91, 0x40c585bb), // c.subw a1, a2
+            (0x9db1, 0x00c585bb), // c.addw a1, a2
+            (0xbffd, 0xfffff06f), // c.j -2
+            (0xa101, 0x4000006f), // c.j 1024
+            (0xdded, 0xfe058de3), // c.beqz a1, -6
+            (0xe581, 0x00059463), // c.bnez a1, 8
+            (0xf181, 0xf00590e3), // c.bnez a1, -256
+            (0x1582, 0x02059593), // c.slli a1, 32
+            (0x2522, 0x00813507), // c.fldsp fa0, 8(sp)
+            (0x4532, 0x00c12503), // c.lwsp a0, 12(sp)
+            (0x6532, 0x10813503), // c.ldsp a0, 264(sp)
+            (0x8582, 0x00058067), // c.jr a1
+            (0x852e, 0x00b00533), // c.mv a0, a1
+            (0x9002, 0x00100073), // c.ebreak
+            (0x9582, 0x000580e7), // c.jalr a1
+            (0x952e, 0x00b50533), // c.add a0, a1
+            (0xa42a, 0x00a13427), // c.fsdsp fa0, 8(sp)
+            (0xc62a, 0x00a12623), // c.swsp a0, 12(sp)
+            (0xe62a, 0x10a13423), // c.sdsp a0, 264(sp)
+        ];
+        for &(compressed, expanded) in cases {
+            assert_eq!(expand(compressed), Some(expanded as i32), "expanding {:#06x}", compressed);
+        }
+    }
+
+    #[test]
+    fn test_expand_illegal() {
+        assert_eq!(expand(0x0000), None);
+        assert_eq!(expand(0x8002), None); // c.jr x0
+        assert_eq!(expand(0x6101), None); // c.addi16sp sp, 0
+    }
+}
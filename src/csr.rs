@@ -0,0 +1,137 @@
+﻿use anyhow::Result;
+
+use crate::snapshot::{Reader, Writer};
+
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 3 << 11;
+pub const MSTATUS_FS: u64 = 3 << 13;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+pub const MSTATUS_UXL: u64 = 3 << 32;
+pub const MSTATUS_SD: u64 = 1 << 63;
+
+pub const MSTATUS_MPP_SHIFT: u64 = 11;
+pub const MSTATUS_FS_SHIFT: u64 = 13;
+
+pub const FS_OFF: u64 = 0;
+pub const FS_INITIAL: u64 = 1;
+pub const FS_DIRTY: u64 = 3;
+
+/// Bits of `mstatus` that software can change; the rest are read-only or hardwired.
+pub const MSTATUS_WRITABLE: u64 = MSTATUS_SIE | MSTATUS_MIE | MSTATUS_SPIE | MSTATUS_MPIE | MSTATUS_SPP | MSTATUS_MPP
+    | MSTATUS_FS | MSTATUS_MPRV | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR;
+
+/// Bits of `mstatus` visible through `sstatus`.
+pub const SSTATUS_VISIBLE: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_UXL | MSTATUS_SD;
+pub const SSTATUS_WRITABLE: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM | MSTATUS_MXR;
+
+pub const MISA_MXL_64: u64 = 2 << 62;
+pub const XLEN_64: u64 = 2;
+
+pub const fn misa_extension(letter: u8) -> u64 {
+    1 << (letter - b'A')
+}
+
+pub const MISA: u64 = MISA_MXL_64
+    | misa_extension(b'I') | misa_extension(b'M') | misa_extension(b'A') | misa_extension(b'F')
+    | misa_extension(b'D') | misa_extension(b'C') | misa_extension(b'S') | misa_extension(b'U');
+
+pub const TVEC_MODE_VECTORED: u64 = 1;
+
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MEIP: u64 = 1 << 11;
+
+pub const SUPPORTED_INTERRUPTS: u64 = MIP_SSIP | MIP_MSIP | MIP_STIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+/// Pending bits machine mode software may set or clear; the rest reflect device state.
+pub const MIP_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+pub const DELEGABLE_INTERRUPTS: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+/// Pending bits the clock drives, through `mtimecmp` and with Sstc `stimecmp`.
+pub const TIMER_INTERRUPTS: u64 = MIP_MTIP | MIP_STIP;
+/// Counter enable bit gating access to `time` and, with Sstc, `stimecmp`.
+pub const COUNTEREN_TM: u64 = 1 << 1;
+
+/// `menvcfg.STCE`: enables the Sstc `stimecmp` register.
+pub const MENVCFG_STCE: u64 = 1 << 63;
+
+/// Every exception except an environment call from machine mode can be handled in supervisor mode.
+pub const DELEGABLE_EXCEPTIONS: u64 = 0xB3FF & !(1 << 11);
+
+/// Trap and translation state. Counters and the floating point CSRs live directly on `Cpu`.
+#[derive(Debug, Clone, Default)]
+pub struct ControlRegisters {
+    pub mstatus: u64,
+    pub medeleg: u64,
+    pub mideleg: u64,
+    pub mtvec: u64,
+    pub mepc: u64,
+    pub mcause: u64,
+    pub mtval: u64,
+    pub mie: u64,
+    pub mip: u64,
+    pub mscratch: u64,
+    pub mcounteren: u64,
+    pub stvec: u64,
+    pub sepc: u64,
+    pub scause: u64,
+    pub stval: u64,
+    pub sscratch: u64,
+    pub scounteren: u64,
+    pub satp: u64,
+    pub menvcfg: u64,
+    pub stimecmp: u64,
+}
+
+impl ControlRegisters {
+    pub fn new() -> ControlRegisters {
+        ControlRegisters {
+            mstatus: (XLEN_64 << 34) | (XLEN_64 << 32) | (FS_INITIAL << MSTATUS_FS_SHIFT),
+            stimecmp: u64::MAX,
+            ..Default::default()
+        }
+    }
+
+    pub fn fs(&self) -> u64 {
+        (self.mstatus & MSTATUS_FS) >> MSTATUS_FS_SHIFT
+    }
+
+    pub fn set_fs(&mut self, fs: u64) {
+        self.mstatus = (self.mstatus & !MSTATUS_FS) | (fs << MSTATUS_FS_SHIFT);
+    }
+
+    pub fn save(&self, snapshot: &mut Writer) {
+        let registers = [
+            self.mstatus, self.medeleg, self.mideleg, self.mtvec, self.mepc, self.mcause, self.mtval, self.mie,
+            self.mip, self.mscratch, self.mcounteren, self.stvec, self.sepc, self.scause, self.stval,
+            self.sscratch, self.scounteren, self.satp, self.menvcfg, self.stimecmp,
+        ];
+        for register in registers {
+            snapshot.u64(register);
+        }
+    }
+
+    /// Reads back what `save` wrote, in the same order.
+    pub fn restore(&mut self, snapshot: &mut Reader) -> Result<()> {
+        let registers = [
+            &mut self.mstatus, &mut self.medeleg, &mut self.mideleg, &mut self.mtvec, &mut self.mepc,
+            &mut self.mcause, &mut self.mtval, &mut self.mie, &mut self.mip, &mut self.mscratch,
+            &mut self.mcounteren, &mut self.stvec, &mut self.sepc, &mut self.scause, &mut self.stval,
+            &mut self.sscratch, &mut self.scounteren, &mut self.satp, &mut self.menvcfg, &mut self.stimecmp,
+        ];
+        for register in registers {
+            *register = snapshot.u64()?;
+        }
+        Ok(())
+    }
+}
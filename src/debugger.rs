@@ -0,0 +1,804 @@
+﻿use std::io::{BufRead, Write};
+use std::mem;
+use std::ops::Range;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::bus::{Bus, Watchpoint};
+use crate::cli::parse_number;
+use crate::commit_log::{CommitLog, Entry};
+use crate::instruction::{self, Instruction, FLOAT_REGISTER_NAMES, REGISTER_NAMES};
+use crate::loader::{self, Symbol};
+use crate::machine::Cpu;
+use crate::opcodes::{OPCODE_JAL, OPCODE_JALR};
+use crate::replay::Position;
+use crate::snapshot;
+use crate::trap::{ExecError, StepOutcome};
+
+const HELP: &str = "\
+commands:
+  break <location>              stop when execution reaches location (b)
+  watch <location> [length]     stop after a store to length bytes at location, 8 by default
+  delete [n]                    remove breakpoint or watchpoint n, or all of them (d)
+  info breakpoints              list breakpoints and watchpoints (i b)
+  info registers                show the integer registers (i r)
+  step [n]                      execute n instructions, 1 by default (s, si)
+  next [n]                      like step, but run through called functions (n, ni)
+  continue                      run until a breakpoint, a watchpoint or the program exits (c)
+  reverse-step [n]              go back n instructions, 1 by default (rs, rsi)
+  reverse-continue [what]       go back to the last breakpoint or watchpoint hit, or with a register
+                                or a location [length], to the last write to it (rc)
+  print <expression>            show a value, or a register by name (p)
+  set <register> <expression>   change a register
+  x/<count><format><size> <location>
+                                examine memory, format is x, d, u, c, s or i and size is b, h, w or g
+  disassemble [location] [n]    show n instructions from location, or the ones around pc (disas)
+  quit                          stop debugging (q)
+
+Locations and expressions are numbers, registers and symbols added or subtracted, like sp+16.
+An empty line repeats the previous command. Going back replays the run from a checkpoint; changing
+a register with set forgets what came after.";
+
+/// Instructions `disassemble` shows when no count is given.
+const DISASSEMBLY_LINES: u64 = 10;
+/// Longest string `x/s` prints.
+const MAX_STRING: usize = 256;
+/// Steps between checkpoints, the most going back has to replay.
+const CHECKPOINT_INTERVAL: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy)]
+enum Point {
+    Breakpoint(u64),
+    /// Stores are caught by physical address, translated when the watchpoint is set.
+    Watchpoint { address: u64, length: u64, physical_address: u64 },
+}
+
+enum Stop {
+    Stepped,
+    Breakpoint(usize),
+    Watchpoint(usize),
+    Exited(i32),
+    Halted,
+    Error(ExecError),
+}
+
+enum Resume {
+    Step(u64),
+    Next(u64),
+    Continue,
+}
+
+/// What `reverse-continue` goes back to.
+enum Target {
+    /// A breakpoint or watchpoint hit.
+    Points,
+    Register(usize),
+    /// Stores to an address, given by the range of physical addresses.
+    Memory(u64, Range<u64>),
+}
+
+/// The machine as it was at a position in the journal.
+struct Checkpoint {
+    position: Position,
+    snapshot: Vec<u8>,
+}
+
+/// Runs a guest under the control of commands read from a REPL.
+pub struct Debugger<'a> {
+    symbols: Vec<Symbol>,
+    /// Breakpoints and watchpoints with their numbers, which count up from 1 as they are set.
+    points: Vec<(usize, Point)>,
+    next_number: usize,
+    /// The guest's exit code once it has exited.
+    exited: &'a dyn Fn(&Cpu) -> Option<i32>,
+    exit_code: Option<i32>,
+    /// Taken while the hart records to a journal, for going back; oldest first.
+    checkpoints: Vec<Checkpoint>,
+    checkpoint_interval: u64,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(symbols: Vec<Symbol>, exited: &'a dyn Fn(&Cpu) -> Option<i32>) -> Debugger<'a> {
+        Debugger {
+            symbols,
+            points: Vec::new(),
+            next_number: 1,
+            exited,
+            exit_code: None,
+            checkpoints: Vec::new(),
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+        }
+    }
+
+    /// Reads commands until `quit` or the end of the input. Returns the guest's exit code, or 0 if it didn't exit.
+    pub fn run(&mut self, cpu: &mut Cpu, bus: &mut Bus, mut input: impl BufRead, mut output: impl Write) -> Result<i32> {
+        self.disassemble(cpu, bus, cpu.pc, 1, &mut output)?;
+        let mut previous = String::new();
+        loop {
+            write!(output, "(dbg) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = match line.trim() {
+                "" => previous.clone(),
+                line => line.to_string(),
+            };
+            match self.command(cpu, bus, &line, &mut output) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(error) => writeln!(output, "{:#}", error)?,
+            }
+            previous = line;
+        }
+        Ok(self.exit_code.unwrap_or(0))
+    }
+
+    /// Executes one command, returning true when the session should end.
+    fn command(&mut self, cpu: &mut Cpu, bus: &mut Bus, line: &str, output: &mut dyn Write) -> Result<bool> {
+        let (name, arguments) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let arguments = arguments.trim();
+        match name {
+            "" => {}
+            "help" | "h" => writeln!(output, "{}", HELP)?,
+            "quit" | "q" => return Ok(true),
+            "break" | "b" => {
+                let address = self.evaluate(cpu, arguments)?;
+                let number = self.add_point(Point::Breakpoint(address));
+                writeln!(output, "Breakpoint {} at {}", number, self.describe(address))?;
+            }
+            "watch" => {
+                let (address, length, physical_address) = self.location(cpu, bus, arguments)?;
+                let number = self.add_point(Point::Watchpoint { address, length, physical_address });
+                self.update_watchpoints(bus);
+                writeln!(output, "Watchpoint {}: {}, {} bytes", number, self.describe(address), length)?;
+            }
+            "delete" | "d" => {
+                if arguments.is_empty() {
+                    self.points.clear();
+                } else {
+                    let number = parse_number(arguments)? as usize;
+                    let index = self.points.iter().position(|(n, _)| *n == number)
+                        .ok_or_else(|| anyhow!("no breakpoint or watchpoint {}", number))?;
+                    self.points.remove(index);
+                }
+                self.update_watchpoints(bus);
+            }
+            "info" | "i" => match arguments {
+                "breakpoints" | "b" | "watchpoints" => self.list_points(output)?,
+                "registers" | "r" => self.show_registers(cpu, output)?,
+                _ => bail!("info what? breakpoints or registers"),
+            },
+            "step" | "s" | "stepi" | "si" => self.resume(cpu, bus, Resume::Step(count(arguments)?), output)?,
+            "next" | "n" | "nexti" | "ni" => self.resume(cpu, bus, Resume::Next(count(arguments)?), output)?,
+            "continue" | "c" => self.resume(cpu, bus, Resume::Continue, output)?,
+            "reverse-step" | "rs" | "reverse-stepi" | "rsi" => self.reverse_step(cpu, bus, count(arguments)?, output)?,
+            "reverse-continue" | "rc" => {
+                let register = instruction::parse_register(arguments.strip_prefix('$').unwrap_or(arguments));
+                let target = match register {
+                    _ if arguments.is_empty() => Target::Points,
+                    Some(index) => Target::Register(index),
+                    None => {
+                        let (address, length, physical_address) = self.location(cpu, bus, arguments)?;
+                        Target::Memory(address, physical_address..physical_address.saturating_add(length))
+                    }
+                };
+                self.reverse_continue(cpu, bus, target, output)?;
+            }
+            "print" | "p" => {
+                let name = arguments.strip_prefix('$').unwrap_or(arguments);
+                if let Some(index) = instruction::parse_float_register(name) {
+                    let bits = cpu.fregisters[index];
+                    // single precision values are NaN-boxed
+                    let value = if bits >> 32 == 0xFFFF_FFFF { f32::from_bits(bits as u32) as f64 } else { f64::from_bits(bits) };
+                    writeln!(output, "{} = {:#x} ({})", FLOAT_REGISTER_NAMES[index], bits, value)?;
+                } else {
+                    let value = self.evaluate(cpu, arguments)?;
+                    match self.symbolize(value) {
+                        Some(_) => writeln!(output, "{} = {}", arguments, self.describe(value))?,
+                        None => writeln!(output, "{} = {:#x} ({})", arguments, value, value as i64)?,
+                    }
+                }
+            }
+            "set" => {
+                let (register, value) = arguments.split_once(['=', ' '])
+                    .ok_or_else(|| anyhow!("usage: set <register> <expression>"))?;
+                let register = register.trim();
+                let register = register.strip_prefix('$').unwrap_or(register);
+                let value = self.evaluate(cpu, value.trim().trim_start_matches('='))?;
+                if register == "pc" {
+                    cpu.pc = value;
+                } else if let Some(index) = instruction::parse_register(register) {
+                    if index == 0 {
+                        bail!("zero is hardwired");
+                    }
+                    cpu.registers[index] = value;
+                } else if let Some(index) = instruction::parse_float_register(register) {
+                    cpu.fregisters[index] = value;
+                } else {
+                    bail!("no register named {:?}", register);
+                }
+                self.diverge(cpu, bus)?;
+            }
+            "disassemble" | "disas" => {
+                let mut parts = arguments.split_whitespace();
+                match parts.next() {
+                    Some(location) => {
+                        let address = self.evaluate(cpu, location)?;
+                        let count = parts.next().map(parse_number).transpose()?.unwrap_or(DISASSEMBLY_LINES);
+                        self.disassemble(cpu, bus, address, count, output)?;
+                    }
+                    None => self.disassemble_around_pc(cpu, bus, output)?,
+                }
+            }
+            _ if name == "x" || name.starts_with("x/") => self.examine(cpu, bus, &name[1..], arguments, output)?,
+            _ => bail!("unknown command {:?}, try help", name),
+        }
+        Ok(false)
+    }
+
+    fn add_point(&mut self, point: Point) -> usize {
+        let number = self.next_number;
+        self.next_number += 1;
+        self.points.push((number, point));
+        number
+    }
+
+    /// Evaluates `location [length]`, returning the address, the length and the physical address.
+    fn location(&self, cpu: &mut Cpu, bus: &mut Bus, arguments: &str) -> Result<(u64, u64, u64)> {
+        let (location, length) = arguments.split_once(char::is_whitespace).unwrap_or((arguments, "8"));
+        let address = self.evaluate(cpu, location)?;
+        let length = parse_number(length.trim())?;
+        if length == 0 {
+            bail!("can't watch zero bytes");
+        }
+        let physical_address = cpu.translate_data(bus, address)
+            .map_err(|_| anyhow!("cannot access memory at {:#x}", address))?;
+        Ok((address, length, physical_address))
+    }
+
+    fn watchpoints(&self) -> impl Iterator<Item = (usize, u64, u64, u64)> + '_ {
+        self.points.iter().filter_map(|(number, point)| match *point {
+            Point::Watchpoint { address, length, physical_address } => Some((*number, address, length, physical_address)),
+            Point::Breakpoint(_) => None,
+        })
+    }
+
+    fn update_watchpoints(&self, bus: &mut Bus) {
+        bus.watchpoints = self.watchpoints()
+            .map(|(_, _, length, physical_address)| Watchpoint {
+                range: physical_address..physical_address.saturating_add(length),
+                loads: false,
+                stores: true,
+            })
+            .collect();
+        bus.watch_hit = None;
+    }
+
+    fn resume(&mut self, cpu: &mut Cpu, bus: &mut Bus, resume: Resume, output: &mut dyn Write) -> Result<()> {
+        if let Some(exit_code) = self.exit_code {
+            bail!("the program has exited with code {}", exit_code);
+        }
+        let stop = match resume {
+            Resume::Step(count) => self.step_instructions(cpu, bus, count, false),
+            Resume::Next(count) => self.step_instructions(cpu, bus, count, true),
+            Resume::Continue => loop {
+                if let Some(stop) = self.step(cpu, bus) {
+                    break stop;
+                }
+            },
+        };
+        self.report(cpu, bus, stop, output)
+    }
+
+    /// Shows why execution stopped and where.
+    fn report(&self, cpu: &mut Cpu, bus: &mut Bus, stop: Stop, output: &mut dyn Write) -> Result<()> {
+        match stop {
+            Stop::Exited(exit_code) => return Ok(writeln!(output, "program exited with code {}", exit_code)?),
+            Stop::Breakpoint(number) => writeln!(output, "Breakpoint {}, {}", number, self.describe(cpu.pc))?,
+            Stop::Watchpoint(number) => {
+                let (_, address, length, _) = self.watchpoints().find(|watchpoint| watchpoint.0 == number).unwrap();
+                let mut bytes = [0; 8];
+                let value = match cpu.read_memory(bus, address, &mut bytes[..length.min(8) as usize]) {
+                    Ok(()) => format!("{:#x}", u64::from_le_bytes(bytes)),
+                    Err(_) => "<unreadable>".to_string(),
+                };
+                writeln!(output, "Watchpoint {}: {} = {}", number, self.describe(address), value)?;
+            }
+            Stop::Halted => writeln!(output, "Halted, waiting for an interrupt but none are enabled")?,
+            Stop::Error(error) => writeln!(output, "Stopped: {}", error)?,
+            Stop::Stepped => {}
+        }
+        self.disassemble(cpu, bus, cpu.pc, 1, output)
+    }
+
+    fn step_instructions(&mut self, cpu: &mut Cpu, bus: &mut Bus, count: u64, over_calls: bool) -> Stop {
+        for _ in 0..count {
+            let call = if over_calls { call_return(cpu, bus) } else { None };
+            if let Some(stop) = self.step(cpu, bus) {
+                return stop;
+            }
+            if let Some((return_address, stack_pointer)) = call {
+                // a recursive call passes the return address again, but deeper down the stack
+                while cpu.pc != return_address || cpu.registers[2] < stack_pointer {
+                    if let Some(stop) = self.step(cpu, bus) {
+                        return stop;
+                    }
+                }
+            }
+        }
+        Stop::Stepped
+    }
+
+    /// Executes one instruction, returning why execution should stop after it, if it should.
+    fn step(&mut self, cpu: &mut Cpu, bus: &mut Bus) -> Option<Stop> {
+        self.checkpoint(cpu, bus);
+        let outcome = match cpu.step(bus) {
+            Ok(outcome) => outcome,
+            Err(error) => return Some(Stop::Error(error)),
+        };
+        if let Some(exit_code) = (self.exited)(cpu) {
+            self.exit_code = Some(exit_code);
+            return Some(Stop::Exited(exit_code));
+        }
+        if outcome == StepOutcome::Halted {
+            return Some(Stop::Halted);
+        }
+        if let Some(index) = bus.watch_hit.take() {
+            return self.watchpoints().nth(index).map(|(number, ..)| Stop::Watchpoint(number));
+        }
+        self.breakpoint_at(cpu.pc).map(Stop::Breakpoint)
+    }
+
+    fn breakpoint_at(&self, pc: u64) -> Option<usize> {
+        self.points.iter()
+            .find(|(_, point)| matches!(point, Point::Breakpoint(address) if *address == pc))
+            .map(|(number, _)| *number)
+    }
+
+    /// Steps taken so far, going by the journal that going back needs.
+    fn steps(&self, cpu: &Cpu) -> Result<u64> {
+        let journal = cpu.journal.as_ref().ok_or_else(|| anyhow!("the run isn't being recorded, there's no going back"))?;
+        Ok(journal.borrow().position().steps)
+    }
+
+    /// Takes a checkpoint when one is due. There's nothing to take while replaying, the run went
+    /// through there before.
+    fn checkpoint(&mut self, cpu: &Cpu, bus: &mut Bus) {
+        let Some(journal) = &cpu.journal else { return };
+        let (position, replaying) = {
+            let journal = journal.borrow();
+            (journal.position(), journal.replaying())
+        };
+        if replaying || self.checkpoints.last().is_some_and(|last| position.steps < last.position.steps + self.checkpoint_interval) {
+            return;
+        }
+        // checkpoints leave out host state, which is all that could fail to save, and a missing
+        // one only means replaying further
+        if let Ok(snapshot) = snapshot::checkpoint(cpu, bus) {
+            self.checkpoints.push(Checkpoint { position, snapshot });
+        }
+    }
+
+    /// Forgets the history after this point, since changing the hart by hand makes it go differently,
+    /// and takes a checkpoint of the change.
+    fn diverge(&mut self, cpu: &Cpu, bus: &mut Bus) -> Result<()> {
+        let Some(journal) = &cpu.journal else { return Ok(()) };
+        let position = {
+            let mut journal = journal.borrow_mut();
+            journal.truncate();
+            journal.position()
+        };
+        self.checkpoints.retain(|checkpoint| checkpoint.position.steps < position.steps);
+        self.checkpoints.push(Checkpoint { position, snapshot: snapshot::checkpoint(cpu, bus)? });
+        Ok(())
+    }
+
+    /// Restores checkpoint `index` and replays up to `steps` steps, returning the last step before
+    /// which the hart was at `target` and why it stopped there.
+    fn replay(&mut self, cpu: &mut Cpu, bus: &mut Bus, index: usize, steps: u64, target: Option<&Target>) -> Result<Option<(u64, Stop)>> {
+        let journal = cpu.journal.clone().ok_or_else(|| anyhow!("the run isn't being recorded"))?;
+        let checkpoint = &self.checkpoints[index];
+        snapshot::rewind(cpu, bus, &checkpoint.snapshot)?;
+        journal.borrow_mut().seek(checkpoint.position);
+        let log = cpu.commit_log.replace(CommitLog::recorder());
+        let watchpoints = match target {
+            Some(Target::Memory(_, range)) => {
+                let watchpoint = Watchpoint { range: range.clone(), loads: false, stores: true };
+                Some(mem::replace(&mut bus.watchpoints, vec![watchpoint]))
+            }
+            _ => None,
+        };
+
+        let mut found = None;
+        loop {
+            let step = journal.borrow().position().steps;
+            if step >= steps {
+                break;
+            }
+            let (pc, registers) = (cpu.pc, cpu.registers);
+            // errors and all, the step goes the way it did the first time
+            let _ = cpu.step(bus);
+            let commit = cpu.commit_log.as_mut().and_then(CommitLog::take_last);
+            let watch_hit = bus.watch_hit.take();
+            let stop = match target {
+                None => None,
+                Some(Target::Points) => match watch_hit {
+                    Some(index) => self.watchpoints().nth(index).map(|(number, ..)| Stop::Watchpoint(number)),
+                    None => self.breakpoint_at(pc).map(Stop::Breakpoint),
+                },
+                Some(&Target::Register(index)) => {
+                    // syscall results don't show up in the commit log
+                    let logged = commit.is_some_and(|commit| {
+                        commit.entries.iter().any(|entry| matches!(entry, Entry::Register(written, _) if *written == index as u64))
+                    });
+                    (logged || cpu.registers[index] != registers[index]).then_some(Stop::Stepped)
+                }
+                Some(Target::Memory(..)) => watch_hit.map(|_| Stop::Stepped),
+            };
+            if let Some(stop) = stop {
+                found = Some((step, stop));
+            }
+        }
+
+        cpu.commit_log = log;
+        if let Some(watchpoints) = watchpoints {
+            bus.watchpoints = watchpoints;
+        }
+        bus.watch_hit = None;
+        self.exit_code = (self.exited)(cpu);
+        Ok(found)
+    }
+
+    /// Puts the machine back the way it was after `steps` steps.
+    fn travel(&mut self, cpu: &mut Cpu, bus: &mut Bus, steps: u64) -> Result<()> {
+        let index = self.checkpoints.iter().rposition(|checkpoint| checkpoint.position.steps <= steps)
+            .ok_or_else(|| anyhow!("no checkpoint from that far back"))?;
+        self.replay(cpu, bus, index, steps, None)?;
+        Ok(())
+    }
+
+    fn reverse_step(&mut self, cpu: &mut Cpu, bus: &mut Bus, count: u64, output: &mut dyn Write) -> Result<()> {
+        let steps = self.steps(cpu)?;
+        let first = self.checkpoints.first().map_or(steps, |checkpoint| checkpoint.position.steps);
+        let target = steps.saturating_sub(count).max(first);
+        if target < steps {
+            self.travel(cpu, bus, target)?;
+        }
+        if steps - target < count {
+            writeln!(output, "No more reverse-execution history.")?;
+        }
+        self.report(cpu, bus, Stop::Stepped, output)
+    }
+
+    /// Goes back to just before the last time the hart got to `target`, searching the intervals
+    /// between checkpoints from the latest one back.
+    fn reverse_continue(&mut self, cpu: &mut Cpu, bus: &mut Bus, target: Target, output: &mut dyn Write) -> Result<()> {
+        let mut end = self.steps(cpu)?;
+        let mut found = None;
+        for index in (0..self.checkpoints.len()).rev() {
+            let start = self.checkpoints[index].position.steps;
+            if start >= end {
+                continue;
+            }
+            found = self.replay(cpu, bus, index, end, Some(&target))?;
+            if found.is_some() {
+                break;
+            }
+            end = start;
+        }
+
+        let Some((steps, stop)) = found else {
+            if let Some(first) = self.checkpoints.first() {
+                self.travel(cpu, bus, first.position.steps)?;
+            }
+            writeln!(output, "No more reverse-execution history.")?;
+            return self.report(cpu, bus, Stop::Stepped, output);
+        };
+        self.travel(cpu, bus, steps)?;
+        match target {
+            Target::Points => {}
+            Target::Register(index) => writeln!(output, "Last write to {}", REGISTER_NAMES[index])?,
+            Target::Memory(address, _) => writeln!(output, "Last write to {}", self.describe(address))?,
+        }
+        self.report(cpu, bus, stop, output)
+    }
+
+    /// Evaluates numbers, registers and symbols added to or subtracted from each other.
+    fn evaluate(&self, cpu: &Cpu, expression: &str) -> Result<u64> {
+        let mut value = 0u64;
+        let mut negative = false;
+        let mut rest = expression.trim();
+        loop {
+            let end = rest.find(['+', '-']).unwrap_or(rest.len());
+            let term = self.term(cpu, rest[..end].trim())?;
+            value = if negative { value.wrapping_sub(term) } else { value.wrapping_add(term) };
+            let Some(operator) = rest[end..].chars().next() else { return Ok(value) };
+            negative = operator == '-';
+            rest = &rest[end + 1..];
+        }
+    }
+
+    fn term(&self, cpu: &Cpu, term: &str) -> Result<u64> {
+        let name = term.strip_prefix('$').unwrap_or(term);
+        if name.is_empty() {
+            bail!("expected a number, register or symbol");
+        } else if name.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_number(name);
+        } else if name == "pc" {
+            return Ok(cpu.pc);
+        } else if let Some(index) = instruction::parse_register(name) {
+            return Ok(cpu.registers[index]);
+        }
+        self.symbols.iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.address)
+            .ok_or_else(|| anyhow!("no register or symbol named {:?}", name))
+    }
+
+    /// `name+offset` for the symbol `address` is in.
+    fn symbolize(&self, address: u64) -> Option<String> {
+        let (symbol, offset) = loader::symbol_at(&self.symbols, address)?;
+        Some(match offset {
+            0 => symbol.name.clone(),
+            _ => format!("{}+{}", symbol.name, offset),
+        })
+    }
+
+    fn describe(&self, address: u64) -> String {
+        match self.symbolize(address) {
+            Some(symbol) => format!("{:#x} <{}>", address, symbol),
+            None => format!("{:#x}", address),
+        }
+    }
+
+    fn list_points(&self, output: &mut dyn Write) -> Result<()> {
+        if self.points.is_empty() {
+            writeln!(output, "no breakpoints or watchpoints")?;
+        }
+        for (number, point) in &self.points {
+            match *point {
+                Point::Breakpoint(address) => writeln!(output, "{}\tbreakpoint\t{}", number, self.describe(address))?,
+                Point::Watchpoint { address, length, .. } =>
+                    writeln!(output, "{}\twatchpoint\t{}, {} bytes", number, self.describe(address), length)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn show_registers(&self, cpu: &Cpu, output: &mut dyn Write) -> Result<()> {
+        for row in (0..32).step_by(4) {
+            let columns: Vec<String> = (row..row + 4)
+                .map(|index| format!("{:<4} {:#018x}", REGISTER_NAMES[index], cpu.registers[index]))
+                .collect();
+            writeln!(output, "{}", columns.join("  "))?;
+        }
+        writeln!(output, "pc   {}, {:?} mode", self.describe(cpu.pc), cpu.privilege)?;
+        Ok(())
+    }
+
+    /// Lists instructions from `address`, marking the one at pc.
+    fn disassemble(&self, cpu: &mut Cpu, bus: &mut Bus, mut address: u64, count: u64, output: &mut dyn Write) -> Result<()> {
+        for _ in 0..count {
+            let marker = if address == cpu.pc { "=>" } else { "  " };
+            let Some(instruction) = fetch(cpu, bus, address) else {
+                writeln!(output, "{} {}:\tcannot access memory", marker, self.describe(address))?;
+                break;
+            };
+            let encoding = match instruction.size {
+                2 => format!("{:04x}", instruction.encoding),
+                _ => format!("{:08x}", instruction.encoding),
+            };
+            let mut text = instruction.disassemble(address);
+            if let Some(label) = instruction.target(address).and_then(|target| self.symbolize(target)) {
+                text = format!("{} <{}>", text, label);
+            }
+            writeln!(output, "{} {}:\t{:<8}  {}", marker, self.describe(address), encoding, text)?;
+            address = address.wrapping_add(instruction.size);
+        }
+        Ok(())
+    }
+
+    fn disassemble_around_pc(&self, cpu: &mut Cpu, bus: &mut Bus, output: &mut dyn Write) -> Result<()> {
+        // with compressed instructions there's no decoding backwards, so find an earlier address that decodes
+        // forwards into pc and show a few of the instructions before it
+        let mut start = cpu.pc;
+        for distance in (2..=16).rev().step_by(2) {
+            let mut address = cpu.pc.wrapping_sub(distance);
+            let mut addresses = Vec::new();
+            while address < cpu.pc {
+                let Some(instruction) = fetch(cpu, bus, address) else { break };
+                addresses.push(address);
+                address += instruction.size;
+            }
+            if address == cpu.pc {
+                start = addresses[addresses.len().saturating_sub(4)];
+                break;
+            }
+        }
+        self.disassemble(cpu, bus, start, DISASSEMBLY_LINES, output)
+    }
+
+    /// Implements `x/<count><format><size>`, `spec` being everything after the `x`.
+    fn examine(&self, cpu: &mut Cpu, bus: &mut Bus, spec: &str, location: &str, output: &mut dyn Write) -> Result<()> {
+        let spec = spec.strip_prefix('/').unwrap_or(spec);
+        let digits = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or(spec.len());
+        let count = if digits == 0 { 1 } else { spec[..digits].parse()? };
+        let mut format = 'x';
+        let mut size = 4;
+        for letter in spec[digits..].chars() {
+            match letter {
+                'x' | 'd' | 'u' | 'c' | 's' | 'i' => format = letter,
+                'b' => size = 1,
+                'h' => size = 2,
+                'w' => size = 4,
+                'g' => size = 8,
+                _ => bail!("unknown format letter {:?}", letter),
+            }
+        }
+        let mut address = self.evaluate(cpu, location)?;
+        let unreadable = |address: u64| anyhow!("cannot access memory at {:#x}", address);
+
+        match format {
+            'i' => return self.disassemble(cpu, bus, address, count, output),
+            's' => {
+                for _ in 0..count {
+                    let mut string = Vec::new();
+                    let mut byte = [0];
+                    while string.len() < MAX_STRING {
+                        let byte_address = address.wrapping_add(string.len() as u64);
+                        cpu.read_memory(bus, byte_address, &mut byte).map_err(|_| unreadable(byte_address))?;
+                        if byte[0] == 0 {
+                            break;
+                        }
+                        string.push(byte[0]);
+                    }
+                    writeln!(output, "{}:\t{:?}", self.describe(address), String::from_utf8_lossy(&string))?;
+                    address = address.wrapping_add(string.len() as u64 + 1);
+                }
+                return Ok(());
+            }
+            'c' => size = 1,
+            _ => {}
+        }
+
+        let per_line = if size <= 2 { 8 } else { 16 / size };
+        for line in (0..count).step_by(per_line as usize) {
+            write!(output, "{}:", self.describe(address.wrapping_add(line * size)))?;
+            for item in line..count.min(line + per_line) {
+                let item_address = address.wrapping_add(item * size);
+                let mut bytes = [0; 8];
+                if cpu.read_memory(bus, item_address, &mut bytes[..size as usize]).is_err() {
+                    writeln!(output)?;
+                    return Err(unreadable(item_address));
+                }
+                let value = u64::from_le_bytes(bytes);
+                let unused_bits = 64 - 8 * size;
+                match format {
+                    'd' => write!(output, "\t{}", ((value << unused_bits) as i64) >> unused_bits)?,
+                    'u' => write!(output, "\t{}", value)?,
+                    'c' => write!(output, "\t{:?}", value as u8 as char)?,
+                    _ => write!(output, "\t{:#0width$x}", value, width = 2 + 2 * size as usize)?,
+                }
+            }
+            writeln!(output)?;
+        }
+        Ok(())
+    }
+}
+
+fn count(arguments: &str) -> Result<u64> {
+    if arguments.is_empty() { Ok(1) } else { parse_number(arguments) }
+}
+
+/// Reads the instruction at `address` without side effects on the hart.
+fn fetch(cpu: &mut Cpu, bus: &mut Bus, address: u64) -> Option<Instruction> {
+    let mut halfword = [0; 2];
+    cpu.read_memory(bus, address, &mut halfword).ok()?;
+    let mut data = u16::from_le_bytes(halfword) as u32;
+    if data & 3 == 3 {
+        cpu.read_memory(bus, address.wrapping_add(2), &mut halfword).ok()?;
+        data |= (u16::from_le_bytes(halfword) as u32) << 16;
+    }
+    Some(Instruction::decode(data as i32))
+}
+
+/// The return address and stack pointer if the instruction at pc calls a function.
+fn call_return(cpu: &mut Cpu, bus: &mut Bus) -> Option<(u64, u64)> {
+    let instruction = fetch(cpu, bus, cpu.pc)?;
+    let call = matches!(instruction.opcode, OPCODE_JAL | OPCODE_JALR) && matches!(instruction.rd, 1 | 5);
+    call.then_some((cpu.pc + instruction.size, cpu.registers[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::bus::{Ram, DRAM_BASE};
+    use crate::machine::Machine;
+    use crate::replay::Journal;
+
+    const PROGRAM: [u32; 7] = [
+        0x00500513, // li a0, 5
+        0x010000ef, // jal ra, f
+        0x00a13023, // sd a0, 0(sp)
+        0x0000006f, // j .
+        0x00000013, // nop
+        0x00150513, // f: addi a0, a0, 1
+        0x00008067, // ret
+    ];
+
+    fn debug(script: &str) -> String {
+        let machine = Machine::new();
+        let mut bus = Bus::new();
+        bus.map(DRAM_BASE, 0x2000, Ram::new(0x2000));
+        for (i, word) in PROGRAM.iter().enumerate() {
+            bus.store32(DRAM_BASE + 4 * i as u64, *word as u64).unwrap();
+        }
+        let mut cpu = Cpu::new(&machine);
+        cpu.pc = DRAM_BASE;
+        cpu.registers[2] = DRAM_BASE + 0x1000;
+        let symbols = vec![
+            Symbol { name: "_start".to_string(), address: DRAM_BASE, size: 0 },
+            Symbol { name: "f".to_string(), address: DRAM_BASE + 0x14, size: 8 },
+        ];
+        let exited = |cpu: &Cpu| (cpu.registers[10] == 8).then_some(3);
+        cpu.journal = Some(Rc::new(RefCell::new(Journal::new())));
+        let mut debugger = Debugger::new(symbols, &exited);
+        debugger.checkpoint_interval = 3;
+        let mut output = Vec::new();
+        let exit_code = debugger.run(&mut cpu, &mut bus, script.as_bytes(), &mut output).unwrap();
+        format!("{}exit code {}", String::from_utf8(output).unwrap(), exit_code)
+    }
+
+    #[test]
+    fn test_stepping() {
+        let output = debug("step\nnext\np a0\nwatch sp\nc\nx/2dg sp-8\n\nbreak f\ninfo b\nset pc = f\np pc\ndisas _start 2\nsi\nquit\n");
+        assert!(output.contains("=> 0x80000004 <_start+4>:\t010000ef  jal 0x80000014 <f>"), "{}", output);
+        assert!(output.contains("=> 0x80000008 <_start+8>:\t00a13023  sd a0, 0(sp)"), "{}", output);
+        assert!(output.contains("a0 = 0x6 (6)"), "{}", output);
+        assert!(output.contains("Watchpoint 1: 0x80001000, 8 bytes"), "{}", output);
+        assert!(output.contains("Watchpoint 1: 0x80001000 = 0x6\n=> 0x8000000c <_start+12>"), "{}", output);
+        assert!(output.contains("0x80000ff8:\t0\t6\n"), "{}", output);
+        assert!(output.contains("Breakpoint 2 at 0x80000014 <f>"), "{}", output);
+        assert!(output.contains("2\tbreakpoint\t0x80000014 <f>"), "{}", output);
+        assert!(output.contains("pc = 0x80000014 <f>"), "{}", output);
+        assert!(output.contains("   0x80000004 <_start+4>:\t010000ef  jal 0x80000014 <f>\n(dbg)"), "{}", output);
+        assert!(output.contains("=> 0x80000018 <f+4>"), "{}", output);
+        assert!(output.ends_with("exit code 0"), "{}", output);
+    }
+
+    #[test]
+    fn test_breakpoints_and_exit() {
+        let output = debug("b f\nc\ni r\nd 1\nd 1\nset a0 7\nc\nc\n");
+        assert!(output.contains("Breakpoint 1, 0x80000014 <f>\n=> 0x80000014 <f>"), "{}", output);
+        assert!(output.contains("a0   0x0000000000000005"), "{}", output);
+        assert!(output.contains("no breakpoint or watchpoint 1"), "{}", output);
+        assert!(output.contains("program exited with code 3"), "{}", output);
+        assert!(output.contains("the program has exited with code 3"), "{}", output);
+        assert!(output.ends_with("exit code 3"), "{}", output);
+    }
+
+    #[test]
+    fn test_going_back() {
+        let output = debug("s 5\nrs\nrc a0\np a0\ns 3\nrc sp+0\nb f\nrc\nrc\nrc zero\nrs\n");
+        assert!(output.contains("(dbg) => 0x80000008 <_start+8>"), "{}", output);
+        assert!(output.contains("(dbg) Last write to a0\n=> 0x80000014 <f>"), "{}", output);
+        assert!(output.contains("a0 = 0x5 (5)"), "{}", output);
+        assert!(output.contains("(dbg) Last write to 0x80001000\n=> 0x80000008 <_start+8>"), "{}", output);
+        assert!(output.contains("(dbg) Breakpoint 1, 0x80000014 <f>\n=> 0x80000014 <f>"), "{}", output);
+        assert!(output.contains("(dbg) No more reverse-execution history.\n=> 0x80000000 <_start>"), "{}", output);
+        assert_eq!(output.matches("No more reverse-execution history.").count(), 3, "{}", output);
+
+        // changing a register starts a new history from there
+        let output = debug("s 2\nset a0 7\ns\nrs\np a0\ns\nrs 2\np a0\n");
+        assert!(output.contains("a0 = 0x7 (7)"), "{}", output);
+        assert_eq!(output.matches("program exited with code 3").count(), 2, "{}", output);
+        assert!(output.contains("a0 = 0x5 (5)"), "{}", output);
+        // back before the exit, the program hasn't exited
+        assert!(output.ends_with("exit code 0"), "{}", output);
+    }
+}
@@ -0,0 +1,189 @@
+﻿//! Cache of decoded basic blocks, keyed by physical address, so code that runs again skips the fetch
+//! and decode and goes straight to the handler picked for each instruction.
+//!
+//! Blocks never cross a page, which keeps invalidation simple: a store to a page with cached code drops
+//! every block on it, and FENCE.I drops everything, both by way of `Bus::code_written`.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::bus::Bus;
+use crate::instruction::Instruction;
+use crate::machine::{handler, Handler};
+use crate::mmu::PAGE_SIZE;
+use crate::opcodes::*;
+
+/// Longest block decoded in one go.
+const MAX_BLOCK_LENGTH: usize = 64;
+
+#[derive(Clone, Copy)]
+pub struct Decoded {
+    pub instruction: Instruction,
+    pub handler: Handler,
+}
+
+/// Where the next instruction is expected to be, within the block that's running.
+struct Cursor {
+    block: Rc<[Decoded]>,
+    index: usize,
+    address: u64,
+}
+
+#[derive(Default)]
+pub struct DecodeCache {
+    blocks: HashMap<u64, Rc<[Decoded]>>,
+    /// Start addresses of the blocks on each page.
+    pages: HashMap<u64, Vec<u64>>,
+    cursor: Option<Cursor>,
+}
+
+impl std::fmt::Debug for DecodeCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DecodeCache").field("blocks", &self.blocks.len()).finish()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache::default()
+    }
+
+    /// Returns the instruction at `address`, decoding the block starting there if it isn't cached.
+    /// `None` means it can't be cached, as it isn't in memory or straddles a page boundary; the caller
+    /// fetches it the usual way then.
+    pub fn lookup(&mut self, bus: &mut Bus, address: u64) -> Option<Decoded> {
+        self.sync(bus);
+        if let Some(cursor) = &mut self.cursor {
+            if cursor.address == address && cursor.index < cursor.block.len() {
+                let decoded = cursor.block[cursor.index];
+                cursor.index += 1;
+                cursor.address += decoded.instruction.size;
+                return Some(decoded);
+            }
+        }
+
+        let block = match self.blocks.get(&address) {
+            Some(block) => Rc::clone(block),
+            None => {
+                let instructions: Rc<[Decoded]> = decode_block(bus, address)?.into();
+                bus.code_pages.insert(address / PAGE_SIZE);
+                self.pages.entry(address / PAGE_SIZE).or_default().push(address);
+                self.blocks.insert(address, Rc::clone(&instructions));
+                instructions
+            }
+        };
+        let decoded = block[0];
+        self.cursor = Some(Cursor { block, index: 1, address: address + decoded.instruction.size });
+        Some(decoded)
+    }
+
+    /// Drops the blocks on pages written since the last lookup.
+    fn sync(&mut self, bus: &mut Bus) {
+        for page in bus.code_written.drain(..) {
+            self.invalidate(page);
+        }
+    }
+
+    /// Drops the blocks on a page that was written.
+    pub fn invalidate(&mut self, page: u64) {
+        for start in self.pages.remove(&page).unwrap_or_default() {
+            self.blocks.remove(&start);
+        }
+        self.cursor = None;
+    }
+
+    #[cfg(test)]
+    pub fn block_starts(&self) -> Vec<u64> {
+        let mut starts: Vec<u64> = self.blocks.keys().copied().collect();
+        starts.sort();
+        starts
+    }
+}
+
+/// Decodes instructions from `address` up to the first one that may change control flow, or the end
+/// of the page.
+pub fn decode_block(bus: &mut Bus, address: u64) -> Option<Vec<Decoded>> {
+    let page_end = (address / PAGE_SIZE + 1) * PAGE_SIZE;
+    let mut instructions = Vec::new();
+    let mut address = address;
+    while instructions.len() < MAX_BLOCK_LENGTH && address + 2 <= page_end && bus.is_memory(address, 2) {
+        let low = bus.load16(address).ok()?;
+        let data = if low & 3 == 3 {
+            if address + 4 > page_end || !bus.is_memory(address, 4) {
+                break;
+            }
+            bus.load32(address).ok()?
+        } else {
+            low
+        };
+        let instruction = Instruction::decode(data as i32);
+        instructions.push(Decoded { instruction, handler: handler(&instruction) });
+        address += instruction.size;
+        if matches!(instruction.opcode, OPCODE_BRANCH | OPCODE_JAL | OPCODE_JALR | OPCODE_SYSTEM | OPCODE_MISC_MEM) {
+            break;
+        }
+    }
+    (!instructions.is_empty()).then_some(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::{Ram, DRAM_BASE};
+
+    fn bus_with(source: &str) -> Bus {
+        let image = assemble(source, DRAM_BASE).unwrap().flat_image();
+        let mut bus = Bus::new();
+        bus.map(DRAM_BASE, 0x2000, Ram::new(0x2000));
+        bus.store_bytes(DRAM_BASE, &image).unwrap();
+        bus
+    }
+
+    #[test]
+    fn test_blocks() {
+        let mut bus = bus_with("addi a0, a0, 1\n.half 0x0585 # c.addi a1, 1\nbnez a0, 0\nnop\n");
+        let mut cache = DecodeCache::new();
+        let first = cache.lookup(&mut bus, DRAM_BASE).unwrap();
+        assert_eq!(first.instruction.size, 4);
+        assert_eq!(cache.lookup(&mut bus, DRAM_BASE + 4).unwrap().instruction.size, 2);
+        assert_eq!(cache.lookup(&mut bus, DRAM_BASE + 6).unwrap().instruction.opcode, OPCODE_BRANCH);
+        // the branch ends the block
+        assert_eq!(cache.block_starts(), [DRAM_BASE]);
+        cache.lookup(&mut bus, DRAM_BASE + 10).unwrap();
+        assert_eq!(cache.block_starts(), [DRAM_BASE, DRAM_BASE + 10]);
+
+        // jumping into the middle of a block starts another one
+        cache.lookup(&mut bus, DRAM_BASE + 4).unwrap();
+        assert_eq!(cache.block_starts(), [DRAM_BASE, DRAM_BASE + 4, DRAM_BASE + 10]);
+
+        // nothing to decode outside memory, or across the end of a page
+        assert!(cache.lookup(&mut bus, 0x1000).is_none());
+        bus.store32(DRAM_BASE + PAGE_SIZE - 2, 0x00000013).unwrap();
+        assert!(cache.lookup(&mut bus, DRAM_BASE + PAGE_SIZE - 2).is_none());
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let mut bus = bus_with("addi a0, a0, 1\nret\n");
+        let mut cache = DecodeCache::new();
+        cache.lookup(&mut bus, DRAM_BASE).unwrap();
+        cache.lookup(&mut bus, DRAM_BASE + 4).unwrap();
+
+        // stores elsewhere keep the block
+        bus.store64(DRAM_BASE + PAGE_SIZE, 0).unwrap();
+        assert!(cache.lookup(&mut bus, DRAM_BASE).is_some());
+        assert_eq!(cache.block_starts(), [DRAM_BASE]);
+
+        // addi a0, a0, 2
+        bus.store32(DRAM_BASE, 0x00250513).unwrap();
+        assert_eq!(bus.code_written, [DRAM_BASE / PAGE_SIZE]);
+        let decoded = cache.lookup(&mut bus, DRAM_BASE).unwrap();
+        assert_eq!(decoded.instruction.immediate_i(), 2);
+
+        bus.flush_code_pages();
+        assert!(bus.code_pages.is_empty());
+        cache.lookup(&mut bus, DRAM_BASE + 4).unwrap();
+        assert_eq!(cache.block_starts(), [DRAM_BASE + 4]);
+    }
+}
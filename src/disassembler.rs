@@ -0,0 +1,487 @@
+﻿use std::fmt;
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use elf::abi::{PF_X, PT_LOAD, SHF_EXECINSTR, SHT_PROGBITS};
+use elf::endian::LittleEndian;
+use elf::ElfBytes;
+
+use crate::instruction::{Instruction, FLOAT_REGISTER_NAMES, REGISTER_NAMES};
+use crate::loader;
+use crate::opcodes::*;
+
+/// Rounding mode operand names, indexed by the `rm` field. 7 selects the dynamic mode, which isn't printed.
+pub const ROUNDING_MODES: [&str; 5] = ["rne", "rtz", "rdn", "rup", "rmm"];
+
+impl Instruction {
+    /// Disassembles the instruction at `pc` in objdump style, showing branch and jump targets as addresses.
+    pub fn disassemble(&self, pc: u64) -> String {
+        self.format(&|offset| format!("{:#x}", pc.wrapping_add_signed(offset)))
+    }
+
+    /// Where a branch or direct jump at `pc` goes.
+    pub fn target(&self, pc: u64) -> Option<u64> {
+        match self.opcode {
+            OPCODE_JAL => Some(pc.wrapping_add_signed(self.immediate_j())),
+            OPCODE_BRANCH => Some(pc.wrapping_add_signed(self.immediate_b())),
+            _ => None,
+        }
+    }
+
+    fn format(&self, target: &dyn Fn(i64) -> String) -> String {
+        let x = |index: i32| REGISTER_NAMES[index as usize].to_string();
+        let f = |index: i32| FLOAT_REGISTER_NAMES[index as usize].to_string();
+        let (rd, rs1, rs2) = (self.rd, self.rs1, self.rs2);
+        let immediate = self.immediate_i();
+        let offset = |base: i32, immediate: i64| format!("{}({})", immediate, x(base));
+
+        match (self.opcode, self.funct3) {
+            _ if self.raw == 0 && self.encoding != 0 => format!(".half {:#06x}", self.encoding),
+            // the canonical unimplemented instruction, `csrrw zero, cycle, zero`
+            _ if self.raw == 0 || self.raw as u32 == 0xC000_1073 => "unimp".to_string(),
+
+            (OPCODE_OP_IMM, F3_ADD) => match (rd, rs1, immediate) {
+                (0, 0, 0) => "nop".to_string(),
+                (_, 0, _) => text("li", [x(rd), immediate.to_string()]),
+                (_, _, 0) => text("mv", [x(rd), x(rs1)]),
+                _ => text("addi", [x(rd), x(rs1), immediate.to_string()]),
+            },
+            (OPCODE_OP_IMM, F3_SLTU) if immediate == 1 => text("seqz", [x(rd), x(rs1)]),
+            (OPCODE_OP_IMM, F3_XOR) if immediate == -1 => text("not", [x(rd), x(rs1)]),
+            (OPCODE_OP_IMM, F3_SLL | F3_SRL) => {
+                let name = match (self.funct3, self.funct7 >> 1) {
+                    (F3_SLL, 0) => "slli",
+                    (F3_SRL, F6_SRL) => "srli",
+                    (F3_SRA, F6_SRA) => "srai",
+                    _ => return self.unknown(),
+                };
+                text(name, [x(rd), x(rs1), self.shamt.to_string()])
+            }
+            (OPCODE_OP_IMM, _) => {
+                let name = ["addi", "", "slti", "sltiu", "xori", "", "ori", "andi"][self.funct3 as usize];
+                text(name, [x(rd), x(rs1), immediate.to_string()])
+            }
+
+            (OPCODE_OP_IMM_32, F3_ADD) if immediate == 0 => text("sext.w", [x(rd), x(rs1)]),
+            (OPCODE_OP_IMM_32, F3_ADD) => text("addiw", [x(rd), x(rs1), immediate.to_string()]),
+            (OPCODE_OP_IMM_32, F3_SLL | F3_SRL) => {
+                let name = match (self.funct3, self.funct7) {
+                    (F3_SLL, 0) => "slliw",
+                    (F3_SRL, F7_SRL) => "srliw",
+                    (F3_SRA, F7_SRA) => "sraiw",
+                    _ => return self.unknown(),
+                };
+                text(name, [x(rd), x(rs1), self.shamtw().to_string()])
+            }
+
+            (OPCODE_LUI, _) => text("lui", [x(rd), ((self.raw as u32) >> 12).to_string()]),
+            (OPCODE_AUIPC, _) => text("auipc", [x(rd), ((self.raw as u32) >> 12).to_string()]),
+
+            (OPCODE_OP, _) if self.size == 2 && self.funct3 == F3_ADD && self.funct7 == F7_ADD && rs1 == 0 =>
+                text("mv", [x(rd), x(rs2)]),
+            (OPCODE_OP, _) | (OPCODE_OP_32, _) => {
+                let word = self.opcode == OPCODE_OP_32;
+                let name = match (self.funct7, self.funct3) {
+                    (F7_SUB, F3_SUB) if rs1 == 0 => return text(if word { "negw" } else { "neg" }, [x(rd), x(rs2)]),
+                    (F7_SLT, F3_SLT) if !word && rs2 == 0 => return text("sltz", [x(rd), x(rs1)]),
+                    (F7_SLT, F3_SLT) if !word && rs1 == 0 => return text("sgtz", [x(rd), x(rs2)]),
+                    (F7_SLTU, F3_SLTU) if !word && rs1 == 0 => return text("snez", [x(rd), x(rs2)]),
+                    (F7_ADD, _) => ["add", "sll", "slt", "sltu", "xor", "srl", "or", "and"][self.funct3 as usize],
+                    (F7_SUB, F3_SUB) => "sub",
+                    (F7_SRA, F3_SRA) => "sra",
+                    (F7_MULDIV, _) => ["mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu"][self.funct3 as usize],
+                    _ => return self.unknown(),
+                };
+                if word {
+                    if matches!(name, "slt" | "sltu" | "xor" | "or" | "and" | "mulh" | "mulhsu" | "mulhu") {
+                        return self.unknown();
+                    }
+                    text(&format!("{}w", name), [x(rd), x(rs1), x(rs2)])
+                } else {
+                    text(name, [x(rd), x(rs1), x(rs2)])
+                }
+            }
+
+            (OPCODE_JAL, _) => match rd {
+                0 => text("j", [target(self.immediate_j())]),
+                1 => text("jal", [target(self.immediate_j())]),
+                _ => text("jal", [x(rd), target(self.immediate_j())]),
+            },
+            (OPCODE_JALR, 0) => match (rd, rs1, immediate) {
+                (0, 1, 0) => "ret".to_string(),
+                (0, _, 0) => text("jr", [x(rs1)]),
+                (0, _, _) => text("jr", [offset(rs1, immediate)]),
+                (1, _, 0) => text("jalr", [x(rs1)]),
+                (1, _, _) => text("jalr", [offset(rs1, immediate)]),
+                _ => text("jalr", [x(rd), offset(rs1, immediate)]),
+            },
+
+            (OPCODE_BRANCH, F3_BEQ | F3_BNE | F3_BLT | F3_BGE | F3_BLTU | F3_BGEU) => {
+                let destination = target(self.immediate_b());
+                match (self.funct3, rs1, rs2) {
+                    (F3_BEQ, _, 0) => text("beqz", [x(rs1), destination]),
+                    (F3_BNE, _, 0) => text("bnez", [x(rs1), destination]),
+                    (F3_BLT, _, 0) => text("bltz", [x(rs1), destination]),
+                    (F3_BGE, _, 0) => text("bgez", [x(rs1), destination]),
+                    (F3_BLT, 0, _) => text("bgtz", [x(rs2), destination]),
+                    (F3_BGE, 0, _) => text("blez", [x(rs2), destination]),
+                    _ => {
+                        let name = ["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"][self.funct3 as usize];
+                        text(name, [x(rs1), x(rs2), destination])
+                    }
+                }
+            }
+
+            (OPCODE_LOAD, F3_LB..=F3_LWU) => {
+                let name = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"][self.funct3 as usize];
+                text(name, [x(rd), offset(rs1, immediate)])
+            }
+            (OPCODE_STORE, F3_SB..=F3_SD) => {
+                let name = ["sb", "sh", "sw", "sd"][self.funct3 as usize];
+                text(name, [x(rs2), offset(rs1, self.immediate_s())])
+            }
+            (OPCODE_LOAD_FP, F3_FLW) => text("flw", [f(rd), offset(rs1, immediate)]),
+            (OPCODE_LOAD_FP, F3_FLD) => text("fld", [f(rd), offset(rs1, immediate)]),
+            (OPCODE_STORE_FP, F3_FSW) => text("fsw", [f(rs2), offset(rs1, self.immediate_s())]),
+            (OPCODE_STORE_FP, F3_FSD) => text("fsd", [f(rs2), offset(rs1, self.immediate_s())]),
+
+            (OPCODE_MISC_MEM, _) if rd != 0 || rs1 != 0 => self.unknown(),
+            (OPCODE_MISC_MEM, 0) => {
+                let (predecessor, successor) = ((self.raw >> 24) & 0xF, (self.raw >> 20) & 0xF);
+                match (self.raw as u32) >> 28 {
+                    0 if predecessor == 0xF && successor == 0xF => "fence".to_string(),
+                    0 => text("fence", [fence_set(predecessor), fence_set(successor)]),
+                    8 if predecessor == 0b0011 && successor == 0b0011 => "fence.tso".to_string(),
+                    _ => self.unknown(),
+                }
+            }
+            (OPCODE_MISC_MEM, 1) if immediate == 0 => "fence.i".to_string(),
+
+            (OPCODE_AMO, F3_AMO_W | F3_AMO_D) => {
+                let name = match self.funct5() {
+                    F5_LR if rs2 == 0 => "lr",
+                    F5_SC => "sc",
+                    F5_AMOSWAP => "amoswap",
+                    F5_AMOADD => "amoadd",
+                    F5_AMOXOR => "amoxor",
+                    F5_AMOAND => "amoand",
+                    F5_AMOOR => "amoor",
+                    F5_AMOMIN => "amomin",
+                    F5_AMOMAX => "amomax",
+                    F5_AMOMINU => "amominu",
+                    F5_AMOMAXU => "amomaxu",
+                    _ => return self.unknown(),
+                };
+                let width = if self.funct3 == F3_AMO_W { "w" } else { "d" };
+                let ordering = ["", ".rl", ".aq", ".aqrl"][(self.funct7 & 3) as usize];
+                let name = format!("{}.{}{}", name, width, ordering);
+                let address = format!("({})", x(rs1));
+                if self.funct5() == F5_LR { text(&name, [x(rd), address]) } else { text(&name, [x(rd), x(rs2), address]) }
+            }
+
+            (OPCODE_SYSTEM, F3_ECALL_EBREAK) => match (self.funct7, rs2, rs1, rd) {
+                (0, IMM_ECALL, 0, 0) => "ecall".to_string(),
+                (0, IMM_EBREAK, 0, 0) => "ebreak".to_string(),
+                (F7_MRET, RS2_XRET, 0, 0) => "mret".to_string(),
+                (F7_SRET, RS2_XRET, 0, 0) => "sret".to_string(),
+                (F7_WFI, RS2_WFI, 0, 0) => "wfi".to_string(),
+                (F7_SFENCE_VMA, 0, 0, 0) => "sfence.vma".to_string(),
+                (F7_SFENCE_VMA, 0, _, 0) => text("sfence.vma", [x(rs1)]),
+                (F7_SFENCE_VMA, _, _, 0) => text("sfence.vma", [x(rs1), x(rs2)]),
+                _ => self.unknown(),
+            },
+            (OPCODE_SYSTEM, F3_CSRRW | F3_CSRRS | F3_CSRRC | F3_CSRRWI | F3_CSRRSI | F3_CSRRCI) => {
+                let id = self.csr();
+                let csr = csr_name(id).unwrap_or_else(|| id.to_string());
+                let source = if self.funct3 >= F3_CSRRWI { rs1.to_string() } else { x(rs1) };
+                match (self.funct3, rd, rs1) {
+                    (F3_CSRRS, _, 0) => match id {
+                        CSR_CYCLE => text("rdcycle", [x(rd)]),
+                        CSR_TIME => text("rdtime", [x(rd)]),
+                        CSR_INSTRET => text("rdinstret", [x(rd)]),
+                        CSR_FFLAGS => text("frflags", [x(rd)]),
+                        CSR_FCSR => text("frcsr", [x(rd)]),
+                        CSR_FRM => text("frrm", [x(rd)]),
+                        _ => text("csrr", [x(rd), csr]),
+                    },
+                    (F3_CSRRW, 0, _) if id == CSR_FFLAGS => text("fsflags", [source]),
+                    (F3_CSRRW, _, _) if id == CSR_FFLAGS => text("fsflags", [x(rd), source]),
+                    (F3_CSRRW, 0, _) if id == CSR_FRM => text("fsrm", [source]),
+                    (F3_CSRRW, _, _) if id == CSR_FRM => text("fsrm", [x(rd), source]),
+                    (F3_CSRRW, 0, _) if id == CSR_FCSR => text("fscsr", [source]),
+                    (F3_CSRRW, _, _) if id == CSR_FCSR => text("fscsr", [x(rd), source]),
+                    (F3_CSRRW, 0, _) => text("csrw", [csr, source]),
+                    (F3_CSRRS, 0, _) => text("csrs", [csr, source]),
+                    (F3_CSRRC, 0, _) => text("csrc", [csr, source]),
+                    (F3_CSRRWI, 0, _) => text("csrwi", [csr, source]),
+                    (F3_CSRRSI, 0, _) => text("csrsi", [csr, source]),
+                    (F3_CSRRCI, 0, _) => text("csrci", [csr, source]),
+                    _ => {
+                        let name = ["", "csrrw", "csrrs", "csrrc", "", "csrrwi", "csrrsi", "csrrci"][self.funct3 as usize];
+                        text(name, [x(rd), csr, source])
+                    }
+                }
+            }
+
+            (OPCODE_OP_FP | OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD, _) => self.format_float(),
+
+            _ => self.unknown(),
+        }
+    }
+
+    fn format_float(&self) -> String {
+        let x = |index: i32| REGISTER_NAMES[index as usize].to_string();
+        let f = |index: i32| FLOAT_REGISTER_NAMES[index as usize].to_string();
+        let (rd, rs1, rs2) = (self.rd, self.rs1, self.rs2);
+        let suffix = match self.fmt() {
+            FMT_S => "s",
+            FMT_D => "d",
+            _ => return self.unknown(),
+        };
+        let rounding = self.funct3;
+        let valid_rounding = rounding == F3_RM_DYNAMIC || (rounding as usize) < ROUNDING_MODES.len();
+        let with_rounding = |name: &str, mut operands: Vec<String>| {
+            match ROUNDING_MODES.get(rounding as usize) {
+                Some(mode) => operands.push(mode.to_string()),
+                None if rounding != F3_RM_DYNAMIC => return self.unknown(),
+                None => {}
+            }
+            text(name, operands)
+        };
+
+        if self.opcode != OPCODE_OP_FP {
+            let name = match self.opcode {
+                OPCODE_MADD => "fmadd",
+                OPCODE_MSUB => "fmsub",
+                OPCODE_NMSUB => "fnmsub",
+                _ => "fnmadd",
+            };
+            return with_rounding(&format!("{}.{}", name, suffix), vec![f(rd), f(rs1), f(rs2), f(self.rs3())]);
+        }
+
+        let integer = ["w", "wu", "l", "lu"];
+        match (self.funct5(), self.funct3, rs2) {
+            (F5_FADD | F5_FSUB | F5_FMUL | F5_FDIV, _, _) => {
+                let name = ["fadd", "fsub", "fmul", "fdiv"][self.funct5() as usize];
+                with_rounding(&format!("{}.{}", name, suffix), vec![f(rd), f(rs1), f(rs2)])
+            }
+            (F5_FSQRT, _, 0) => with_rounding(&format!("fsqrt.{}", suffix), vec![f(rd), f(rs1)]),
+            (F5_FSGNJ, F3_FSGNJ..=F3_FSGNJX, _) if rs1 == rs2 => {
+                let name = ["fmv", "fneg", "fabs"][self.funct3 as usize];
+                text(&format!("{}.{}", name, suffix), [f(rd), f(rs1)])
+            }
+            (F5_FSGNJ, F3_FSGNJ..=F3_FSGNJX, _) => {
+                let name = ["fsgnj", "fsgnjn", "fsgnjx"][self.funct3 as usize];
+                text(&format!("{}.{}", name, suffix), [f(rd), f(rs1), f(rs2)])
+            }
+            (F5_FMINMAX, F3_FMIN | F3_FMAX, _) => {
+                let name = ["fmin", "fmax"][self.funct3 as usize];
+                text(&format!("{}.{}", name, suffix), [f(rd), f(rs1), f(rs2)])
+            }
+            // widening is exact, so there's no rounding mode to show
+            (F5_FCVT_FMT_FMT, _, FMT_S) if self.fmt() == FMT_D && valid_rounding => text("fcvt.d.s", [f(rd), f(rs1)]),
+            (F5_FCVT_FMT_FMT, _, FMT_D) if self.fmt() == FMT_S => with_rounding("fcvt.s.d", vec![f(rd), f(rs1)]),
+            (F5_FCMP, F3_FLE..=F3_FEQ, _) => {
+                let name = ["fle", "flt", "feq"][self.funct3 as usize];
+                text(&format!("{}.{}", name, suffix), [x(rd), f(rs1), f(rs2)])
+            }
+            (F5_FCVT_INT_FMT, _, RS2_FCVT_W..=RS2_FCVT_LU) =>
+                with_rounding(&format!("fcvt.{}.{}", integer[rs2 as usize], suffix), vec![x(rd), f(rs1)]),
+            (F5_FCVT_FMT_INT, _, RS2_FCVT_W | RS2_FCVT_WU) if self.fmt() == FMT_D && valid_rounding =>
+                text(&format!("fcvt.d.{}", integer[rs2 as usize]), [f(rd), x(rs1)]),
+            (F5_FCVT_FMT_INT, _, RS2_FCVT_W..=RS2_FCVT_LU) =>
+                with_rounding(&format!("fcvt.{}.{}", suffix, integer[rs2 as usize]), vec![f(rd), x(rs1)]),
+            (F5_FMV_X_FCLASS, F3_FMV_X, 0) => text(if self.fmt() == FMT_S { "fmv.x.w" } else { "fmv.x.d" }, [x(rd), f(rs1)]),
+            (F5_FMV_X_FCLASS, F3_FCLASS, 0) => text(&format!("fclass.{}", suffix), [x(rd), f(rs1)]),
+            (F5_FMV_FMT_X, 0, 0) => text(if self.fmt() == FMT_S { "fmv.w.x" } else { "fmv.d.x" }, [f(rd), x(rs1)]),
+            _ => self.unknown(),
+        }
+    }
+
+    fn unknown(&self) -> String {
+        match self.size {
+            2 => format!(".half {:#06x}", self.encoding),
+            _ => format!(".word {:#010x}", self.encoding as u32),
+        }
+    }
+}
+
+/// Shows branch and jump targets as offsets from the instruction, since its address isn't known.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.format(&|offset| offset.to_string()))
+    }
+}
+
+/// Lists the executable sections of an ELF file like `objdump -d`, or its executable segments if it has no
+/// section headers.
+pub fn dump_elf(elf_bytes: &[u8], output: &mut dyn Write) -> Result<()> {
+    let elf_file = ElfBytes::<LittleEndian>::minimal_parse(elf_bytes).context("not an ELF file")?;
+    let symbols = loader::symbols(elf_bytes);
+
+    let mut code = Vec::new();
+    if let (Some(sections), Some(strings)) = elf_file.section_headers_with_strtab()? {
+        for section in sections.iter().filter(|section| section.sh_type == SHT_PROGBITS && section.sh_flags & SHF_EXECINSTR as u64 != 0) {
+            let (data, _) = elf_file.section_data(&section)?;
+            code.push((format!("section {}", strings.get(section.sh_name as usize)?), section.sh_addr, data));
+        }
+    }
+    if code.is_empty() {
+        for segment in elf_file.segments().into_iter().flatten().filter(|segment| segment.p_type == PT_LOAD && segment.p_flags & PF_X != 0) {
+            let data = elf_bytes.get(segment.p_offset as usize..(segment.p_offset + segment.p_filesz) as usize)
+                .context("segment extends past the end of the file")?;
+            code.push((format!("segment at {:#x}", segment.p_vaddr), segment.p_vaddr, data));
+        }
+    }
+
+    for (name, address, data) in code {
+        writeln!(output, "\nDisassembly of {}:", name)?;
+        let mut offset = 0;
+        while offset + 2 <= data.len() {
+            let pc = address + offset as u64;
+            for symbol in symbols.iter().filter(|symbol| symbol.address == pc) {
+                writeln!(output, "\n{:016x} <{}>:", pc, symbol.name)?;
+            }
+
+            let low = u16::from_le_bytes([data[offset], data[offset + 1]]) as u32;
+            let word = match data.get(offset + 2..offset + 4) {
+                Some(high) if low & 3 == 3 => low | (u16::from_le_bytes([high[0], high[1]]) as u32) << 16,
+                _ => low,
+            };
+            let instruction = Instruction::decode(word as i32);
+            let encoding = match instruction.size {
+                2 => format!("{:04x}", instruction.encoding),
+                _ => format!("{:08x}", instruction.encoding),
+            };
+            let mut text = instruction.disassemble(pc);
+            if let Some((symbol, offset)) = instruction.target(pc).and_then(|target| loader::symbol_at(&symbols, target)) {
+                text += &match offset {
+                    0 => format!(" <{}>", symbol.name),
+                    _ => format!(" <{}+{:#x}>", symbol.name, offset),
+                };
+            }
+            writeln!(output, "{:8x}:\t{:<8}  {}", pc, encoding, text)?;
+            offset += instruction.size as usize;
+        }
+    }
+    Ok(())
+}
+
+fn text(name: &str, operands: impl IntoIterator<Item = String>) -> String {
+    let operands: Vec<String> = operands.into_iter().collect();
+    if operands.is_empty() { name.to_string() } else { format!("{} {}", name, operands.join(", ")) }
+}
+
+fn fence_set(bits: i32) -> String {
+    let set: String = "iorw".chars().enumerate().filter(|(i, _)| bits & (8 >> i) != 0).map(|(_, c)| c).collect();
+    if set.is_empty() { "0".to_string() } else { set }
+}
+
+pub fn csr_name(id: u64) -> Option<String> {
+    let name = match id {
+        CSR_FFLAGS => "fflags",
+        CSR_FRM => "frm",
+        CSR_FCSR => "fcsr",
+        CSR_CYCLE => "cycle",
+        CSR_TIME => "time",
+        CSR_INSTRET => "instret",
+        CSR_HPMCOUNTER3..=CSR_HPMCOUNTER31 => return Some(format!("hpmcounter{}", id - CSR_CYCLE)),
+        CSR_SSTATUS => "sstatus",
+        CSR_SIE => "sie",
+        CSR_STVEC => "stvec",
+        CSR_SCOUNTEREN => "scounteren",
+        CSR_SSCRATCH => "sscratch",
+        CSR_SEPC => "sepc",
+        CSR_SCAUSE => "scause",
+        CSR_STVAL => "stval",
+        CSR_SIP => "sip",
+        CSR_STIMECMP => "stimecmp",
+        CSR_SATP => "satp",
+        CSR_MSTATUS => "mstatus",
+        CSR_MISA => "misa",
+        CSR_MEDELEG => "medeleg",
+        CSR_MIDELEG => "mideleg",
+        CSR_MIE => "mie",
+        CSR_MTVEC => "mtvec",
+        CSR_MCOUNTEREN => "mcounteren",
+        CSR_MENVCFG => "menvcfg",
+        CSR_MCOUNTINHIBIT => "mcountinhibit",
+        CSR_MHPMEVENT3..=CSR_MHPMEVENT31 => return Some(format!("mhpmevent{}", id - CSR_MHPMEVENT3 + 3)),
+        CSR_MSCRATCH => "mscratch",
+        CSR_MEPC => "mepc",
+        CSR_MCAUSE => "mcause",
+        CSR_MTVAL => "mtval",
+        CSR_MIP => "mip",
+        CSR_PMPCFG0..=CSR_PMPCFG15 => return Some(format!("pmpcfg{}", id - CSR_PMPCFG0)),
+        CSR_PMPADDR0..=CSR_PMPADDR63 => return Some(format!("pmpaddr{}", id - CSR_PMPADDR0)),
+        CSR_TSELECT => "tselect",
+        CSR_MCYCLE => "mcycle",
+        CSR_MINSTRET => "minstret",
+        CSR_MHPMCOUNTER3..=CSR_MHPMCOUNTER31 => return Some(format!("mhpmcounter{}", id - CSR_MCYCLE)),
+        CSR_MVENDORID => "mvendorid",
+        CSR_MARCHID => "marchid",
+        CSR_MIMPID => "mimpid",
+        CSR_MHARTID => "mhartid",
+        CSR_MCONFIGPTR => "mconfigptr",
+        _ if (CSR_TSELECT + 1..=CSR_TDATA3).contains(&id) => return Some(format!("tdata{}", id - CSR_TSELECT)),
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disassemble(encoding: u32, pc: u64) -> String {
+        Instruction::decode(encoding as i32).disassemble(pc)
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let cases = [
+            (0x00000013, "nop"),
+            (0x00500513, "li a0, 5"),
+            (0x00058513, "mv a0, a1"),
+            (0xfff5c513, "not a0, a1"),
+            (0x0005851b, "sext.w a0, a1"),
+            (0x12345537, "lui a0, 74565"),
+            (0x02b50533, "mul a0, a0, a1"),
+            (0x01058567, "jalr a0, 16(a1)"),
+            (0x00008067, "ret"),
+            (0x00a13023, "sd a0, 0(sp)"),
+            (0x0ff0000f, "fence"),
+            (0x8330000f, "fence.tso"),
+            (0x06b6252f, "amoadd.w.aqrl a0, a1, (a2)"),
+            (0x100525af, "lr.w a1, (a0)"),
+            (0x02c58553, "fadd.d fa0, fa1, fa2, rne"),
+            (0x02c5f553, "fadd.d fa0, fa1, fa2"),
+            (0x22b58553, "fmv.d fa0, fa1"),
+            (0xc2051553, "fcvt.w.d a0, fa0, rtz"),
+            (0x02c5d553, ".word 0x02c5d553"),
+            (0x30002573, "csrr a0, mstatus"),
+            (0x14059073, "csrw sscratch, a1"),
+            (0xc0002573, "rdcycle a0"),
+            (0x00000073, "ecall"),
+            (0x30200073, "mret"),
+            (0x557d, "li a0, -1"),
+            (0x852e, "mv a0, a1"),
+            (0x8082, "ret"),
+            (0x0000, "unimp"),
+        ];
+        for (encoding, expected) in cases {
+            assert_eq!(disassemble(encoding, 0), expected, "{:#x}", encoding);
+        }
+    }
+
+    #[test]
+    fn test_targets() {
+        assert_eq!(disassemble(0x010000ef, 0x8000_0004), "jal 0x80000014");
+        assert_eq!(disassemble(0xffdff06f, 0x8000_0004), "j 0x80000000");
+        assert_eq!(disassemble(0x00b50463, 0x1000), "beq a0, a1, 0x1008");
+        assert_eq!(disassemble(0x00050463, 0x1000), "beqz a0, 0x1008");
+        assert_eq!(Instruction::decode(0x00b50463).target(0x1000), Some(0x1008));
+        assert_eq!(Instruction::decode(0x00008067).target(0x1000), None);
+        assert_eq!(Instruction::decode(0xffdff06fu32 as i32).to_string(), "j -4");
+    }
+}
@@ -0,0 +1,610 @@
+﻿use std::cmp::Ordering;
+
+pub const FLAG_INEXACT: u64 = 1 << 0;
+pub const FLAG_UNDERFLOW: u64 = 1 << 1;
+pub const FLAG_OVERFLOW: u64 = 1 << 2;
+pub const FLAG_DIVIDE_BY_ZERO: u64 = 1 << 3;
+pub const FLAG_INVALID: u64 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    NearestEven,
+    TowardZero,
+    Down,
+    Up,
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+    pub fn from_bits(bits: u64) -> Option<RoundingMode> {
+        match bits {
+            0 => Some(RoundingMode::NearestEven),
+            1 => Some(RoundingMode::TowardZero),
+            2 => Some(RoundingMode::Down),
+            3 => Some(RoundingMode::Up),
+            4 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+/// Binary interchange format. Values are always passed around as raw bits in the low part of an u64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    exponent_bits: i32,
+    fraction_bits: i32,
+}
+
+pub const SINGLE: Format = Format { exponent_bits: 8, fraction_bits: 23 };
+pub const DOUBLE: Format = Format { exponent_bits: 11, fraction_bits: 52 };
+
+impl Format {
+    fn bias(self) -> i32 { (1 << (self.exponent_bits - 1)) - 1 }
+    fn precision(self) -> i32 { self.fraction_bits + 1 }
+    fn min_exponent(self) -> i32 { 1 - self.bias() }
+    fn exponent_mask(self) -> u64 { (1 << self.exponent_bits) - 1 }
+    fn fraction_mask(self) -> u64 { (1 << self.fraction_bits) - 1 }
+    pub fn sign_bit(self) -> u64 { 1 << (self.exponent_bits + self.fraction_bits) }
+
+    pub fn canonical_nan(self) -> u64 {
+        (self.exponent_mask() << self.fraction_bits) | (1 << (self.fraction_bits - 1))
+    }
+
+    fn infinity(self, sign: bool) -> u64 { self.with_sign(sign, self.exponent_mask() << self.fraction_bits) }
+    fn zero(self, sign: bool) -> u64 { self.with_sign(sign, 0) }
+    fn largest(self, sign: bool) -> u64 { self.infinity(sign) - 1 }
+    fn with_sign(self, sign: bool, magnitude: u64) -> u64 { if sign { magnitude | self.sign_bit() } else { magnitude } }
+
+    fn is_nan(self, bits: u64) -> bool {
+        (bits >> self.fraction_bits) & self.exponent_mask() == self.exponent_mask() && bits & self.fraction_mask() != 0
+    }
+
+    fn is_signaling_nan(self, bits: u64) -> bool {
+        self.is_nan(bits) && bits & (1 << (self.fraction_bits - 1)) == 0
+    }
+
+    fn unpack(self, bits: u64) -> Value {
+        let sign = bits & self.sign_bit() != 0;
+        let exponent = ((bits >> self.fraction_bits) & self.exponent_mask()) as i32;
+        let fraction = bits & self.fraction_mask();
+        if exponent == self.exponent_mask() as i32 {
+            if fraction == 0 {
+                Value::Infinity(sign)
+            } else {
+                Value::NaN { signaling: self.is_signaling_nan(bits) }
+            }
+        } else if exponent == 0 {
+            if fraction == 0 {
+                Value::Zero(sign)
+            } else {
+                Value::Finite { sign, exponent: self.min_exponent() - self.fraction_bits, significand: fraction as u128 }
+            }
+        } else {
+            let significand = (fraction | (1 << self.fraction_bits)) as u128;
+            Value::Finite { sign, exponent: exponent - self.bias() - self.fraction_bits, significand }
+        }
+    }
+}
+
+/// Unpacked operand; a finite value equals `significand * 2^exponent`.
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    NaN { signaling: bool },
+    Infinity(bool),
+    Zero(bool),
+    Finite { sign: bool, exponent: i32, significand: u128 },
+}
+
+/// Position of the remainder dropped by a right shift, relative to half of the last kept unit.
+#[derive(PartialEq)]
+enum Remainder {
+    Zero,
+    BelowHalf,
+    Half,
+    AboveHalf,
+}
+
+/// IEEE-754 arithmetic with a fixed rounding mode that accumulates exception flags,
+/// mirroring what a single floating point instruction sees.
+pub struct FloatContext {
+    pub rounding_mode: RoundingMode,
+    pub flags: u64,
+}
+
+impl FloatContext {
+    pub fn new(rounding_mode: RoundingMode) -> FloatContext {
+        FloatContext { rounding_mode, flags: 0 }
+    }
+
+    pub fn add(&mut self, f: Format, a: u64, b: u64) -> u64 {
+        let sum = self.sum(f.unpack(a), f.unpack(b));
+        self.pack(f, sum)
+    }
+
+    pub fn sub(&mut self, f: Format, a: u64, b: u64) -> u64 {
+        self.add(f, a, b ^ f.sign_bit())
+    }
+
+    pub fn mul(&mut self, f: Format, a: u64, b: u64) -> u64 {
+        let product = self.product(f.unpack(a), f.unpack(b));
+        self.pack(f, product)
+    }
+
+    /// Computes `a * b + c` with a single rounding.
+    pub fn fused_multiply_add(&mut self, f: Format, a: u64, b: u64, c: u64) -> u64 {
+        let product = self.product(f.unpack(a), f.unpack(b));
+        let sum = self.sum(product, f.unpack(c));
+        self.pack(f, sum)
+    }
+
+    pub fn div(&mut self, f: Format, a: u64, b: u64) -> u64 {
+        match (f.unpack(a), f.unpack(b)) {
+            (a @ Value::NaN { .. }, b) | (a, b @ Value::NaN { .. }) => self.propagate_nan(f, &[a, b]),
+            (Value::Infinity(_), Value::Infinity(_)) | (Value::Zero(_), Value::Zero(_)) => self.invalid(f),
+            (Value::Infinity(sa), b) => f.infinity(sa ^ sign_of(b)),
+            (a, Value::Infinity(sb)) => f.zero(sign_of(a) ^ sb),
+            (Value::Zero(sa), b) => f.zero(sa ^ sign_of(b)),
+            (a, Value::Zero(sb)) => {
+                self.flags |= FLAG_DIVIDE_BY_ZERO;
+                f.infinity(sign_of(a) ^ sb)
+            }
+            (Value::Finite { sign: sa, exponent: ea, significand: ma }, Value::Finite { sign: sb, exponent: eb, significand: mb }) => {
+                let (ea, ma) = normalize(ea, ma, 63);
+                let (eb, mb) = normalize(eb, mb, 63);
+                let dividend = ma << 64;
+                let quotient = dividend / mb;
+                let sticky = (dividend % mb != 0) as u128;
+                self.round(f, sa ^ sb, ea - eb - 64, quotient | sticky)
+            }
+        }
+    }
+
+    pub fn sqrt(&mut self, f: Format, a: u64) -> u64 {
+        match f.unpack(a) {
+            a @ Value::NaN { .. } => self.propagate_nan(f, &[a]),
+            Value::Zero(sign) => f.zero(sign),
+            Value::Infinity(false) => f.infinity(false),
+            Value::Infinity(true) | Value::Finite { sign: true, .. } => self.invalid(f),
+            Value::Finite { sign: false, exponent, significand } => {
+                let (mut exponent, mut significand) = normalize(exponent, significand, 125);
+                if exponent % 2 != 0 {
+                    exponent -= 1;
+                    significand <<= 1;
+                }
+                let root = isqrt(significand);
+                let sticky = (root * root != significand) as u128;
+                self.round(f, false, exponent / 2, root | sticky)
+            }
+        }
+    }
+
+    pub fn convert(&mut self, from: Format, to: Format, a: u64) -> u64 {
+        let value = from.unpack(a);
+        if let Value::NaN { signaling: true } = value {
+            self.flags |= FLAG_INVALID;
+        }
+        self.pack(to, value)
+    }
+
+    /// Converts to a `width`-bit integer, sign-extended to 64 bits. Out of range values saturate.
+    pub fn float_to_int(&mut self, f: Format, a: u64, signed: bool, width: u32) -> u64 {
+        let (min, max): (i128, i128) = if signed {
+            (-(1 << (width - 1)), (1 << (width - 1)) - 1)
+        } else {
+            (0, (1 << width) - 1)
+        };
+        let saturated = |value: i128| sign_extend(value as u64, width);
+
+        let (sign, magnitude, inexact) = match f.unpack(a) {
+            Value::NaN { .. } => {
+                self.flags |= FLAG_INVALID;
+                return saturated(max);
+            }
+            Value::Infinity(sign) => {
+                self.flags |= FLAG_INVALID;
+                return saturated(if sign { min } else { max });
+            }
+            Value::Zero(_) => return 0,
+            Value::Finite { sign, exponent, significand } => {
+                if exponent >= 0 {
+                    if exponent > 64 {
+                        self.flags |= FLAG_INVALID;
+                        return saturated(if sign { min } else { max });
+                    }
+                    (sign, significand << exponent, false)
+                } else {
+                    let (magnitude, remainder) = shift_right(significand, -exponent);
+                    let magnitude = magnitude + self.round_up(sign, magnitude, &remainder) as u128;
+                    (sign, magnitude, remainder != Remainder::Zero)
+                }
+            }
+        };
+
+        let value = if sign { -(magnitude as i128) } else { magnitude as i128 };
+        if value < min || value > max {
+            self.flags |= FLAG_INVALID;
+            saturated(if sign { min } else { max })
+        } else {
+            if inexact {
+                self.flags |= FLAG_INEXACT;
+            }
+            saturated(value)
+        }
+    }
+
+    /// Converts the low `width` bits of `value` to a floating point number.
+    pub fn int_to_float(&mut self, f: Format, value: u64, signed: bool, width: u32) -> u64 {
+        let value = if signed { sign_extend(value, width) as i64 as i128 } else { (value & mask(width)) as i128 };
+        if value == 0 {
+            f.zero(false)
+        } else {
+            self.round(f, value < 0, 0, value.unsigned_abs())
+        }
+    }
+
+    /// Quiet comparison (FEQ) when `signaling` is false, signaling comparison (FLT, FLE) otherwise.
+    pub fn compare(&mut self, f: Format, a: u64, b: u64, signaling: bool) -> Option<Ordering> {
+        if f.is_nan(a) || f.is_nan(b) {
+            if signaling || f.is_signaling_nan(a) || f.is_signaling_nan(b) {
+                self.flags |= FLAG_INVALID;
+            }
+            return None;
+        }
+        let key = |bits: u64| {
+            let magnitude = (bits & !f.sign_bit()) as i128;
+            if bits & f.sign_bit() != 0 { -magnitude } else { magnitude }
+        };
+        Some(key(a).cmp(&key(b)))
+    }
+
+    /// FMIN/FMAX: NaN operands are ignored unless both are NaN, and -0 is smaller than +0.
+    pub fn min_max(&mut self, f: Format, a: u64, b: u64, max: bool) -> u64 {
+        if f.is_signaling_nan(a) || f.is_signaling_nan(b) {
+            self.flags |= FLAG_INVALID;
+        }
+        match (f.is_nan(a), f.is_nan(b)) {
+            (true, true) => f.canonical_nan(),
+            (true, false) => b,
+            (false, true) => a,
+            (false, false) => {
+                let key = |bits: u64| {
+                    let magnitude = (bits & !f.sign_bit()) as i128;
+                    if bits & f.sign_bit() != 0 { -magnitude - 1 } else { magnitude }
+                };
+                if (key(a) < key(b)) ^ max { a } else { b }
+            }
+        }
+    }
+
+    fn product(&mut self, a: Value, b: Value) -> Value {
+        match (a, b) {
+            (Value::NaN { signaling: sa }, Value::NaN { signaling: sb }) => self.nan(sa || sb),
+            (Value::NaN { signaling }, _) | (_, Value::NaN { signaling }) => self.nan(signaling),
+            (Value::Infinity(_), Value::Zero(_)) | (Value::Zero(_), Value::Infinity(_)) => self.nan(true),
+            (Value::Infinity(sa), b) => Value::Infinity(sa ^ sign_of(b)),
+            (a, Value::Infinity(sb)) => Value::Infinity(sign_of(a) ^ sb),
+            (Value::Zero(sa), b) => Value::Zero(sa ^ sign_of(b)),
+            (a, Value::Zero(sb)) => Value::Zero(sign_of(a) ^ sb),
+            (Value::Finite { sign: sa, exponent: ea, significand: ma }, Value::Finite { sign: sb, exponent: eb, significand: mb }) => {
+                Value::Finite { sign: sa ^ sb, exponent: ea + eb, significand: ma * mb }
+            }
+        }
+    }
+
+    /// Exact sum of two unpacked values, with the low bit of the significand acting as a sticky bit.
+    fn sum(&mut self, a: Value, b: Value) -> Value {
+        match (a, b) {
+            (Value::NaN { signaling: sa }, Value::NaN { signaling: sb }) => self.nan(sa || sb),
+            (Value::NaN { signaling }, _) | (_, Value::NaN { signaling }) => self.nan(signaling),
+            (Value::Infinity(sa), Value::Infinity(sb)) if sa != sb => self.nan(true),
+            (Value::Infinity(sign), _) | (_, Value::Infinity(sign)) => Value::Infinity(sign),
+            (Value::Zero(sa), Value::Zero(sb)) => Value::Zero(if sa == sb { sa } else { self.rounding_mode == RoundingMode::Down }),
+            (Value::Zero(_), value) | (value, Value::Zero(_)) => value,
+            (Value::Finite { sign: sa, exponent: ea, significand: ma }, Value::Finite { sign: sb, exponent: eb, significand: mb }) => {
+                // both significands get at least 19 spare low bits, so aligning is exact unless the
+                // smaller operand is too small to cause any cancellation
+                let (ea, ma) = normalize(ea, ma, 125);
+                let (eb, mb) = normalize(eb, mb, 125);
+                let ((sa, ea, ma), (sb, eb, mb)) = if ea >= eb { ((sa, ea, ma), (sb, eb, mb)) } else { ((sb, eb, mb), (sa, ea, ma)) };
+                let mb = shift_right_jam(mb, ea - eb);
+                if sa == sb {
+                    Value::Finite { sign: sa, exponent: ea, significand: ma + mb }
+                } else {
+                    match ma.cmp(&mb) {
+                        Ordering::Greater => Value::Finite { sign: sa, exponent: ea, significand: ma - mb },
+                        Ordering::Less => Value::Finite { sign: sb, exponent: ea, significand: mb - ma },
+                        Ordering::Equal => Value::Zero(self.rounding_mode == RoundingMode::Down),
+                    }
+                }
+            }
+        }
+    }
+
+    fn nan(&mut self, invalid: bool) -> Value {
+        if invalid {
+            self.flags |= FLAG_INVALID;
+        }
+        Value::NaN { signaling: false }
+    }
+
+    fn invalid(&mut self, f: Format) -> u64 {
+        self.flags |= FLAG_INVALID;
+        f.canonical_nan()
+    }
+
+    fn propagate_nan(&mut self, f: Format, operands: &[Value]) -> u64 {
+        if operands.iter().any(|x| matches!(x, Value::NaN { signaling: true })) {
+            self.flags |= FLAG_INVALID;
+        }
+        f.canonical_nan()
+    }
+
+    fn pack(&mut self, f: Format, value: Value) -> u64 {
+        match value {
+            Value::NaN { .. } => f.canonical_nan(),
+            Value::Infinity(sign) => f.infinity(sign),
+            Value::Zero(sign) => f.zero(sign),
+            Value::Finite { sign, exponent, significand } => self.round(f, sign, exponent, significand),
+        }
+    }
+
+    fn round_up(&self, sign: bool, kept: u128, remainder: &Remainder) -> bool {
+        match (self.rounding_mode, remainder) {
+            (_, Remainder::Zero) => false,
+            (RoundingMode::NearestEven, Remainder::Half) => kept & 1 != 0,
+            (RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude, r) => *r != Remainder::BelowHalf,
+            (RoundingMode::TowardZero, _) => false,
+            (RoundingMode::Down, _) => sign,
+            (RoundingMode::Up, _) => !sign,
+        }
+    }
+
+    /// Rounds `significand * 2^exponent` to format `f`, raising inexact, underflow and overflow.
+    /// Tininess is detected after rounding, as RISC-V requires.
+    fn round(&mut self, f: Format, sign: bool, exponent: i32, significand: u128) -> u64 {
+        let precision = f.precision();
+        let leading = exponent + 127 - significand.leading_zeros() as i32;
+        let mut lsb = leading.max(f.min_exponent()) - (precision - 1);
+
+        let (kept, remainder) = shift_right(significand, lsb - exponent);
+        let mut kept = kept + self.round_up(sign, kept, &remainder) as u128;
+        if kept >> precision != 0 {
+            kept >>= 1;
+            lsb += 1;
+        }
+
+        if remainder != Remainder::Zero {
+            self.flags |= FLAG_INEXACT;
+            let tiny = leading < f.min_exponent() - 1 || (leading == f.min_exponent() - 1 && {
+                // only a carry into the smallest normal number can make an unbounded result not tiny
+                let (unbounded, remainder) = shift_right(significand, leading - (precision - 1) - exponent);
+                (unbounded + self.round_up(sign, unbounded, &remainder) as u128) >> precision == 0
+            });
+            if tiny {
+                self.flags |= FLAG_UNDERFLOW;
+            }
+        }
+
+        if kept >> (precision - 1) == 0 {
+            return f.with_sign(sign, kept as u64);
+        }
+        if lsb + precision - 1 > f.bias() {
+            self.flags |= FLAG_OVERFLOW | FLAG_INEXACT;
+            let to_infinity = match self.rounding_mode {
+                RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+                RoundingMode::TowardZero => false,
+                RoundingMode::Down => sign,
+                RoundingMode::Up => !sign,
+            };
+            return if to_infinity { f.infinity(sign) } else { f.largest(sign) };
+        }
+        let biased_exponent = (lsb + precision - 1 + f.bias()) as u64;
+        f.with_sign(sign, (biased_exponent << f.fraction_bits) | (kept as u64 & f.fraction_mask()))
+    }
+}
+
+/// FCLASS result: a one-hot mask describing the kind of value.
+pub fn classify(f: Format, bits: u64) -> u64 {
+    let shift = match f.unpack(bits) {
+        Value::Infinity(true) => 0,
+        Value::Finite { sign: true, .. } if (bits >> f.fraction_bits) & f.exponent_mask() != 0 => 1,
+        Value::Finite { sign: true, .. } => 2,
+        Value::Zero(true) => 3,
+        Value::Zero(false) => 4,
+        Value::Finite { sign: false, .. } if (bits >> f.fraction_bits) & f.exponent_mask() == 0 => 5,
+        Value::Finite { sign: false, .. } => 6,
+        Value::Infinity(false) => 7,
+        Value::NaN { signaling: true } => 8,
+        Value::NaN { signaling: false } => 9,
+    };
+    1 << shift
+}
+
+fn sign_of(value: Value) -> bool {
+    match value {
+        Value::NaN { .. } => false,
+        Value::Infinity(sign) | Value::Zero(sign) | Value::Finite { sign, .. } => sign,
+    }
+}
+
+fn mask(width: u32) -> u64 {
+    if width == 64 { u64::MAX } else { (1 << width) - 1 }
+}
+
+fn sign_extend(value: u64, width: u32) -> u64 {
+    ((value << (64 - width)) as i64 >> (64 - width)) as u64
+}
+
+/// Shifts the significand so that its most significant bit ends up at bit `msb`.
+fn normalize(exponent: i32, significand: u128, msb: i32) -> (i32, u128) {
+    let shift = msb - (127 - significand.leading_zeros() as i32);
+    if shift >= 0 {
+        (exponent - shift, significand << shift)
+    } else {
+        (exponent - shift, shift_right_jam(significand, -shift))
+    }
+}
+
+fn shift_right_jam(value: u128, shift: i32) -> u128 {
+    let (kept, remainder) = shift_right(value, shift);
+    kept | (remainder != Remainder::Zero) as u128
+}
+
+fn shift_right(value: u128, shift: i32) -> (u128, Remainder) {
+    if shift <= 0 {
+        return (value << -shift, Remainder::Zero);
+    }
+    let (kept, dropped, half) = match shift {
+        1..=127 => (value >> shift, value & ((1 << shift) - 1), 1u128 << (shift - 1)),
+        128 => (0, value, 1 << 127),
+        _ => (0, (value != 0) as u128, 2),
+    };
+    let remainder = match dropped.cmp(&half) {
+        _ if dropped == 0 => Remainder::Zero,
+        Ordering::Less => Remainder::BelowHalf,
+        Ordering::Equal => Remainder::Half,
+        Ordering::Greater => Remainder::AboveHalf,
+    };
+    (kept, remainder)
+}
+
+fn isqrt(value: u128) -> u128 {
+    let mut root = 0u128;
+    let mut remainder = value;
+    let mut bit = 1u128 << 126;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if remainder >= root + bit {
+            remainder -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> FloatContext {
+        FloatContext::new(RoundingMode::NearestEven)
+    }
+
+    #[test]
+    fn test_matches_host_arithmetic() {
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let mut random = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..20000 {
+            let (a, b, c) = (f64::from_bits(random()), f64::from_bits(random()), f64::from_bits(random()));
+            let (a, b, c) = (a.to_bits(), b.to_bits(), c.to_bits());
+            let host = |x: f64| if x.is_nan() { DOUBLE.canonical_nan() } else { x.to_bits() };
+            let (fa, fb, fc) = (f64::from_bits(a), f64::from_bits(b), f64::from_bits(c));
+            assert_eq!(context().add(DOUBLE, a, b), host(fa + fb));
+            assert_eq!(context().mul(DOUBLE, a, b), host(fa * fb));
+            assert_eq!(context().div(DOUBLE, a, b), host(fa / fb));
+            assert_eq!(context().sqrt(DOUBLE, a), host(fa.sqrt()));
+            assert_eq!(context().fused_multiply_add(DOUBLE, a, b, c), host(fa.mul_add(fb, fc)));
+
+            let (a, b) = (random() & 0xFFFF_FFFF, random() & 0xFFFF_FFFF);
+            let host = |x: f32| if x.is_nan() { SINGLE.canonical_nan() } else { x.to_bits() as u64 };
+            let (fa, fb) = (f32::from_bits(a as u32), f32::from_bits(b as u32));
+            assert_eq!(context().add(SINGLE, a, b), host(fa + fb));
+            assert_eq!(context().mul(SINGLE, a, b), host(fa * fb));
+            assert_eq!(context().div(SINGLE, a, b), host(fa / fb));
+            assert_eq!(context().sqrt(SINGLE, a), host(fa.sqrt()));
+            assert_eq!(context().convert(SINGLE, DOUBLE, a), if fa.is_nan() { DOUBLE.canonical_nan() } else { (fa as f64).to_bits() });
+            let d = random();
+            let x = f64::from_bits(d) as f32;
+            assert_eq!(context().convert(DOUBLE, SINGLE, d), if x.is_nan() { SINGLE.canonical_nan() } else { x.to_bits() as u64 });
+        }
+    }
+
+    #[test]
+    fn test_rounding_modes() {
+        let one = 1.0f64.to_bits();
+        let three = 3.0f64.to_bits();
+        let third = 1.0f64 / 3.0;
+        for (mode, expected) in [
+            (RoundingMode::NearestEven, third),
+            (RoundingMode::TowardZero, third),
+            (RoundingMode::Down, third),
+            (RoundingMode::Up, third.next_up()),
+            (RoundingMode::NearestMaxMagnitude, third),
+        ] {
+            let mut ctx = FloatContext::new(mode);
+            assert_eq!(f64::from_bits(ctx.div(DOUBLE, one, three)), expected);
+            assert_eq!(ctx.flags, FLAG_INEXACT);
+        }
+
+        let mut ctx = FloatContext::new(RoundingMode::Down);
+        assert_eq!(ctx.sub(DOUBLE, one, one), (-0.0f64).to_bits());
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut ctx = context();
+        assert_eq!(ctx.mul(DOUBLE, f64::MAX.to_bits(), 2.0f64.to_bits()), f64::INFINITY.to_bits());
+        assert_eq!(ctx.flags, FLAG_OVERFLOW | FLAG_INEXACT);
+
+        let mut ctx = FloatContext::new(RoundingMode::TowardZero);
+        assert_eq!(ctx.mul(DOUBLE, f64::MAX.to_bits(), 2.0f64.to_bits()), f64::MAX.to_bits());
+
+        let mut ctx = context();
+        ctx.div(SINGLE, 1.0f32.to_bits() as u64, 0);
+        assert_eq!(ctx.flags, FLAG_DIVIDE_BY_ZERO);
+
+        let mut ctx = context();
+        assert_eq!(ctx.sqrt(SINGLE, (-1.0f32).to_bits() as u64), SINGLE.canonical_nan());
+        assert_eq!(ctx.flags, FLAG_INVALID);
+
+        let mut ctx = context();
+        ctx.mul(DOUBLE, f64::MIN_POSITIVE.to_bits(), 0.5f64.to_bits());
+        assert_eq!(ctx.flags, 0);
+        ctx.mul(DOUBLE, f64::MIN_POSITIVE.to_bits(), 0.3f64.to_bits());
+        assert_eq!(ctx.flags, FLAG_UNDERFLOW | FLAG_INEXACT);
+    }
+
+    #[test]
+    fn test_integer_conversions() {
+        let mut ctx = context();
+        assert_eq!(ctx.float_to_int(DOUBLE, 2.5f64.to_bits(), true, 32), 2);
+        assert_eq!(ctx.float_to_int(DOUBLE, (-2.5f64).to_bits(), true, 64), -2i64 as u64);
+        assert_eq!(ctx.flags, FLAG_INEXACT);
+
+        let mut ctx = context();
+        assert_eq!(ctx.float_to_int(DOUBLE, 1e20f64.to_bits(), true, 32), i32::MAX as u64);
+        assert_eq!(ctx.float_to_int(DOUBLE, (-1.0f64).to_bits(), false, 64), 0);
+        assert_eq!(ctx.float_to_int(SINGLE, SINGLE.canonical_nan(), false, 32), u64::MAX);
+        assert_eq!(ctx.flags, FLAG_INVALID);
+
+        let mut ctx = context();
+        assert_eq!(ctx.int_to_float(SINGLE, u64::MAX, false, 64), 1.8446744e19f32.to_bits() as u64);
+        assert_eq!(ctx.int_to_float(DOUBLE, 0xFFFF_FFFF, true, 32), (-1.0f64).to_bits());
+    }
+
+    #[test]
+    fn test_min_max_and_classify() {
+        let mut ctx = context();
+        let (negative_zero, positive_zero) = ((-0.0f64).to_bits(), 0.0f64.to_bits());
+        assert_eq!(ctx.min_max(DOUBLE, positive_zero, negative_zero, false), negative_zero);
+        assert_eq!(ctx.min_max(DOUBLE, negative_zero, positive_zero, true), positive_zero);
+        assert_eq!(ctx.min_max(DOUBLE, DOUBLE.canonical_nan(), 1.0f64.to_bits(), false), 1.0f64.to_bits());
+        assert_eq!(ctx.flags, 0);
+        assert_eq!(ctx.compare(DOUBLE, negative_zero, positive_zero, false), Some(Ordering::Equal));
+
+        assert_eq!(classify(DOUBLE, f64::NEG_INFINITY.to_bits()), 1 << 0);
+        assert_eq!(classify(DOUBLE, 1u64), 1 << 5);
+        assert_eq!(classify(SINGLE, 0x7F80_0001), 1 << 8);
+        assert_eq!(classify(SINGLE, SINGLE.canonical_nan()), 1 << 9);
+    }
+}
@@ -0,0 +1,616 @@
+﻿use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use anyhow::{bail, Context, Result};
+
+use crate::bus::{Bus, Watchpoint};
+use crate::instruction::{FLOAT_REGISTER_NAMES, REGISTER_NAMES};
+use crate::machine::Cpu;
+use crate::opcodes::*;
+use crate::trap::{ExecError, Privilege, StepOutcome};
+
+/// GDB's register numbers for RISC-V.
+const PC_REGISTER: u64 = 32;
+const FIRST_FLOAT_REGISTER: u64 = 33;
+const FIRST_CSR_REGISTER: u64 = 65;
+const PRIVILEGE_REGISTER: u64 = FIRST_CSR_REGISTER + 4096;
+
+/// CSRs described to GDB besides the floating-point ones.
+const CSRS: [(&str, u64); 29] = [
+    ("sstatus", CSR_SSTATUS), ("sie", CSR_SIE), ("stvec", CSR_STVEC), ("scounteren", CSR_SCOUNTEREN),
+    ("sscratch", CSR_SSCRATCH), ("sepc", CSR_SEPC), ("scause", CSR_SCAUSE), ("stval", CSR_STVAL), ("sip", CSR_SIP),
+    ("stimecmp", CSR_STIMECMP), ("satp", CSR_SATP),
+    ("mstatus", CSR_MSTATUS), ("misa", CSR_MISA), ("medeleg", CSR_MEDELEG), ("mideleg", CSR_MIDELEG), ("mie", CSR_MIE),
+    ("mtvec", CSR_MTVEC), ("mcounteren", CSR_MCOUNTEREN), ("menvcfg", CSR_MENVCFG), ("mscratch", CSR_MSCRATCH),
+    ("mepc", CSR_MEPC), ("mcause", CSR_MCAUSE), ("mtval", CSR_MTVAL), ("mip", CSR_MIP),
+    ("mcycle", CSR_MCYCLE), ("minstret", CSR_MINSTRET), ("cycle", CSR_CYCLE), ("time", CSR_TIME), ("instret", CSR_INSTRET),
+];
+
+/// Instructions run between checks for a Ctrl-C from GDB.
+const INTERRUPT_CHECK_INTERVAL: u64 = 4096;
+
+/// A stream GDB is connected through.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Waits for GDB to connect to `address`, which is a TCP port, a `host:port` pair or a Unix socket path.
+pub fn accept(address: &str) -> Result<Box<dyn Connection>> {
+    if address.contains('/') {
+        // a socket left behind by an earlier run would make binding fail
+        let _ = fs::remove_file(address);
+        let listener = UnixListener::bind(address).with_context(|| format!("can't listen on {}", address))?;
+        eprintln!("waiting for gdb to connect to {}", address);
+        Ok(Box::new(listener.accept()?.0))
+    } else {
+        let address = match address.parse::<u16>() {
+            Ok(port) => format!("127.0.0.1:{}", port),
+            Err(_) => address.to_string(),
+        };
+        let listener = TcpListener::bind(&address).with_context(|| format!("can't listen on {}", address))?;
+        eprintln!("waiting for gdb to connect to {}", address);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// How a debugging session ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Exited(i32),
+    /// GDB detached or went away, and the guest should keep running on its own.
+    Detached,
+    Killed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+struct Watch {
+    kind: WatchKind,
+    address: u64,
+    length: u64,
+}
+
+enum Stop {
+    Trap,
+    /// An exception the guest has no handler for, reported as the signal a Unix process would get.
+    Fault(ExecError),
+    Watchpoint(WatchKind, u64),
+    Interrupted,
+    Exited(i32),
+}
+
+/// Serves the GDB remote serial protocol for one hart.
+pub struct GdbStub<'a> {
+    connection: Box<dyn Connection>,
+    /// Bytes received but not handled yet.
+    input: VecDeque<u8>,
+    acknowledge: bool,
+    breakpoints: Vec<u64>,
+    watchpoints: Vec<Watch>,
+    last_stop: String,
+    /// The guest's exit code once it has exited.
+    exited: &'a dyn Fn(&Cpu) -> Option<i32>,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(connection: Box<dyn Connection>, exited: &'a dyn Fn(&Cpu) -> Option<i32>) -> GdbStub<'a> {
+        GdbStub {
+            connection,
+            input: VecDeque::new(),
+            acknowledge: true,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            last_stop: "S05".to_string(),
+            exited,
+        }
+    }
+
+    /// Handles packets until the guest exits or GDB detaches, kills it or disconnects.
+    pub fn serve(&mut self, cpu: &mut Cpu, bus: &mut Bus) -> Result<Outcome> {
+        loop {
+            let Some(packet) = self.receive()? else {
+                bus.watchpoints.clear();
+                return Ok(Outcome::Detached);
+            };
+            if let Some(outcome) = self.handle(cpu, bus, &packet)? {
+                bus.watchpoints.clear();
+                return Ok(outcome);
+            }
+        }
+    }
+
+    fn handle(&mut self, cpu: &mut Cpu, bus: &mut Bus, packet: &str) -> Result<Option<Outcome>> {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => {
+                let mut reply = String::new();
+                for register in 0..=PC_REGISTER {
+                    reply += &hex(&read_register(cpu, register).unwrap_or(0).to_le_bytes());
+                }
+                reply
+            }
+            "G" => match decode_hex(arguments) {
+                Some(bytes) if bytes.len() == 8 * (PC_REGISTER as usize + 1) => {
+                    for (register, value) in bytes.chunks(8).enumerate() {
+                        write_register(cpu, register as u64, u64::from_le_bytes(value.try_into().unwrap()));
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match parse_hex(arguments) {
+                Some(register) if is_register(register) => match read_register(cpu, register) {
+                    Some(value) => hex(&value.to_le_bytes()),
+                    None => "x".repeat(16),
+                },
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let written = arguments.split_once('=').and_then(|(register, value)| {
+                    let value = decode_hex(value).filter(|value| value.len() == 8)?;
+                    Some(write_register(cpu, parse_hex(register)?, u64::from_le_bytes(value.try_into().unwrap())))
+                });
+                ok_or_error(written == Some(true))
+            }
+            "m" => {
+                let mut bytes = Vec::new();
+                let read = parse_range(arguments).is_some_and(|(address, length)| {
+                    bytes.resize(length as usize, 0);
+                    cpu.read_memory(bus, address, &mut bytes).is_ok()
+                });
+                if read { hex(&bytes) } else { "E01".to_string() }
+            }
+            "M" => {
+                let written = arguments.split_once(':').is_some_and(|(range, data)| {
+                    match (parse_range(range), decode_hex(data)) {
+                        (Some((address, length)), Some(bytes)) if bytes.len() as u64 == length =>
+                            cpu.write_memory(bus, address, &bytes).is_ok(),
+                        _ => false,
+                    }
+                });
+                ok_or_error(written)
+            }
+            "Z" | "z" => self.set_point(cpu, bus, command == "Z", arguments),
+            "s" | "c" => {
+                if let Some(address) = parse_hex(arguments) {
+                    cpu.pc = address;
+                }
+                let stop = self.resume(cpu, bus, command == "s")?;
+                self.last_stop = match stop {
+                    Stop::Trap => "S05".to_string(),
+                    Stop::Fault(error) => match error {
+                        ExecError::IllegalInstruction { .. } => "S04".to_string(),
+                        ExecError::MemoryFault { .. } => "S0b".to_string(),
+                        ExecError::Breakpoint { .. } | ExecError::Unhandled { .. } => "S05".to_string(),
+                    },
+                    Stop::Watchpoint(kind, address) => {
+                        let name = match kind {
+                            WatchKind::Write => "watch",
+                            WatchKind::Read => "rwatch",
+                            WatchKind::Access => "awatch",
+                        };
+                        format!("T05{}:{:x};", name, address)
+                    }
+                    Stop::Interrupted => "S02".to_string(),
+                    Stop::Exited(exit_code) => {
+                        self.send(&format!("W{:02x}", exit_code as u8))?;
+                        return Ok(Some(Outcome::Exited(exit_code)));
+                    }
+                };
+                self.last_stop.clone()
+            }
+            "D" => {
+                self.send("OK")?;
+                return Ok(Some(Outcome::Detached));
+            }
+            "k" => return Ok(Some(Outcome::Killed)),
+            // there's a single thread, whichever one GDB names
+            "H" | "T" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+        self.send(&reply)?;
+        Ok(None)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = parse_range(range) else { return "E01".to_string() };
+            let description = target_description();
+            let start = (offset as usize).min(description.len());
+            let end = (start + length as usize).min(description.len());
+            let more = if end < description.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &description[start..end]);
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.acknowledge = false;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Handles `Z` and `z`: type 0 and 1 are breakpoints, 2 to 4 write, read and access watchpoints.
+    fn set_point(&mut self, cpu: &mut Cpu, bus: &mut Bus, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+        let (Some(kind), Some(address), Some(length)) =
+            (fields.next(), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex)) else {
+            return "E01".to_string();
+        };
+        let kind = match kind {
+            "0" | "1" => {
+                self.breakpoints.retain(|&breakpoint| breakpoint != address);
+                if insert {
+                    self.breakpoints.push(address);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let same = |watch: &Watch| watch.kind == kind && watch.address == address && watch.length == length;
+        if insert {
+            if cpu.translate_data(bus, address).is_err() {
+                return "E01".to_string();
+            }
+            self.watchpoints.push(Watch { kind, address, length });
+        } else if let Some(index) = self.watchpoints.iter().position(same) {
+            self.watchpoints.remove(index);
+        }
+        self.update_watchpoints(cpu, bus);
+        "OK".to_string()
+    }
+
+    /// Points the bus at the physical addresses of the watchpoints, as translated right now.
+    fn update_watchpoints(&self, cpu: &mut Cpu, bus: &mut Bus) {
+        let watchpoints = self.watchpoints.iter()
+            .map(|watch| {
+                let physical_address = cpu.translate_data(bus, watch.address).unwrap_or(watch.address);
+                Watchpoint {
+                    range: physical_address..physical_address.saturating_add(watch.length),
+                    loads: watch.kind != WatchKind::Write,
+                    stores: watch.kind != WatchKind::Read,
+                }
+            })
+            .collect();
+        bus.watchpoints = watchpoints;
+        bus.watch_hit = None;
+    }
+
+    fn resume(&mut self, cpu: &mut Cpu, bus: &mut Bus, single_step: bool) -> Result<Stop> {
+        let mut executed: u64 = 0;
+        loop {
+            let outcome = match cpu.step(bus) {
+                Ok(outcome) => outcome,
+                Err(error) => return Ok(Stop::Fault(error)),
+            };
+            executed += 1;
+            if let Some(exit_code) = (self.exited)(cpu) {
+                return Ok(Stop::Exited(exit_code));
+            }
+            if let Some(index) = bus.watch_hit.take() {
+                let watch = &self.watchpoints[index];
+                return Ok(Stop::Watchpoint(watch.kind, watch.address));
+            }
+            if single_step || outcome == StepOutcome::Halted || self.breakpoints.contains(&cpu.pc) {
+                return Ok(Stop::Trap);
+            }
+            if executed.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && self.interrupted()? {
+                return Ok(Stop::Interrupted);
+            }
+        }
+    }
+
+    /// Polls the connection for the Ctrl-C GDB sends to stop a running target.
+    fn interrupted(&mut self) -> Result<bool> {
+        self.connection.set_nonblocking(true)?;
+        let mut buffer = [0; 64];
+        let result = self.connection.read(&mut buffer);
+        self.connection.set_nonblocking(false)?;
+        match result {
+            // stop when GDB goes away, so that the next receive sees it
+            Ok(0) => Ok(true),
+            Ok(length) => {
+                self.input.extend(&buffer[..length]);
+                match self.input.iter().position(|&byte| byte == 0x03) {
+                    Some(index) => {
+                        self.input.remove(index);
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        if let Some(byte) = self.input.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0];
+        match self.connection.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Reads the next packet, acknowledging it. Returns `None` once GDB disconnects.
+    fn receive(&mut self) -> Result<Option<String>> {
+        loop {
+            // skip acknowledgements, and Ctrl-C while the target is already stopped
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let (Some(high), Some(low)) = (self.read_byte()?, self.read_byte()?) else { return Ok(None) };
+            let checksum = std::str::from_utf8(&[high, low]).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+            let valid = checksum == Some(data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+            if self.acknowledge {
+                self.connection.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let mut packet = String::with_capacity(data.len() + 4);
+        packet.push('$');
+        for character in data.chars() {
+            // characters with a meaning in the framing are escaped
+            if matches!(character, '$' | '#' | '}' | '*') {
+                packet.push('}');
+                packet.push((character as u8 ^ 0x20) as char);
+            } else {
+                packet.push(character);
+            }
+        }
+        let checksum = packet.bytes().skip(1).fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(packet, "#{:02x}", checksum).unwrap();
+
+        loop {
+            self.connection.write_all(packet.as_bytes())?;
+            self.connection.flush()?;
+            if !self.acknowledge {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'+') => return Ok(()),
+                Some(b'-') => continue,
+                Some(byte) => bail!("expected an acknowledgement from gdb, got {:?}", byte as char),
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+fn is_register(register: u64) -> bool {
+    register < FIRST_CSR_REGISTER || register == PRIVILEGE_REGISTER || (register - FIRST_CSR_REGISTER) < 4096
+}
+
+/// Reads a register by GDB number, `None` if it's currently inaccessible.
+fn read_register(cpu: &mut Cpu, register: u64) -> Option<u64> {
+    match register {
+        0..=31 => Some(cpu.registers[register as usize]),
+        PC_REGISTER => Some(cpu.pc),
+        33..=64 => Some(cpu.fregisters[(register - FIRST_FLOAT_REGISTER) as usize]),
+        PRIVILEGE_REGISTER => Some(cpu.privilege as u64),
+        _ => cpu.debug_read_csr(register - FIRST_CSR_REGISTER),
+    }
+}
+
+fn write_register(cpu: &mut Cpu, register: u64, value: u64) -> bool {
+    match register {
+        0 => {}
+        1..=31 => cpu.registers[register as usize] = value,
+        PC_REGISTER => cpu.pc = value,
+        33..=64 => cpu.fregisters[(register - FIRST_FLOAT_REGISTER) as usize] = value,
+        PRIVILEGE_REGISTER => cpu.privilege = Privilege::from_bits(value),
+        _ if is_register(register) => return cpu.debug_write_csr(register - FIRST_CSR_REGISTER, value),
+        _ => return false,
+    }
+    true
+}
+
+/// The target description GDB reads with `qXfer:features:read`, numbering registers the way GDB does.
+fn target_description() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n");
+    xml += "<architecture>riscv:rv64</architecture>\n<feature name=\"org.gnu.gdb.riscv.cpu\">\n";
+    for (number, name) in REGISTER_NAMES.iter().enumerate() {
+        let kind = match *name {
+            "sp" | "gp" | "tp" => "data_ptr",
+            "ra" => "code_ptr",
+            _ => "int",
+        };
+        writeln!(xml, "<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>", name, kind, number).unwrap();
+    }
+    writeln!(xml, "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>", PC_REGISTER).unwrap();
+    xml += "</feature>\n<feature name=\"org.gnu.gdb.riscv.fpu\">\n";
+    for (number, name) in FLOAT_REGISTER_NAMES.iter().enumerate() {
+        writeln!(xml, "<reg name=\"{}\" bitsize=\"64\" type=\"ieee_double\" regnum=\"{}\"/>",
+            name, FIRST_FLOAT_REGISTER + number as u64).unwrap();
+    }
+    for (name, csr) in [("fflags", CSR_FFLAGS), ("frm", CSR_FRM), ("fcsr", CSR_FCSR)] {
+        writeln!(xml, "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>", name, FIRST_CSR_REGISTER + csr).unwrap();
+    }
+    xml += "</feature>\n<feature name=\"org.gnu.gdb.riscv.csr\">\n";
+    for (name, csr) in CSRS {
+        writeln!(xml, "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>", name, FIRST_CSR_REGISTER + csr).unwrap();
+    }
+    xml += "</feature>\n<feature name=\"org.gnu.gdb.riscv.virtual\">\n";
+    writeln!(xml, "<reg name=\"priv\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>", PRIVILEGE_REGISTER).unwrap();
+    xml += "</feature>\n</target>\n";
+    xml
+}
+
+fn ok_or_error(success: bool) -> String {
+    if success { "OK" } else { "E01" }.to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+/// Parses the `address,length` of memory packets.
+fn parse_range(text: &str) -> Option<(u64, u64)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use crate::bus::{Ram, DRAM_BASE};
+    use crate::machine::Machine;
+
+    const PROGRAM: [u32; 7] = [
+        0x00500513, // li a0, 5
+        0x010000ef, // jal ra, f
+        0x00a13023, // sd a0, 0(sp)
+        0x0000006f, // j .
+        0x00000013, // nop
+        0x00150513, // f: addi a0, a0, 1
+        0x00008067, // ret
+    ];
+
+    /// Sends a packet the way GDB does and returns the reply.
+    fn request(stream: &mut UnixStream, data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(stream, "${}#{:02x}", data, checksum).unwrap();
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+');
+        reply(stream)
+    }
+
+    fn reply(stream: &mut UnixStream) -> String {
+        let mut packet = Vec::new();
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            packet.push(byte[0]);
+        }
+        stream.read_exact(&mut [0; 2]).unwrap();
+        stream.write_all(b"+").unwrap();
+        assert_eq!(packet[0], b'$');
+        String::from_utf8(packet[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let machine = Machine::new();
+        let mut bus = Bus::new();
+        bus.map(DRAM_BASE, 0x2000, Ram::new(0x2000));
+        for (i, word) in PROGRAM.iter().enumerate() {
+            bus.store32(DRAM_BASE + 4 * i as u64, *word as u64).unwrap();
+        }
+        let mut cpu = Cpu::new(&machine);
+        cpu.pc = DRAM_BASE;
+        cpu.registers[2] = DRAM_BASE + 0x1000;
+
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let gdb = thread::spawn(move || {
+            let client = &mut client;
+            assert!(request(client, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+            let xml = request(client, "qXfer:features:read:target.xml:0,ffff");
+            assert!(xml.starts_with("l<?xml") && xml.contains("riscv:rv64") && xml.contains("regnum=\"833\""), "{}", xml);
+            assert_eq!(request(client, "?"), "S05");
+            let registers = request(client, "g");
+            assert_eq!(registers.len(), 33 * 16);
+            assert!(registers.ends_with("0000008000000000"));
+            assert_eq!(request(client, "m80000000,4"), "13055000");
+
+            assert_eq!(request(client, "Z0,80000008,4"), "OK");
+            assert_eq!(request(client, "c"), "S05");
+            assert_eq!(request(client, "p20"), "0800008000000000");
+            assert_eq!(request(client, "pa"), "0600000000000000");
+            assert_eq!(request(client, "Pa=2a00000000000000"), "OK");
+            assert_eq!(request(client, "Z2,80001000,8"), "OK");
+            assert_eq!(request(client, "c"), "T05watch:80001000;");
+            assert_eq!(request(client, "m80001000,8"), "2a00000000000000");
+            assert_eq!(request(client, "M80001000,2:beef"), "OK");
+            assert_eq!(request(client, "m80001000,3"), "beef00");
+            assert_eq!(request(client, "p341"), "002000000a000000");
+            assert_eq!(request(client, "p1041"), "0300000000000000");
+            assert_eq!(request(client, "s"), "S05");
+            assert_eq!(request(client, "s"), "S05");
+
+            // the program now spins until GDB interrupts it
+            write!(client, "$c#63").unwrap();
+            client.read_exact(&mut [0]).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            client.write_all(&[0x03]).unwrap();
+            assert_eq!(reply(client), "S02");
+            assert_eq!(request(client, "D"), "OK");
+        });
+
+        let exited = |_: &Cpu| None;
+        let outcome = GdbStub::new(Box::new(server), &exited).serve(&mut cpu, &mut bus).unwrap();
+        gdb.join().unwrap();
+        assert_eq!(outcome, Outcome::Detached);
+        assert_eq!(cpu.pc, DRAM_BASE + 0xC);
+        assert!(bus.watchpoints.is_empty());
+    }
+}
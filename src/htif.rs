@@ -0,0 +1,198 @@
+﻿use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use anyhow::Result;
+
+use crate::bus::{Bus, Device, InterruptLines};
+use crate::snapshot::{Reader, Writer};
+use crate::uart::ConsoleInput;
+
+const REGISTER_SIZE: u64 = 8;
+
+const DEVICE_SYSTEM: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+/// The host-target interface used by spike and riscv-tests, living at the `tohost` and `fromhost` symbols.
+///
+/// A command written to `tohost` packs a device in bits 63:56, a command in bits 55:48 and a payload
+/// below. Device 0 with an odd payload is the exit status `(code << 1) | 1`; device 1 is the console,
+/// whose replies arrive in `fromhost`.
+pub struct Htif {
+    state: Rc<RefCell<State>>,
+}
+
+struct State {
+    tohost: u64,
+    fromhost: u64,
+    input: Option<ConsoleInput>,
+    waiting_for_input: bool,
+    exit_code: Option<i32>,
+}
+
+/// One of the two registers; both share the state so commands can answer through `fromhost`.
+struct Register {
+    state: Rc<RefCell<State>>,
+    fromhost: bool,
+}
+
+impl Htif {
+    /// An interface whose console reads from `input`, if any.
+    pub fn new(input: Option<ConsoleInput>) -> Htif {
+        let state = State { tohost: 0, fromhost: 0, input, waiting_for_input: false, exit_code: None };
+        Htif { state: Rc::new(RefCell::new(state)) }
+    }
+
+    /// Maps the registers over whatever memory holds the symbols.
+    pub fn map(&self, bus: &mut Bus, tohost: u64, fromhost: Option<u64>) {
+        bus.map(tohost, REGISTER_SIZE, Register { state: self.state.clone(), fromhost: false });
+        if let Some(fromhost) = fromhost {
+            bus.map(fromhost, REGISTER_SIZE, Register { state: self.state.clone(), fromhost: true });
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.state.borrow().exit_code
+    }
+}
+
+impl State {
+    fn command(&mut self) {
+        let device = self.tohost >> 56;
+        let command = (self.tohost >> 48) & 0xFF;
+        let payload = self.tohost & ((1 << 48) - 1);
+        match (device, command) {
+            (DEVICE_SYSTEM, _) if payload & 1 == 1 => self.exit_code = Some((payload >> 1) as i32),
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                // a console that stopped working isn't something the guest can act on
+                if !self.input.as_ref().is_some_and(ConsoleInput::replaying) {
+                    let _ = io::stdout().write_all(&[payload as u8]).and_then(|_| io::stdout().flush());
+                }
+                self.fromhost = (DEVICE_CONSOLE << 56) | (CONSOLE_PUTCHAR << 48);
+            }
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => self.waiting_for_input = true,
+            _ => (),
+        }
+        // the host has consumed the command
+        self.tohost = 0;
+    }
+
+    fn poll_input(&mut self) {
+        if !self.waiting_for_input || self.fromhost != 0 {
+            return;
+        }
+        if let Some(byte) = self.input.as_ref().and_then(ConsoleInput::poll) {
+            self.fromhost = (DEVICE_CONSOLE << 56) | (CONSOLE_GETCHAR << 48) | byte as u64;
+            self.waiting_for_input = false;
+        }
+    }
+}
+
+impl Device for Register {
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        let state = self.state.borrow();
+        let register = if self.fromhost { state.fromhost } else { state.tohost };
+        let value = register >> (8 * offset);
+        Some(if size >= 8 { value } else { value & ((1 << (8 * size)) - 1) })
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Option<()> {
+        let mut state = self.state.borrow_mut();
+        let mask = if size >= 8 { u64::MAX } else { ((1 << (8 * size)) - 1) << (8 * offset) };
+        if self.fromhost {
+            state.fromhost = (state.fromhost & !mask) | ((value << (8 * offset)) & mask);
+        } else {
+            state.tohost = (state.tohost & !mask) | ((value << (8 * offset)) & mask);
+            if state.tohost != 0 {
+                state.command();
+            }
+        }
+        Some(())
+    }
+
+    fn exit_code(&self) -> Option<i32> {
+        self.state.borrow().exit_code
+    }
+
+    fn tick(&mut self, _interrupts: &mut InterruptLines) {
+        if !self.fromhost {
+            self.state.borrow_mut().poll_input();
+        }
+    }
+
+    // the registers share their state, which the tohost one saves for both
+    fn save(&self, snapshot: &mut Writer) {
+        if !self.fromhost {
+            let state = self.state.borrow();
+            snapshot.u64(state.tohost);
+            snapshot.u64(state.fromhost);
+            snapshot.bool(state.waiting_for_input);
+            snapshot.bool(state.exit_code.is_some());
+            snapshot.u32(state.exit_code.unwrap_or_default() as u32);
+        }
+    }
+
+    fn restore(&mut self, snapshot: &mut Reader) -> Result<()> {
+        if !self.fromhost {
+            let mut state = self.state.borrow_mut();
+            state.tohost = snapshot.u64()?;
+            state.fromhost = snapshot.u64()?;
+            state.waiting_for_input = snapshot.bool()?;
+            let exited = snapshot.bool()?;
+            let exit_code = snapshot.u32()? as i32;
+            state.exit_code = exited.then_some(exit_code);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver};
+
+    use super::*;
+    use crate::bus::Ram;
+
+    const TOHOST: u64 = 0x1000;
+    const FROMHOST: u64 = 0x1040;
+
+    fn htif(input: Option<Receiver<u8>>) -> (Htif, Bus) {
+        let mut bus = Bus::new();
+        bus.map(0, 0x2000, Ram::new(0x2000));
+        let htif = Htif::new(input.map(ConsoleInput::from));
+        htif.map(&mut bus, TOHOST, Some(FROMHOST));
+        (htif, bus)
+    }
+
+    #[test]
+    fn test_exit() {
+        let (htif, mut bus) = htif(None);
+        bus.store32(TOHOST + 4, 0).unwrap();
+        assert_eq!(htif.exit_code(), None);
+        bus.store32(TOHOST, (3 << 1) | 1).unwrap();
+        assert_eq!(htif.exit_code(), Some(3));
+        assert_eq!(bus.load64(TOHOST), Ok(0));
+        // the memory around the registers is untouched
+        bus.store64(TOHOST + 8, 5).unwrap();
+        assert_eq!(bus.load64(TOHOST + 8), Ok(5));
+    }
+
+    #[test]
+    fn test_console() {
+        let (sender, receiver) = mpsc::channel();
+        let (htif, mut bus) = htif(Some(receiver));
+        bus.store64(TOHOST, (DEVICE_CONSOLE << 56) | (CONSOLE_GETCHAR << 48)).unwrap();
+        bus.tick(0);
+        assert_eq!(bus.load64(FROMHOST), Ok(0));
+
+        sender.send(b'x').unwrap();
+        bus.tick(0);
+        assert_eq!(bus.load64(FROMHOST), Ok((DEVICE_CONSOLE << 56) | b'x' as u64));
+        bus.store64(FROMHOST, 0).unwrap();
+        bus.tick(0);
+        assert_eq!(bus.load64(FROMHOST), Ok(0));
+        assert_eq!(htif.exit_code(), None);
+    }
+}
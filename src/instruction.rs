@@ -1,5 +1,41 @@
-﻿pub struct Instruction {
+﻿use crate::compressed;
+
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+pub const FLOAT_REGISTER_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// Parses an integer register name, either `x5` or its ABI name `t0`.
+pub fn parse_register(name: &str) -> Option<usize> {
+    if name == "fp" {
+        return Some(8);
+    }
+    parse_numbered(name, "x").or_else(|| REGISTER_NAMES.iter().position(|&abi_name| abi_name == name))
+}
+
+/// Parses a floating-point register name, either `f10` or its ABI name `fa0`.
+pub fn parse_float_register(name: &str) -> Option<usize> {
+    parse_numbered(name, "f").or_else(|| FLOAT_REGISTER_NAMES.iter().position(|&abi_name| abi_name == name))
+}
+
+fn parse_numbered(name: &str, prefix: &str) -> Option<usize> {
+    let number = name.strip_prefix(prefix)?;
+    // no leading zeros or signs, so that names like `x05` aren't accepted
+    if number.starts_with('0') && number.len() > 1 || !number.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    number.parse().ok().filter(|&index| index < 32)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Instruction {
     pub raw: i32,
+    pub encoding: i32, // as fetched from memory, before expanding compressed instructions
     pub size: u64,
     pub opcode: i32,
     pub rd: i32,
@@ -13,34 +49,44 @@
 impl Instruction {
     pub fn decode(data: i32) -> Instruction {
         if data & 3 == 3 {
-            Instruction {
-                raw: data,
-                size: 4,
-                opcode: data & 0x7F,
-                rd: (data >> 7) & 0x1F,
-                funct3: (data >> 12) & 0x07,
-                rs1: (data >> 15) & 0x1F,
-                rs2: (data >> 20) & 0x1F,
-                funct7: (data >> 25) & 0x7F,
-                shamt: (data >> 20) & 0x3F,
-            }
+            Self::decode_fields(data, data, 4)
         } else {
-            unimplemented!("Instruction with length other that 4 encountered");
+            // illegal compressed encodings expand to 0, which is not a valid instruction either
+            Self::decode_fields(compressed::expand(data as u16).unwrap_or(0), data & 0xFFFF, 2)
+        }
+    }
+
+    fn decode_fields(data: i32, encoding: i32, size: u64) -> Instruction {
+        Instruction {
+            raw: data,
+            encoding,
+            size,
+            opcode: data & 0x7F,
+            rd: (data >> 7) & 0x1F,
+            funct3: (data >> 12) & 0x07,
+            rs1: (data >> 15) & 0x1F,
+            rs2: (data >> 20) & 0x1F,
+            funct7: (data >> 25) & 0x7F,
+            shamt: (data >> 20) & 0x3F,
         }
     }
     
     pub fn shamtw(&self) -> i32 { self.rs2 } // defined as (self.raw >> 20) & 0x1F
+    pub fn funct6(&self) -> i32 { self.funct7 >> 1 } // shamt[5] dropped
+    pub fn funct5(&self) -> i32 { self.funct7 >> 2 } // aq and rl bits dropped
+    pub fn fmt(&self) -> i32 { self.funct7 & 3 }
+    pub fn rs3(&self) -> i32 { (self.raw >> 27) & 0x1F }
     pub fn csr(&self) -> u64 { ((self.raw as u64) >> 20) & 0xFFF }
     pub fn immediate_i(&self) -> i64 { (self.raw >> 20) as i64 }
     pub fn immediate_u(&self) -> i64 { (self.raw & !0xFFF) as i64 }
     pub fn immediate_s(&self) -> i64 { (((self.raw >> 7) & 0x1F) | ((self.raw >> 20) & !0x1F)) as i64 }
 
     pub fn immediate_b(&self) -> i64 {
-        (((self.raw >> 8 - 1) & 0b_1111_0) | ((self.raw >> 25 - 5) & 0b_11_1111_00000) | ((self.raw << -(7 - 11)) & 0b1_000_0000_0000) | (self.raw >> 31 - 12) & !0b111111111111) as i64
+        (((self.raw >> (8 - 1)) & 0b1_1110) | ((self.raw >> (25 - 5)) & 0b111_1110_0000) | ((self.raw << -(7 - 11)) & 0b1000_0000_0000) | (self.raw >> (31 - 12)) & !0b1111_1111_1111) as i64
     }
 
     pub fn immediate_j(&self) -> i64 {
-        ((self.raw >> 21 - 1) & 0b11111111110 | (self.raw >> 20 - 11) & 0b100000000000 | self.raw & 0b11111111000000000000 | (self.raw >> 31-20) & !0xFFFFF) as i64
+        ((self.raw >> (21 - 1)) & 0b11111111110 | (self.raw >> (20 - 11)) & 0b100000000000 | self.raw & 0b11111111000000000000 | (self.raw >> (31 - 20)) & !0xFFFFF) as i64
     }
 
     pub fn immediate_i_unsigned(&self) -> u64 { self.immediate_i() as u64 }
@@ -69,6 +115,18 @@ mod tests {
         assert_eq!(i.immediate_u_unsigned(), u64::MAX - 4095);
     }
 
+    #[test]
+    fn test_register_names() {
+        assert_eq!(parse_register("x31"), Some(31));
+        assert_eq!(parse_register("a0"), Some(10));
+        assert_eq!(parse_register("fp"), Some(8));
+        assert_eq!(parse_register("x32"), None);
+        assert_eq!(parse_register("x05"), None);
+        assert_eq!(parse_float_register("fa1"), Some(11));
+        assert_eq!(parse_float_register("f0"), Some(0));
+        assert_eq!(parse_float_register("a0"), None);
+    }
+
     #[test]
     fn test_immediate_s() {
         let i = Instruction::decode(-1);
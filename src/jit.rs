@@ -0,0 +1,1016 @@
+﻿//! Dynamic binary translation of RV64IM code to x86-64, built with the `jit` feature.
+//!
+//! Blocks that keep getting run are translated once into machine code working on the guest registers
+//! in `Cpu::registers` directly. Loads, stores and the harder parts of the M extension call back into
+//! the interpreter's own code, and anything else, CSR accesses and system instructions among them, is
+//! left to `Cpu::step`. Where a block ends by jumping to another translated block on the same physical
+//! page the jump gets patched to go there directly. Translated code is dropped a page at a time, when
+//! the page is written, so such links never outlive the block they lead to.
+
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("the jit feature needs an x86-64 host");
+
+use std::collections::HashMap;
+use std::io;
+use std::mem::offset_of;
+use std::ptr;
+
+use anyhow::{bail, Result};
+
+use crate::bus::Bus;
+use crate::decode_cache::decode_block;
+use crate::instruction::Instruction;
+use crate::machine::{div_signed, div_unsigned, mulhsu, Cpu};
+use crate::mmu::PAGE_SIZE;
+use crate::opcodes::*;
+use crate::trap::{ExecError, StepOutcome, Trap};
+
+/// Executable memory for translated code. Once it fills up everything gets translated anew.
+const CODE_SIZE: usize = 16 << 20;
+/// Room kept free for translating one block, far more than 64 instructions take.
+const MAX_BLOCK_CODE: usize = 64 << 10;
+/// Times the interpreter has to run an instruction before a block starting there is translated.
+const HOT_THRESHOLD: u32 = 16;
+/// Instructions translated code runs before returning to check for interrupts.
+const SLICE: u64 = 1000;
+
+// what translated code returns
+const EXIT_NORMAL: u64 = 0;
+/// Reached a block on the same page, with `Context::link` pointing at the jump that could go there.
+const EXIT_LINK: u64 = 1;
+/// An instruction raised an exception. It hasn't retired, the interpreter runs it again to take the trap.
+const EXIT_FAULT: u64 = 2;
+
+// what helpers leave in `Context::status`
+const STATUS_OK: u64 = 0;
+const STATUS_FAULT: u64 = 1;
+/// The instruction completed, but the block has to end after it: it wrote code or made the guest exit.
+const STATUS_STOP: u64 = 2;
+
+/// Shared between translated code, which keeps a pointer to it in `rbx`, and the helpers it calls.
+#[repr(C)]
+struct Context {
+    cpu: *mut Cpu<'static>,
+    bus: *mut Bus,
+    /// Instructions left in this slice. Blocks that don't fit return before running.
+    budget: i64,
+    status: u64,
+    link: u64,
+}
+
+const BUDGET: i32 = offset_of!(Context, budget) as i32;
+const STATUS: i32 = offset_of!(Context, status) as i32;
+const LINK: i32 = offset_of!(Context, link) as i32;
+// fields of the `Cpu` that `r12` points to
+const PC: i32 = offset_of!(Cpu<'static>, pc) as i32;
+const CYCLES: i32 = offset_of!(Cpu<'static>, cycles) as i32;
+const INSTRUCTIONS_RETIRED: i32 = offset_of!(Cpu<'static>, instructions_retired) as i32;
+
+fn register(index: i32) -> i32 {
+    (offset_of!(Cpu<'static>, registers) + 8 * index as usize) as i32
+}
+
+// x86-64 registers
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R12: u8 = 12;
+
+// condition codes, inverted by flipping the lowest bit
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_A: u8 = 0x7;
+const CC_L: u8 = 0xC;
+const CC_GE: u8 = 0xD;
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    entry: u64,
+    /// Instructions in the block, all of which retire unless one of them faults.
+    length: u64,
+}
+
+pub struct Jit {
+    memory: CodeMemory,
+    /// Runs translated code: `enter(context, cpu, block)`.
+    enter: unsafe extern "C" fn(*mut Context, *mut Cpu<'static>, u64) -> u64,
+    /// Where translated code jumps to return from `enter`.
+    epilogue: u64,
+    /// Where blocks start in `memory`, after `enter`.
+    blocks_start: usize,
+    /// Blocks by physical address, `None` where the first instruction can't be translated.
+    blocks: HashMap<u64, Option<Block>>,
+    /// Addresses in `blocks` on each page.
+    pages: HashMap<u64, Vec<u64>>,
+    /// Times the interpreter ran the instruction at each physical address.
+    heat: HashMap<u64, u32>,
+}
+
+impl Jit {
+    pub fn new() -> Result<Jit> {
+        let mut memory = CodeMemory::new()?;
+        let mut assembler = Assembler::new(memory.address());
+        for register in [RBX, 5, R12, 13, 14, 15] {
+            assembler.push(register);
+        }
+        // keep the stack 16-byte aligned for calls to helpers
+        assembler.register(true, &[0x83], 5, 4);
+        assembler.bytes(&[8]);
+        assembler.register(true, &[0x89], RDI, RBX);
+        assembler.register(true, &[0x89], RSI, R12);
+        assembler.register(false, &[0xFF], 4, RDX);
+        let epilogue = assembler.position();
+        assembler.register(true, &[0x83], 0, 4);
+        assembler.bytes(&[8]);
+        for register in [15, 14, 13, R12, 5, RBX] {
+            assembler.pop(register);
+        }
+        assembler.bytes(&[0xC3]);
+
+        let start = memory.push(&assembler.code);
+        // SAFETY: the code just written follows the System V calling convention for this signature
+        let enter = unsafe { std::mem::transmute::<usize, unsafe extern "C" fn(*mut Context, *mut Cpu<'static>, u64) -> u64>(start as usize) };
+        Ok(Jit {
+            blocks_start: memory.used,
+            memory,
+            enter,
+            epilogue,
+            blocks: HashMap::new(),
+            pages: HashMap::new(),
+            heat: HashMap::new(),
+        })
+    }
+
+    /// `Cpu::run` with translated code where there is some.
+    pub fn run(&mut self, cpu: &mut Cpu, bus: &mut Bus, limit: Option<u64>) -> Result<StepOutcome, ExecError> {
+        let mut outcome = StepOutcome::Retired;
+        let mut steps = 0;
+        while limit.is_none_or(|limit| steps < limit) {
+            self.sync(cpu, bus);
+            let budget = limit.map_or(SLICE, |limit| (limit - steps).min(SLICE));
+            outcome = match self.block(cpu, bus, budget) {
+                None => {
+                    steps += 1;
+                    cpu.step(bus)?
+                }
+                Some(block) => {
+                    if let Some(interrupt) = cpu.take_interrupt(bus) {
+                        steps += 1;
+                        StepOutcome::Trapped(Trap::Interrupt(interrupt))
+                    } else {
+                        let retired = cpu.instructions_retired;
+                        let (exit, link) = self.execute(cpu, bus, block, budget);
+                        steps += cpu.instructions_retired.wrapping_sub(retired);
+                        if exit == EXIT_FAULT {
+                            steps += 1;
+                            cpu.step(bus)?
+                        } else {
+                            if exit == EXIT_LINK {
+                                self.link(cpu, bus, link);
+                            }
+                            cpu.exit_code(bus).map_or(StepOutcome::Retired, StepOutcome::Exited)
+                        }
+                    }
+                }
+            };
+            if matches!(outcome, StepOutcome::Exited(_) | StepOutcome::Halted) {
+                break;
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Drops the blocks on pages written since the last call, passing them on to the decode cache.
+    fn sync(&mut self, cpu: &mut Cpu, bus: &mut Bus) {
+        for page in bus.code_written.drain(..) {
+            for address in self.pages.remove(&page).unwrap_or_default() {
+                self.blocks.remove(&address);
+            }
+            if let Some(cache) = &mut cpu.decode_cache {
+                cache.invalidate(page);
+            }
+        }
+    }
+
+    /// The block to run at `pc`, if it's been translated, or just became hot enough to be, and fits in
+    /// the budget.
+    fn block(&mut self, cpu: &mut Cpu, bus: &mut Bus, budget: u64) -> Option<Block> {
+        let address = cpu.translate_instruction(bus, cpu.pc).ok()?;
+        if let Some(block) = self.blocks.get(&address) {
+            return block.filter(|block| block.length <= budget);
+        }
+        let heat = self.heat.entry(address).or_default();
+        *heat += 1;
+        if *heat < HOT_THRESHOLD {
+            return None;
+        }
+        self.heat.remove(&address);
+        let block = self.translate(bus, address);
+        self.blocks.insert(address, block);
+        self.pages.entry(address / PAGE_SIZE).or_default().push(address);
+        bus.code_pages.insert(address / PAGE_SIZE);
+        block.filter(|block| block.length <= budget)
+    }
+
+    fn translate(&mut self, bus: &mut Bus, address: u64) -> Option<Block> {
+        let decoded = decode_block(bus, address)?;
+        let length = decoded.iter().take_while(|decoded| translatable(&decoded.instruction)).count();
+        if length == 0 {
+            return None;
+        }
+        if self.memory.used + MAX_BLOCK_CODE > CODE_SIZE {
+            // nothing links into the old code once the blocks are gone
+            self.memory.used = self.blocks_start;
+            self.blocks.clear();
+            self.pages.clear();
+        }
+        let mut translator = Translator {
+            assembler: Assembler::new(self.memory.address()),
+            epilogue: self.epilogue,
+            address,
+            length: length as u64,
+            exits: Vec::new(),
+        };
+        translator.block(decoded[..length].iter().map(|decoded| &decoded.instruction));
+        let entry = self.memory.push(&translator.assembler.code);
+        Some(Block { entry, length: length as u64 })
+    }
+
+    fn execute(&mut self, cpu: &mut Cpu, bus: &mut Bus, block: Block, budget: u64) -> (u64, u64) {
+        let cpu = (cpu as *mut Cpu).cast::<Cpu<'static>>();
+        let mut context = Context { cpu, bus, budget: budget as i64, status: STATUS_OK, link: 0 };
+        // SAFETY: the block was translated for the code at `cpu.pc`, and only touches the CPU and bus
+        // through these pointers while neither is otherwise borrowed
+        let exit = unsafe { (self.enter)(&mut context, cpu, block.entry) };
+        (exit, context.link)
+    }
+
+    /// Points the jump at `site` to the block at `pc`, if that's been translated.
+    fn link(&mut self, cpu: &mut Cpu, bus: &mut Bus, site: u64) {
+        let Ok(address) = cpu.translate_instruction(bus, cpu.pc) else { return };
+        if let Some(Some(block)) = self.blocks.get(&address) {
+            let displacement = block.entry.wrapping_sub(site + 4) as i32;
+            // SAFETY: `site` is the displacement of a jump in translated code that's not running
+            unsafe { ptr::write_unaligned(site as *mut i32, displacement) };
+        }
+    }
+}
+
+/// Whether an instruction has a translation. Everything here behaves exactly like its case in
+/// `Cpu::execute_generic`.
+fn translatable(instruction: &Instruction) -> bool {
+    match (instruction.opcode, instruction.funct3, instruction.funct7) {
+        // srli and srai share funct3
+        (OPCODE_OP_IMM, F3_SRL, _) => matches!(instruction.funct6(), F6_SRL | F6_SRA),
+        key => matches!(
+            key,
+            (OPCODE_OP_IMM, F3_ADD | F3_SLT | F3_SLTU | F3_AND | F3_OR | F3_XOR | F3_SLL, _)
+                | (OPCODE_OP_IMM_32, F3_ADD | F3_SLL, _)
+                | (OPCODE_OP_IMM_32, F3_SRL, F7_SRL)
+                | (OPCODE_OP_IMM_32, F3_SRA, F7_SRA)
+                | (OPCODE_LUI | OPCODE_AUIPC | OPCODE_JAL | OPCODE_JALR, _, _)
+                | (OPCODE_OP, _, F7_ADD | F7_MULDIV)
+                | (OPCODE_OP, F3_SUB | F3_SRA, F7_SUB)
+                | (OPCODE_OP_32, F3_ADD, F7_ADD)
+                | (OPCODE_OP_32, F3_SLL, F7_SLL)
+                | (OPCODE_OP_32, F3_SRL, F7_SRL)
+                | (OPCODE_OP_32, F3_SUB | F3_SRA, F7_SUB)
+                | (OPCODE_OP_32, F3_MULW | F3_DIVW | F3_DIVUW | F3_REMW | F3_REMUW, F7_MULDIV)
+                | (OPCODE_BRANCH, F3_BEQ | F3_BNE | F3_BLT | F3_BGE | F3_BLTU | F3_BGEU, _)
+                | (OPCODE_LOAD, F3_LB | F3_LH | F3_LW | F3_LD | F3_LBU | F3_LHU | F3_LWU, _)
+                | (OPCODE_STORE, F3_SB | F3_SH | F3_SW | F3_SD, _)
+        ),
+    }
+}
+
+/// A way out of a block, emitted after its instructions.
+struct Exit {
+    /// Displacement of the jump to the exit.
+    fixup: usize,
+    /// Where the guest continues, relative to the start of the block.
+    pc_offset: i64,
+    retired: u64,
+    code: u64,
+}
+
+/// Translates one block. Guest registers stay in memory: `r12` points to the `Cpu` and `rbx` to the
+/// `Context`, while `rax`, `rcx` and `rdx` are scratch.
+struct Translator {
+    assembler: Assembler,
+    epilogue: u64,
+    /// Physical address of the block.
+    address: u64,
+    length: u64,
+    exits: Vec<Exit>,
+}
+
+impl Translator {
+    fn block<'a>(&mut self, instructions: impl Iterator<Item = &'a Instruction>) {
+        let a = &mut self.assembler;
+        a.memory(true, &[0x81], 7, RBX, BUDGET);
+        a.immediate32(self.length as i32);
+        let out_of_budget = a.jump_forward(Some(CC_L));
+        a.memory(true, &[0x81], 5, RBX, BUDGET);
+        a.immediate32(self.length as i32);
+
+        let mut pc_offset = 0;
+        let mut ends_in_jump = false;
+        for (index, instruction) in instructions.enumerate() {
+            ends_in_jump = self.instruction(instruction, pc_offset, index as u64);
+            pc_offset += instruction.size as i64;
+        }
+        if !ends_in_jump {
+            self.exit_to(pc_offset, self.length);
+        }
+
+        self.assembler.bind(out_of_budget);
+        self.assembler.move_immediate32(RAX, EXIT_NORMAL as i32);
+        self.assembler.jump(self.epilogue);
+        for exit in std::mem::take(&mut self.exits) {
+            self.assembler.bind(exit.fixup);
+            self.retire(exit.pc_offset, exit.retired);
+            self.assembler.move_immediate32(RAX, exit.code as i32);
+            self.assembler.jump(self.epilogue);
+        }
+    }
+
+    /// Translates the instruction at `pc_offset` into the block, the `index`th one. Returns whether it
+    /// ended the block.
+    fn instruction(&mut self, instruction: &Instruction, pc_offset: i64, index: u64) -> bool {
+        let (rd, rs1, rs2) = (instruction.rd, instruction.rs1, instruction.rs2);
+        let immediate = instruction.immediate_i() as i32;
+        let has_effects = matches!(instruction.opcode, OPCODE_LOAD | OPCODE_STORE | OPCODE_JAL | OPCODE_JALR | OPCODE_BRANCH);
+        if rd == 0 && !has_effects {
+            return false;
+        }
+        let a = &mut self.assembler;
+        match (instruction.opcode, instruction.funct3, instruction.funct7) {
+            (OPCODE_OP_IMM, F3_ADD, _) => a.operation_immediate(rd, rs1, 0, immediate),
+            (OPCODE_OP_IMM, F3_OR, _) => a.operation_immediate(rd, rs1, 1, immediate),
+            (OPCODE_OP_IMM, F3_AND, _) => a.operation_immediate(rd, rs1, 4, immediate),
+            (OPCODE_OP_IMM, F3_XOR, _) => a.operation_immediate(rd, rs1, 6, immediate),
+            (OPCODE_OP_IMM, F3_SLT, _) => a.compare_immediate(rd, rs1, immediate, CC_L),
+            (OPCODE_OP_IMM, F3_SLTU, _) => a.compare_immediate(rd, rs1, immediate, CC_B),
+            (OPCODE_OP_IMM, F3_SLL, _) => a.shift_immediate(true, rd, rs1, 4, instruction.shamt),
+            (OPCODE_OP_IMM, F3_SRL, _) if instruction.funct6() == F6_SRL => a.shift_immediate(true, rd, rs1, 5, instruction.shamt),
+            (OPCODE_OP_IMM, F3_SRA, _) if instruction.funct6() == F6_SRA => a.shift_immediate(true, rd, rs1, 7, instruction.shamt),
+            (OPCODE_OP_IMM_32, F3_ADD, _) => {
+                a.load_guest(RAX, rs1);
+                a.register(false, &[0x81], 0, RAX);
+                a.immediate32(immediate);
+                a.sign_extend_32();
+                a.store_guest(rd, RAX);
+            }
+            (OPCODE_OP_IMM_32, F3_SLL, _) => a.shift_immediate(false, rd, rs1, 4, instruction.shamtw()),
+            (OPCODE_OP_IMM_32, F3_SRL, F7_SRL) => a.shift_immediate(false, rd, rs1, 5, instruction.shamtw()),
+            (OPCODE_OP_IMM_32, F3_SRA, F7_SRA) => a.shift_immediate(false, rd, rs1, 7, instruction.shamt),
+
+            (OPCODE_LUI, _, _) => {
+                a.memory(true, &[0xC7], 0, R12, register(rd));
+                a.immediate32(instruction.immediate_u() as i32);
+            }
+            (OPCODE_AUIPC, _, _) => {
+                a.memory(true, &[0x8B], RAX, R12, PC);
+                a.register(true, &[0x81], 0, RAX);
+                a.immediate32(instruction.immediate_u() as i32);
+                a.add_immediate(RAX, pc_offset as i32);
+                a.store_guest(rd, RAX);
+            }
+
+            (OPCODE_OP, F3_ADD, F7_ADD) => a.operation(true, rd, rs1, rs2, 0x03),
+            (OPCODE_OP, F3_SUB, F7_SUB) => a.operation(true, rd, rs1, rs2, 0x2B),
+            (OPCODE_OP, F3_AND, F7_AND) => a.operation(true, rd, rs1, rs2, 0x23),
+            (OPCODE_OP, F3_OR, F7_OR) => a.operation(true, rd, rs1, rs2, 0x0B),
+            (OPCODE_OP, F3_XOR, F7_XOR) => a.operation(true, rd, rs1, rs2, 0x33),
+            (OPCODE_OP, F3_SLT, F7_SLT) => a.compare(rd, rs1, rs2, CC_L),
+            (OPCODE_OP, F3_SLTU, F7_SLTU) => a.compare(rd, rs1, rs2, CC_B),
+            (OPCODE_OP, F3_SLL, F7_SLL) => a.shift(true, rd, rs1, rs2, 4),
+            (OPCODE_OP, F3_SRL, F7_SRL) => a.shift(true, rd, rs1, rs2, 5),
+            (OPCODE_OP, F3_SRA, F7_SRA) => a.shift(true, rd, rs1, rs2, 7),
+            (OPCODE_OP, F3_MUL, F7_MULDIV) => a.multiply(true, rd, rs1, rs2),
+            (OPCODE_OP, F3_MULH, F7_MULDIV) => a.multiply_high(rd, rs1, rs2, 5),
+            (OPCODE_OP, F3_MULHU, F7_MULDIV) => a.multiply_high(rd, rs1, rs2, 4),
+            (OPCODE_OP, F3_MULHSU, F7_MULDIV) => a.call_arithmetic(rd, rs1, rs2, multiply_high_signed_unsigned),
+            (OPCODE_OP, F3_DIV, F7_MULDIV) => a.call_arithmetic(rd, rs1, rs2, divide),
+            (OPCODE_OP, F3_DIVU, F7_MULDIV) => a.call_arithmetic(rd, rs1, rs2, divide_unsigned),
+            (OPCODE_OP, F3_REM, F7_MULDIV) => a.call_arithmetic(rd, rs1, rs2, remainder),
+            (OPCODE_OP, F3_REMU, F7_MULDIV) => a.call_arithmetic(rd, rs1, rs2, remainder_unsigned),
+
+            (OPCODE_OP_32, F3_ADD, F7_ADD) => a.operation(false, rd, rs1, rs2, 0x03),
+            (OPCODE_OP_32, F3_SUB, F7_SUB) => a.operation(false, rd, rs1, rs2, 0x2B),
+            (OPCODE_OP_32, F3_SLL, F7_SLL) => a.shift(false, rd, rs1, rs2, 4),
+            (OPCODE_OP_32, F3_SRL, F7_SRL) => a.shift(false, rd, rs1, rs2, 5),
+            (OPCODE_OP_32, F3_SRA, F7_SRA) => a.shift(false, rd, rs1, rs2, 7),
+            (OPCODE_OP_32, F3_MULW, F7_MULDIV) => a.multiply(false, rd, rs1, rs2),
+            (OPCODE_OP_32, F3_DIVW, F7_MULDIV) => a.call_arithmetic(rd, rs1, rs2, divide_word),
+            (OPCODE_OP_32, F3_DIVUW, F7_MULDIV) => a.call_arithmetic(rd, rs1, rs2, divide_unsigned_word),
+            (OPCODE_OP_32, F3_REMW, F7_MULDIV) => a.call_arithmetic(rd, rs1, rs2, remainder_word),
+            (OPCODE_OP_32, F3_REMUW, F7_MULDIV) => a.call_arithmetic(rd, rs1, rs2, remainder_unsigned_word),
+
+            (OPCODE_LOAD, _, _) => {
+                let size = 1 << (instruction.funct3 & 3);
+                a.load_guest(RSI, rs1);
+                a.add_immediate(RSI, immediate);
+                a.move_immediate32(RDX, size);
+                a.call_helper(load as *const () as u64);
+                self.check_status(pc_offset, instruction.size as i64, index);
+                let a = &mut self.assembler;
+                match instruction.funct3 {
+                    F3_LB => a.bytes(&[0x48, 0x0F, 0xBE, 0xC0]), // movsx rax, al
+                    F3_LH => a.bytes(&[0x48, 0x0F, 0xBF, 0xC0]), // movsx rax, ax
+                    F3_LW => a.sign_extend_32(),
+                    _ => (),
+                }
+                a.store_guest(rd, RAX);
+            }
+            (OPCODE_STORE, _, _) => {
+                a.load_guest(RSI, rs1);
+                a.add_immediate(RSI, instruction.immediate_s() as i32);
+                a.load_guest(RDX, rs2);
+                a.move_immediate32(RCX, 1 << instruction.funct3);
+                a.call_helper(store as *const () as u64);
+                self.check_status(pc_offset, instruction.size as i64, index);
+            }
+
+            (OPCODE_BRANCH, funct3, _) => {
+                let condition = match funct3 {
+                    F3_BEQ => CC_E,
+                    F3_BNE => CC_NE,
+                    F3_BLT => CC_L,
+                    F3_BGE => CC_GE,
+                    F3_BLTU => CC_B,
+                    _ => CC_AE,
+                };
+                a.load_guest(RAX, rs1);
+                a.memory(true, &[0x3B], RAX, R12, register(rs2));
+                let not_taken = a.jump_forward(Some(condition ^ 1));
+                self.exit_to(pc_offset + instruction.immediate_b(), self.length);
+                self.assembler.bind(not_taken);
+                self.exit_to(pc_offset + instruction.size as i64, self.length);
+                return true;
+            }
+            (OPCODE_JAL, _, _) => {
+                a.link_register(rd, pc_offset + instruction.size as i64);
+                self.exit_to(pc_offset + instruction.immediate_j(), self.length);
+                return true;
+            }
+            (OPCODE_JALR, _, _) => {
+                a.load_guest(RCX, rs1);
+                a.add_immediate(RCX, immediate);
+                a.register(true, &[0x83], 4, RCX);
+                a.bytes(&[0xFE]); // and rcx, -2
+                a.link_register(rd, pc_offset + instruction.size as i64);
+                a.memory(true, &[0x89], RCX, R12, PC);
+                self.retire(0, self.length);
+                self.assembler.move_immediate32(RAX, EXIT_NORMAL as i32);
+                self.assembler.jump(self.epilogue);
+                return true;
+            }
+            _ => unreachable!("no translation for {:?}", instruction),
+        }
+        false
+    }
+
+    /// After a call to a helper, leaves the block if the instruction faulted or the block has to stop.
+    fn check_status(&mut self, pc_offset: i64, size: i64, index: u64) {
+        self.assembler.memory(true, &[0x83], 7, RBX, STATUS);
+        self.assembler.bytes(&[STATUS_FAULT as u8]);
+        let fixup = self.assembler.jump_forward(Some(CC_E));
+        self.exits.push(Exit { fixup, pc_offset, retired: index, code: EXIT_FAULT });
+        let fixup = self.assembler.jump_forward(Some(CC_A));
+        self.exits.push(Exit { fixup, pc_offset: pc_offset + size, retired: index + 1, code: EXIT_NORMAL });
+    }
+
+    /// Leaves the block for a fixed address. A block on the same page can be linked to later.
+    fn exit_to(&mut self, pc_offset: i64, retired: u64) {
+        self.retire(pc_offset, retired);
+        let a = &mut self.assembler;
+        let target = self.address.wrapping_add_signed(pc_offset);
+        if target / PAGE_SIZE == self.address / PAGE_SIZE {
+            a.bytes(&[0xE9, 0, 0, 0, 0]);
+            let site = a.position() - 4;
+            a.move_immediate(RAX, site);
+            a.memory(true, &[0x89], RAX, RBX, LINK);
+            a.move_immediate32(RAX, EXIT_LINK as i32);
+        } else {
+            a.move_immediate32(RAX, EXIT_NORMAL as i32);
+        }
+        a.jump(self.epilogue);
+    }
+
+    fn retire(&mut self, pc_offset: i64, retired: u64) {
+        let a = &mut self.assembler;
+        for (field, amount) in [(PC, pc_offset), (INSTRUCTIONS_RETIRED, retired as i64), (CYCLES, retired as i64)] {
+            if amount != 0 {
+                a.memory(true, &[0x81], 0, R12, field);
+                a.immediate32(amount as i32);
+            }
+        }
+    }
+}
+
+/// Just enough of an x86-64 assembler, for code that will run at `address`.
+struct Assembler {
+    code: Vec<u8>,
+    address: u64,
+}
+
+impl Assembler {
+    fn new(address: u64) -> Assembler {
+        Assembler { code: Vec::new(), address }
+    }
+
+    fn position(&self) -> u64 {
+        self.address + self.code.len() as u64
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn immediate32(&mut self, value: i32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn rex(&mut self, wide: bool, reg: u8, rm: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (rm >> 3);
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    /// `opcode reg, [base + displacement]`, where `reg` may also be an opcode extension.
+    fn memory(&mut self, wide: bool, opcode: &[u8], reg: u8, base: u8, displacement: i32) {
+        self.rex(wide, reg, base);
+        self.bytes(opcode);
+        self.code.push(0x80 | (reg & 7) << 3 | (base & 7));
+        if base & 7 == 4 {
+            self.code.push(0x24);
+        }
+        self.immediate32(displacement);
+    }
+
+    /// `opcode rm, reg` between registers, where `reg` may also be an opcode extension.
+    fn register(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: u8) {
+        self.rex(wide, reg, rm);
+        self.bytes(opcode);
+        self.code.push(0xC0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    fn push(&mut self, register: u8) {
+        self.rex(false, 0, register);
+        self.code.push(0x50 | (register & 7));
+    }
+
+    fn pop(&mut self, register: u8) {
+        self.rex(false, 0, register);
+        self.code.push(0x58 | (register & 7));
+    }
+
+    fn move_immediate(&mut self, register: u8, value: u64) {
+        self.rex(true, 0, register);
+        self.code.push(0xB8 | (register & 7));
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Zero-extended to 64 bits.
+    fn move_immediate32(&mut self, register: u8, value: i32) {
+        self.rex(false, 0, register);
+        self.code.push(0xB8 | (register & 7));
+        self.immediate32(value);
+    }
+
+    fn add_immediate(&mut self, register: u8, value: i32) {
+        if value != 0 {
+            self.register(true, &[0x81], 0, register);
+            self.immediate32(value);
+        }
+    }
+
+    /// movsxd rax, eax
+    fn sign_extend_32(&mut self) {
+        self.bytes(&[0x48, 0x63, 0xC0]);
+    }
+
+    fn jump(&mut self, target: u64) {
+        self.code.push(0xE9);
+        let displacement = target.wrapping_sub(self.position() + 4) as i32;
+        self.immediate32(displacement);
+    }
+
+    /// A jump, conditional or not, to a place yet to be bound. Returns the offset of its displacement.
+    fn jump_forward(&mut self, condition: Option<u8>) -> usize {
+        match condition {
+            Some(condition) => self.bytes(&[0x0F, 0x80 | condition]),
+            None => self.code.push(0xE9),
+        }
+        self.immediate32(0);
+        self.code.len() - 4
+    }
+
+    /// Makes the jump at `fixup` go to the current position.
+    fn bind(&mut self, fixup: usize) {
+        let displacement = (self.code.len() - (fixup + 4)) as i32;
+        self.code[fixup..fixup + 4].copy_from_slice(&displacement.to_le_bytes());
+    }
+
+    /// Calls a helper taking the context as the first argument, the others already in place.
+    fn call_helper(&mut self, function: u64) {
+        self.register(true, &[0x89], RBX, RDI);
+        self.move_immediate(RAX, function);
+        self.bytes(&[0xFF, 0xD0]); // call rax
+    }
+
+    fn load_guest(&mut self, register: u8, index: i32) {
+        self.memory(true, &[0x8B], register, R12, self::register(index));
+    }
+
+    fn store_guest(&mut self, index: i32, register: u8) {
+        if index != 0 {
+            self.memory(true, &[0x89], register, R12, self::register(index));
+        }
+    }
+
+    /// Stores the address `pc_offset` from the start of the block in `rd`.
+    fn link_register(&mut self, rd: i32, pc_offset: i64) {
+        if rd != 0 {
+            self.memory(true, &[0x8B], RAX, R12, PC);
+            self.add_immediate(RAX, pc_offset as i32);
+            self.store_guest(rd, RAX);
+        }
+    }
+
+    /// rd = rs1 op immediate, with `extension` picking the operation of opcode 0x81.
+    fn operation_immediate(&mut self, rd: i32, rs1: i32, extension: u8, immediate: i32) {
+        self.load_guest(RAX, rs1);
+        self.register(true, &[0x81], extension, RAX);
+        self.immediate32(immediate);
+        self.store_guest(rd, RAX);
+    }
+
+    /// rd = rs1 op rs2, with the 32-bit forms sign-extending their result.
+    fn operation(&mut self, wide: bool, rd: i32, rs1: i32, rs2: i32, opcode: u8) {
+        self.load_guest(RAX, rs1);
+        self.memory(wide, &[opcode], RAX, R12, register(rs2));
+        if !wide {
+            self.sign_extend_32();
+        }
+        self.store_guest(rd, RAX);
+    }
+
+    fn compare_immediate(&mut self, rd: i32, rs1: i32, immediate: i32, condition: u8) {
+        self.load_guest(RAX, rs1);
+        self.register(true, &[0x81], 7, RAX);
+        self.immediate32(immediate);
+        self.set_condition(rd, condition);
+    }
+
+    fn compare(&mut self, rd: i32, rs1: i32, rs2: i32, condition: u8) {
+        self.load_guest(RAX, rs1);
+        self.memory(true, &[0x3B], RAX, R12, register(rs2));
+        self.set_condition(rd, condition);
+    }
+
+    fn set_condition(&mut self, rd: i32, condition: u8) {
+        self.bytes(&[0x0F, 0x90 | condition, 0xC0]); // setcc al
+        self.bytes(&[0x0F, 0xB6, 0xC0]); // movzx eax, al
+        self.store_guest(rd, RAX);
+    }
+
+    /// Shifts by an immediate, `extension` 4 for left, 5 for logical right, 7 for arithmetic right.
+    fn shift_immediate(&mut self, wide: bool, rd: i32, rs1: i32, extension: u8, amount: i32) {
+        self.load_guest(RAX, rs1);
+        self.register(wide, &[0xC1], extension, RAX);
+        self.bytes(&[amount as u8]);
+        if !wide {
+            self.sign_extend_32();
+        }
+        self.store_guest(rd, RAX);
+    }
+
+    /// Shifts by a register, which x86 masks to the same 5 or 6 bits RISC-V uses.
+    fn shift(&mut self, wide: bool, rd: i32, rs1: i32, rs2: i32, extension: u8) {
+        self.load_guest(RAX, rs1);
+        self.load_guest(RCX, rs2);
+        self.register(wide, &[0xD3], extension, RAX);
+        if !wide {
+            self.sign_extend_32();
+        }
+        self.store_guest(rd, RAX);
+    }
+
+    fn multiply(&mut self, wide: bool, rd: i32, rs1: i32, rs2: i32) {
+        self.load_guest(RAX, rs1);
+        self.memory(wide, &[0x0F, 0xAF], RAX, R12, register(rs2));
+        if !wide {
+            self.sign_extend_32();
+        }
+        self.store_guest(rd, RAX);
+    }
+
+    /// The upper half of a full multiplication, `extension` 5 for signed or 4 for unsigned.
+    fn multiply_high(&mut self, rd: i32, rs1: i32, rs2: i32, extension: u8) {
+        self.load_guest(RAX, rs1);
+        self.memory(true, &[0xF7], extension, R12, register(rs2));
+        self.store_guest(rd, RDX);
+    }
+
+    fn call_arithmetic(&mut self, rd: i32, rs1: i32, rs2: i32, function: extern "C" fn(u64, u64) -> u64) {
+        self.load_guest(RDI, rs1);
+        self.load_guest(RSI, rs2);
+        self.move_immediate(RAX, function as *const () as u64);
+        self.bytes(&[0xFF, 0xD0]); // call rax
+        self.store_guest(rd, RAX);
+    }
+}
+
+/// Loads for translated code, through the same path as the interpreter.
+unsafe extern "C" fn load(context: *mut Context, address: u64, size: u64) -> u64 {
+    let context = &mut *context;
+    match (*context.cpu).load(&mut *context.bus, address, size) {
+        Ok(value) => {
+            context.status = STATUS_OK;
+            value
+        }
+        Err(_) => {
+            context.status = STATUS_FAULT;
+            0
+        }
+    }
+}
+
+unsafe extern "C" fn store(context: *mut Context, address: u64, value: u64, size: u64) {
+    let context = &mut *context;
+    let (cpu, bus) = (&mut *context.cpu, &mut *context.bus);
+    context.status = match cpu.store(bus, address, size, value) {
+        Ok(()) if !bus.code_written.is_empty() || cpu.exit_code(bus).is_some() => STATUS_STOP,
+        Ok(()) => STATUS_OK,
+        Err(_) => STATUS_FAULT,
+    };
+}
+
+extern "C" fn multiply_high_signed_unsigned(a: u64, b: u64) -> u64 {
+    mulhsu(a as i64, b)
+}
+
+extern "C" fn divide(a: u64, b: u64) -> u64 {
+    div_signed(a, b).0
+}
+
+extern "C" fn divide_unsigned(a: u64, b: u64) -> u64 {
+    div_unsigned(a, b).0
+}
+
+extern "C" fn remainder(a: u64, b: u64) -> u64 {
+    div_signed(a, b).1
+}
+
+extern "C" fn remainder_unsigned(a: u64, b: u64) -> u64 {
+    div_unsigned(a, b).1
+}
+
+extern "C" fn divide_word(a: u64, b: u64) -> u64 {
+    div_signed(a as i32 as u64, b as i32 as u64).0 as i32 as u64
+}
+
+extern "C" fn divide_unsigned_word(a: u64, b: u64) -> u64 {
+    div_unsigned(a as u32 as u64, b as u32 as u64).0 as i32 as u64
+}
+
+extern "C" fn remainder_word(a: u64, b: u64) -> u64 {
+    div_signed(a as i32 as u64, b as i32 as u64).1 as i32 as u64
+}
+
+extern "C" fn remainder_unsigned_word(a: u64, b: u64) -> u64 {
+    div_unsigned(a as u32 as u64, b as u32 as u64).1 as i32 as u64
+}
+
+/// Readable, writable and executable memory, filled from the start.
+struct CodeMemory {
+    base: *mut u8,
+    used: usize,
+}
+
+impl CodeMemory {
+    fn new() -> Result<CodeMemory> {
+        let protection = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+        // SAFETY: a fresh anonymous mapping doesn't alias anything
+        let base = unsafe { libc::mmap(ptr::null_mut(), CODE_SIZE, protection, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0) };
+        if base == libc::MAP_FAILED {
+            bail!("can't map memory for translated code: {}", io::Error::last_os_error());
+        }
+        Ok(CodeMemory { base: base.cast(), used: 0 })
+    }
+
+    /// Where the next code goes.
+    fn address(&self) -> u64 {
+        self.base as u64 + self.used as u64
+    }
+
+    /// Appends code, returning its address.
+    fn push(&mut self, code: &[u8]) -> u64 {
+        assert!(self.used + code.len() <= CODE_SIZE, "translated code doesn't fit");
+        let address = self.address();
+        // SAFETY: the range is within the mapping and checked above
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), self.base.add(self.used), code.len()) };
+        self.used += code.len();
+        address
+    }
+}
+
+impl Drop for CodeMemory {
+    fn drop(&mut self) {
+        // SAFETY: nothing runs translated code once the JIT is gone
+        unsafe { libc::munmap(self.base.cast(), CODE_SIZE) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::{Ram, DRAM_BASE};
+    use crate::machine::Machine;
+
+    const MEMORY_SIZE: usize = 0x10000;
+
+    /// Runs a program in the interpreter, or with the JIT, returning the outcome, registers, PC,
+    /// instruction count and memory it ended with.
+    fn run(source: &str, limit: Option<u64>, translated: bool) -> (Result<StepOutcome, ExecError>, [u64; 32], u64, u64, Vec<u8>) {
+        let image = assemble(source, DRAM_BASE).unwrap().flat_image();
+        let machine = Machine::new();
+        let mut bus = Bus::new();
+        bus.map(DRAM_BASE, MEMORY_SIZE as u64, Ram::new(MEMORY_SIZE));
+        bus.store_bytes(DRAM_BASE, &image).unwrap();
+        let mut cpu = Cpu::new(&machine);
+        cpu.pc = DRAM_BASE;
+        let outcome = if translated {
+            Jit::new().unwrap().run(&mut cpu, &mut bus, limit)
+        } else {
+            cpu.run(&mut bus, limit)
+        };
+        let mut memory = vec![0; MEMORY_SIZE];
+        bus.load_bytes(DRAM_BASE, &mut memory).unwrap();
+        (outcome, cpu.registers, cpu.pc, cpu.instructions_retired, memory)
+    }
+
+    fn assert_same(source: &str, limit: Option<u64>) -> [u64; 32] {
+        let interpreted = run(source, limit, false);
+        let translated = run(source, limit, true);
+        assert_eq!(translated.0, interpreted.0);
+        assert_eq!(translated.1, interpreted.1);
+        assert_eq!(translated.2, interpreted.2, "pc");
+        assert_eq!(translated.3, interpreted.3, "instructions retired");
+        assert!(translated.4 == interpreted.4, "memory differs");
+        translated.1
+    }
+
+    /// Every instruction with a translation, in a loop.
+    const INSTRUCTIONS: &str = "
+    .text
+    _start:
+    li s0, 40
+    la s1, data
+    li a0, 0x123456789
+    li a1, -77
+    loop:
+    addi t0, a0, -5
+    slti t1, a1, 3
+    sltiu t2, a1, 3
+    andi t3, a0, 0x7f0
+    ori t4, a1, 0x15
+    xori t5, a0, -1
+    slli t6, a0, 37
+    srli a2, a1, 3
+    srai a3, a1, 2
+    srli s2, a0, 33
+    srai s3, a1, 60
+    addiw a4, a0, 2047
+    slliw s4, a0, 7
+    srliw s5, a1, 9
+    sraiw a5, a1, 4
+    lui a6, 0xfffff
+    auipc a7, 3
+    add t0, t0, t1
+    sub t1, t2, t3
+    and t2, t4, t5
+    or t3, t6, a2
+    xor t4, a3, a4
+    slt t5, a5, a6
+    sltu t6, a6, a7
+    sll a2, a0, a1
+    srl a3, a1, a0
+    sra a4, a1, s0
+    mul a5, a0, a1
+    mulh a6, a0, a1
+    mulhu a7, a0, a1
+    mulhsu t0, a1, a0
+    div t1, a0, a1
+    divu t2, a1, s0
+    rem t3, a0, zero
+    remu t4, a1, a0
+    addw t5, a0, a1
+    subw t6, a1, a0
+    sllw s6, a1, a0
+    srlw s7, a1, s0
+    sraw a2, a1, s0
+    mulw a3, a0, a1
+    divw s8, a0, a1
+    divuw s9, a1, s0
+    remw s10, a0, a1
+    remuw s11, a1, zero
+    add t0, t0, s2
+    xor t1, s3, s4
+    add t2, s5, s6
+    xor t3, s7, s8
+    add t4, s9, s10
+    xor t5, s11, t5
+    sd a0, 0(s1)
+    sw a1, 8(s1)
+    sh a2, 12(s1)
+    sb a3, 14(s1)
+    lb a4, 14(s1)
+    lh a5, 12(s1)
+    lw a6, 8(s1)
+    ld a7, 0(s1)
+    lbu t0, 3(s1)
+    lhu t1, 6(s1)
+    lwu t2, 4(s1)
+    beq t0, t1, 1f
+    bne t0, t1, 1f
+    1:  blt a1, a0, 2f
+    nop
+    2:  bge a1, a0, 3f
+    bltu a1, a0, 3f
+    bgeu a0, a1, 3f
+    3:  jal ra, function
+    add a0, a0, a7
+    xor a1, a1, t5
+    addi s1, s1, 16
+    addi s0, s0, -1
+    bnez s0, loop
+    wfi
+    function:
+    mulw a0, a0, a1
+    ret
+    .data
+    data: .zero 1024
+";
+
+    #[test]
+    fn test_instructions() {
+        assert_same(INSTRUCTIONS, None);
+        for limit in [1, 17, 1000, 1234] {
+            assert_same(INSTRUCTIONS, Some(limit));
+        }
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let source = "
+            li s0, 100
+            la t0, patched
+            lw t1, 0(t0)
+            li t2, 0x100000
+        loop:
+        patched:
+            addi a0, a0, 1
+            add t1, t1, t2              # next time add one more
+            sw t1, 0(t0)
+            addi s0, s0, -1
+            bnez s0, loop
+            wfi
+        ";
+        assert_eq!(assert_same(source, None)[10], 5050);
+    }
+
+    #[test]
+    fn test_faults() {
+        let source = "
+            la t0, handler
+            csrw mtvec, t0
+            li s0, 50
+            li s1, 0x1000               # nothing there
+        loop:
+            addi a0, a0, 1
+            ld a1, 0(s1)
+            addi a2, a2, 1
+            addi s0, s0, -1
+            bnez s0, loop
+            wfi
+        handler:
+            addi a3, a3, 1
+            csrr t1, mepc
+            addi t1, t1, 4
+            csrw mepc, t1
+            mret
+        ";
+        let registers = assert_same(source, None);
+        assert_eq!(registers[10..14], [50, 0, 50, 50]);
+    }
+}
@@ -1,25 +1,169 @@
-﻿use elf::{ElfBytes, ParseError};
-use elf::abi::PT_LOAD;
+﻿use std::fmt;
+
+use elf::{ElfBytes, ParseError};
+use elf::abi::{PT_LOAD, PT_PHDR, SHN_UNDEF, STT_FUNC, STT_NOTYPE, STT_OBJECT};
 use elf::endian::{LittleEndian};
 
-use crate::bus::Bus;
+use crate::bus::{AccessFault, Bus, DRAM_BASE};
+use crate::csr::misa_extension;
+use crate::mmu::PAGE_SIZE;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_HWCAP: u64 = 16;
+const AT_RANDOM: u64 = 25;
+
+/// Single-letter extensions reported in `AT_HWCAP`, encoded like `misa`.
+const HWCAP: u64 = misa_extension(b'I') | misa_extension(b'M') | misa_extension(b'A')
+    | misa_extension(b'F') | misa_extension(b'D') | misa_extension(b'C');
+
+/// Linux executables are linked far below where RAM starts on the boards firmware and kernels target.
+pub fn is_user_program(elf_bytes: &[u8]) -> bool {
+    ElfBytes::<LittleEndian>::minimal_parse(elf_bytes).is_ok_and(|elf_file| elf_file.ehdr.e_entry < DRAM_BASE)
+}
+
+/// Looks up a symbol's address in the ELF symbol table.
+pub fn find_symbol(elf_bytes: &[u8], name: &str) -> Option<u64> {
+    let elf_file = ElfBytes::<LittleEndian>::minimal_parse(elf_bytes).ok()?;
+    let (symbols, strings) = elf_file.symbol_table().ok()??;
+    symbols.iter()
+        .find(|symbol| strings.get(symbol.st_name as usize).is_ok_and(|symbol_name| symbol_name == name))
+        .map(|symbol| symbol.st_value)
+}
+
+/// A named code or data address from an ELF symbol table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u64,
+    /// Zero for labels, which extend to the next symbol.
+    pub size: u64,
+}
+
+/// The function, object and label symbols of an ELF file sorted by address, or nothing if it has no symbol table.
+pub fn symbols(elf_bytes: &[u8]) -> Vec<Symbol> {
+    let Ok(elf_file) = ElfBytes::<LittleEndian>::minimal_parse(elf_bytes) else { return Vec::new() };
+    let Ok(Some((symbols, strings))) = elf_file.symbol_table() else { return Vec::new() };
+    let mut result: Vec<Symbol> = symbols.iter()
+        .filter(|symbol| matches!(symbol.st_symtype(), STT_FUNC | STT_OBJECT | STT_NOTYPE) && symbol.st_shndx != SHN_UNDEF)
+        .filter_map(|symbol| {
+            let name = strings.get(symbol.st_name as usize).ok()?;
+            // skip assembler temporaries and the `$x`/`$d` mapping symbols
+            (!name.is_empty() && !name.starts_with(".L") && !name.starts_with('$')).then(|| Symbol {
+                name: name.to_string(),
+                address: symbol.st_value,
+                size: symbol.st_size,
+            })
+        })
+        .collect();
+    result.sort_by_key(|symbol| symbol.address);
+    result
+}
+
+/// The symbol `address` falls in, and how far into it the address is.
+pub fn symbol_at(symbols: &[Symbol], address: u64) -> Option<(&Symbol, u64)> {
+    let index = symbols.partition_point(|symbol| symbol.address <= address).checked_sub(1)?;
+    let symbol = &symbols[index];
+    let offset = address - symbol.address;
+    (symbol.size == 0 || offset < symbol.size).then_some((symbol, offset))
+}
 
-pub fn load_elf_file(bus: &mut Bus, elf_bytes: &[u8]) -> Result<EntryPoint, LoaderError> {
+pub fn load_elf_file(bus: &mut Bus, elf_bytes: &[u8]) -> Result<LoadedElf, LoaderError> {
     let elf_file = ElfBytes::<LittleEndian>::minimal_parse(elf_bytes)
-        .or_else(|x| Err(LoaderError::ParseError(x)))?;
+        .map_err(LoaderError::ParseError)?;
 
     let segments_to_load = elf_file
         .segments().ok_or(LoaderError::NoSegments)?
         .iter().filter(|x| x.p_type == PT_LOAD);
 
+    let segments = elf_file.segments().ok_or(LoaderError::NoSegments)?;
+    let program_headers = match segments.iter().find(|x| x.p_type == PT_PHDR) {
+        Some(phdr) => phdr.p_vaddr,
+        // without PT_PHDR, find the headers in whichever segment loads that part of the file
+        None => segments.iter()
+            .filter(|x| x.p_type == PT_LOAD)
+            .find(|x| (x.p_offset..x.p_offset + x.p_filesz).contains(&elf_file.ehdr.e_phoff))
+            .map_or(0, |x| x.p_vaddr + (elf_file.ehdr.e_phoff - x.p_offset)),
+    };
+
+    let mut end = 0;
     for segment in segments_to_load {
         let from = segment.p_offset as usize;
         let length = segment.p_filesz as usize;
 
-        bus.store_bytes(segment.p_paddr, &elf_bytes[from..from + length]);
+        bus.store_bytes(segment.p_paddr, &elf_bytes[from..from + length])
+            .map_err(|fault| LoaderError::UnmappedSegment(fault.address))?;
+        end = end.max(segment.p_vaddr + segment.p_memsz);
     }
 
-    Ok(EntryPoint(elf_file.ehdr.e_entry))
+    Ok(LoadedElf {
+        entry_point: EntryPoint(elf_file.ehdr.e_entry),
+        end: end.next_multiple_of(PAGE_SIZE),
+        program_headers,
+        program_header_count: elf_file.ehdr.e_phnum as u64,
+        program_header_size: elf_file.ehdr.e_phentsize as u64,
+    })
+}
+
+/// Builds the System V initial process stack below `stack_top` and returns the stack pointer.
+///
+/// From the stack pointer up: argc, the argv and envp pointer arrays (each null-terminated), the auxiliary
+/// vector, and then the strings and `AT_RANDOM` bytes they point to.
+pub fn setup_stack(bus: &mut Bus, program: &LoadedElf, stack_top: u64, args: &[String], env: &[String], random: [u8; 16]) -> Result<u64, LoaderError> {
+    let fault = |fault: AccessFault| LoaderError::StackOverflow(fault.address);
+    let mut position = stack_top;
+    let mut push_bytes = |bus: &mut Bus, bytes: &[u8]| {
+        position = position.checked_sub(bytes.len() as u64).ok_or(LoaderError::StackOverflow(0))?;
+        bus.store_bytes(position, bytes).map(|_| position).map_err(fault)
+    };
+
+    // pushed in reverse so the strings end up in order, argv[0] lowest
+    let mut push_strings = |bus: &mut Bus, strings: &[String]| {
+        let mut pointers = strings.iter().rev()
+            .map(|string| push_bytes(bus, &[string.as_bytes(), &[0]].concat()))
+            .collect::<Result<Vec<_>, _>>()?;
+        pointers.reverse();
+        Ok::<_, LoaderError>(pointers)
+    };
+    let env_pointers = push_strings(bus, env)?;
+    let arg_pointers = push_strings(bus, args)?;
+    let random_pointer = push_bytes(bus, &random)?;
+
+    let auxiliary_vector = [
+        (AT_PHDR, program.program_headers),
+        (AT_PHENT, program.program_header_size),
+        (AT_PHNUM, program.program_header_count),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, program.entry_point.virtual_address()),
+        (AT_HWCAP, HWCAP),
+        (AT_RANDOM, random_pointer),
+        (AT_NULL, 0),
+    ];
+    let mut words = vec![args.len() as u64];
+    words.extend(&arg_pointers);
+    words.push(0);
+    words.extend(&env_pointers);
+    words.push(0);
+    words.extend(auxiliary_vector.iter().flat_map(|(key, value)| [*key, *value]));
+
+    let stack_pointer = position.checked_sub(8 * words.len() as u64).ok_or(LoaderError::StackOverflow(0))? & !15;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    bus.store_bytes(stack_pointer, &bytes).map_err(fault)?;
+    Ok(stack_pointer)
+}
+
+pub struct LoadedElf {
+    pub entry_point: EntryPoint,
+    /// Page-aligned end of the highest segment, where a process' heap starts.
+    pub end: u64,
+    /// Virtual address of the program header table, zero if it isn't loaded.
+    pub program_headers: u64,
+    pub program_header_count: u64,
+    pub program_header_size: u64,
 }
 
 pub struct EntryPoint(u64);
@@ -34,5 +178,68 @@ impl EntryPoint {
 pub enum LoaderError {
     NoSegments,
     ParseError(ParseError),
+    UnmappedSegment(u64),
+    StackOverflow(u64),
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoaderError::NoSegments => write!(f, "ELF file has no program headers"),
+            LoaderError::ParseError(e) => write!(f, "ELF parse error: {}", e),
+            LoaderError::UnmappedSegment(address) => write!(f, "segment doesn't fit in memory at {:#x}", address),
+            LoaderError::StackOverflow(address) => write!(f, "initial stack doesn't fit in memory at {:#x}", address),
+        }
+    }
 }
 
+impl std::error::Error for LoaderError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Ram;
+
+    #[test]
+    fn test_setup_stack() {
+        let mut bus = Bus::new();
+        bus.map(0, 0x1000, Ram::new(0x1000));
+        let program = LoadedElf {
+            entry_point: EntryPoint(0x1_0000),
+            end: 0x2_0000,
+            program_headers: 0x1_0040,
+            program_header_count: 4,
+            program_header_size: 56,
+        };
+        let args = ["prog".to_string(), "-v".to_string()];
+        let env = ["HOME=/".to_string()];
+        let sp = setup_stack(&mut bus, &program, 0x1000, &args, &env, [7; 16]).unwrap();
+        assert_eq!(sp % 16, 0);
+
+        let mut words = [0u64; 24];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = bus.load64(sp + 8 * i as u64).unwrap();
+        }
+        let string = |bus: &mut Bus, address: u64| {
+            let mut bytes = [0; 6];
+            bus.load_bytes(address, &mut bytes).unwrap();
+            String::from_utf8_lossy(&bytes).into_owned()
+        };
+        assert_eq!(words[0], 2);
+        assert_eq!(string(&mut bus, words[1]), "prog\0-");
+        assert_eq!(string(&mut bus, words[2]), "-v\0HOM");
+        assert_eq!(words[3], 0);
+        assert_eq!(string(&mut bus, words[4]), "HOME=/");
+        assert_eq!(words[5], 0);
+
+        let auxv: Vec<_> = words[6..].chunks(2).map(|pair| (pair[0], pair[1])).collect();
+        assert_eq!(auxv[0], (AT_PHDR, 0x1_0040));
+        assert_eq!(auxv[2], (AT_PHNUM, 4));
+        assert_eq!(auxv[4], (AT_ENTRY, 0x1_0000));
+        assert_eq!(auxv[6].0, AT_RANDOM);
+        assert_eq!(bus.load64(auxv[6].1), Ok(0x0707_0707_0707_0707));
+        assert_eq!(auxv[7], (AT_NULL, 0));
+
+        assert!(matches!(setup_stack(&mut bus, &program, 0x10, &args, &env, [0; 16]), Err(LoaderError::StackOverflow(_))));
+    }
+}
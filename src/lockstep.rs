@@ -0,0 +1,202 @@
+﻿//! Lockstep co-simulation against a commit log recorded by a reference simulator, such as Spike's
+//! `--log-commits`. The emulator steps alongside the trace and stops at the first instruction whose
+//! PC, encoding, register writes or memory writes differ.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::bus::Bus;
+use crate::commit_log::{Commit, CommitLog};
+use crate::instruction::Instruction;
+use crate::machine::Cpu;
+use crate::opcodes::OPCODE_SYSTEM;
+
+/// Matching instructions shown before a divergence.
+const CONTEXT: usize = 8;
+
+/// Steps in a row that may end in traps or interrupts before the emulator is considered stuck.
+const MAX_STEPS_WITHOUT_RETIRING: usize = 1000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every instruction in the trace matched.
+    Matched(u64),
+    /// The guest exited before the trace ended.
+    Exited(i32),
+    Diverged,
+}
+
+pub struct Lockstep<'a> {
+    exited: &'a dyn Fn(&Cpu) -> Option<i32>,
+    /// The instructions that matched most recently, oldest first.
+    history: VecDeque<Commit>,
+    matched: u64,
+}
+
+impl<'a> Lockstep<'a> {
+    pub fn new(exited: &'a dyn Fn(&Cpu) -> Option<i32>) -> Lockstep<'a> {
+        Lockstep { exited, history: VecDeque::new(), matched: 0 }
+    }
+
+    /// Runs the guest against `trace`, reporting a divergence to `output`.
+    ///
+    /// Lines other than commit lines are skipped, as is everything before the trace reaches the
+    /// guest's entry point, which skips Spike's boot ROM. With Linux syscalls emulated, instructions
+    /// the reference ran outside of user mode are skipped too, since they belong to its kernel.
+    pub fn run(&mut self, cpu: &mut Cpu, bus: &mut Bus, trace: impl BufRead, mut output: impl Write) -> Result<Outcome> {
+        if cpu.commit_log.is_none() {
+            cpu.commit_log = Some(CommitLog::recorder());
+        }
+        let user_mode_only = cpu.syscalls.is_some();
+        let mut synchronized = false;
+
+        for (number, line) in trace.lines().enumerate() {
+            let line = line.context("can't read the trace")?;
+            let Some(expected) = Commit::parse(&line) else { continue };
+            if !synchronized && expected.pc != cpu.pc || user_mode_only && expected.privilege != 0 {
+                continue;
+            }
+            synchronized = true;
+
+            let actual = match self.retire(cpu, bus, user_mode_only)? {
+                Ok(commit) => commit,
+                Err(exit_code) => return Ok(Outcome::Exited(exit_code)),
+            };
+            if let Some(difference) = difference(&expected, &actual) {
+                writeln!(output, "divergence after {} matching instructions, at line {} of the trace: {}", self.matched, number + 1, difference)?;
+                for commit in &self.history {
+                    writeln!(output, "          {}", describe(commit))?;
+                }
+                writeln!(output, "expected: {}", describe(&expected))?;
+                writeln!(output, "actual:   {}", describe(&actual))?;
+                return Ok(Outcome::Diverged);
+            }
+
+            self.matched += 1;
+            if self.history.len() == CONTEXT {
+                self.history.pop_front();
+            }
+            self.history.push_back(actual);
+        }
+
+        if !synchronized {
+            bail!("the trace never reaches the entry point 0x{:x}", cpu.pc);
+        }
+        Ok(Outcome::Matched(self.matched))
+    }
+
+    /// Steps until an instruction retires, or returns the exit code when the guest exits first.
+    fn retire(&mut self, cpu: &mut Cpu, bus: &mut Bus, user_mode_only: bool) -> Result<Result<Commit, i32>> {
+        for _ in 0..MAX_STEPS_WITHOUT_RETIRING {
+            if let Some(exit_code) = (self.exited)(cpu) {
+                return Ok(Err(exit_code));
+            }
+            cpu.step(bus)?;
+            let Some(commit) = cpu.commit_log.as_mut().and_then(CommitLog::take_last) else { continue };
+            // an emulated syscall retires, but the reference traps into its kernel instead
+            let syscall = user_mode_only && commit.encoding == OPCODE_SYSTEM as u32;
+            if !syscall {
+                return Ok(Ok(commit));
+            }
+        }
+        bail!("no instruction retired in {} steps, stuck at 0x{:x}", MAX_STEPS_WITHOUT_RETIRING, cpu.pc)
+    }
+}
+
+fn difference(expected: &Commit, actual: &Commit) -> Option<String> {
+    if expected.pc != actual.pc {
+        return Some(format!("pc is 0x{:x} instead of 0x{:x}", actual.pc, expected.pc));
+    }
+    if (expected.encoding, expected.size) != (actual.encoding, actual.size) {
+        return Some("different instruction encodings".to_string());
+    }
+    if expected.register_writes() != actual.register_writes() {
+        return Some("different register writes".to_string());
+    }
+    if expected.stores() != actual.stores() {
+        return Some("different memory writes".to_string());
+    }
+    None
+}
+
+fn describe(commit: &Commit) -> String {
+    format!("{}    ; {}", commit, Instruction::decode(commit.encoding as i32).disassemble(commit.pc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::{Ram, DRAM_BASE};
+    use crate::machine::Machine;
+
+    const SOURCE: &str = "
+        li a0, 10
+        li a1, 0
+    1:  add a1, a1, a0
+        sd a1, 64(zero)
+        addi a0, a0, -1
+        bnez a0, 1b
+        ecall
+    ";
+
+    fn lockstep(trace: &str) -> (Outcome, String) {
+        let program = assemble(SOURCE, DRAM_BASE).unwrap();
+        let machine = Machine::new();
+        let mut bus = Bus::new();
+        bus.map(0, 0x1000, Ram::new(0x1000));
+        bus.map(DRAM_BASE, 0x1000, Ram::new(0x1000));
+        bus.store_bytes(DRAM_BASE, &program.flat_image()).unwrap();
+        let mut cpu = Cpu::new(&machine);
+        cpu.pc = DRAM_BASE;
+        // stop at the ecall
+        let exited = |cpu: &Cpu| (cpu.pc == DRAM_BASE + 0x18).then_some(0);
+        let mut output = Vec::new();
+        let outcome = Lockstep::new(&exited).run(&mut cpu, &mut bus, trace.as_bytes(), &mut output).unwrap();
+        (outcome, String::from_utf8(output).unwrap())
+    }
+
+    fn reference_trace() -> String {
+        let mut trace = "core   0: 3 0x0000000000001000 (0x00000297) x5  0x0000000000001000\n".to_string();
+        let mut a0 = 10;
+        let mut a1 = 0;
+        trace += "core   0: 3 0x0000000080000000 (0x00a00513) x10 0x000000000000000a\n";
+        trace += "core   0: 3 0x0000000080000004 (0x00000593) x11 0x0000000000000000\n";
+        while a0 != 0 {
+            a1 += a0;
+            a0 -= 1;
+            trace += &format!("core   0: 3 0x0000000080000008 (0x00a585b3) x11 0x{:016x}\n", a1);
+            trace += &format!("core   0: 3 0x000000008000000c (0x04b03023) mem 0x0000000000000040 0x{:016x}\n", a1);
+            trace += "core   0: 0x0000000080000010 (0xfff50513) addi    a0, a0, -1\n";
+            trace += &format!("core   0: 3 0x0000000080000010 (0xfff50513) x10 0x{:016x}\n", a0);
+            trace += "core   0: 3 0x0000000080000014 (0xfe051ae3)\n";
+        }
+        trace
+    }
+
+    #[test]
+    fn test_lockstep() {
+        let trace = reference_trace();
+        assert_eq!(lockstep(&trace), (Outcome::Matched(42), String::new()));
+        let past_exit = trace.clone() + "core   0: 3 0x0000000080000018 (0x00000073)\n";
+        assert_eq!(lockstep(&past_exit).0, Outcome::Exited(0));
+        let cut = trace.lines().take(12).collect::<Vec<_>>().join("\n");
+        assert_eq!(lockstep(&cut).0, Outcome::Matched(9));
+
+        let wrong_store = trace.replace("mem 0x0000000000000040 0x0000000000000022", "mem 0x0000000000000040 0x0000000000000023");
+        let (outcome, report) = lockstep(&wrong_store);
+        assert_eq!(outcome, Outcome::Diverged);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "divergence after 15 matching instructions, at line 20 of the trace: different memory writes");
+        assert_eq!(lines.len(), 1 + CONTEXT + 2);
+        assert_eq!(lines[CONTEXT], "          core   0: 3 0x0000000080000008 (0x00a585b3) x11 0x0000000000000022    ; add a1, a1, a0");
+        assert_eq!(lines[CONTEXT + 2], "actual:   core   0: 3 0x000000008000000c (0x04b03023) mem 0x0000000000000040 0x0000000000000022    ; sd a1, 64(zero)");
+
+        let wrong_register = trace.replace("x10 0x0000000000000008", "x10 0x0000000000000007");
+        assert!(lockstep(&wrong_register).1.lines().next().unwrap().ends_with("different register writes"));
+        let skipped = trace.replacen("core   0: 3 0x0000000080000008", "core   0: 3 0x000000008000000c", 1);
+        assert!(lockstep(&skipped).1.starts_with("divergence after 2 matching instructions, at line 4 of the trace: pc is 0x80000008 instead of 0x8000000c"));
+    }
+}
@@ -1,16 +1,56 @@
-﻿use std::time::Instant;
-use crate::bus::Bus;
+﻿use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Result};
+use crate::bus::{Bus, InterruptLines};
+use crate::commit_log::CommitLog;
+use crate::decode_cache::{Decoded, DecodeCache};
+use crate::csr::*;
+use crate::float::{self, FloatContext, Format, RoundingMode, DOUBLE, SINGLE};
 use crate::instruction::Instruction;
+use crate::mmu::{self, AccessType, TranslationContext, PAGE_SIZE, SATP_MODE_BARE, SATP_MODE_SHIFT, SATP_MODE_SV39, SATP_MODE_SV48, SATP_PPN_MASK};
 use crate::opcodes::*;
+use crate::replay::SharedJournal;
+use crate::snapshot::{Reader, Writer};
+use crate::syscall::LinuxSyscalls;
+use crate::trap::{Exception, ExecError, Interrupt, Privilege, StepOutcome, Trap};
+
+/// Platform timer frequency, matching QEMU's virt board.
+pub const DEFAULT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+/// Where the machine's sense of time comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    Host,
+    /// Counts retired instructions as if the hart ran this many per second, so runs repeat exactly.
+    Virtual { instructions_per_second: u64 },
+}
 
 #[derive(Debug)]
 pub struct Machine {
     pub start: Instant,
+    /// Rate at which `mtime` and the `time` CSR count, in Hz.
+    pub timebase_frequency: u64,
+    pub clock: Clock,
 }
 
 impl Machine {
     pub fn new() -> Machine {
-        Machine { start: Instant::now() }
+        Machine { start: Instant::now(), timebase_frequency: DEFAULT_TIMEBASE_FREQUENCY, clock: Clock::Host }
+    }
+
+    /// Time since the machine was started, once the hart has retired `instructions_retired` instructions.
+    pub fn elapsed(&self, instructions_retired: u64) -> Duration {
+        match self.clock {
+            Clock::Host => self.start.elapsed(),
+            Clock::Virtual { instructions_per_second } => {
+                Duration::from_nanos((instructions_retired as u128 * 1_000_000_000 / instructions_per_second as u128) as u64)
+            }
+        }
+    }
+
+    /// Timer ticks since the machine was started.
+    pub fn time(&self, instructions_retired: u64) -> u64 {
+        (self.elapsed(instructions_retired).as_nanos() * self.timebase_frequency as u128 / 1_000_000_000) as u64
     }
 }
 
@@ -18,27 +58,232 @@ impl Machine {
 pub struct Cpu<'a> {
     pub machine: &'a Machine,
     pub registers: [u64; 32],
+    pub fregisters: [u64; 32],
+    pub fcsr: u64,
     pub pc: u64,
     pub cycles: u64,
     pub instructions_retired: u64,
+    /// `mtime` as of the current step.
+    pub time: u64,
+    /// Supervisor external interrupt signal from the interrupt controller.
+    pub external_seip: bool,
+    pub reservation: Option<u64>,
+    pub privilege: Privilege,
+    pub csr: ControlRegisters,
+    /// When set, ECALL is handled as a Linux syscall instead of trapping.
+    pub syscalls: Option<LinuxSyscalls>,
+    /// When set, every executed instruction and the state it changed gets logged.
+    pub commit_log: Option<CommitLog>,
+    /// Decoded instructions, skipping fetch and decode for code that ran before.
+    pub decode_cache: Option<DecodeCache>,
+    /// When set, the time each step sees is recorded there or replayed from it.
+    pub journal: Option<SharedJournal>,
+    /// When set, exceptions stop the hart with an error instead of trapping, for programs that install
+    /// no trap handler.
+    pub stop_on_exception: bool,
 }
 
 impl Cpu<'_> {
-    pub fn new(machine: &Machine) -> Cpu {
-        Cpu { registers: [0; 32], pc: 0, cycles: 0, instructions_retired: 0, machine }
+    pub fn new(machine: &Machine) -> Cpu<'_> {
+        Cpu {
+            registers: [0; 32],
+            fregisters: [0; 32],
+            fcsr: 0,
+            pc: 0,
+            cycles: 0,
+            instructions_retired: 0,
+            time: 0,
+            external_seip: false,
+            reservation: None,
+            privilege: Privilege::Machine,
+            csr: ControlRegisters::new(),
+            syscalls: None,
+            commit_log: None,
+            decode_cache: Some(DecodeCache::new()),
+            journal: None,
+            stop_on_exception: false,
+            machine,
+        }
+    }
+
+    /// Executes one instruction or takes one trap. Exceptions the guest doesn't handle come back as
+    /// errors without changing the hart.
+    pub fn step(&mut self, bus: &mut Bus) -> Result<StepOutcome, ExecError> {
+        if let Some(interrupt) = self.take_interrupt(bus) {
+            return Ok(StepOutcome::Trapped(Trap::Interrupt(interrupt)));
+        }
+
+        let result = self.fetch_decoded(bus)
+            .and_then(|decoded| self.execute_with(decoded.handler, &decoded.instruction, bus).map(|()| decoded.instruction));
+        let instruction = match result {
+            Ok(instruction) => instruction,
+            Err(exception) => {
+                if let Some(log) = &mut self.commit_log {
+                    log.exception(self.pc, &exception);
+                }
+                if !self.handles_exceptions() {
+                    return Err(ExecError::new(self.pc, exception));
+                }
+                self.take_trap(Trap::Exception(exception));
+                return Ok(StepOutcome::Trapped(Trap::Exception(exception)));
+            }
+        };
+
+        if let Some(exit_code) = self.exit_code(bus) {
+            return Ok(StepOutcome::Exited(exit_code));
+        }
+        let wfi = instruction.opcode == OPCODE_SYSTEM && instruction.funct3 == F3_ECALL_EBREAK
+            && instruction.funct7 == F7_WFI && instruction.rs2 == RS2_WFI;
+        if wfi && self.csr.mie == 0 {
+            return Ok(StepOutcome::Halted);
+        }
+        Ok(StepOutcome::Retired)
+    }
+
+    /// Ticks the devices and traps to the highest priority interrupt that is pending and enabled, if any.
+    pub fn take_interrupt(&mut self, bus: &mut Bus) -> Option<Interrupt> {
+        let read = || self.machine.time(self.instructions_retired);
+        let (time, has_time) = match &self.journal {
+            Some(journal) => {
+                let mut journal = journal.borrow_mut();
+                (journal.step(read), journal.has_time())
+            }
+            None => (read(), true),
+        };
+        bus.tick(time);
+        let timers = self.csr.mip & TIMER_INTERRUPTS;
+        self.sample_interrupt_lines(&bus.interrupts);
+        if let Some(journal) = &self.journal {
+            if !has_time {
+                // a replay only has the clock where the guest saw it, and the timers didn't change in between
+                self.csr.mip = (self.csr.mip & !TIMER_INTERRUPTS) | timers;
+            } else if self.csr.mip & TIMER_INTERRUPTS != timers {
+                journal.borrow_mut().keep_time();
+            }
+        }
+        let interrupt = self.pending_interrupt()?;
+        self.take_trap(Trap::Interrupt(interrupt));
+        Some(interrupt)
+    }
+
+    /// Set once the guest has asked to exit, through a syscall or a device.
+    pub fn exit_code(&self, bus: &Bus) -> Option<i32> {
+        self.syscalls.as_ref().and_then(|syscalls| syscalls.exit_code).or(bus.exit_code)
+    }
+
+    /// Appends the architectural state to a snapshot, along with the emulated process for user programs.
+    pub fn save(&self, snapshot: &mut Writer) -> Result<()> {
+        for register in self.registers.iter().chain(&self.fregisters) {
+            snapshot.u64(*register);
+        }
+        for value in [self.fcsr, self.pc, self.cycles, self.instructions_retired, self.time] {
+            snapshot.u64(value);
+        }
+        snapshot.bool(self.external_seip);
+        snapshot.bool(self.reservation.is_some());
+        snapshot.u64(self.reservation.unwrap_or_default());
+        snapshot.u8(self.privilege as u8);
+        self.csr.save(snapshot);
+        snapshot.bool(self.syscalls.is_some());
+        match &self.syscalls {
+            Some(syscalls) => syscalls.save(snapshot),
+            None => Ok(()),
+        }
     }
 
-    pub fn step(&mut self, bus: &mut Bus) {
-        let instruction = self.fetch(bus);
-        self.execute(&instruction, bus);
+    /// Reads back what `save` wrote.
+    pub fn restore(&mut self, snapshot: &mut Reader) -> Result<()> {
+        for register in self.registers.iter_mut().chain(&mut self.fregisters) {
+            *register = snapshot.u64()?;
+        }
+        self.registers[0] = 0;
+        for value in [&mut self.fcsr, &mut self.pc, &mut self.cycles, &mut self.instructions_retired, &mut self.time] {
+            *value = snapshot.u64()?;
+        }
+        self.external_seip = snapshot.bool()?;
+        let reserved = snapshot.bool()?;
+        let reservation = snapshot.u64()?;
+        self.reservation = reserved.then_some(reservation);
+        self.privilege = Privilege::from_bits(snapshot.u8()? as u64);
+        self.csr.restore(snapshot)?;
+        match (snapshot.bool()?, &mut self.syscalls) {
+            (true, Some(syscalls)) => syscalls.restore(snapshot),
+            (false, None) => Ok(()),
+            (true, None) => bail!("the snapshot is of a user program, not a machine"),
+            (false, Some(_)) => bail!("the snapshot is of a machine, not a user program"),
+        }
     }
 
-    pub fn fetch(&mut self, bus: &mut Bus) -> Instruction {
-        let data = bus.load32(self.pc) as i32;
-        Instruction::decode(data)
+    /// Steps until the guest exits or halts, or an error comes up. With a `limit`, stops after that
+    /// many steps and returns the outcome of the last one.
+    pub fn run(&mut self, bus: &mut Bus, limit: Option<u64>) -> Result<StepOutcome, ExecError> {
+        let mut outcome = StepOutcome::Retired;
+        let mut steps = 0;
+        while limit.is_none_or(|limit| steps < limit) {
+            outcome = self.step(bus)?;
+            steps += 1;
+            if matches!(outcome, StepOutcome::Exited(_) | StepOutcome::Halted) {
+                break;
+            }
+        }
+        Ok(outcome)
     }
 
-    pub fn execute(&mut self, instruction: &Instruction, bus: &mut Bus) {
+    fn sample_interrupt_lines(&mut self, lines: &InterruptLines) {
+        self.time = lines.mtime;
+        let mut mip = self.csr.mip & !(MIP_MSIP | MIP_MTIP | MIP_MEIP);
+        if lines.msip {
+            mip |= MIP_MSIP;
+        }
+        if lines.mtip {
+            mip |= MIP_MTIP;
+        }
+        if lines.meip {
+            mip |= MIP_MEIP;
+        }
+        self.external_seip = lines.seip;
+        if self.csr.menvcfg & MENVCFG_STCE != 0 {
+            mip &= !MIP_STIP;
+            if self.time >= self.csr.stimecmp {
+                mip |= MIP_STIP;
+            }
+        }
+        self.csr.mip = mip;
+    }
+
+    /// Fetches and decodes the instruction at `pc`, through the decode cache when there is one.
+    fn fetch_decoded(&mut self, bus: &mut Bus) -> Result<Decoded, Exception> {
+        if self.decode_cache.is_some() {
+            let physical_address = self.translate_instruction(bus, self.pc)?;
+            if let Some(decoded) = self.decode_cache.as_mut().and_then(|cache| cache.lookup(bus, physical_address)) {
+                return Ok(decoded);
+            }
+        }
+        let instruction = self.fetch(bus)?;
+        Ok(Decoded { instruction, handler: handler(&instruction) })
+    }
+
+    pub fn fetch(&mut self, bus: &mut Bus) -> Result<Instruction, Exception> {
+        // 32-bit instructions only need 2-byte alignment, so fetch them in two halves
+        let low = self.fetch16(bus, self.pc)?;
+        let data = if low & 3 == 3 { low | (self.fetch16(bus, self.pc + 2)? << 16) } else { low };
+        Ok(Instruction::decode(data as i32))
+    }
+
+    fn fetch16(&mut self, bus: &mut Bus, address: u64) -> Result<u64, Exception> {
+        let physical_address = self.translate(bus, address, AccessType::Instruction)?;
+        bus.load16(physical_address).map_err(|_| Exception::InstructionAccessFault(address))
+    }
+
+    fn execute_with(&mut self, handler: Handler, instruction: &Instruction, bus: &mut Bus) -> Result<(), Exception> {
+        if let Some(log) = &mut self.commit_log {
+            log.begin(self.pc, instruction, self.privilege);
+        }
+        handler(self, instruction, bus)
+    }
+
+    /// Executes any instruction, for the ones without a handler of their own.
+    fn execute_generic(&mut self, instruction: &Instruction, bus: &mut Bus) -> Result<(), Exception> {
         let pc = self.pc;
         let next_instruction_address = self.pc + instruction.size;
         let mut new_pc = next_instruction_address;
@@ -58,17 +303,17 @@ impl Cpu<'_> {
             (OPCODE_OP_IMM, F3_OR, _) => write_rd(instruction.immediate_i_unsigned() | rs1_value),
             (OPCODE_OP_IMM, F3_XOR, _) => write_rd(instruction.immediate_i_unsigned() ^ rs1_value),
             (OPCODE_OP_IMM, F3_SLL, _) => write_rd(rs1_value << instruction.shamt),
-            (OPCODE_OP_IMM, F3_SRL, F7_SRL) => write_rd(rs1_value >> instruction.shamt),
-            (OPCODE_OP_IMM, F3_SRA, F7_SRA) => write_rd((rs1_value_signed >> instruction.shamt) as u64),
+            (OPCODE_OP_IMM, F3_SRL, _) if instruction.funct6() == F6_SRL => write_rd(rs1_value >> instruction.shamt),
+            (OPCODE_OP_IMM, F3_SRA, _) if instruction.funct6() == F6_SRA => write_rd((rs1_value_signed >> instruction.shamt) as u64),
 
             (OPCODE_OP_IMM_32, F3_ADD, _) => write_rd((rs1_value as u32).wrapping_add_signed(instruction.immediate_i() as i32) as i32 as u64),
-            (OPCODE_OP_IMM_32, F3_SLL, _) => write_rd(((rs1_value as u32) << instruction.shamt) as u64),
-            (OPCODE_OP_IMM_32, F3_SRL, F7_SRL) => write_rd(((rs1_value as u32) >> instruction.shamtw()) as u64),
-            (OPCODE_OP_IMM_32, F3_SRA, F7_SRA) => write_rd(((rs1_value_signed as i32) >> instruction.shamt) as u64),
+            (OPCODE_OP_IMM_32, F3_SLL, _) => write_rd(((rs1_value as u32) << instruction.shamtw()) as i32 as u64),
+            (OPCODE_OP_IMM_32, F3_SRL, F7_SRL) => write_rd(((rs1_value as u32) >> instruction.shamtw()) as i32 as u64),
+            (OPCODE_OP_IMM_32, F3_SRA, F7_SRA) => write_rd(((rs1_value_signed as i32) >> instruction.shamtw()) as u64),
 
             (OPCODE_LUI, _, _) => write_rd(instruction.immediate_u_unsigned()),
 
-            (OPCODE_AUIPC, _, _) => write_rd(pc + instruction.immediate_u_unsigned()),
+            (OPCODE_AUIPC, _, _) => write_rd(pc.wrapping_add(instruction.immediate_u_unsigned())),
 
             (OPCODE_OP, F3_ADD, F7_ADD) => write_rd(rs1_value.wrapping_add(rs2_value)),
             (OPCODE_OP, F3_SLT, F7_SLT) => write_rd((rs1_value_signed < rs2_value_signed) as u64),
@@ -92,14 +337,14 @@ impl Cpu<'_> {
             // TODO less casts?
             (OPCODE_OP_32, F3_ADD, F7_ADD) => write_rd(rs1_value.wrapping_add(rs2_value) as i32 as u64),
             (OPCODE_OP_32, F3_SUB, F7_SUB) => write_rd((rs1_value as u32).wrapping_sub(rs2_value as u32) as i32 as u64),
-            (OPCODE_OP_32, F3_SLL, F7_SLL) => write_rd(((rs1_value as u32) << ((rs2_value as u32) & 0x1F)) as u64),
-            (OPCODE_OP_32, F3_SRL, F7_SRL) => write_rd(((rs1_value as u32) >> ((rs2_value as u32) & 0x1F)) as u64),
+            (OPCODE_OP_32, F3_SLL, F7_SLL) => write_rd(((rs1_value as u32) << ((rs2_value as u32) & 0x1F)) as i32 as u64),
+            (OPCODE_OP_32, F3_SRL, F7_SRL) => write_rd(((rs1_value as u32) >> ((rs2_value as u32) & 0x1F)) as i32 as u64),
             (OPCODE_OP_32, F3_SRA, F7_SRA) => write_rd(((rs1_value_signed as i32) >> (rs2_value & 0x1F)) as u64),
             (OPCODE_OP_32, F3_MULW, F7_MULDIV) => write_rd((rs1_value as u32).wrapping_mul(rs2_value as u32) as i32 as u64),
-            (OPCODE_OP_32, F3_DIVW, F7_MULDIV) => write_rd(div_signed(rs1_value as i32 as u64, rs2_value as i32 as u64).0 as u32 as u64),
-            (OPCODE_OP_32, F3_REMW, F7_MULDIV) => write_rd(div_signed(rs1_value as i32 as u64, rs2_value as i32 as u64).1 as u32 as u64),
-            (OPCODE_OP_32, F3_DIVUW, F7_MULDIV) => write_rd(div_unsigned(rs1_value as u32 as u64, rs2_value as u32 as u64).0 as u32 as u64),
-            (OPCODE_OP_32, F3_REMUW, F7_MULDIV) => write_rd(div_unsigned(rs1_value as u32 as u64, rs2_value as u32 as u64).1 as u32 as u64),
+            (OPCODE_OP_32, F3_DIVW, F7_MULDIV) => write_rd(div_signed(rs1_value as i32 as u64, rs2_value as i32 as u64).0 as i32 as u64),
+            (OPCODE_OP_32, F3_REMW, F7_MULDIV) => write_rd(div_signed(rs1_value as i32 as u64, rs2_value as i32 as u64).1 as i32 as u64),
+            (OPCODE_OP_32, F3_DIVUW, F7_MULDIV) => write_rd(div_unsigned(rs1_value as u32 as u64, rs2_value as u32 as u64).0 as i32 as u64),
+            (OPCODE_OP_32, F3_REMUW, F7_MULDIV) => write_rd(div_unsigned(rs1_value as u32 as u64, rs2_value as u32 as u64).1 as i32 as u64),
 
             (OPCODE_JAL, _, _) => {
                 write_rd(next_instruction_address);
@@ -118,52 +363,403 @@ impl Cpu<'_> {
             (OPCODE_BRANCH, F3_BLTU, _) => if rs1_value < rs2_value { new_pc = pc.wrapping_add_signed(instruction.immediate_b()) },
             (OPCODE_BRANCH, F3_BGEU, _) => if rs1_value >= rs2_value { new_pc = pc.wrapping_add_signed(instruction.immediate_b()) },
 
-            (OPCODE_LOAD, F3_LB, _) => write_rd(bus.load8(rs1_value.wrapping_add_signed(instruction.immediate_i())) as i8 as u64),
-            (OPCODE_LOAD, F3_LH, _) => write_rd(bus.load16(rs1_value.wrapping_add_signed(instruction.immediate_i())) as i16 as u64),
-            (OPCODE_LOAD, F3_LW, _) => write_rd(bus.load32(rs1_value.wrapping_add_signed(instruction.immediate_i())) as i32 as u64),
-            (OPCODE_LOAD, F3_LD, _) => write_rd(bus.load64(rs1_value.wrapping_add_signed(instruction.immediate_i()))),
-            (OPCODE_LOAD, F3_LBU, _) => write_rd(bus.load8(rs1_value.wrapping_add_signed(instruction.immediate_i()))),
-            (OPCODE_LOAD, F3_LHU, _) => write_rd(bus.load16(rs1_value.wrapping_add_signed(instruction.immediate_i()))),
-            (OPCODE_LOAD, F3_LWU, _) => write_rd(bus.load32(rs1_value.wrapping_add_signed(instruction.immediate_i()))),
+            (OPCODE_LOAD, F3_LB, _) => write_rd(self.load(bus, rs1_value.wrapping_add_signed(instruction.immediate_i()), 1)? as i8 as u64),
+            (OPCODE_LOAD, F3_LH, _) => write_rd(self.load(bus, rs1_value.wrapping_add_signed(instruction.immediate_i()), 2)? as i16 as u64),
+            (OPCODE_LOAD, F3_LW, _) => write_rd(self.load(bus, rs1_value.wrapping_add_signed(instruction.immediate_i()), 4)? as i32 as u64),
+            (OPCODE_LOAD, F3_LD, _) => write_rd(self.load(bus, rs1_value.wrapping_add_signed(instruction.immediate_i()), 8)?),
+            (OPCODE_LOAD, F3_LBU, _) => write_rd(self.load(bus, rs1_value.wrapping_add_signed(instruction.immediate_i()), 1)?),
+            (OPCODE_LOAD, F3_LHU, _) => write_rd(self.load(bus, rs1_value.wrapping_add_signed(instruction.immediate_i()), 2)?),
+            (OPCODE_LOAD, F3_LWU, _) => write_rd(self.load(bus, rs1_value.wrapping_add_signed(instruction.immediate_i()), 4)?),
 
-            (OPCODE_STORE, F3_SB, _) => bus.store8(rs1_value.wrapping_add_signed(instruction.immediate_s()), rs2_value),
-            (OPCODE_STORE, F3_SH, _) => bus.store16(rs1_value.wrapping_add_signed(instruction.immediate_s()), rs2_value),
-            (OPCODE_STORE, F3_SW, _) => bus.store32(rs1_value.wrapping_add_signed(instruction.immediate_s()), rs2_value),
-            (OPCODE_STORE, F3_SD, _) => bus.store64(rs1_value.wrapping_add_signed(instruction.immediate_s()), rs2_value),
+            (OPCODE_STORE, F3_SB, _) => self.store(bus, rs1_value.wrapping_add_signed(instruction.immediate_s()), 1, rs2_value)?,
+            (OPCODE_STORE, F3_SH, _) => self.store(bus, rs1_value.wrapping_add_signed(instruction.immediate_s()), 2, rs2_value)?,
+            (OPCODE_STORE, F3_SW, _) => self.store(bus, rs1_value.wrapping_add_signed(instruction.immediate_s()), 4, rs2_value)?,
+            (OPCODE_STORE, F3_SD, _) => self.store(bus, rs1_value.wrapping_add_signed(instruction.immediate_s()), 8, rs2_value)?,
 
+            (OPCODE_LOAD_FP | OPCODE_STORE_FP | OPCODE_OP_FP | OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD, _, _) if self.csr.fs() == FS_OFF => {
+                return Err(self.undefined_instruction(instruction));
+            }
+
+            (OPCODE_LOAD_FP, F3_FLW, _) => {
+                let value = self.load(bus, rs1_value.wrapping_add_signed(instruction.immediate_i()), 4)?;
+                self.write_float(instruction.rd, SINGLE, value);
+            }
+            (OPCODE_LOAD_FP, F3_FLD, _) => {
+                let value = self.load(bus, rs1_value.wrapping_add_signed(instruction.immediate_i()), 8)?;
+                self.write_float(instruction.rd, DOUBLE, value);
+            }
+
+            (OPCODE_STORE_FP, F3_FSW, _) => self.store(bus, rs1_value.wrapping_add_signed(instruction.immediate_s()), 4, self.fregisters[instruction.rs2 as usize])?,
+            (OPCODE_STORE_FP, F3_FSD, _) => self.store(bus, rs1_value.wrapping_add_signed(instruction.immediate_s()), 8, self.fregisters[instruction.rs2 as usize])?,
+
+            (OPCODE_OP_FP | OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD, _, _) => match self.execute_float(instruction, rs1_value) {
+                Some(FloatResult::Integer(value)) => write_rd(value),
+                Some(FloatResult::Float(format, value)) => self.write_float(instruction.rd, format, value),
+                None => return Err(self.undefined_instruction(instruction)),
+            },
+
+            (OPCODE_MISC_MEM, F3_FENCE_I, _) => bus.flush_code_pages(),
             (OPCODE_MISC_MEM, _, _) => (),
 
+            (OPCODE_AMO, F3_AMO_W | F3_AMO_D, _) => write_rd(self.execute_atomic(instruction, rs1_value, rs2_value, bus)?),
+
             (OPCODE_SYSTEM, F3_CSRRW | F3_CSRRS | F3_CSRRC | F3_CSRRWI | F3_CSRRSI | F3_CSRRCI, _) => {
                 let csr = instruction.csr();
+                let immediate = instruction.rs1 as u64;
+                // CSRRS and CSRRC with x0 or a zero immediate only read the register
+                let write = matches!(instruction.funct3, F3_CSRRW | F3_CSRRWI) || instruction.rs1 != 0;
                 let result = match instruction.funct3 {
-                    F3_CSRRW => self.csr_operation(csr, |_| rs1_value),
-                    F3_CSRRS => self.csr_operation(csr, |old_value| old_value | rs1_value),
-                    F3_CSRRC => self.csr_operation(csr, |old_value| old_value | old_value & (!rs1_value)),
-                    F3_CSRRWI => self.csr_operation(csr, |_| instruction.rs1 as u64),
-                    F3_CSRRSI => self.csr_operation(csr, |old_value| old_value | (instruction.rs1 as u64)),
-                    F3_CSRRCI => self.csr_operation(csr, |old_value| old_value | old_value & (!(instruction.rs1 as u64))),
+                    F3_CSRRW => self.csr_operation(csr, write, |_| rs1_value),
+                    F3_CSRRS => self.csr_operation(csr, write, |old_value| old_value | rs1_value),
+                    F3_CSRRC => self.csr_operation(csr, write, |old_value| old_value & (!rs1_value)),
+                    F3_CSRRWI => self.csr_operation(csr, write, |_| immediate),
+                    F3_CSRRSI => self.csr_operation(csr, write, |old_value| old_value | immediate),
+                    F3_CSRRCI => self.csr_operation(csr, write, |old_value| old_value & (!immediate)),
                     _ => None
                 };
-                if let Some(result) = result {
-                    write_rd(result);
-                } else {
-                    self.undefined_instruction(instruction)
+                match result {
+                    Some(result) => write_rd(result),
+                    None => return Err(self.undefined_instruction(instruction)),
+                }
+            }
+            (OPCODE_SYSTEM, F3_ECALL_EBREAK, 0) => match instruction.rs2 {
+                IMM_ECALL => match self.syscalls.as_mut() {
+                    Some(syscalls) => syscalls.handle(&mut self.registers, bus, self.machine.elapsed(self.instructions_retired)),
+                    None => return Err(self.environment_call()),
+                },
+                IMM_EBREAK => return Err(Exception::Breakpoint(pc)),
+                _ => return Err(self.undefined_instruction(instruction)),
+            },
+            (OPCODE_SYSTEM, F3_ECALL_EBREAK, F7_MRET) if instruction.rs2 == RS2_XRET => {
+                if self.privilege < Privilege::Machine {
+                    return Err(self.undefined_instruction(instruction));
+                }
+                new_pc = self.mret();
+            }
+            (OPCODE_SYSTEM, F3_ECALL_EBREAK, F7_SRET) if instruction.rs2 == RS2_XRET => {
+                if self.privilege < Privilege::Supervisor || (self.privilege == Privilege::Supervisor && self.csr.mstatus & MSTATUS_TSR != 0) {
+                    return Err(self.undefined_instruction(instruction));
+                }
+                new_pc = self.sret();
+            }
+            (OPCODE_SYSTEM, F3_ECALL_EBREAK, F7_WFI) if instruction.rs2 == RS2_WFI => {
+                if self.privilege == Privilege::User || (self.privilege == Privilege::Supervisor && self.csr.mstatus & MSTATUS_TW != 0) {
+                    return Err(self.undefined_instruction(instruction));
+                }
+            }
+            (OPCODE_SYSTEM, F3_ECALL_EBREAK, F7_SFENCE_VMA) if instruction.rd == 0 => {
+                // there is no TLB to flush, page tables are walked on every access
+                if self.privilege == Privilege::User || (self.privilege == Privilege::Supervisor && self.csr.mstatus & MSTATUS_TVM != 0) {
+                    return Err(self.undefined_instruction(instruction));
                 }
             }
-            (OPCODE_SYSTEM, F3_ECALL_EBREAK, 0) => if instruction.rs2 == IMM_ECALL {} else if instruction.rs2 == IMM_EBREAK {} else { self.undefined_instruction(instruction) },
 
-            (_, _, _) => self.undefined_instruction(instruction),
+            (_, _, _) => return Err(self.undefined_instruction(instruction)),
         }
 
-        if let Some(rd) = new_rd_value {
-            self.write_register(instruction.rd, rd);
+        self.retire(instruction, new_rd_value, new_pc);
+        Ok(())
+    }
+
+    /// Finishes an instruction: writes its result to `rd` and moves on to `new_pc`.
+    fn retire(&mut self, instruction: &Instruction, rd_value: Option<u64>, new_pc: u64) {
+        if let Some(value) = rd_value {
+            self.write_register(instruction.rd, value);
+            if let Some(log) = &mut self.commit_log {
+                log.register(instruction.rd, value);
+            }
+        }
+        if let Some(log) = &mut self.commit_log {
+            log.commit();
         }
 
         self.pc = new_pc;
         self.cycles += 1;
         self.instructions_retired += 1;
+    }
 
-        ()
+    fn execute_atomic(&mut self, instruction: &Instruction, virtual_address: u64, rs2_value: u64, bus: &mut Bus) -> Result<u64, Exception> {
+        let word = instruction.funct3 == F3_AMO_W;
+        let size = if word { 4 } else { 8 };
+        let access = if instruction.funct5() == F5_LR { AccessType::Load } else { AccessType::Store };
+        if !virtual_address.is_multiple_of(size) {
+            return Err(match access {
+                AccessType::Load => Exception::LoadAddressMisaligned(virtual_address),
+                _ => Exception::StoreAddressMisaligned(virtual_address),
+            });
+        }
+        let address = self.translate(bus, virtual_address, access)?;
+        let load = |bus: &mut Bus| bus.load(address, size)
+            .map(|value| if word { value as i32 as u64 } else { value })
+            .map_err(|_| access.access_fault(virtual_address));
+        let store = |bus: &mut Bus, value: u64| bus.store(address, size, value)
+            .map_err(|_| Exception::StoreAccessFault(virtual_address));
+
+        match instruction.funct5() {
+            F5_LR => {
+                let value = load(bus)?;
+                self.log_load(virtual_address);
+                self.reservation = Some(address);
+                Ok(value)
+            }
+            F5_SC => {
+                if self.reservation.take() == Some(address) {
+                    store(bus, rs2_value)?;
+                    self.log_store(virtual_address, size, rs2_value);
+                    Ok(0)
+                } else {
+                    Ok(1)
+                }
+            }
+            funct5 => {
+                let old_value = load(bus)?;
+                // word operands are sign-extended, which keeps both signed and unsigned ordering intact
+                let operand = if word { rs2_value as i32 as u64 } else { rs2_value };
+                let new_value = match funct5 {
+                    F5_AMOSWAP => operand,
+                    F5_AMOADD => old_value.wrapping_add(operand),
+                    F5_AMOXOR => old_value ^ operand,
+                    F5_AMOAND => old_value & operand,
+                    F5_AMOOR => old_value | operand,
+                    F5_AMOMIN => (old_value as i64).min(operand as i64) as u64,
+                    F5_AMOMAX => (old_value as i64).max(operand as i64) as u64,
+                    F5_AMOMINU => old_value.min(operand),
+                    F5_AMOMAXU => old_value.max(operand),
+                    _ => return Err(self.undefined_instruction(instruction)),
+                };
+                store(bus, new_value)?;
+                self.log_load(virtual_address);
+                self.log_store(virtual_address, size, new_value);
+                Ok(old_value)
+            }
+        }
+    }
+
+    fn execute_float(&mut self, instruction: &Instruction, rs1_value: u64) -> Option<FloatResult> {
+        let format = match instruction.fmt() {
+            FMT_S => SINGLE,
+            FMT_D => DOUBLE,
+            _ => return None,
+        };
+        let a = self.read_float(instruction.rs1, format);
+        let b = self.read_float(instruction.rs2, format);
+        let rounding_mode = RoundingMode::from_bits(if instruction.funct3 == F3_RM_DYNAMIC { self.fcsr >> 5 } else { instruction.funct3 as u64 });
+        let mut ctx = FloatContext::new(rounding_mode.unwrap_or(RoundingMode::NearestEven));
+        let float = |value: u64| Some(FloatResult::Float(format, value));
+        let integer = |value: u64| Some(FloatResult::Integer(value));
+
+        if instruction.opcode != OPCODE_OP_FP {
+            rounding_mode?;
+            let c = self.read_float(instruction.rs3(), format);
+            let sign = format.sign_bit();
+            let (a, c) = match instruction.opcode {
+                OPCODE_MADD => (a, c),
+                OPCODE_MSUB => (a, c ^ sign),
+                OPCODE_NMSUB => (a ^ sign, c),
+                _ => (a ^ sign, c ^ sign),
+            };
+            let result = ctx.fused_multiply_add(format, a, b, c);
+            self.accrue_float_flags(ctx.flags);
+            return float(result);
+        }
+
+        let result = match (instruction.funct5(), instruction.funct3, instruction.rs2) {
+            (F5_FADD, _, _) => float(ctx.add(format, a, b)),
+            (F5_FSUB, _, _) => float(ctx.sub(format, a, b)),
+            (F5_FMUL, _, _) => float(ctx.mul(format, a, b)),
+            (F5_FDIV, _, _) => float(ctx.div(format, a, b)),
+            (F5_FSQRT, _, 0) => float(ctx.sqrt(format, a)),
+            (F5_FSGNJ, F3_FSGNJ, _) => return float((a & !format.sign_bit()) | (b & format.sign_bit())),
+            (F5_FSGNJ, F3_FSGNJN, _) => return float((a & !format.sign_bit()) | (!b & format.sign_bit())),
+            (F5_FSGNJ, F3_FSGNJX, _) => return float(a ^ (b & format.sign_bit())),
+            (F5_FMINMAX, F3_FMIN, _) => float(ctx.min_max(format, a, b, false)),
+            (F5_FMINMAX, F3_FMAX, _) => float(ctx.min_max(format, a, b, true)),
+            (F5_FCVT_FMT_FMT, _, source @ (FMT_S | FMT_D)) if source != instruction.fmt() => {
+                let source = if source == FMT_S { SINGLE } else { DOUBLE };
+                float(ctx.convert(source, format, self.read_float(instruction.rs1, source)))
+            }
+            (F5_FCMP, F3_FEQ, _) => integer((ctx.compare(format, a, b, false) == Some(Ordering::Equal)) as u64),
+            (F5_FCMP, F3_FLT, _) => integer((ctx.compare(format, a, b, true) == Some(Ordering::Less)) as u64),
+            (F5_FCMP, F3_FLE, _) => integer(matches!(ctx.compare(format, a, b, true), Some(Ordering::Less | Ordering::Equal)) as u64),
+            (F5_FCVT_INT_FMT, _, RS2_FCVT_W) => integer(ctx.float_to_int(format, a, true, 32)),
+            (F5_FCVT_INT_FMT, _, RS2_FCVT_WU) => integer(ctx.float_to_int(format, a, false, 32)),
+            (F5_FCVT_INT_FMT, _, RS2_FCVT_L) => integer(ctx.float_to_int(format, a, true, 64)),
+            (F5_FCVT_INT_FMT, _, RS2_FCVT_LU) => integer(ctx.float_to_int(format, a, false, 64)),
+            (F5_FCVT_FMT_INT, _, RS2_FCVT_W) => float(ctx.int_to_float(format, rs1_value, true, 32)),
+            (F5_FCVT_FMT_INT, _, RS2_FCVT_WU) => float(ctx.int_to_float(format, rs1_value, false, 32)),
+            (F5_FCVT_FMT_INT, _, RS2_FCVT_L) => float(ctx.int_to_float(format, rs1_value, true, 64)),
+            (F5_FCVT_FMT_INT, _, RS2_FCVT_LU) => float(ctx.int_to_float(format, rs1_value, false, 64)),
+            (F5_FMV_X_FCLASS, F3_FMV_X, 0) if format == SINGLE => return integer(self.fregisters[instruction.rs1 as usize] as i32 as u64),
+            (F5_FMV_X_FCLASS, F3_FMV_X, 0) => return integer(self.fregisters[instruction.rs1 as usize]),
+            (F5_FMV_X_FCLASS, F3_FCLASS, 0) => return integer(float::classify(format, a)),
+            (F5_FMV_FMT_X, 0, 0) => return float(rs1_value),
+            _ => return None,
+        };
+
+        let uses_rounding_mode = matches!(instruction.funct5(), F5_FADD | F5_FSUB | F5_FMUL | F5_FDIV | F5_FSQRT | F5_FCVT_FMT_FMT | F5_FCVT_INT_FMT | F5_FCVT_FMT_INT);
+        if uses_rounding_mode {
+            rounding_mode?;
+        }
+        self.accrue_float_flags(ctx.flags);
+        result
+    }
+
+    fn accrue_float_flags(&mut self, flags: u64) {
+        if flags != 0 {
+            self.csr.set_fs(FS_DIRTY);
+            self.fcsr |= flags;
+            if let Some(log) = &mut self.commit_log {
+                log.control_register(CSR_FFLAGS, self.fcsr & 0x1F);
+            }
+        }
+    }
+
+    fn read_float(&self, index: i32, format: Format) -> u64 {
+        let value = self.fregisters[index as usize];
+        if format == SINGLE {
+            // single precision values must be NaN-boxed, anything else reads as the canonical NaN
+            if value >> 32 == 0xFFFF_FFFF { value & 0xFFFF_FFFF } else { SINGLE.canonical_nan() }
+        } else {
+            value
+        }
+    }
+
+    fn write_float(&mut self, index: i32, format: Format, value: u64) {
+        self.csr.set_fs(FS_DIRTY);
+        self.fregisters[index as usize] = if format == SINGLE { value | 0xFFFF_FFFF_0000_0000 } else { value };
+        if let Some(log) = &mut self.commit_log {
+            log.float_register(index, self.fregisters[index as usize]);
+        }
+    }
+
+    pub fn load(&mut self, bus: &mut Bus, address: u64, size: u64) -> Result<u64, Exception> {
+        let value = if crosses_page(address, size) {
+            let mut value = 0;
+            for i in 0..size {
+                value |= self.load_within_page(bus, address.wrapping_add(i), 1)? << (8 * i);
+            }
+            value
+        } else {
+            self.load_within_page(bus, address, size)?
+        };
+        self.log_load(address);
+        Ok(value)
+    }
+
+    fn load_within_page(&mut self, bus: &mut Bus, address: u64, size: u64) -> Result<u64, Exception> {
+        let physical_address = self.translate(bus, address, AccessType::Load)?;
+        let value = bus.load(physical_address, size).map_err(|_| Exception::LoadAccessFault(address))?;
+        bus.watch_load(physical_address, size);
+        Ok(value)
+    }
+
+    pub fn store(&mut self, bus: &mut Bus, address: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if crosses_page(address, size) {
+            // translate every byte first so a fault leaves memory untouched
+            let mut physical_addresses = [0; 8];
+            for i in 0..size {
+                physical_addresses[i as usize] = self.translate(bus, address.wrapping_add(i), AccessType::Store)?;
+            }
+            for i in 0..size {
+                bus.store8(physical_addresses[i as usize], value >> (8 * i))
+                    .map_err(|_| Exception::StoreAccessFault(address.wrapping_add(i)))?;
+            }
+        } else {
+            let physical_address = self.translate(bus, address, AccessType::Store)?;
+            bus.store(physical_address, size, value).map_err(|_| Exception::StoreAccessFault(address))?;
+        }
+        self.log_store(address, size, value);
+        Ok(())
+    }
+
+    fn log_load(&mut self, address: u64) {
+        if let Some(log) = &mut self.commit_log {
+            log.load(address);
+        }
+    }
+
+    fn log_store(&mut self, address: u64, size: u64, value: u64) {
+        if let Some(log) = &mut self.commit_log {
+            log.store(address, size, value);
+        }
+    }
+
+    fn translate(&mut self, bus: &mut Bus, address: u64, access: AccessType) -> Result<u64, Exception> {
+        let mstatus = self.csr.mstatus;
+        let privilege = if access != AccessType::Instruction && mstatus & MSTATUS_MPRV != 0 {
+            Privilege::from_bits(mstatus >> MSTATUS_MPP_SHIFT)
+        } else {
+            self.privilege
+        };
+        let context = TranslationContext {
+            satp: self.csr.satp,
+            privilege,
+            supervisor_user_memory: mstatus & MSTATUS_SUM != 0,
+            make_executable_readable: mstatus & MSTATUS_MXR != 0,
+        };
+        mmu::translate(bus, address, access, &context)
+    }
+
+    /// Copies memory the way loads at the current privilege see it, for debuggers.
+    pub fn read_memory(&mut self, bus: &mut Bus, address: u64, bytes: &mut [u8]) -> Result<(), Exception> {
+        let mut done = 0;
+        while done < bytes.len() {
+            let virtual_address = address.wrapping_add(done as u64);
+            let length = (PAGE_SIZE - virtual_address % PAGE_SIZE).min((bytes.len() - done) as u64) as usize;
+            let physical_address = self.translate(bus, virtual_address, AccessType::Load)?;
+            bus.load_bytes(physical_address, &mut bytes[done..done + length])
+                .map_err(|_| Exception::LoadAccessFault(virtual_address))?;
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// Writes memory for debuggers, which may also patch read-only pages. Doesn't trigger watchpoints.
+    pub fn write_memory(&mut self, bus: &mut Bus, address: u64, bytes: &[u8]) -> Result<(), Exception> {
+        let watch_hit = bus.watch_hit;
+        let mut done = 0;
+        while done < bytes.len() {
+            let virtual_address = address.wrapping_add(done as u64);
+            let length = (PAGE_SIZE - virtual_address % PAGE_SIZE).min((bytes.len() - done) as u64) as usize;
+            let physical_address = self.translate(bus, virtual_address, AccessType::Load)?;
+            bus.store_bytes(physical_address, &bytes[done..done + length])
+                .map_err(|_| Exception::StoreAccessFault(virtual_address))?;
+            done += length;
+        }
+        bus.watch_hit = watch_hit;
+        Ok(())
+    }
+
+    /// Reads a CSR the way machine mode sees it, for debuggers.
+    pub fn debug_read_csr(&mut self, id: u64) -> Option<u64> {
+        let privilege = std::mem::replace(&mut self.privilege, Privilege::Machine);
+        let value = self.read_control_register(id);
+        self.privilege = privilege;
+        value
+    }
+
+    /// Writes a CSR the way machine mode would, for debuggers. Returns false for read-only or missing CSRs.
+    pub fn debug_write_csr(&mut self, id: u64, value: u64) -> bool {
+        let privilege = std::mem::replace(&mut self.privilege, Privilege::Machine);
+        let read_only = (id >> 10) & 3 == 3;
+        let written = !read_only && self.write_control_register(id, value);
+        self.privilege = privilege;
+        if written && matches!(id, CSR_MCYCLE | CSR_MINSTRET) {
+            // no instruction retires after this write, so undo the compensation for one
+            self.cycles = self.cycles.wrapping_add((id == CSR_MCYCLE) as u64);
+            self.instructions_retired = self.instructions_retired.wrapping_add((id == CSR_MINSTRET) as u64);
+        }
+        written
+    }
+
+    /// The physical address an instruction fetch from `address` would access.
+    pub fn translate_instruction(&mut self, bus: &mut Bus, address: u64) -> Result<u64, Exception> {
+        self.translate(bus, address, AccessType::Instruction)
+    }
+
+    /// The physical address a load from `address` would access.
+    pub fn translate_data(&mut self, bus: &mut Bus, address: u64) -> Result<u64, Exception> {
+        self.translate(bus, address, AccessType::Load)
     }
 
     fn read_register(&self, index: i32) -> u64 {
@@ -178,36 +774,458 @@ impl Cpu<'_> {
 
     fn read_control_register(&self, id: u64) -> Option<u64> {
         match id {
+            CSR_FFLAGS | CSR_FRM | CSR_FCSR if self.csr.fs() == FS_OFF => None,
+            CSR_FFLAGS => Some(self.fcsr & 0x1F),
+            CSR_FRM => Some((self.fcsr >> 5) & 0x7),
+            CSR_FCSR => Some(self.fcsr & 0xFF),
+            CSR_CYCLE | CSR_TIME | CSR_INSTRET | CSR_HPMCOUNTER3..=CSR_HPMCOUNTER31 if !self.counter_enabled(id) => None,
             CSR_CYCLE => Some(self.cycles),
             CSR_INSTRET => Some(self.instructions_retired),
-            CSR_TIME => Some(self.machine.start.elapsed().as_millis() as u64),
+            CSR_TIME => Some(self.time),
+            CSR_HPMCOUNTER3..=CSR_HPMCOUNTER31 => Some(0),
+
+            CSR_SSTATUS => Some(self.read_mstatus() & SSTATUS_VISIBLE),
+            CSR_SIE => Some(self.csr.mie & self.csr.mideleg),
+            CSR_SIP => Some(self.mip() & self.csr.mideleg),
+            CSR_STVEC => Some(self.csr.stvec),
+            CSR_SCOUNTEREN => Some(self.csr.scounteren),
+            CSR_SSCRATCH => Some(self.csr.sscratch),
+            CSR_SEPC => Some(self.csr.sepc),
+            CSR_SCAUSE => Some(self.csr.scause),
+            CSR_STVAL => Some(self.csr.stval),
+            CSR_STIMECMP if !self.stimecmp_accessible() => None,
+            CSR_STIMECMP => Some(self.csr.stimecmp),
+            CSR_SATP if self.satp_trapped() => None,
+            CSR_SATP => Some(self.csr.satp),
+
+            CSR_MSTATUS => Some(self.read_mstatus()),
+            CSR_MISA => Some(MISA),
+            CSR_MEDELEG => Some(self.csr.medeleg),
+            CSR_MIDELEG => Some(self.csr.mideleg),
+            CSR_MIE => Some(self.csr.mie),
+            CSR_MIP => Some(self.mip()),
+            CSR_MTVEC => Some(self.csr.mtvec),
+            CSR_MCOUNTEREN => Some(self.csr.mcounteren),
+            CSR_MENVCFG => Some(self.csr.menvcfg),
+            CSR_MSCRATCH => Some(self.csr.mscratch),
+            CSR_MEPC => Some(self.csr.mepc),
+            CSR_MCAUSE => Some(self.csr.mcause),
+            CSR_MTVAL => Some(self.csr.mtval),
+            CSR_MCYCLE => Some(self.cycles),
+            CSR_MINSTRET => Some(self.instructions_retired),
+            CSR_MVENDORID | CSR_MARCHID | CSR_MIMPID | CSR_MHARTID | CSR_MCONFIGPTR => Some(0),
+
+            // hardwired to zero: no PMP entries, performance counters or debug triggers
+            CSR_PMPCFG0..=CSR_PMPCFG15 | CSR_PMPADDR0..=CSR_PMPADDR63 => Some(0),
+            CSR_MHPMCOUNTER3..=CSR_MHPMCOUNTER31 | CSR_MHPMEVENT3..=CSR_MHPMEVENT31 | CSR_MCOUNTINHIBIT => Some(0),
+            CSR_TSELECT..=CSR_TDATA3 => Some(0),
             _ => None
         }
     }
 
-    fn write_control_register(&self, _id: u64, _value: u64) -> bool { false }
+    fn write_control_register(&mut self, id: u64, value: u64) -> bool {
+        match id {
+            CSR_FFLAGS | CSR_FRM | CSR_FCSR => {
+                self.csr.set_fs(FS_DIRTY);
+                match id {
+                    CSR_FFLAGS => self.fcsr = (self.fcsr & !0x1F) | (value & 0x1F),
+                    CSR_FRM => self.fcsr = (self.fcsr & 0x1F) | ((value & 0x7) << 5),
+                    _ => self.fcsr = value & 0xFF,
+                }
+            }
+
+            CSR_SSTATUS => self.csr.mstatus = (self.csr.mstatus & !SSTATUS_WRITABLE) | (value & SSTATUS_WRITABLE),
+            CSR_SIE => self.csr.mie = (self.csr.mie & !self.csr.mideleg) | (value & self.csr.mideleg),
+            CSR_SIP => {
+                let writable = MIP_SSIP & self.csr.mideleg;
+                self.csr.mip = (self.csr.mip & !writable) | (value & writable);
+            }
+            CSR_STVEC => self.csr.stvec = value & !0b10,
+            CSR_SCOUNTEREN => self.csr.scounteren = value & 0xFFFF_FFFF,
+            CSR_SSCRATCH => self.csr.sscratch = value,
+            CSR_SEPC => self.csr.sepc = value & !1,
+            CSR_SCAUSE => self.csr.scause = value,
+            CSR_STVAL => self.csr.stval = value,
+            CSR_STIMECMP if !self.stimecmp_accessible() => return false,
+            CSR_STIMECMP => self.csr.stimecmp = value,
+            CSR_SATP if self.satp_trapped() => return false,
+            CSR_SATP => {
+                // writes selecting an unsupported translation mode are ignored
+                if matches!(value >> SATP_MODE_SHIFT, SATP_MODE_BARE | SATP_MODE_SV39 | SATP_MODE_SV48) {
+                    self.csr.satp = value & ((0xF << SATP_MODE_SHIFT) | (0xFFFF << 44) | SATP_PPN_MASK);
+                }
+            }
+
+            CSR_MSTATUS => {
+                let mpp = Privilege::from_bits(value >> MSTATUS_MPP_SHIFT) as u64;
+                let value = (value & !MSTATUS_MPP) | (mpp << MSTATUS_MPP_SHIFT);
+                self.csr.mstatus = (self.csr.mstatus & !MSTATUS_WRITABLE) | (value & MSTATUS_WRITABLE);
+            }
+            CSR_MISA => (),
+            CSR_MEDELEG => self.csr.medeleg = value & DELEGABLE_EXCEPTIONS,
+            CSR_MIDELEG => self.csr.mideleg = value & DELEGABLE_INTERRUPTS,
+            CSR_MIE => self.csr.mie = value & SUPPORTED_INTERRUPTS,
+            CSR_MIP => {
+                // with Sstc the supervisor timer interrupt follows stimecmp instead
+                let writable = if self.csr.menvcfg & MENVCFG_STCE != 0 { MIP_WRITABLE & !MIP_STIP } else { MIP_WRITABLE };
+                self.csr.mip = (self.csr.mip & !writable) | (value & writable);
+            }
+            CSR_MTVEC => self.csr.mtvec = value & !0b10,
+            CSR_MCOUNTEREN => self.csr.mcounteren = value & 0xFFFF_FFFF,
+            CSR_MENVCFG => self.csr.menvcfg = value & MENVCFG_STCE,
+            CSR_MSCRATCH => self.csr.mscratch = value,
+            CSR_MEPC => self.csr.mepc = value & !1,
+            CSR_MCAUSE => self.csr.mcause = value,
+            CSR_MTVAL => self.csr.mtval = value,
+            // the writing instruction itself still retires, so compensate for its increment
+            CSR_MCYCLE => self.cycles = value.wrapping_sub(1),
+            CSR_MINSTRET => self.instructions_retired = value.wrapping_sub(1),
+
+            CSR_PMPCFG0..=CSR_PMPCFG15 | CSR_PMPADDR0..=CSR_PMPADDR63 => (),
+            CSR_MHPMCOUNTER3..=CSR_MHPMCOUNTER31 | CSR_MHPMEVENT3..=CSR_MHPMEVENT31 | CSR_MCOUNTINHIBIT => (),
+            CSR_TSELECT..=CSR_TDATA3 => (),
+            _ => return false,
+        }
+        true
+    }
+
+    fn csr_operation(&mut self, id: u64, write: bool, operation: impl FnOnce(u64) -> u64) -> Option<u64> {
+        let read_only = (id >> 10) & 3 == 3;
+        let minimum_privilege = (id >> 8) & 3;
+        if (self.privilege as u64) < minimum_privilege || (write && read_only) {
+            return None;
+        }
 
-    fn csr_operation(&mut self, id: u64, operation: impl FnOnce(u64) -> u64) -> Option<u64> {
         let old_value = self.read_control_register(id)?;
-        self.write_control_register(id, operation(old_value));
+        if let (CSR_TIME, Some(journal)) = (id, &self.journal) {
+            journal.borrow_mut().keep_time();
+        }
+        if write {
+            if !self.write_control_register(id, operation(old_value)) {
+                return None;
+            }
+            if let (Some(value), Some(log)) = (self.read_control_register(id), &mut self.commit_log) {
+                log.control_register(id, value);
+            }
+        }
         Some(old_value)
     }
 
-    fn undefined_instruction(&self, instruction: &Instruction) { unimplemented!("Unimplemented instruction: ({}, {}, {})", instruction.opcode, instruction.funct3, instruction.funct7) }
+    fn read_mstatus(&self) -> u64 {
+        let dirty = self.csr.fs() == FS_DIRTY;
+        self.csr.mstatus | if dirty { MSTATUS_SD } else { 0 }
+    }
+
+    fn satp_trapped(&self) -> bool {
+        self.privilege == Privilege::Supervisor && self.csr.mstatus & MSTATUS_TVM != 0
+    }
+
+    /// `mip` as software reads it: the writable SEIP bit is ORed with the interrupt controller's signal.
+    fn mip(&self) -> u64 {
+        self.csr.mip | if self.external_seip { MIP_SEIP } else { 0 }
+    }
+
+    fn stimecmp_accessible(&self) -> bool {
+        self.privilege == Privilege::Machine
+            || (self.csr.menvcfg & MENVCFG_STCE != 0 && self.csr.mcounteren & COUNTEREN_TM != 0)
+    }
+
+    fn counter_enabled(&self, id: u64) -> bool {
+        let bit = id - CSR_CYCLE;
+        let machine_allows = (self.csr.mcounteren >> bit) & 1 != 0;
+        let supervisor_allows = (self.csr.scounteren >> bit) & 1 != 0;
+        match self.privilege {
+            Privilege::Machine => true,
+            Privilege::Supervisor => machine_allows,
+            Privilege::User => machine_allows && supervisor_allows,
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.mip() & self.csr.mie;
+        let machine_enabled = self.privilege < Privilege::Machine || self.csr.mstatus & MSTATUS_MIE != 0;
+        let supervisor_enabled = self.privilege < Privilege::Supervisor
+            || (self.privilege == Privilege::Supervisor && self.csr.mstatus & MSTATUS_SIE != 0);
+
+        // interrupts handled in machine mode always win over delegated ones
+        let machine_interrupts = if machine_enabled { pending & !self.csr.mideleg } else { 0 };
+        let supervisor_interrupts = if supervisor_enabled { pending & self.csr.mideleg } else { 0 };
+        [machine_interrupts, supervisor_interrupts].into_iter()
+            .find_map(|enabled| Interrupt::PRIORITY.into_iter().find(|interrupt| enabled & interrupt.mask() != 0))
+    }
+
+    /// Whether exceptions trap into the guest. User programs with emulated syscalls have no kernel to
+    /// handle anything, and the embedder can say the program has no handlers with `stop_on_exception`.
+    fn handles_exceptions(&self) -> bool {
+        self.syscalls.is_none() && !self.stop_on_exception
+    }
+
+    fn take_trap(&mut self, trap: Trap) {
+        let (code, delegated) = match trap {
+            Trap::Exception(exception) => (exception.cause(), self.csr.medeleg),
+            Trap::Interrupt(interrupt) => (interrupt.cause(), self.csr.mideleg),
+        };
+        let value = match trap {
+            Trap::Exception(exception) => exception.value(),
+            Trap::Interrupt(_) => 0,
+        };
+
+        if self.privilege <= Privilege::Supervisor && (delegated >> code) & 1 != 0 {
+            let sie = (self.csr.mstatus & MSTATUS_SIE) != 0;
+            let mut mstatus = self.csr.mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+            if sie {
+                mstatus |= MSTATUS_SPIE;
+            }
+            if self.privilege == Privilege::Supervisor {
+                mstatus |= MSTATUS_SPP;
+            }
+            self.csr.mstatus = mstatus;
+
+            self.csr.sepc = self.pc;
+            self.csr.scause = trap.cause();
+            self.csr.stval = value;
+            self.privilege = Privilege::Supervisor;
+            self.pc = trap_vector(self.csr.stvec, trap);
+        } else {
+            let mie = (self.csr.mstatus & MSTATUS_MIE) != 0;
+            let mut mstatus = self.csr.mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+            if mie {
+                mstatus |= MSTATUS_MPIE;
+            }
+            mstatus |= (self.privilege as u64) << MSTATUS_MPP_SHIFT;
+            self.csr.mstatus = mstatus;
+
+            self.csr.mepc = self.pc;
+            self.csr.mcause = trap.cause();
+            self.csr.mtval = value;
+            self.privilege = Privilege::Machine;
+            self.pc = trap_vector(self.csr.mtvec, trap);
+        }
+    }
+
+    fn mret(&mut self) -> u64 {
+        let mpp = Privilege::from_bits(self.csr.mstatus >> MSTATUS_MPP_SHIFT);
+        let mpie = self.csr.mstatus & MSTATUS_MPIE != 0;
+        let mut mstatus = self.csr.mstatus & !(MSTATUS_MIE | MSTATUS_MPP);
+        mstatus |= MSTATUS_MPIE | ((Privilege::User as u64) << MSTATUS_MPP_SHIFT);
+        if mpie {
+            mstatus |= MSTATUS_MIE;
+        }
+        if mpp != Privilege::Machine {
+            mstatus &= !MSTATUS_MPRV;
+        }
+        self.csr.mstatus = mstatus;
+        self.privilege = mpp;
+        self.csr.mepc
+    }
+
+    fn sret(&mut self) -> u64 {
+        let spp = if self.csr.mstatus & MSTATUS_SPP != 0 { Privilege::Supervisor } else { Privilege::User };
+        let spie = self.csr.mstatus & MSTATUS_SPIE != 0;
+        let mut mstatus = self.csr.mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV);
+        mstatus |= MSTATUS_SPIE;
+        if spie {
+            mstatus |= MSTATUS_SIE;
+        }
+        self.csr.mstatus = mstatus;
+        self.privilege = spp;
+        self.csr.sepc
+    }
+
+    fn environment_call(&self) -> Exception {
+        match self.privilege {
+            Privilege::User => Exception::EnvironmentCallFromUMode,
+            Privilege::Supervisor => Exception::EnvironmentCallFromSMode,
+            Privilege::Machine => Exception::EnvironmentCallFromMMode,
+        }
+    }
+
+    fn undefined_instruction(&self, instruction: &Instruction) -> Exception {
+        Exception::IllegalInstruction(instruction.encoding as u32 as u64)
+    }
+}
+
+/// Executes one decoded instruction, leaving the hart as it was if it raises an exception.
+pub type Handler = fn(&mut Cpu, &Instruction, &mut Bus) -> Result<(), Exception>;
+
+/// Picks how to execute an instruction once, when it is decoded. The instructions that make up most
+/// of typical workloads get handlers of their own, everything else goes through the full decoder.
+pub fn handler(instruction: &Instruction) -> Handler {
+    let handler: Handler = match (instruction.opcode, instruction.funct3, instruction.funct7) {
+        (OPCODE_OP_IMM, F3_ADD, _) => addi,
+        (OPCODE_OP_IMM, F3_AND, _) => andi,
+        (OPCODE_OP_IMM, F3_SLL, _) => slli,
+        (OPCODE_OP_IMM, F3_SRL, _) if instruction.funct6() == F6_SRL => srli,
+        (OPCODE_OP_IMM_32, F3_ADD, _) => addiw,
+        (OPCODE_OP, F3_ADD, F7_ADD) => add,
+        (OPCODE_OP, F3_SUB, F7_SUB) => sub,
+        (OPCODE_LUI, _, _) => lui,
+        (OPCODE_AUIPC, _, _) => auipc,
+        (OPCODE_JAL, _, _) => jal,
+        (OPCODE_JALR, _, _) => jalr,
+        (OPCODE_BRANCH, F3_BEQ, _) => beq,
+        (OPCODE_BRANCH, F3_BNE, _) => bne,
+        (OPCODE_BRANCH, F3_BLT, _) => blt,
+        (OPCODE_BRANCH, F3_BGE, _) => bge,
+        (OPCODE_BRANCH, F3_BLTU, _) => bltu,
+        (OPCODE_BRANCH, F3_BGEU, _) => bgeu,
+        (OPCODE_LOAD, F3_LB, _) => lb,
+        (OPCODE_LOAD, F3_LW, _) => lw,
+        (OPCODE_LOAD, F3_LD, _) => ld,
+        (OPCODE_LOAD, F3_LBU, _) => lbu,
+        (OPCODE_LOAD, F3_LWU, _) => lwu,
+        (OPCODE_STORE, F3_SB, _) => sb,
+        (OPCODE_STORE, F3_SW, _) => sw,
+        (OPCODE_STORE, F3_SD, _) => sd,
+        _ => |cpu, instruction, bus| cpu.execute_generic(instruction, bus),
+    };
+    handler
+}
+
+// These have to behave exactly like their cases in `Cpu::execute_generic`.
+macro_rules! immediate_handlers {
+    ($($name:ident: |$instruction:ident, $value:ident| $result:expr;)*) => {$(
+        fn $name(cpu: &mut Cpu, $instruction: &Instruction, _: &mut Bus) -> Result<(), Exception> {
+            let $value = cpu.read_register($instruction.rs1);
+            cpu.retire($instruction, Some($result), cpu.pc + $instruction.size);
+            Ok(())
+        }
+    )*};
+}
+
+macro_rules! register_handlers {
+    ($($name:ident: |$a:ident, $b:ident| $result:expr;)*) => {$(
+        fn $name(cpu: &mut Cpu, instruction: &Instruction, _: &mut Bus) -> Result<(), Exception> {
+            let $a = cpu.read_register(instruction.rs1);
+            let $b = cpu.read_register(instruction.rs2);
+            cpu.retire(instruction, Some($result), cpu.pc + instruction.size);
+            Ok(())
+        }
+    )*};
+}
+
+macro_rules! branch_handlers {
+    ($($name:ident: |$a:ident, $b:ident| $taken:expr;)*) => {$(
+        fn $name(cpu: &mut Cpu, instruction: &Instruction, _: &mut Bus) -> Result<(), Exception> {
+            let $a = cpu.read_register(instruction.rs1);
+            let $b = cpu.read_register(instruction.rs2);
+            let new_pc = if $taken { cpu.pc.wrapping_add_signed(instruction.immediate_b()) } else { cpu.pc + instruction.size };
+            cpu.retire(instruction, None, new_pc);
+            Ok(())
+        }
+    )*};
+}
+
+macro_rules! load_handlers {
+    ($($name:ident: $size:literal, |$value:ident| $result:expr;)*) => {$(
+        fn $name(cpu: &mut Cpu, instruction: &Instruction, bus: &mut Bus) -> Result<(), Exception> {
+            let address = cpu.read_register(instruction.rs1).wrapping_add_signed(instruction.immediate_i());
+            let $value = cpu.load(bus, address, $size)?;
+            cpu.retire(instruction, Some($result), cpu.pc + instruction.size);
+            Ok(())
+        }
+    )*};
+}
+
+macro_rules! store_handlers {
+    ($($name:ident: $size:literal;)*) => {$(
+        fn $name(cpu: &mut Cpu, instruction: &Instruction, bus: &mut Bus) -> Result<(), Exception> {
+            let address = cpu.read_register(instruction.rs1).wrapping_add_signed(instruction.immediate_s());
+            cpu.store(bus, address, $size, cpu.read_register(instruction.rs2))?;
+            cpu.retire(instruction, None, cpu.pc + instruction.size);
+            Ok(())
+        }
+    )*};
+}
+
+immediate_handlers! {
+    addi: |instruction, a| a.wrapping_add_signed(instruction.immediate_i());
+    andi: |instruction, a| a & instruction.immediate_i_unsigned();
+    slli: |instruction, a| a << instruction.shamt;
+    srli: |instruction, a| a >> instruction.shamt;
+    addiw: |instruction, a| (a as u32).wrapping_add_signed(instruction.immediate_i() as i32) as i32 as u64;
+}
+
+register_handlers! {
+    add: |a, b| a.wrapping_add(b);
+    sub: |a, b| a.wrapping_sub(b);
+}
+
+branch_handlers! {
+    beq: |a, b| a == b;
+    bne: |a, b| a != b;
+    blt: |a, b| (a as i64) < (b as i64);
+    bge: |a, b| (a as i64) >= (b as i64);
+    bltu: |a, b| a < b;
+    bgeu: |a, b| a >= b;
+}
+
+load_handlers! {
+    lb: 1, |value| value as i8 as u64;
+    lw: 4, |value| value as i32 as u64;
+    ld: 8, |value| value;
+    lbu: 1, |value| value;
+    lwu: 4, |value| value;
+}
+
+store_handlers! {
+    sb: 1;
+    sw: 4;
+    sd: 8;
 }
 
-fn mulhsu(a: i64, b: u64) -> u64 {
+fn lui(cpu: &mut Cpu, instruction: &Instruction, _: &mut Bus) -> Result<(), Exception> {
+    cpu.retire(instruction, Some(instruction.immediate_u_unsigned()), cpu.pc + instruction.size);
+    Ok(())
+}
+
+fn auipc(cpu: &mut Cpu, instruction: &Instruction, _: &mut Bus) -> Result<(), Exception> {
+    cpu.retire(instruction, Some(cpu.pc.wrapping_add(instruction.immediate_u_unsigned())), cpu.pc + instruction.size);
+    Ok(())
+}
+
+fn jal(cpu: &mut Cpu, instruction: &Instruction, _: &mut Bus) -> Result<(), Exception> {
+    let new_pc = cpu.pc.wrapping_add_signed(instruction.immediate_j());
+    cpu.retire(instruction, Some(cpu.pc + instruction.size), new_pc);
+    Ok(())
+}
+
+fn jalr(cpu: &mut Cpu, instruction: &Instruction, _: &mut Bus) -> Result<(), Exception> {
+    let new_pc = cpu.read_register(instruction.rs1).wrapping_add_signed(instruction.immediate_i()) & !1;
+    cpu.retire(instruction, Some(cpu.pc + instruction.size), new_pc);
+    Ok(())
+}
+
+fn trap_vector(tvec: u64, trap: Trap) -> u64 {
+    let base = tvec & !3;
+    match trap {
+        Trap::Interrupt(interrupt) if tvec & 3 == TVEC_MODE_VECTORED => base + 4 * interrupt.cause(),
+        _ => base,
+    }
+}
+
+fn crosses_page(address: u64, size: u64) -> bool {
+    (address % PAGE_SIZE) + size > PAGE_SIZE
+}
+
+enum FloatResult {
+    Integer(u64),
+    Float(Format, u64),
+}
+
+pub fn mulhsu(a: i64, b: u64) -> u64 {
     // based on https://github.com/riscv-software-src/riscv-isa-sim/blob/90aa49f85b589c91754ea224bc2f1492dd99efa3/riscv/arith.h#L40
     let negate = a < 0;
-    let res = mulhu((if negate { -a } else { a } as u64), b);
-    if negate { !res + ((a as u64) * b == 0) as u64 } else { res }
+    let res = mulhu(a.unsigned_abs(), b);
+    if negate { (!res).wrapping_add(((a as u64).wrapping_mul(b) == 0) as u64) } else { res }
 }
 
-fn mulhu(a: u64, b: u64) -> u64 {
+pub fn mulhu(a: u64, b: u64) -> u64 {
     ((a as u128).wrapping_mul(b as u128) >> 64) as u64
 }
 
-fn div_unsigned(a: u64, b: u64) -> (u64, u64) {
+#[allow(clippy::manual_checked_ops)] // the zero divisor case is spelled out the way the spec gives it
+pub fn div_unsigned(a: u64, b: u64) -> (u64, u64) {
     if b == 0 {
         (u64::MAX, a)
     } else {
@@ -215,7 +1233,7 @@ fn div_unsigned(a: u64, b: u64) -> (u64, u64) {
     }
 }
 
-fn div_signed(a: u64, b: u64) -> (u64, u64) {
+pub fn div_signed(a: u64, b: u64) -> (u64, u64) {
     let a = a as i64;
     let b = b as i64;
     let result =
@@ -228,3 +1246,422 @@ fn div_signed(a: u64, b: u64) -> (u64, u64) {
         };
     (result.0 as u64, result.1 as u64)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Ram;
+    use crate::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+    use crate::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+    use crate::replay::Journal;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn test_bus(size: usize) -> Bus {
+        let mut bus = Bus::new();
+        bus.map(0, size as u64, Ram::new(size));
+        bus
+    }
+
+    fn run(program: &[u32], setup: impl FnOnce(&mut Cpu, &mut Bus)) -> (Cpu<'static>, Bus) {
+        let machine = Box::leak(Box::new(Machine::new()));
+        let mut bus = test_bus(0x1000);
+        let mut cpu = Cpu::new(machine);
+        for (i, word) in program.iter().enumerate() {
+            bus.store32(i as u64 * 4, *word as u64).unwrap();
+        }
+        setup(&mut cpu, &mut bus);
+        for _ in program {
+            cpu.step(&mut bus).unwrap();
+        }
+        (cpu, bus)
+    }
+
+    #[test]
+    fn test_lr_sc() {
+        let program = [
+            0x1005a52f, // lr.w a0, (a1)
+            0x18d5a62f, // sc.w a2, a3, (a1)
+            0x18d5a62f, // sc.w a2, a3, (a1)
+        ];
+        let (cpu, mut bus) = run(&program, |cpu, bus| {
+            cpu.registers[11] = 0x800;
+            cpu.registers[13] = 7;
+            bus.store32(0x800, 0xFFFF_FFFE).unwrap();
+        });
+        assert_eq!(cpu.registers[10], (-2i64) as u64);
+        assert_eq!(cpu.registers[12], 1);
+        assert_eq!(bus.load32(0x800).unwrap(), 7);
+        assert_eq!(cpu.reservation, None);
+    }
+
+    #[test]
+    fn test_amo() {
+        let program = [
+            0x00d5a72f, // amoadd.w a4, a3, (a1)
+            0xe0d5b7af, // amomaxu.d a5, a3, (a1)
+            0x80d5a72f, // amomin.w a4, a3, (a1)
+        ];
+        let (cpu, mut bus) = run(&program, |cpu, bus| {
+            cpu.registers[11] = 0x800;
+            cpu.registers[13] = u64::MAX;
+            bus.store64(0x800, 0x1_0000_0000).unwrap();
+        });
+        assert_eq!(cpu.registers[15], 0x1_FFFF_FFFF);
+        assert_eq!(cpu.registers[14], u64::MAX);
+        assert_eq!(bus.load64(0x800).unwrap(), 0xFFFF_FFFF_FFFF_FFFF);
+    }
+
+    #[test]
+    fn test_word_operations() {
+        let program = [
+            0x01f5159b, // slliw a1, a0, 31
+            0x0006d61b, // srliw a2, a3, 0
+            0x00f5173b, // sllw a4, a0, a5
+            0x02a6d83b, // divuw a6, a3, a0
+            0x02f6e8bb, // remw a7, a3, a5
+            0x0206d913, // srli s2, a3, 32
+            0x43f6d993, // srai s3, a3, 63
+        ];
+        let (cpu, _) = run(&program, |cpu, _| {
+            cpu.registers[10] = 1;
+            cpu.registers[13] = 0xF000_0001_8000_0000;
+            cpu.registers[15] = 31;
+        });
+        let minimum = i32::MIN as i64 as u64;
+        assert_eq!(cpu.registers[11..=17], [minimum, minimum, 0xF000_0001_8000_0000, minimum, 31, minimum, (-2i64) as u64]);
+        assert_eq!(cpu.registers[18], 0xF000_0001);
+        assert_eq!(cpu.registers[19], u64::MAX);
+    }
+
+    #[test]
+    fn test_compressed_and_unaligned_fetch() {
+        let machine = Machine::new();
+        let mut bus = test_bus(0x1000);
+        let mut cpu = Cpu::new(&machine);
+        bus.store16(0, 0x4515).unwrap(); // c.li a0, 5
+        bus.store32(2, 0x00150513).unwrap(); // addi a0, a0, 1
+        bus.store16(6, 0x85aa).unwrap(); // c.mv a1, a0
+        for _ in 0..3 {
+            cpu.step(&mut bus).unwrap();
+        }
+        assert_eq!(cpu.registers[11], 6);
+        assert_eq!(cpu.pc, 8);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        for cached in [true, false] {
+            let machine = Machine::new();
+            let mut bus = test_bus(0x1000);
+            let mut cpu = Cpu::new(&machine);
+            if !cached {
+                cpu.decode_cache = None;
+            }
+            bus.store32(0, 0x00150513).unwrap(); // addi a0, a0, 1
+            bus.store32(4, 0x00b02023).unwrap(); // sw a1, 0(zero)
+            bus.store32(8, 0xff9ff06f).unwrap(); // j 0
+            cpu.registers[11] = 0x00250513; // addi a0, a0, 2
+            for _ in 0..4 {
+                cpu.step(&mut bus).unwrap();
+            }
+            assert_eq!(cpu.registers[10], 3);
+            assert_eq!(cpu.pc, 4);
+        }
+    }
+
+    #[test]
+    fn test_fence_i() {
+        let program = [
+            0x00150513, // addi a0, a0, 1
+            0x0000100f, // fence.i
+        ];
+        let (cpu, bus) = run(&program, |_, _| ());
+        assert_eq!(cpu.registers[10], 1);
+        assert!(bus.code_pages.is_empty());
+        assert_eq!(bus.code_written, [0]);
+    }
+
+    #[test]
+    fn test_floating_point() {
+        let program = [
+            0xd2257553, // fcvt.d.l fa0, a0
+            0xd225f5d3, // fcvt.d.l fa1, a1
+            0x1ab53653, // fdiv.d fa2, fa0, fa1, rup
+            0x00c63027, // fsd fa2, 0(a2)
+            0x401676d3, // fcvt.s.d fa3, fa2
+            0xe00686d3, // fmv.x.w a3, fa3
+            0x00102773, // frflags a4
+            0xa2a597d3, // flt.d a5, fa1, fa0
+        ];
+        let (cpu, mut bus) = run(&program, |cpu, _| {
+            cpu.registers[10] = 1;
+            cpu.registers[11] = 3;
+            cpu.registers[12] = 0x800;
+        });
+        assert_eq!(bus.load64(0x800).unwrap(), (1.0f64 / 3.0).next_up().to_bits());
+        assert_eq!(cpu.fregisters[13], 0xFFFF_FFFF_0000_0000 | (1.0f32 / 3.0).to_bits() as u64);
+        assert_eq!(cpu.registers[13], (1.0f32 / 3.0).to_bits() as u64);
+        assert_eq!(cpu.registers[14], float::FLAG_INEXACT);
+        assert_eq!(cpu.registers[15], 0);
+    }
+
+    #[test]
+    fn test_traps() {
+        let machine = Machine::new();
+        let mut bus = test_bus(0x1000);
+        let mut cpu = Cpu::new(&machine);
+        let program = [
+            0x10000293, // li t0, 0x100
+            0x30529073, // csrw mtvec, t0
+            0x34131073, // csrw mepc, t1
+            0x30200073, // mret
+        ];
+        for (i, word) in program.iter().enumerate() {
+            bus.store32(i as u64 * 4, *word as u64).unwrap();
+        }
+        bus.store32(0x20, 0x00000073).unwrap(); // ecall
+        bus.store32(0x24, 0x00000000).unwrap(); // illegal
+        cpu.registers[6] = 0x20;
+
+        for _ in 0..5 {
+            cpu.step(&mut bus).unwrap();
+        }
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.csr.mcause, 8);
+        assert_eq!(cpu.csr.mepc, 0x20);
+        assert_eq!((cpu.csr.mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT, Privilege::User as u64);
+
+        cpu.pc = 0x24;
+        assert_eq!(cpu.step(&mut bus), Ok(StepOutcome::Trapped(Trap::Exception(Exception::IllegalInstruction(0)))));
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.csr.mcause, 2);
+        assert_eq!(cpu.csr.mepc, 0x24);
+    }
+
+    #[test]
+    fn test_supervisor_mode() {
+        let machine = Machine::new();
+        let mut bus = test_bus(0x3000);
+        let mut cpu = Cpu::new(&machine);
+        let program = [
+            0x0005a503, // lw a0, 0(a1)
+            0x0006a603, // lw a2, 0(a3)
+            0x00000073, // ecall
+            0x14202573, // csrr a0, scause
+        ];
+        for (i, word) in program.iter().enumerate() {
+            bus.store32(i as u64 * 4, *word as u64).unwrap();
+        }
+        // a single user gigapage mapping the first GiB onto itself
+        bus.store64(0x2000, 0xDF).unwrap();
+        bus.store32(0x1000, 0x1234_5678).unwrap();
+        cpu.csr.satp = (SATP_MODE_SV39 << SATP_MODE_SHIFT) | 2;
+        cpu.csr.medeleg = 1 << 8;
+        cpu.csr.mtvec = 0x100;
+        cpu.csr.stvec = 0xC;
+        cpu.privilege = Privilege::User;
+        cpu.registers[11] = 0x1000;
+        cpu.registers[13] = 0x4000_0000;
+
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.registers[10], 0x1234_5678);
+
+        // the second gigapage is unmapped and page faults are not delegated
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.csr.mcause, 13);
+        assert_eq!(cpu.csr.mtval, 0x4000_0000);
+
+        cpu.privilege = Privilege::User;
+        cpu.pc = 8;
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.pc, 0xC);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.csr.sepc, 8);
+        assert_eq!(cpu.csr.mstatus & MSTATUS_SPP, 0);
+
+        // supervisor mode cannot execute user pages
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.csr.mcause, 12);
+        assert_eq!(cpu.csr.mtval, 0xC);
+    }
+
+    #[test]
+    fn test_access_faults() {
+        let program = [
+            0x0005a503, // lw a0, 0(a1)
+        ];
+        let (cpu, _) = run(&program, |cpu, _| {
+            cpu.csr.mtvec = 0x100;
+            cpu.registers[11] = 0x2000;
+        });
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.csr.mcause, 5);
+        assert_eq!(cpu.csr.mtval, 0x2000);
+
+        let (cpu, _) = run(&[0x0000006f], |cpu, _| {
+            cpu.csr.mtvec = 0x100;
+            cpu.pc = 0x1000; // j . outside memory
+        });
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.csr.mcause, 1);
+        assert_eq!(cpu.csr.mtval, 0x1000);
+    }
+
+    #[test]
+    fn test_step_outcomes() {
+        let machine = Machine::new();
+        let mut bus = test_bus(0x1000);
+        let mut cpu = Cpu::new(&machine);
+        bus.store32(0, 0x00000013).unwrap(); // nop
+        bus.store32(4, 0x00100073).unwrap(); // ebreak
+        bus.store32(8, 0x0005a503).unwrap(); // lw a0, 0(a1)
+        bus.store32(12, 0xffffffff).unwrap(); // illegal
+        bus.store32(16, 0x10500073).unwrap(); // wfi
+        bus.store32(20, 0x00000013).unwrap(); // nop
+        bus.store32(24, 0x00000013).unwrap(); // nop
+        bus.store32(0x100, 0x10500073).unwrap(); // wfi
+        cpu.registers[11] = 0x2000;
+        cpu.stop_on_exception = true;
+
+        // without a trap handler exceptions stop the hart where it was
+        assert_eq!(cpu.step(&mut bus), Ok(StepOutcome::Retired));
+        assert_eq!(cpu.step(&mut bus), Err(ExecError::Breakpoint { pc: 4 }));
+        assert_eq!(cpu.pc, 4);
+        cpu.pc = 8;
+        assert_eq!(cpu.step(&mut bus), Err(ExecError::MemoryFault { pc: 8, address: 0x2000, access: AccessType::Load }));
+        cpu.pc = 12;
+        assert_eq!(cpu.step(&mut bus), Err(ExecError::IllegalInstruction { pc: 12, encoding: 0xffffffff }));
+        assert_eq!(cpu.csr.mcause, 0);
+
+        cpu.pc = 16;
+        assert_eq!(cpu.step(&mut bus), Ok(StepOutcome::Halted));
+        cpu.pc = 16;
+        cpu.csr.mie = MIP_MTIP;
+        assert_eq!(cpu.run(&mut bus, Some(3)), Ok(StepOutcome::Retired));
+        assert_eq!(cpu.pc, 28);
+
+        cpu.pc = 8;
+        cpu.csr.mie = 0;
+        cpu.csr.mtvec = 0x100;
+        cpu.stop_on_exception = false;
+        assert_eq!(cpu.step(&mut bus), Ok(StepOutcome::Trapped(Trap::Exception(Exception::LoadAccessFault(0x2000)))));
+        assert_eq!(cpu.run(&mut bus, None), Ok(StepOutcome::Halted));
+        assert_eq!(cpu.pc, 0x104);
+    }
+
+    #[test]
+    fn test_timer_interrupts() {
+        // a stopped clock keeps mtime at zero
+        let machine = Machine { timebase_frequency: 0, ..Machine::new() };
+        let mut bus = test_bus(0x1000);
+        bus.map(CLINT_BASE, CLINT_SIZE, Clint::new(None));
+        bus.store32(0, 0x00000013).unwrap(); // nop
+        let mut cpu = Cpu::new(&machine);
+        cpu.csr.mtvec = 0x100;
+        cpu.csr.mie = MIP_MTIP;
+        cpu.csr.mstatus |= MSTATUS_MIE;
+
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.pc, 4);
+        bus.store64(CLINT_BASE + 0x4000, 0).unwrap();
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.csr.mcause, (1 << 63) | 7);
+
+        // Sstc: stimecmp raises the supervisor timer interrupt without going through machine mode
+        bus.store64(CLINT_BASE + 0x4000, u64::MAX).unwrap();
+        cpu.csr.menvcfg = MENVCFG_STCE;
+        cpu.csr.stimecmp = 0;
+        cpu.csr.mie = MIP_STIP;
+        cpu.csr.mideleg = MIP_STIP;
+        cpu.csr.stvec = 0x200;
+        cpu.privilege = Privilege::User;
+        cpu.pc = 0;
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.csr.scause, (1 << 63) | 5);
+        assert_eq!(cpu.time, 0);
+    }
+
+    #[test]
+    fn test_replayed_timer_interrupts() {
+        // a loop, and a handler that moves the deadline along
+        let outcomes = |machine: &Machine, journal: &SharedJournal| {
+            let mut bus = test_bus(0x1000);
+            bus.map(CLINT_BASE, CLINT_SIZE, Clint::new(Some(journal.clone())));
+            for i in 0..32 {
+                bus.store32(4 * i, 0x00000013).unwrap(); // nop
+            }
+            bus.store32(0x80, 0xf81ff06f).unwrap(); // j 0
+            bus.store32(0x100, 0x00533023).unwrap(); // sd t0, 0(t1)
+            bus.store32(0x104, 0x0c828293).unwrap(); // addi t0, t0, 200
+            bus.store32(0x108, 0x30200073).unwrap(); // mret
+            bus.store64(CLINT_BASE + 0x4000, 150).unwrap();
+            let mut cpu = Cpu::new(machine);
+            cpu.journal = Some(journal.clone());
+            cpu.registers[5] = 300;
+            cpu.registers[6] = CLINT_BASE + 0x4000;
+            cpu.csr.mtvec = 0x100;
+            cpu.csr.mie = MIP_MTIP;
+            cpu.csr.mstatus |= MSTATUS_MIE;
+            (0..200).map(|_| cpu.step(&mut bus).unwrap()).collect::<Vec<_>>()
+        };
+        let machine = Machine { clock: Clock::Virtual { instructions_per_second: 1_000_000 }, ..Machine::new() };
+        let journal = Rc::new(RefCell::new(Journal::new()));
+        let recorded = outcomes(&machine, &journal);
+        assert_eq!(recorded.iter().filter(|outcome| matches!(outcome, StepOutcome::Trapped(_))).count(), 10);
+
+        // the interrupts come at the same steps with the clock stopped
+        let stopped = Machine { timebase_frequency: 0, ..Machine::new() };
+        let replayed = Rc::new(RefCell::new(Journal::load(&journal.borrow().save()).unwrap()));
+        assert_eq!(outcomes(&stopped, &replayed), recorded);
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        // ten timer ticks per instruction
+        let machine = Machine { clock: Clock::Virtual { instructions_per_second: 1_000_000 }, ..Machine::new() };
+        let mut bus = test_bus(0x1000);
+        for i in 0..10 {
+            bus.store32(4 * i, 0x00000013).unwrap(); // nop
+        }
+        bus.store32(40, 0xc0102573).unwrap(); // rdtime a0
+        let mut cpu = Cpu::new(&machine);
+        cpu.run(&mut bus, Some(11)).unwrap();
+        assert_eq!(cpu.registers[10], 100);
+        assert_eq!(machine.elapsed(3), Duration::from_micros(3));
+    }
+
+    #[test]
+    fn test_external_interrupts() {
+        let machine = Machine::new();
+        let mut bus = test_bus(0x1000);
+        bus.map(PLIC_BASE, PLIC_SIZE, Plic::new());
+        bus.store32(PLIC_BASE + 4 * 10, 1).unwrap(); // source 10 priority
+        bus.store32(PLIC_BASE + 0x2080, 1 << 10).unwrap(); // enabled for the supervisor context
+        bus.interrupts.sources = 1 << 10;
+
+        let mut cpu = Cpu::new(&machine);
+        cpu.csr.mie = MIP_SEIP;
+        cpu.csr.mideleg = MIP_SEIP;
+        cpu.csr.stvec = 0x200;
+        cpu.privilege = Privilege::User;
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.csr.scause, (1 << 63) | 9);
+        // the controller's signal shows up in mip without being part of the writable bit
+        assert_eq!(cpu.read_control_register(CSR_SIP), Some(MIP_SEIP));
+        assert_eq!(cpu.csr.mip & MIP_SEIP, 0);
+
+        assert_eq!(bus.load32(PLIC_BASE + 0x20_1004), Ok(10));
+        bus.store32(0x200, 0x00000013).unwrap(); // nop
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.read_control_register(CSR_SIP), Some(0));
+    }
+}
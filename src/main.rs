@@ -1,30 +1,307 @@
-mod opcodes;
+﻿mod opcodes;
+mod compressed;
+mod float;
+mod csr;
+mod trap;
+mod mmu;
 mod bus;
+mod uart;
+mod clint;
+mod plic;
+mod htif;
 mod instruction;
+mod disassembler;
+mod assembler;
+mod commit_log;
+mod decode_cache;
 mod machine;
 mod loader;
+mod syscall;
+mod cli;
+mod debugger;
+mod gdbstub;
+mod lockstep;
+mod snapshot;
+mod replay;
+#[cfg(feature = "jit")]
+mod jit;
 
-use std::fs::File;
-use std::io::{self, Read};
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::process;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::Instant;
 
-use crate::bus::Bus;
-use crate::machine::{Cpu, Machine};
+use anyhow::{bail, Context, Result};
 
-fn main() -> io::Result<()> {
-    let machinussy = Machine::new();
-    let mut bussy = Bus::new(64 * 1024 * 1024);
+use crate::bus::{Bus, Ram, DRAM_BASE};
+use crate::cli::{AssembleOptions, Command, Options, INSTRUCTION_LIMIT_EXIT_CODE, USAGE};
+use crate::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+use crate::commit_log::CommitLog;
+use crate::debugger::Debugger;
+use crate::gdbstub::{GdbStub, Outcome};
+use crate::htif::Htif;
+use crate::lockstep::Lockstep;
+use crate::machine::{Clock, Cpu, Machine};
+use crate::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+use crate::replay::Journal;
+use crate::syscall::{LinuxSyscalls, Random};
+use crate::trap::{ExecError, Privilege, StepOutcome};
+use crate::uart::{ConsoleInput, Uart, UART_BASE, UART_SIZE};
+
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+/// Exit code when GDB kills the guest, as if by SIGKILL.
+const KILLED_EXIT_CODE: i32 = 128 + 9;
+
+fn main() {
+    let command = match cli::parse_command(env::args().skip(1)) {
+        Ok(Some(command)) => command,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(error) => {
+            eprintln!("error: {:#}", error);
+            process::exit(2);
+        }
+    };
+    let result = match command {
+        Command::Run(options) => run(&options),
+        Command::Disassemble(program) => disassemble(&program).map(|_| 0),
+        Command::Assemble(options) => assemble(&options).map(|_| 0),
+    };
+    match result {
+        Ok(exit_code) => process::exit(exit_code),
+        Err(error) => {
+            eprintln!("error: {:#}", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn disassemble(program: &str) -> Result<()> {
+    let image = fs::read(program).with_context(|| format!("can't read {}", program))?;
+    disassembler::dump_elf(&image, &mut io::stdout().lock())
+}
+
+fn assemble(options: &AssembleOptions) -> Result<()> {
+    let source = fs::read_to_string(&options.source).with_context(|| format!("can't read {}", options.source))?;
+    let program = assembler::assemble(&source, options.base).with_context(|| format!("can't assemble {}", options.source))?;
+    let image = if options.flat { program.flat_image() } else { program.elf() };
+    fs::write(&options.output, image).with_context(|| format!("can't write {}", options.output))
+}
+
+fn run(options: &Options) -> Result<i32> {
+    let mut machinussy = Machine::new();
+    // deterministic runs get all of their input before they start, so it arrives the same way every time
+    let mut input = Vec::new();
+    if options.deterministic {
+        machinussy.clock = Clock::Virtual { instructions_per_second: options.clock_rate };
+        if !options.debug {
+            io::stdin().read_to_end(&mut input).context("can't read stdin")?;
+        }
+    }
+    // input from the host goes through a journal when recording or replaying, and always under the
+    // debugger, which replays to go back
+    let journal = match &options.replay {
+        Some(path) => {
+            let recording = fs::read(path).with_context(|| format!("can't read {}", path))?;
+            Some(Journal::load(&recording).with_context(|| format!("can't replay {}", path))?)
+        }
+        None => (options.debug || options.record.is_some()).then(Journal::new),
+    }.map(|journal| Rc::new(RefCell::new(journal)));
+    let mut bussy = Bus::new();
     let mut cpussy = Cpu::new(&machinussy);
+    cpussy.journal = journal.clone();
+    let image = fs::read(&options.program).with_context(|| format!("can't read {}", options.program))?;
+    let memory_size = options.memory_size;
+    let mut htif = None;
 
-    {
-        let mut file = File::open("kod.elf")?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-        let entry_point = loader::load_elf_file(&mut bussy, buf.as_ref())
-            .expect("ELF parse error");
-        cpussy.pc = entry_point.virtual_address();
+    if options.raw_binary.is_none() && loader::is_user_program(&image) {
+        // a Linux process: memory starts at zero, the stack sits at the top and the kernel is emulated
+        bussy.map(0, memory_size, Ram::new(memory_size as usize));
+        let program = loader::load_elf_file(&mut bussy, &image)?;
+        let env: Vec<String> = env::vars().map(|(key, value)| format!("{}={}", key, value)).collect();
+        let mut seeded = Random::new(options.seed);
+        let mut read_random = || -> Result<[u8; 16]> {
+            let mut random = [0; 16];
+            if options.deterministic {
+                seeded.fill(&mut random);
+            } else {
+                File::open("/dev/urandom")?.read_exact(&mut random)?;
+            }
+            Ok(random)
+        };
+        let random = match &journal {
+            Some(journal) => journal.borrow_mut().startup_random(read_random)?,
+            None => read_random()?,
+        };
+        cpussy.registers[2] = loader::setup_stack(&mut bussy, &program, memory_size, &options.args, &env, random)?;
+        cpussy.pc = program.entry_point.virtual_address();
+        cpussy.privilege = Privilege::User;
+        // like Linux, let user code read cycle, time and instret
+        cpussy.csr.mcounteren = 0b111;
+        cpussy.csr.scounteren = 0b111;
+        let mmap_top = cpussy.registers[2].saturating_sub(STACK_SIZE);
+        let mut syscalls = LinuxSyscalls::new(program.end, mmap_top);
+        if options.deterministic {
+            syscalls.make_deterministic(seeded, input);
+        }
+        syscalls.journal = journal.clone();
+        cpussy.syscalls = Some(syscalls);
+    } else {
+        bussy.map(DRAM_BASE, memory_size, Ram::new(memory_size as usize));
+        bussy.map(CLINT_BASE, CLINT_SIZE, Clint::new(journal.clone()));
+        bussy.map(PLIC_BASE, PLIC_SIZE, Plic::new());
+        // programs built for spike talk to the console through HTIF, everything else gets the UART
+        let tohost = options.raw_binary.is_none().then(|| loader::find_symbol(&image, "tohost")).flatten();
+        // the debugger reads its commands from stdin
+        let stdin = if options.debug {
+            mpsc::channel().1
+        } else if options.deterministic {
+            uart::queued_input(&input)
+        } else {
+            uart::spawn_stdin_reader()
+        };
+        let (uart_input, htif_input) = match tohost {
+            Some(_) => (mpsc::channel().1, Some(stdin)),
+            None => (stdin, None),
+        };
+        bussy.map(UART_BASE, UART_SIZE, Uart::new(ConsoleInput::new(uart_input, journal.clone()), io::stdout()));
+
+        if let Some(address) = options.raw_binary {
+            bussy.store_bytes(address, &image)
+                .map_err(|fault| anyhow::anyhow!("image doesn't fit in memory at {:#x}", fault.address))?;
+            cpussy.pc = address;
+        } else {
+            let program = loader::load_elf_file(&mut bussy, &image)?;
+            cpussy.pc = program.entry_point.virtual_address();
+        }
+        if let Some(tohost) = tohost {
+            let interface = Htif::new(htif_input.map(|input| ConsoleInput::new(input, journal.clone())));
+            interface.map(&mut bussy, tohost, loader::find_symbol(&image, "fromhost"));
+            htif = Some(interface);
+        }
+    }
+    if let Some(entry) = options.entry {
+        cpussy.pc = entry;
+    }
+    if !options.decode_cache {
+        cpussy.decode_cache = None;
+    }
+    cpussy.stop_on_exception = options.stop_on_exception;
+    if let Some(path) = &options.restore {
+        let snapshot = fs::read(path).with_context(|| format!("can't read {}", path))?;
+        snapshot::restore(&mut cpussy, &mut bussy, &snapshot).with_context(|| format!("can't restore {}", path))?;
+    }
+    if options.log_commits {
+        let output: Box<dyn Write> = match &options.log_file {
+            Some(path) => Box::new(BufWriter::new(File::create(path).with_context(|| format!("can't create {}", path))?)),
+            None => Box::new(BufWriter::new(io::stderr())),
+        };
+        cpussy.commit_log = Some(CommitLog::new(output, options.log_range.clone()));
     }
 
-    loop {
-        cpussy.step(&mut bussy);
+    let exited = |cpu: &Cpu| {
+        cpu.syscalls.as_ref().and_then(|syscalls| syscalls.exit_code)
+            .or_else(|| htif.as_ref().and_then(Htif::exit_code))
+    };
+    let record = || match (&options.record, &journal) {
+        (Some(path), Some(journal)) => fs::write(path, journal.borrow().save()).with_context(|| format!("can't write {}", path)),
+        _ => Ok(()),
+    };
+    if options.debug {
+        let mut debugger = Debugger::new(loader::symbols(&image), &exited);
+        let exit_code = debugger.run(&mut cpussy, &mut bussy, io::stdin().lock(), io::stdout())?;
+        record()?;
+        return Ok(exit_code);
+    }
+    if let Some(address) = &options.gdb {
+        let connection = gdbstub::accept(address)?;
+        match GdbStub::new(connection, &exited).serve(&mut cpussy, &mut bussy)? {
+            Outcome::Exited(exit_code) => return Ok(exit_code),
+            Outcome::Killed => return Ok(KILLED_EXIT_CODE),
+            Outcome::Detached => {}
+        }
+    }
+
+    if let Some(path) = &options.lockstep {
+        let trace = BufReader::new(File::open(path).with_context(|| format!("can't open {}", path))?);
+        return match Lockstep::new(&exited).run(&mut cpussy, &mut bussy, trace, io::stderr())? {
+            lockstep::Outcome::Matched(count) => {
+                eprintln!("all {} instructions in the trace matched", count);
+                Ok(0)
+            }
+            lockstep::Outcome::Exited(exit_code) => {
+                eprintln!("the program exited with code {} before the trace ended", exit_code);
+                Ok(exit_code)
+            }
+            lockstep::Outcome::Diverged => Ok(1),
+        };
+    }
+
+    let started = Instant::now();
+    let outcome = if options.trace {
+        run_traced(&mut cpussy, &mut bussy, options.max_instructions)
+    } else if options.jit && cpussy.commit_log.is_none() && journal.is_none() {
+        run_translated(&mut cpussy, &mut bussy, options.max_instructions)?
+    } else {
+        cpussy.run(&mut bussy, options.max_instructions)
+    };
+    if options.stats {
+        let seconds = started.elapsed().as_secs_f64();
+        let instructions = cpussy.instructions_retired;
+        eprintln!("{} instructions in {:.3}s, {:.2} MIPS", instructions, seconds, instructions as f64 / seconds / 1e6);
+    }
+    record()?;
+    let outcome = outcome?;
+    if let Some(path) = &options.save_snapshot {
+        let snapshot = snapshot::save(&cpussy, &mut bussy).context("can't save a snapshot")?;
+        fs::write(path, snapshot).with_context(|| format!("can't write {}", path))?;
+    }
+    match outcome {
+        StepOutcome::Exited(exit_code) => {
+            if htif.is_some() && exit_code != 0 {
+                // riscv-tests report the number of the failing test case
+                eprintln!("*** FAILED *** (tohost = {})", exit_code);
+            }
+            Ok(exit_code)
+        }
+        StepOutcome::Halted => bail!("waiting for an interrupt at 0x{:x}, but none are enabled", cpussy.pc),
+        StepOutcome::Retired | StepOutcome::Trapped(_) => {
+            eprintln!("stopped after {} instructions", options.max_instructions.unwrap_or_default());
+            Ok(INSTRUCTION_LIMIT_EXIT_CODE)
+        }
+    }
+}
+
+#[cfg(feature = "jit")]
+fn run_translated(cpu: &mut Cpu, bus: &mut Bus, limit: Option<u64>) -> Result<Result<StepOutcome, ExecError>> {
+    Ok(jit::Jit::new()?.run(cpu, bus, limit))
+}
+
+#[cfg(not(feature = "jit"))]
+fn run_translated(_cpu: &mut Cpu, _bus: &mut Bus, _limit: Option<u64>) -> Result<Result<StepOutcome, ExecError>> {
+    bail!("this build has no JIT, it needs the jit feature")
+}
+
+/// `Cpu::run`, printing every instruction to stderr before it executes.
+fn run_traced(cpu: &mut Cpu, bus: &mut Bus, limit: Option<u64>) -> Result<StepOutcome, ExecError> {
+    let mut outcome = StepOutcome::Retired;
+    let mut steps = 0;
+    while limit.is_none_or(|limit| steps < limit) {
+        match cpu.fetch(bus) {
+            Ok(instruction) => eprintln!("0x{:016x} (0x{:08x})", cpu.pc, instruction.encoding),
+            Err(_) => eprintln!("0x{:016x} fetch fault", cpu.pc),
+        }
+        outcome = cpu.step(bus)?;
+        steps += 1;
+        if matches!(outcome, StepOutcome::Exited(_) | StepOutcome::Halted) {
+            break;
+        }
     }
+    Ok(outcome)
 }
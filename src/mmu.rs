@@ -0,0 +1,181 @@
+﻿use crate::bus::Bus;
+use crate::trap::{Exception, Privilege};
+
+pub const SATP_MODE_SHIFT: u64 = 60;
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_MODE_SV48: u64 = 9;
+pub const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PTE_PPN_SHIFT: u64 = 10;
+const PTE_RESERVED: u64 = !((1 << 54) - 1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    Instruction,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    pub fn page_fault(self, address: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionPageFault(address),
+            AccessType::Load => Exception::LoadPageFault(address),
+            AccessType::Store => Exception::StorePageFault(address),
+        }
+    }
+
+    pub fn access_fault(self, address: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionAccessFault(address),
+            AccessType::Load => Exception::LoadAccessFault(address),
+            AccessType::Store => Exception::StoreAccessFault(address),
+        }
+    }
+}
+
+/// Everything from the hart's state that influences address translation.
+#[derive(Debug, Clone, Copy)]
+pub struct TranslationContext {
+    pub satp: u64,
+    /// Privilege the access is performed with, after applying `mstatus.MPRV`.
+    pub privilege: Privilege,
+    /// `mstatus.SUM`: supervisor may access user pages.
+    pub supervisor_user_memory: bool,
+    /// `mstatus.MXR`: loads from executable pages succeed.
+    pub make_executable_readable: bool,
+}
+
+/// Translates a virtual address with an Sv39 or Sv48 page table walk, updating the accessed and dirty bits.
+pub fn translate(bus: &mut Bus, address: u64, access: AccessType, context: &TranslationContext) -> Result<u64, Exception> {
+    let levels = match context.satp >> SATP_MODE_SHIFT {
+        _ if context.privilege == Privilege::Machine => return Ok(address),
+        SATP_MODE_SV39 => 3,
+        SATP_MODE_SV48 => 4,
+        _ => return Ok(address),
+    };
+
+    // the unused upper bits must be copies of the highest translated bit
+    let virtual_bits = 12 + 9 * levels;
+    if ((address as i64) << (64 - virtual_bits) >> (64 - virtual_bits)) as u64 != address {
+        return Err(access.page_fault(address));
+    }
+
+    let mut table = (context.satp & SATP_PPN_MASK) * PAGE_SIZE;
+    for level in (0..levels).rev() {
+        let vpn = (address >> (12 + 9 * level)) & 0x1FF;
+        let pte_address = table + vpn * 8;
+        let pte = bus.load64(pte_address).map_err(|_| access.access_fault(address))?;
+
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte & PTE_RESERVED != 0 {
+            return Err(access.page_fault(address));
+        }
+
+        let ppn = (pte >> PTE_PPN_SHIFT) & SATP_PPN_MASK;
+        if pte & (PTE_R | PTE_X) == 0 {
+            table = ppn * PAGE_SIZE;
+            continue;
+        }
+
+        let permitted = match access {
+            AccessType::Instruction => pte & PTE_X != 0,
+            AccessType::Load => pte & PTE_R != 0 || (context.make_executable_readable && pte & PTE_X != 0),
+            AccessType::Store => pte & PTE_W != 0,
+        };
+        let user_page = pte & PTE_U != 0;
+        let privilege_allowed = match context.privilege {
+            Privilege::User => user_page,
+            _ => !user_page || (access != AccessType::Instruction && context.supervisor_user_memory),
+        };
+        let superpage_aligned = ppn & ((1 << (9 * level)) - 1) == 0;
+        if !permitted || !privilege_allowed || !superpage_aligned {
+            return Err(access.page_fault(address));
+        }
+
+        let mut updated = pte | PTE_A;
+        if access == AccessType::Store {
+            updated |= PTE_D;
+        }
+        if updated != pte {
+            bus.store64(pte_address, updated).map_err(|_| access.access_fault(address))?;
+        }
+
+        let offset_bits = 12 + 9 * level;
+        return Ok(((ppn * PAGE_SIZE) & !((1 << offset_bits) - 1)) | (address & ((1 << offset_bits) - 1)));
+    }
+
+    Err(access.page_fault(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Ram;
+
+    const ROOT: u64 = 0x1000;
+    const LEVEL1: u64 = 0x2000;
+    const LEVEL0: u64 = 0x3000;
+
+    fn pte(address: u64, flags: u64) -> u64 {
+        ((address / PAGE_SIZE) << PTE_PPN_SHIFT) | flags | PTE_V
+    }
+
+    fn context(privilege: Privilege) -> TranslationContext {
+        TranslationContext {
+            satp: (SATP_MODE_SV39 << SATP_MODE_SHIFT) | (ROOT / PAGE_SIZE),
+            privilege,
+            supervisor_user_memory: false,
+            make_executable_readable: false,
+        }
+    }
+
+    fn page_tables() -> Bus {
+        let mut bus = Bus::new();
+        bus.map(0, 0x20_0000, Ram::new(0x20_0000));
+        // 0x4000_0000: 2 MiB megapage at 0x0, supervisor only
+        bus.store64(ROOT + 8, pte(LEVEL1, 0)).unwrap();
+        bus.store64(LEVEL1, pte(0, PTE_R | PTE_W | PTE_X)).unwrap();
+        // 0x4020_5000: user page at 0x8000
+        bus.store64(LEVEL1 + 8, pte(LEVEL0, 0)).unwrap();
+        bus.store64(LEVEL0 + 5 * 8, pte(0x8000, PTE_R | PTE_U)).unwrap();
+        bus
+    }
+
+    #[test]
+    fn test_translate() {
+        let mut bus = page_tables();
+        let supervisor = context(Privilege::Supervisor);
+        assert_eq!(translate(&mut bus, 0x4012_3456, AccessType::Load, &supervisor), Ok(0x12_3456));
+        assert_eq!(translate(&mut bus, 0x4020_5abc, AccessType::Load, &context(Privilege::User)), Ok(0x8abc));
+        assert_eq!(translate(&mut bus, 0x4012_3456, AccessType::Store, &supervisor), Ok(0x12_3456));
+        assert_eq!(bus.load64(LEVEL1).unwrap() & (PTE_A | PTE_D), PTE_A | PTE_D);
+        assert_eq!(translate(&mut bus, 0x1234, AccessType::Load, &context(Privilege::Machine)), Ok(0x1234));
+    }
+
+    #[test]
+    fn test_page_faults() {
+        let mut bus = page_tables();
+        let supervisor = context(Privilege::Supervisor);
+        let user = context(Privilege::User);
+        assert_eq!(translate(&mut bus, 0x4020_5000, AccessType::Store, &user), Err(Exception::StorePageFault(0x4020_5000)));
+        assert_eq!(translate(&mut bus, 0x4000_0000, AccessType::Instruction, &user), Err(Exception::InstructionPageFault(0x4000_0000)));
+        assert_eq!(translate(&mut bus, 0x4020_5000, AccessType::Load, &supervisor), Err(Exception::LoadPageFault(0x4020_5000)));
+        assert_eq!(translate(&mut bus, 0x8000_0000, AccessType::Load, &supervisor), Err(Exception::LoadPageFault(0x8000_0000)));
+        let unmapped_root = TranslationContext { satp: (SATP_MODE_SV39 << SATP_MODE_SHIFT) | 0x1000, ..supervisor };
+        assert_eq!(translate(&mut bus, 0x4000_0000, AccessType::Store, &unmapped_root), Err(Exception::StoreAccessFault(0x4000_0000)));
+        assert_eq!(translate(&mut bus, 0x0000_0040_0000_0000, AccessType::Load, &supervisor), Err(Exception::LoadPageFault(0x0000_0040_0000_0000)));
+
+        let sum = TranslationContext { supervisor_user_memory: true, ..supervisor };
+        assert_eq!(translate(&mut bus, 0x4020_5000, AccessType::Load, &sum), Ok(0x8000));
+    }
+}
@@ -11,8 +11,16 @@ pub const OPCODE_JALR: i32 = 0b1100111;
 pub const OPCODE_BRANCH: i32 = 0b1100011;
 pub const OPCODE_LOAD: i32 = 0b0000011;
 pub const OPCODE_STORE: i32 = 0b0100011;
+pub const OPCODE_LOAD_FP: i32 = 0b0000111;
+pub const OPCODE_STORE_FP: i32 = 0b0100111;
 pub const OPCODE_MISC_MEM: i32 = 0b0001111;
 pub const OPCODE_SYSTEM: i32 = 0b1110011;
+pub const OPCODE_AMO: i32 = 0b0101111;
+pub const OPCODE_OP_FP: i32 = 0b1010011;
+pub const OPCODE_MADD: i32 = 0b1000011;
+pub const OPCODE_MSUB: i32 = 0b1000111;
+pub const OPCODE_NMSUB: i32 = 0b1001011;
+pub const OPCODE_NMADD: i32 = 0b1001111;
 
 pub const F3_ADD: i32 = 0;
 pub const F3_SUB: i32 = 0;
@@ -60,6 +68,63 @@ pub const F3_SH: i32 = 1;
 pub const F3_SW: i32 = 2;
 pub const F3_SD: i32 = 3;
 
+pub const F3_FLW: i32 = 2;
+pub const F3_FLD: i32 = 3;
+pub const F3_FSW: i32 = 2;
+pub const F3_FSD: i32 = 3;
+
+pub const F3_AMO_W: i32 = 2;
+pub const F3_AMO_D: i32 = 3;
+
+pub const F5_AMOADD: i32 = 0b00000;
+pub const F5_AMOSWAP: i32 = 0b00001;
+pub const F5_LR: i32 = 0b00010;
+pub const F5_SC: i32 = 0b00011;
+pub const F5_AMOXOR: i32 = 0b00100;
+pub const F5_AMOOR: i32 = 0b01000;
+pub const F5_AMOAND: i32 = 0b01100;
+pub const F5_AMOMIN: i32 = 0b10000;
+pub const F5_AMOMAX: i32 = 0b10100;
+pub const F5_AMOMINU: i32 = 0b11000;
+pub const F5_AMOMAXU: i32 = 0b11100;
+
+pub const FMT_S: i32 = 0;
+pub const FMT_D: i32 = 1;
+
+pub const F5_FADD: i32 = 0b00000;
+pub const F5_FSUB: i32 = 0b00001;
+pub const F5_FMUL: i32 = 0b00010;
+pub const F5_FDIV: i32 = 0b00011;
+pub const F5_FSGNJ: i32 = 0b00100;
+pub const F5_FMINMAX: i32 = 0b00101;
+pub const F5_FCVT_FMT_FMT: i32 = 0b01000;
+pub const F5_FSQRT: i32 = 0b01011;
+pub const F5_FCMP: i32 = 0b10100;
+pub const F5_FCVT_INT_FMT: i32 = 0b11000;
+pub const F5_FCVT_FMT_INT: i32 = 0b11010;
+pub const F5_FMV_X_FCLASS: i32 = 0b11100;
+pub const F5_FMV_FMT_X: i32 = 0b11110;
+
+pub const F3_FSGNJ: i32 = 0;
+pub const F3_FSGNJN: i32 = 1;
+pub const F3_FSGNJX: i32 = 2;
+pub const F3_FMIN: i32 = 0;
+pub const F3_FMAX: i32 = 1;
+pub const F3_FLE: i32 = 0;
+pub const F3_FLT: i32 = 1;
+pub const F3_FEQ: i32 = 2;
+pub const F3_FMV_X: i32 = 0;
+pub const F3_FCLASS: i32 = 1;
+pub const F3_RM_DYNAMIC: i32 = 7;
+
+pub const RS2_FCVT_W: i32 = 0;
+pub const RS2_FCVT_WU: i32 = 1;
+pub const RS2_FCVT_L: i32 = 2;
+pub const RS2_FCVT_LU: i32 = 3;
+
+pub const F3_FENCE: i32 = 0;
+pub const F3_FENCE_I: i32 = 1;
+
 pub const F3_ECALL_EBREAK: i32 = 0;
 pub const F3_CSRRW: i32 = 1;
 pub const F3_CSRRS: i32 = 2;
@@ -80,12 +145,72 @@ pub const F7_SUB: i32 = 0b0100000;
 pub const F7_SRA: i32 = 0b0100000;
 pub const F7_MULDIV: i32 = 1;
 
+// RV64 immediate shifts take bit 25 for the sixth bit of the shift amount
+pub const F6_SRL: i32 = 0;
+pub const F6_SRA: i32 = 0b010000;
+
 pub const IMM_ECALL: i32 = 0;
 pub const IMM_EBREAK: i32 = 1;
 
+pub const F7_MRET: i32 = 0b0011000;
+pub const F7_SRET: i32 = 0b0001000;
+pub const F7_WFI: i32 = 0b0001000;
+pub const F7_SFENCE_VMA: i32 = 0b0001001;
+pub const RS2_XRET: i32 = 2;
+pub const RS2_WFI: i32 = 5;
+
+pub const CSR_FFLAGS: u64 = 0x001;
+pub const CSR_FRM: u64 = 0x002;
+pub const CSR_FCSR: u64 = 0x003;
 pub const CSR_CYCLE: u64 = 0xC00;
 pub const CSR_TIME: u64 = 0xC01;
 pub const CSR_INSTRET: u64 = 0xC02;
+pub const CSR_HPMCOUNTER3: u64 = 0xC03;
+pub const CSR_HPMCOUNTER31: u64 = 0xC1F;
+
+pub const CSR_SSTATUS: u64 = 0x100;
+pub const CSR_SIE: u64 = 0x104;
+pub const CSR_STVEC: u64 = 0x105;
+pub const CSR_SCOUNTEREN: u64 = 0x106;
+pub const CSR_SSCRATCH: u64 = 0x140;
+pub const CSR_SEPC: u64 = 0x141;
+pub const CSR_SCAUSE: u64 = 0x142;
+pub const CSR_STVAL: u64 = 0x143;
+pub const CSR_SIP: u64 = 0x144;
+pub const CSR_STIMECMP: u64 = 0x14D;
+pub const CSR_SATP: u64 = 0x180;
+
+pub const CSR_MSTATUS: u64 = 0x300;
+pub const CSR_MISA: u64 = 0x301;
+pub const CSR_MEDELEG: u64 = 0x302;
+pub const CSR_MIDELEG: u64 = 0x303;
+pub const CSR_MIE: u64 = 0x304;
+pub const CSR_MTVEC: u64 = 0x305;
+pub const CSR_MCOUNTEREN: u64 = 0x306;
+pub const CSR_MENVCFG: u64 = 0x30A;
+pub const CSR_MCOUNTINHIBIT: u64 = 0x320;
+pub const CSR_MHPMEVENT3: u64 = 0x323;
+pub const CSR_MHPMEVENT31: u64 = 0x33F;
+pub const CSR_MSCRATCH: u64 = 0x340;
+pub const CSR_MEPC: u64 = 0x341;
+pub const CSR_MCAUSE: u64 = 0x342;
+pub const CSR_MTVAL: u64 = 0x343;
+pub const CSR_MIP: u64 = 0x344;
+pub const CSR_PMPCFG0: u64 = 0x3A0;
+pub const CSR_PMPCFG15: u64 = 0x3AF;
+pub const CSR_PMPADDR0: u64 = 0x3B0;
+pub const CSR_PMPADDR63: u64 = 0x3EF;
+pub const CSR_TSELECT: u64 = 0x7A0;
+pub const CSR_TDATA3: u64 = 0x7A3;
+pub const CSR_MCYCLE: u64 = 0xB00;
+pub const CSR_MINSTRET: u64 = 0xB02;
+pub const CSR_MHPMCOUNTER3: u64 = 0xB03;
+pub const CSR_MHPMCOUNTER31: u64 = 0xB1F;
+pub const CSR_MVENDORID: u64 = 0xF11;
+pub const CSR_MARCHID: u64 = 0xF12;
+pub const CSR_MIMPID: u64 = 0xF13;
+pub const CSR_MHARTID: u64 = 0xF14;
+pub const CSR_MCONFIGPTR: u64 = 0xF15;
 
 
 
@@ -0,0 +1,217 @@
+﻿use anyhow::Result;
+
+use crate::bus::{Device, InterruptLines};
+use crate::snapshot::{Reader, Writer};
+
+pub const PLIC_BASE: u64 = 0x0C00_0000;
+pub const PLIC_SIZE: u64 = 0x400_0000;
+
+/// Interrupt sources 1 to 63; source 0 means "no interrupt".
+const SOURCES: usize = 64;
+/// Hart 0 machine mode and hart 0 supervisor mode, in the order QEMU's virt board uses.
+const CONTEXTS: usize = 2;
+const MACHINE_CONTEXT: usize = 0;
+const SUPERVISOR_CONTEXT: usize = 1;
+
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+const PRIORITY_MASK: u32 = 0x7;
+
+/// SiFive-compatible platform-level interrupt controller with level-triggered sources.
+pub struct Plic {
+    priority: [u32; SOURCES],
+    pending: u64,
+    /// Sources claimed by a context and not completed yet; they can't become pending again until then.
+    claimed: u64,
+    enable: [u64; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+}
+
+impl Plic {
+    pub fn new() -> Plic {
+        Plic {
+            priority: [0; SOURCES],
+            pending: 0,
+            claimed: 0,
+            enable: [0; CONTEXTS],
+            threshold: [0; CONTEXTS],
+        }
+    }
+
+    /// The highest-priority pending source enabled for `context` above its threshold, lowest id first on ties.
+    fn best_source(&self, context: usize) -> Option<usize> {
+        let candidates = self.pending & self.enable[context];
+        (1..SOURCES)
+            .filter(|&source| candidates & (1 << source) != 0 && self.priority[source] > self.threshold[context])
+            .min_by_key(|&source| (u32::MAX - self.priority[source], source))
+    }
+
+    fn claim(&mut self, context: usize) -> u64 {
+        match self.best_source(context) {
+            Some(source) => {
+                self.pending &= !(1 << source);
+                self.claimed |= 1 << source;
+                source as u64
+            }
+            None => 0,
+        }
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u64, _size: u64) -> Option<u64> {
+        let value = match offset {
+            PRIORITY..PENDING => self.priority.get((offset / 4) as usize).copied().unwrap_or(0) as u64,
+            PENDING..ENABLE => word(self.pending, offset - PENDING),
+            ENABLE..CONTEXT => match context_register(offset - ENABLE, ENABLE_STRIDE) {
+                Some((context, offset)) => word(self.enable[context], offset),
+                None => 0,
+            },
+            _ => match context_register(offset - CONTEXT, CONTEXT_STRIDE) {
+                Some((context, 0)) => self.threshold[context] as u64,
+                Some((context, 4)) => self.claim(context),
+                _ => 0,
+            },
+        };
+        Some(value)
+    }
+
+    fn write(&mut self, offset: u64, _size: u64, value: u64) -> Option<()> {
+        match offset {
+            PRIORITY..PENDING => {
+                let source = (offset / 4) as usize;
+                if (1..SOURCES).contains(&source) {
+                    self.priority[source] = value as u32 & PRIORITY_MASK;
+                }
+            }
+            PENDING..ENABLE => (),
+            ENABLE..CONTEXT => {
+                if let Some((context, offset)) = context_register(offset - ENABLE, ENABLE_STRIDE) {
+                    // source 0 doesn't exist, so it can't be enabled either
+                    self.enable[context] = set_word(self.enable[context], offset, value) & !1;
+                }
+            }
+            _ => match context_register(offset - CONTEXT, CONTEXT_STRIDE) {
+                Some((context, 0)) => self.threshold[context] = value as u32 & PRIORITY_MASK,
+                Some((_, 4)) if (1..SOURCES as u64).contains(&value) => self.claimed &= !(1 << value),
+                _ => (),
+            },
+        }
+        Some(())
+    }
+
+    fn tick(&mut self, interrupts: &mut InterruptLines) {
+        // a level-triggered gateway: pending follows the line unless the source is being serviced
+        self.pending = (self.pending & self.claimed) | (interrupts.sources & !self.claimed & !1);
+        interrupts.meip = self.best_source(MACHINE_CONTEXT).is_some();
+        interrupts.seip = self.best_source(SUPERVISOR_CONTEXT).is_some();
+    }
+
+    fn save(&self, snapshot: &mut Writer) {
+        for priority in self.priority {
+            snapshot.u32(priority);
+        }
+        snapshot.u64(self.pending);
+        snapshot.u64(self.claimed);
+        for context in 0..CONTEXTS {
+            snapshot.u64(self.enable[context]);
+            snapshot.u32(self.threshold[context]);
+        }
+    }
+
+    fn restore(&mut self, snapshot: &mut Reader) -> Result<()> {
+        for priority in &mut self.priority {
+            *priority = snapshot.u32()? & PRIORITY_MASK;
+        }
+        self.pending = snapshot.u64()?;
+        self.claimed = snapshot.u64()?;
+        for context in 0..CONTEXTS {
+            self.enable[context] = snapshot.u64()?;
+            self.threshold[context] = snapshot.u32()? & PRIORITY_MASK;
+        }
+        Ok(())
+    }
+}
+
+/// Splits an offset into per-context blocks of `stride` bytes into the context and the offset within it.
+fn context_register(offset: u64, stride: u64) -> Option<(usize, u64)> {
+    let context = (offset / stride) as usize;
+    (context < CONTEXTS).then_some((context, offset % stride))
+}
+
+/// 32-bit word of a 64-bit bitmap at a byte offset.
+fn word(bitmap: u64, offset: u64) -> u64 {
+    match offset {
+        0 => bitmap & 0xFFFF_FFFF,
+        4 => bitmap >> 32,
+        _ => 0,
+    }
+}
+
+fn set_word(bitmap: u64, offset: u64, value: u64) -> u64 {
+    match offset {
+        0 => (bitmap & !0xFFFF_FFFF) | (value & 0xFFFF_FFFF),
+        4 => (bitmap & 0xFFFF_FFFF) | (value << 32),
+        _ => bitmap,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UART: u64 = 10;
+    const DISK: u64 = 33;
+
+    fn tick(plic: &mut Plic, sources: u64) -> InterruptLines {
+        let mut interrupts = InterruptLines { sources, ..Default::default() };
+        plic.tick(&mut interrupts);
+        interrupts
+    }
+
+    #[test]
+    fn test_claim_complete() {
+        let mut plic = Plic::new();
+        plic.write(PRIORITY + 4 * UART, 4, 1);
+        plic.write(PRIORITY + 4 * DISK, 4, 3);
+        plic.write(ENABLE + ENABLE_STRIDE, 4, 1 << UART);
+        plic.write(ENABLE + ENABLE_STRIDE + 4, 4, 1 << (DISK - 32));
+
+        let lines = tick(&mut plic, (1 << UART) | (1 << DISK));
+        assert!(lines.seip);
+        assert!(!lines.meip);
+        assert_eq!(plic.read(PENDING, 4), Some(1 << UART));
+        assert_eq!(plic.read(PENDING + 4, 4), Some(1 << (DISK - 32)));
+
+        let claim = CONTEXT + CONTEXT_STRIDE + 4;
+        assert_eq!(plic.read(claim, 4), Some(DISK));
+        assert_eq!(plic.read(claim, 4), Some(UART));
+        assert_eq!(plic.read(claim, 4), Some(0));
+
+        // still asserted, but in service until completed
+        assert!(!tick(&mut plic, (1 << UART) | (1 << DISK)).seip);
+        plic.write(claim, 4, UART);
+        assert!(tick(&mut plic, (1 << UART) | (1 << DISK)).seip);
+        assert_eq!(plic.read(claim, 4), Some(UART));
+    }
+
+    #[test]
+    fn test_threshold() {
+        let mut plic = Plic::new();
+        plic.write(PRIORITY + 4 * UART, 4, 2);
+        plic.write(ENABLE, 4, 1 << UART);
+        plic.write(CONTEXT, 4, 2);
+        assert!(!tick(&mut plic, 1 << UART).meip);
+        assert_eq!(plic.read(CONTEXT + 4, 4), Some(0));
+
+        plic.write(CONTEXT, 4, 1);
+        assert!(tick(&mut plic, 1 << UART).meip);
+        // a source whose line drops stops being pending
+        assert!(!tick(&mut plic, 0).meip);
+    }
+}
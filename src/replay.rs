@@ -0,0 +1,351 @@
+﻿//! Recording the inputs a run gets from the host, so the run can be played back exactly.
+//!
+//! Everything else the hart does follows from its state, so a journal only keeps what comes from
+//! outside: the clock on the steps where the guest sees it, bytes polled from the console, the
+//! results of syscalls that reach the host and the randomness a process starts with. Replaying
+//! hands those back in the same order instead of asking the host again, and once the recording runs
+//! out the journal goes back to recording. Together with checkpoints, this is what lets the debugger
+//! go backwards: restore an earlier checkpoint and replay up to any step after it.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+
+use crate::snapshot::{Reader, Writer};
+
+const MAGIC: &[u8; 8] = b"RVJOURNL";
+const VERSION: u32 = 1;
+
+/// The journal is shared by the hart, the console devices and the syscalls, like the HTIF state.
+pub type SharedJournal = Rc<RefCell<Journal>>;
+
+/// How far into a journal a run is, counted per kind of input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Position {
+    pub steps: u64,
+    /// Times the console was polled for input.
+    polls: u64,
+    syscalls: u64,
+}
+
+/// What a syscall that reached the host did to the process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Syscall {
+    /// The value left in a0.
+    pub result: u64,
+    /// Guest memory written, in order.
+    pub stores: Vec<(u64, Vec<u8>)>,
+    /// `mmap` takes space for file mappings.
+    pub mmap_bottom: u64,
+}
+
+#[derive(Default)]
+pub struct Journal {
+    /// The process' `AT_RANDOM` bytes.
+    random: Option<[u8; 16]>,
+    /// Clock values by step, for the steps where the guest saw the clock.
+    times: Vec<(u64, u64)>,
+    /// The clock for the current step.
+    time: u64,
+    /// Input bytes by poll; polls that found nothing aren't kept.
+    input: Vec<(u64, u8)>,
+    syscalls: Vec<Syscall>,
+    position: Position,
+    /// How far the recording goes.
+    end: Position,
+    replaying: bool,
+}
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Journal").field("position", &self.position).field("end", &self.end)
+            .field("replaying", &self.replaying).finish_non_exhaustive()
+    }
+}
+
+impl Journal {
+    /// An empty journal, recording.
+    pub fn new() -> Journal {
+        Journal::default()
+    }
+
+    /// Reads a journal written by `save`, ready to replay from the start.
+    pub fn load(data: &[u8]) -> Result<Journal> {
+        let mut journal = Reader::new(data);
+        if journal.bytes(MAGIC.len()).ok() != Some(MAGIC) {
+            bail!("not a journal");
+        }
+        let version = journal.u32()?;
+        if version != VERSION {
+            bail!("journal format version {} isn't supported, only version {} is", version, VERSION);
+        }
+        let end = Position { steps: journal.u64()?, polls: journal.u64()?, syscalls: journal.u64()? };
+        let started = journal.bool()?;
+        let random = started.then_some(journal.bytes(16)?.try_into().unwrap());
+        let times = (0..journal.u64()?).map(|_| Ok((journal.u64()?, journal.u64()?))).collect::<Result<_>>()?;
+        let input = (0..journal.u64()?).map(|_| Ok((journal.u64()?, journal.u8()?))).collect::<Result<_>>()?;
+        let syscalls = (0..journal.u64()?).map(|_| {
+            let result = journal.u64()?;
+            let mmap_bottom = journal.u64()?;
+            let stores = (0..journal.u32()?).map(|_| {
+                let address = journal.u64()?;
+                let length = journal.u32()? as usize;
+                Ok((address, journal.bytes(length)?.to_vec()))
+            }).collect::<Result<_>>()?;
+            Ok(Syscall { result, stores, mmap_bottom })
+        }).collect::<Result<_>>()?;
+        journal.finish()?;
+        Ok(Journal { random, times, input, syscalls, time: 0, position: Position::default(), end, replaying: true })
+    }
+
+    /// Writes out everything recorded so far.
+    pub fn save(&self) -> Vec<u8> {
+        let end = self.recorded();
+        let mut journal = Writer::default();
+        journal.bytes(MAGIC);
+        journal.u32(VERSION);
+        for value in [end.steps, end.polls, end.syscalls] {
+            journal.u64(value);
+        }
+        journal.bool(self.random.is_some());
+        journal.bytes(&self.random.unwrap_or_default());
+        journal.u64(self.times.len() as u64);
+        for &(step, time) in &self.times {
+            journal.u64(step);
+            journal.u64(time);
+        }
+        journal.u64(self.input.len() as u64);
+        for &(poll, byte) in &self.input {
+            journal.u64(poll);
+            journal.u8(byte);
+        }
+        journal.u64(self.syscalls.len() as u64);
+        for syscall in &self.syscalls {
+            journal.u64(syscall.result);
+            journal.u64(syscall.mmap_bottom);
+            journal.u32(syscall.stores.len() as u32);
+            for (address, data) in &syscall.stores {
+                journal.u64(*address);
+                journal.u32(data.len() as u32);
+                journal.bytes(data);
+            }
+        }
+        journal.finish()
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Whether inputs come from the recording rather than the host.
+    pub fn replaying(&self) -> bool {
+        self.replaying
+    }
+
+    fn recorded(&self) -> Position {
+        if self.replaying { self.end } else { self.position }
+    }
+
+    /// Carries on from `position`, which must be one the run went through, replaying up to the end of
+    /// the recording.
+    pub fn seek(&mut self, position: Position) {
+        self.end = self.recorded();
+        self.position = position;
+        self.replaying = position.steps < self.end.steps;
+    }
+
+    /// Forgets everything recorded after the current position and records from here, for when the run
+    /// takes a different course.
+    pub fn truncate(&mut self) {
+        let position = self.position;
+        self.times.retain(|&(step, _)| step < position.steps);
+        self.input.retain(|&(poll, _)| poll < position.polls);
+        self.syscalls.truncate(position.syscalls as usize);
+        self.end = position;
+        self.replaying = false;
+    }
+
+    /// The randomness a process starts with: `read` from the host, or as recorded.
+    pub fn startup_random(&mut self, read: impl FnOnce() -> Result<[u8; 16]>) -> Result<[u8; 16]> {
+        match self.random {
+            Some(random) if self.replaying => Ok(random),
+            _ => {
+                let random = read()?;
+                self.random = Some(random);
+                Ok(random)
+            }
+        }
+    }
+
+    /// Starts the next step, returning the clock for it: `read` from the host, or as recorded. The
+    /// value only goes into the recording if the guest sees it, see `keep_time`.
+    pub fn step(&mut self, read: impl FnOnce() -> u64) -> u64 {
+        if self.replaying && self.position.steps >= self.end.steps {
+            self.replaying = false;
+        }
+        let step = self.position.steps;
+        self.position.steps += 1;
+        self.time = if self.replaying {
+            // the last time the guest saw, which is what it sees on this step if anything; before
+            // that the clock can be anything
+            let index = self.times.partition_point(|&(seen, _)| seen <= step);
+            index.checked_sub(1).map_or_else(read, |index| self.times[index].1)
+        } else {
+            read()
+        };
+        self.time
+    }
+
+    /// Records the clock for this step, because the guest sees it: it read the clock, set it, or a
+    /// timer interrupt came up or went away.
+    pub fn keep_time(&mut self) {
+        let Some(step) = self.position.steps.checked_sub(1) else { return };
+        if !self.replaying && self.times.last().is_none_or(|&(last, _)| last != step) {
+            self.times.push((step, self.time));
+        }
+    }
+
+    /// Whether the clock for this step is the one the run had: always when recording, and when
+    /// replaying on the steps where the guest saw it.
+    pub fn has_time(&self) -> bool {
+        let step = self.position.steps.wrapping_sub(1);
+        !self.replaying || self.times.binary_search_by_key(&step, |&(seen, _)| seen).is_ok()
+    }
+
+    /// The console input for this poll: `poll` the host, or as recorded.
+    pub fn input(&mut self, poll: impl FnOnce() -> Option<u8>) -> Option<u8> {
+        let index = self.position.polls;
+        self.position.polls += 1;
+        if self.replaying {
+            return self.input.binary_search_by_key(&index, |&(poll, _)| poll).ok().map(|found| self.input[found].1);
+        }
+        let byte = poll();
+        if let Some(byte) = byte {
+            self.input.push((index, byte));
+        }
+        byte
+    }
+
+    /// The next syscall that reached the host when replaying, or `None` to perform it and `record` it.
+    /// That includes a recording with fewer syscalls than the run makes.
+    pub fn replay_syscall(&mut self) -> Option<Syscall> {
+        if !self.replaying {
+            return None;
+        }
+        let syscall = self.syscalls.get(self.position.syscalls as usize)?.clone();
+        self.position.syscalls += 1;
+        Some(syscall)
+    }
+
+    pub fn record_syscall(&mut self, syscall: Syscall) {
+        self.syscalls.push(syscall);
+        self.position.syscalls += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records `steps` steps polling for a byte every step, reading the clock on two out of three and
+    /// making a syscall every other, from `source`.
+    fn record(journal: &mut Journal, steps: u64, mut source: impl FnMut(u64) -> u64) -> Vec<u64> {
+        let mut seen = Vec::new();
+        for _ in 0..steps {
+            let step = journal.position().steps;
+            let time = journal.step(|| source(step) / 3);
+            if step % 3 != 1 {
+                journal.keep_time();
+                seen.push(time);
+            }
+            seen.push(journal.input(|| source(step).is_multiple_of(4).then_some(step as u8)).map_or(u64::MAX, u64::from));
+            if step.is_multiple_of(2) {
+                let syscall = journal.replay_syscall().unwrap_or_else(|| {
+                    let syscall = Syscall { result: source(step), stores: vec![(step, vec![1, 2])], mmap_bottom: 7 };
+                    journal.record_syscall(syscall.clone());
+                    syscall
+                });
+                seen.push(syscall.result);
+            }
+        }
+        seen
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut journal = Journal::new();
+        let first = record(&mut journal, 100, |step| step * 7 % 13);
+        let middle = journal.position();
+        let second = record(&mut journal, 100, |step| step * 7 % 13);
+
+        journal.seek(Position::default());
+        assert!(journal.replaying());
+        let never = |_| -> u64 { panic!("replaying read the host") };
+        assert_eq!(record(&mut journal, 100, never), first);
+        assert_eq!(journal.position(), middle);
+        assert_eq!(record(&mut journal, 100, never), second);
+        // past the recording, inputs come from the host again
+        assert_eq!(record(&mut journal, 1, |_| 30), [10, u64::MAX, 30]);
+        assert!(!journal.replaying());
+
+        journal.startup_random(|| Ok([3; 16])).unwrap();
+        let mut loaded = Journal::load(&journal.save()).unwrap();
+        assert_eq!(loaded.startup_random(|| panic!("replaying read the host")).unwrap(), [3; 16]);
+        assert_eq!(record(&mut loaded, 200, never)[..], [first, second].concat());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut journal = Journal::new();
+        let first = record(&mut journal, 50, |step| step);
+        let middle = journal.position();
+        record(&mut journal, 50, |step| step);
+
+        journal.seek(middle);
+        journal.truncate();
+        assert!(!journal.replaying());
+        let other = record(&mut journal, 50, |step| step + 1000);
+        journal.seek(Position::default());
+        let never = |_| -> u64 { panic!("replaying read the host") };
+        assert_eq!(record(&mut journal, 100, never), [first, other].concat());
+    }
+
+    #[test]
+    fn test_time() {
+        let mut journal = Journal::new();
+        for step in 0..10 {
+            journal.step(|| step * 100);
+            if step == 4 || step == 7 {
+                journal.keep_time();
+            }
+        }
+        assert_eq!(journal.times, [(4, 400), (7, 700)]);
+
+        // before the guest first saw the clock, replaying asks the host
+        let mut loaded = Journal::load(&journal.save()).unwrap();
+        let times: Vec<_> = (0..10).map(|_| (loaded.step(|| 42), loaded.has_time())).collect();
+        assert_eq!(times, [
+            (42, false), (42, false), (42, false), (42, false), (400, true),
+            (400, false), (400, false), (700, true), (700, false), (700, false),
+        ]);
+    }
+
+    #[test]
+    fn test_syscalls_run_out() {
+        let mut journal = Journal::new();
+        journal.record_syscall(Syscall { result: 1, stores: Vec::new(), mmap_bottom: 0 });
+        let mut loaded = Journal::load(&journal.save()).unwrap();
+        assert_eq!(loaded.replay_syscall().map(|syscall| syscall.result), Some(1));
+        assert_eq!(loaded.replay_syscall(), None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let saved = Journal::new().save();
+        assert_eq!(Journal::load(b"RVSNAPSH").unwrap_err().to_string(), "not a journal");
+        assert!(Journal::load(&saved[..saved.len() - 1]).is_err());
+        assert!(Journal::load(&saved).is_ok());
+    }
+}
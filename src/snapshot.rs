@@ -0,0 +1,242 @@
+﻿//! Saving a whole machine to a file and restoring it later, to carry on exactly where it stopped.
+//!
+//! A snapshot starts with a magic number and a format version, followed by the hart, the bus and
+//! every mapped device in mapping order. RAM is stored as its non-zero pages only, so a mostly empty
+//! 64 MiB machine takes a few kilobytes. Restoring needs a machine with the same mappings, which is
+//! what running the same program with the same options sets up.
+//!
+//! The debugger keeps checkpoints in the same format in memory, to go back in time within a run.
+
+use anyhow::{bail, Result};
+
+use crate::bus::Bus;
+use crate::machine::Cpu;
+
+const MAGIC: &[u8; 8] = b"RVSNAPSH";
+/// Bumped whenever the layout changes, so older snapshots get rejected instead of misread.
+const VERSION: u32 = 2;
+
+/// Serializes state into a snapshot, little-endian.
+#[derive(Default)]
+pub struct Writer {
+    data: Vec<u8>,
+    checkpoint: bool,
+}
+
+impl Writer {
+    /// Whether this is a checkpoint, which leaves host state such as open files alone.
+    pub fn is_checkpoint(&self) -> bool {
+        self.checkpoint
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+}
+
+/// Reads back what a `Writer` wrote, failing when the snapshot ends early.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    checkpoint: bool,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, checkpoint: false }
+    }
+
+    pub fn is_checkpoint(&self) -> bool {
+        self.checkpoint
+    }
+
+    /// Checks that everything was read.
+    pub fn finish(self) -> Result<()> {
+        if !self.data.is_empty() {
+            bail!("the snapshot has {} bytes of trailing data", self.data.len());
+        }
+        Ok(())
+    }
+
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        if length > self.data.len() {
+            bail!("the snapshot is truncated");
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+}
+
+pub fn save(cpu: &Cpu, bus: &mut Bus) -> Result<Vec<u8>> {
+    save_as(cpu, bus, false)
+}
+
+/// Saves the guest state of a machine, to go back to it later in the same run with `rewind`.
+pub fn checkpoint(cpu: &Cpu, bus: &mut Bus) -> Result<Vec<u8>> {
+    save_as(cpu, bus, true)
+}
+
+fn save_as(cpu: &Cpu, bus: &mut Bus, checkpoint: bool) -> Result<Vec<u8>> {
+    let mut snapshot = Writer { checkpoint, ..Writer::default() };
+    snapshot.bytes(MAGIC);
+    snapshot.u32(VERSION);
+    cpu.save(&mut snapshot)?;
+    bus.save(&mut snapshot);
+    Ok(snapshot.data)
+}
+
+/// Puts a machine set up like the one that was saved back into the saved state.
+pub fn restore(cpu: &mut Cpu, bus: &mut Bus, data: &[u8]) -> Result<()> {
+    restore_as(cpu, bus, Reader::new(data))
+}
+
+pub fn rewind(cpu: &mut Cpu, bus: &mut Bus, checkpoint: &[u8]) -> Result<()> {
+    restore_as(cpu, bus, Reader { data: checkpoint, checkpoint: true })
+}
+
+fn restore_as(cpu: &mut Cpu, bus: &mut Bus, mut snapshot: Reader) -> Result<()> {
+    if snapshot.bytes(MAGIC.len()).ok() != Some(MAGIC) {
+        bail!("not a snapshot");
+    }
+    let version = snapshot.u32()?;
+    if version != VERSION {
+        bail!("snapshot format version {} isn't supported, only version {} is", version, VERSION);
+    }
+    cpu.restore(&mut snapshot)?;
+    bus.restore(&mut snapshot)?;
+    snapshot.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::{Ram, DRAM_BASE};
+    use crate::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+    use crate::machine::Machine;
+    use crate::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+    use crate::uart::{Uart, UART_BASE, UART_SIZE};
+
+    const MEMORY_SIZE: u64 = 0x10_0000;
+
+    /// Fills a buffer with a running sum, through the devices' registers as well as memory.
+    const PROGRAM: &str = "
+        li s0, 1000
+        la s1, buffer
+        li s2, 0x2004000                # mtimecmp
+        li s3, 0xC000028                # PLIC priority of source 10
+        li s4, 0x10000007               # UART scratch
+    loop:
+        add a0, a0, s0
+        sd a0, 0(s1)
+        sd a0, 0(s2)
+        sw s0, 0(s3)
+        sb a0, 0(s4)
+        addi s1, s1, 8
+        addi s0, s0, -1
+        bnez s0, loop
+        wfi
+        .data
+    buffer:
+    ";
+
+    fn machine(machine: &Machine) -> (Cpu<'_>, Bus) {
+        let mut bus = Bus::new();
+        bus.map(DRAM_BASE, MEMORY_SIZE, Ram::new(MEMORY_SIZE as usize));
+        bus.map(CLINT_BASE, CLINT_SIZE, Clint::new(None));
+        bus.map(PLIC_BASE, PLIC_SIZE, Plic::new());
+        bus.map(UART_BASE, UART_SIZE, Uart::new(mpsc::channel().1, io::sink()));
+        bus.store_bytes(DRAM_BASE, &assemble(PROGRAM, DRAM_BASE).unwrap().flat_image()).unwrap();
+        let mut cpu = Cpu::new(machine);
+        cpu.pc = DRAM_BASE;
+        (cpu, bus)
+    }
+
+    fn memory(bus: &mut Bus) -> Vec<u8> {
+        let mut memory = vec![0; MEMORY_SIZE as usize];
+        bus.load_bytes(DRAM_BASE, &mut memory).unwrap();
+        memory
+    }
+
+    #[test]
+    fn test_resume() {
+        // a stopped clock, so both runs see the same time
+        let stopped = Machine { timebase_frequency: 0, ..Machine::new() };
+        let (mut cpu, mut bus) = machine(&stopped);
+        cpu.run(&mut bus, Some(3000)).unwrap();
+        let snapshot = save(&cpu, &mut bus).unwrap();
+        assert!(snapshot.len() < 3 * 4096, "{} bytes", snapshot.len());
+        cpu.run(&mut bus, None).unwrap();
+
+        let (mut resumed, mut resumed_bus) = machine(&stopped);
+        restore(&mut resumed, &mut resumed_bus, &snapshot).unwrap();
+        assert_eq!(resumed.instructions_retired, 3000);
+        resumed.run(&mut resumed_bus, None).unwrap();
+        assert_eq!(resumed.registers, cpu.registers);
+        assert_eq!(resumed.pc, cpu.pc);
+        assert_eq!(resumed.instructions_retired, cpu.instructions_retired);
+        assert!(memory(&mut resumed_bus) == memory(&mut bus));
+        for address in [CLINT_BASE + 0x4000, PLIC_BASE + 0x28, UART_BASE + 7] {
+            assert_eq!(resumed_bus.load64(address), bus.load64(address));
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let clock = Machine::new();
+        let (cpu, mut bus) = machine(&clock);
+        let snapshot = save(&cpu, &mut bus).unwrap();
+        let (mut cpu, mut bus) = machine(&clock);
+
+        let mut newer = snapshot.clone();
+        newer[8] += 1;
+        let error = restore(&mut cpu, &mut bus, &newer).unwrap_err();
+        assert_eq!(error.to_string(), "snapshot format version 3 isn't supported, only version 2 is");
+        let error = restore(&mut cpu, &mut bus, &snapshot[..snapshot.len() - 1]).unwrap_err();
+        assert_eq!(error.to_string(), "the snapshot is truncated");
+        assert_eq!(restore(&mut cpu, &mut bus, b"hello").unwrap_err().to_string(), "not a snapshot");
+
+        let mut bigger = Bus::new();
+        bigger.map(DRAM_BASE, 2 * MEMORY_SIZE, Ram::new(2 * MEMORY_SIZE as usize));
+        assert!(restore(&mut cpu, &mut bigger, &snapshot).is_err());
+    }
+}
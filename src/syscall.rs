@@ -0,0 +1,739 @@
+﻿use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+use crate::bus::Bus;
+use crate::mmu::PAGE_SIZE;
+use crate::replay::{SharedJournal, Syscall};
+use crate::snapshot::{Reader, Writer};
+
+// syscall numbers from the generic Linux ABI that RISC-V uses
+const SYS_IOCTL: u64 = 29;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_WRITEV: u64 = 66;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETPID: u64 = 172;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_GETRANDOM: u64 = 278;
+
+const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+const ENOTTY: i32 = 25;
+const ESPIPE: i32 = 29;
+const ENOSYS: i32 = 38;
+
+const AT_FDCWD: i64 = -100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+const O_ACCMODE: u64 = 0o3;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const CLOCK_REALTIME: u64 = 0;
+
+const S_IFCHR: u64 = 0o020000;
+
+/// Largest single read or write; guests get a short count for anything bigger, as they must be ready for.
+const MAX_TRANSFER: u64 = 1 << 20;
+const MAX_PATH: u64 = 4096;
+
+/// The process' view of the program: emulated Linux syscalls for a statically linked user-mode binary.
+///
+/// Guest memory is accessed at physical addresses, so this only works with translation off.
+#[derive(Debug)]
+pub struct LinuxSyscalls {
+    files: Vec<Option<GuestFile>>,
+    initial_break: u64,
+    program_break: u64,
+    /// Lowest address handed out by `mmap`; anonymous mappings are allocated downwards from here.
+    mmap_bottom: u64,
+    deterministic: Option<Deterministic>,
+    /// When set, syscalls that reach the host are recorded there or replayed from it.
+    pub journal: Option<SharedJournal>,
+    /// Memory written by the syscall being recorded.
+    stores: Option<Vec<(u64, Vec<u8>)>>,
+    pub exit_code: Option<i32>,
+}
+
+/// Stand-ins for the host's nondeterministic inputs. The wall clock also starts at the Unix epoch.
+#[derive(Debug)]
+struct Deterministic {
+    random: Random,
+    stdin: VecDeque<u8>,
+}
+
+/// SplitMix64, the guest's randomness in deterministic mode.
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn fill(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next().to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum GuestFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    Host(File),
+}
+
+type SyscallResult = Result<u64, i32>;
+
+impl LinuxSyscalls {
+    /// `program_break` is where the heap starts and `mmap_top` the end of the area `mmap` may use.
+    pub fn new(program_break: u64, mmap_top: u64) -> LinuxSyscalls {
+        LinuxSyscalls {
+            files: vec![Some(GuestFile::Stdin), Some(GuestFile::Stdout), Some(GuestFile::Stderr)],
+            initial_break: program_break,
+            program_break,
+            mmap_bottom: mmap_top,
+            deterministic: None,
+            journal: None,
+            stores: None,
+            exit_code: None,
+        }
+    }
+
+    /// Makes the process repeat exactly: `getrandom` draws from `random` and reads from stdin get `stdin`,
+    /// the whole of it having been read beforehand.
+    pub fn make_deterministic(&mut self, random: Random, stdin: Vec<u8>) {
+        self.deterministic = Some(Deterministic { random, stdin: stdin.into() });
+    }
+
+    /// Appends the process state to a snapshot. Files the guest opened on the host can't be saved.
+    pub fn save(&self, snapshot: &mut Writer) -> Result<()> {
+        // files and the seeded generator carry on across checkpoints, replaying doesn't touch them
+        if !snapshot.is_checkpoint() {
+            snapshot.u32(self.files.len() as u32);
+            for file in &self.files {
+                snapshot.u8(match file {
+                    None => 0,
+                    Some(GuestFile::Stdin) => 1,
+                    Some(GuestFile::Stdout) => 2,
+                    Some(GuestFile::Stderr) => 3,
+                    Some(GuestFile::Host(_)) => bail!("can't save a process with files open"),
+                });
+            }
+        }
+        for value in [self.initial_break, self.program_break, self.mmap_bottom] {
+            snapshot.u64(value);
+        }
+        snapshot.bool(self.exit_code.is_some());
+        snapshot.u32(self.exit_code.unwrap_or_default() as u32);
+        if !snapshot.is_checkpoint() {
+            snapshot.bool(self.deterministic.is_some());
+            snapshot.u64(self.deterministic.as_ref().map_or(0, |deterministic| deterministic.random.state));
+        }
+        Ok(())
+    }
+
+    /// Reads back what `save` wrote.
+    pub fn restore(&mut self, snapshot: &mut Reader) -> Result<()> {
+        if !snapshot.is_checkpoint() {
+            let count = snapshot.u32()?;
+            self.files = (0..count).map(|_| match snapshot.u8()? {
+                0 => Ok(None),
+                1 => Ok(Some(GuestFile::Stdin)),
+                2 => Ok(Some(GuestFile::Stdout)),
+                3 => Ok(Some(GuestFile::Stderr)),
+                kind => bail!("unknown kind of file {}", kind),
+            }).collect::<Result<_>>()?;
+        }
+        for value in [&mut self.initial_break, &mut self.program_break, &mut self.mmap_bottom] {
+            *value = snapshot.u64()?;
+        }
+        let exited = snapshot.bool()?;
+        let exit_code = snapshot.u32()? as i32;
+        self.exit_code = exited.then_some(exit_code);
+        if !snapshot.is_checkpoint() {
+            // whether to be deterministic is up to the run doing the restoring
+            let seeded = snapshot.bool()?;
+            let state = snapshot.u64()?;
+            if let (true, Some(deterministic)) = (seeded, &mut self.deterministic) {
+                deterministic.random.state = state;
+            }
+        }
+        Ok(())
+    }
+
+    /// Performs the syscall numbered by a7 with arguments in a0 to a5, leaving the result or `-errno` in a0.
+    /// `elapsed` is the time since the machine started.
+    pub fn handle(&mut self, registers: &mut [u64; 32], bus: &mut Bus, elapsed: Duration) {
+        let number = registers[17];
+        let args: [u64; 6] = registers[10..16].try_into().unwrap();
+        registers[10] = match self.journal.clone().filter(|_| reaches_host(number, &args)) {
+            Some(journal) => self.journaled(&journal, number, args, bus, elapsed),
+            None => self.perform(number, args, bus, elapsed),
+        };
+    }
+
+    fn perform(&mut self, number: u64, args: [u64; 6], bus: &mut Bus, elapsed: Duration) -> u64 {
+        match self.dispatch(number, args, bus, elapsed) {
+            Ok(value) => value,
+            Err(errno) => (-(errno as i64)) as u64,
+        }
+    }
+
+    /// Performs a syscall and records what it did, or when replaying, does to the process what it did
+    /// the first time without going to the host.
+    fn journaled(&mut self, journal: &SharedJournal, number: u64, args: [u64; 6], bus: &mut Bus, elapsed: Duration) -> u64 {
+        let replayed = journal.borrow_mut().replay_syscall();
+        if let Some(syscall) = replayed {
+            for (address, data) in &syscall.stores {
+                // faults come back the same way they did the first time
+                let _ = bus.store_bytes(*address, data);
+            }
+            self.mmap_bottom = syscall.mmap_bottom;
+            return syscall.result;
+        }
+        self.stores = Some(Vec::new());
+        let result = self.perform(number, args, bus, elapsed);
+        let stores = self.stores.take().unwrap_or_default();
+        journal.borrow_mut().record_syscall(Syscall { result, stores, mmap_bottom: self.mmap_bottom });
+        result
+    }
+
+    fn dispatch(&mut self, number: u64, args: [u64; 6], bus: &mut Bus, elapsed: Duration) -> SyscallResult {
+        match number {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_code = Some(args[0] as i32);
+                Ok(0)
+            }
+            SYS_READ => self.read(bus, args[0], args[1], args[2]),
+            SYS_WRITE => self.write(bus, args[0], args[1], args[2]),
+            SYS_WRITEV => self.writev(bus, args[0], args[1], args[2]),
+            SYS_OPENAT => self.openat(bus, args[0] as i64, args[1], args[2], args[3]),
+            SYS_CLOSE => self.close(args[0]),
+            SYS_LSEEK => self.lseek(args[0], args[1] as i64, args[2]),
+            SYS_FSTAT => self.fstat(bus, args[0], args[1]),
+            SYS_NEWFSTATAT => self.newfstatat(bus, args[0] as i64, args[1], args[2], args[3]),
+            SYS_BRK => self.brk(bus, args[0]),
+            SYS_MMAP => self.mmap(bus, args[0], args[1], args[3], args[4], args[5]),
+            SYS_MUNMAP => self.munmap(bus, args[0], args[1]),
+            SYS_MPROTECT => Ok(0),
+            SYS_CLOCK_GETTIME => self.clock_gettime(bus, args[0], args[1], elapsed),
+            SYS_GETRANDOM => self.getrandom(bus, args[0], args[1]),
+            SYS_UNAME => self.uname(bus, args[0]),
+            // there's no terminal to configure, which also tells stdio not to line buffer
+            SYS_IOCTL => self.file(args[0]).and(Err(ENOTTY)),
+            // a single-threaded process with no signal delivery and root credentials
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(1),
+            SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => Ok(0),
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            _ => Err(ENOSYS),
+        }
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut GuestFile, i32> {
+        self.files.get_mut(fd as usize).and_then(Option::as_mut).ok_or(EBADF)
+    }
+
+    /// Writes guest memory, keeping a copy when the syscall is being recorded.
+    fn store_bytes(&mut self, bus: &mut Bus, address: u64, data: &[u8]) -> Result<(), i32> {
+        if let Some(stores) = &mut self.stores {
+            stores.push((address, data.to_vec()));
+        }
+        bus.store_bytes(address, data).map_err(|_| EFAULT)
+    }
+
+    fn zero(&mut self, bus: &mut Bus, address: u64, length: u64) -> Result<(), i32> {
+        self.store_bytes(bus, address, &vec![0; length as usize])
+    }
+
+    fn read(&mut self, bus: &mut Bus, fd: u64, buffer: u64, count: u64) -> SyscallResult {
+        let mut data = vec![0; count.min(MAX_TRANSFER) as usize];
+        let file = self.files.get_mut(fd as usize).and_then(Option::as_mut).ok_or(EBADF)?;
+        let length = match file {
+            GuestFile::Stdin => match &mut self.deterministic {
+                Some(deterministic) => deterministic.stdin.read(&mut data),
+                None => io::stdin().read(&mut data),
+            },
+            GuestFile::Stdout | GuestFile::Stderr => return Err(EBADF),
+            GuestFile::Host(file) => file.read(&mut data),
+        }.map_err(errno)?;
+        self.store_bytes(bus, buffer, &data[..length])?;
+        Ok(length as u64)
+    }
+
+    fn write(&mut self, bus: &mut Bus, fd: u64, buffer: u64, count: u64) -> SyscallResult {
+        let data = load_bytes(bus, buffer, count.min(MAX_TRANSFER))?;
+        let length = match self.file(fd)? {
+            GuestFile::Stdin => return Err(EBADF),
+            GuestFile::Stdout => io::stdout().write(&data).and_then(|length| io::stdout().flush().map(|_| length)),
+            GuestFile::Stderr => io::stderr().write(&data),
+            GuestFile::Host(file) => file.write(&data),
+        }.map_err(errno)?;
+        Ok(length as u64)
+    }
+
+    fn writev(&mut self, bus: &mut Bus, fd: u64, vectors: u64, count: u64) -> SyscallResult {
+        let mut total = 0;
+        for i in 0..count {
+            let base = load(bus, vectors + 16 * i)?;
+            let length = load(bus, vectors + 16 * i + 8)?;
+            let written = self.write(bus, fd, base, length)?;
+            total += written;
+            if written < length {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn openat(&mut self, bus: &mut Bus, directory: i64, path: u64, flags: u64, mode: u64) -> SyscallResult {
+        let path = load_string(bus, path)?;
+        if directory != AT_FDCWD && !path.starts_with('/') {
+            // descriptors don't remember their paths, so there's nothing to resolve against
+            return Err(ENOSYS);
+        }
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+            .mode(mode as u32);
+        let file = options.open(&path).map_err(errno)?;
+
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        self.files[fd] = Some(GuestFile::Host(file));
+        Ok(fd as u64)
+    }
+
+    fn close(&mut self, fd: u64) -> SyscallResult {
+        self.file(fd)?;
+        self.files[fd as usize] = None;
+        Ok(0)
+    }
+
+    fn lseek(&mut self, fd: u64, offset: i64, whence: u64) -> SyscallResult {
+        let position = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+        match self.file(fd)? {
+            GuestFile::Host(file) => file.seek(position).map_err(errno),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    fn fstat(&mut self, bus: &mut Bus, fd: u64, buffer: u64) -> SyscallResult {
+        let stat = match self.file(fd)? {
+            GuestFile::Host(file) => Stat::from(&file.metadata().map_err(errno)?),
+            _ => Stat::terminal(),
+        };
+        self.store_bytes(bus, buffer, &stat.to_bytes())?;
+        Ok(0)
+    }
+
+    fn newfstatat(&mut self, bus: &mut Bus, directory: i64, path: u64, buffer: u64, flags: u64) -> SyscallResult {
+        let path = load_string(bus, path)?;
+        if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+            return self.fstat(bus, directory as u64, buffer);
+        }
+        if directory != AT_FDCWD && !path.starts_with('/') {
+            return Err(ENOSYS);
+        }
+        let stat = Stat::from(&fs::metadata(&path).map_err(errno)?);
+        self.store_bytes(bus, buffer, &stat.to_bytes())?;
+        Ok(0)
+    }
+
+    fn brk(&mut self, bus: &mut Bus, address: u64) -> SyscallResult {
+        if address >= self.initial_break && address <= self.mmap_bottom {
+            // memory given back and then reused has to come back zeroed
+            if address > self.program_break {
+                self.zero(bus, self.program_break, address - self.program_break)?;
+            }
+            self.program_break = address;
+        }
+        Ok(self.program_break)
+    }
+
+    fn mmap(&mut self, bus: &mut Bus, address: u64, length: u64, flags: u64, fd: u64, offset: u64) -> SyscallResult {
+        if length == 0 {
+            return Err(EINVAL);
+        }
+        let length = length.next_multiple_of(PAGE_SIZE);
+        let address = if flags & MAP_FIXED != 0 {
+            self.zero(bus, address, length)?;
+            address
+        } else {
+            let bottom = self.mmap_bottom.checked_sub(length).ok_or(ENOMEM)?;
+            if bottom < self.program_break {
+                return Err(ENOMEM);
+            }
+            self.mmap_bottom = bottom;
+            bottom
+        };
+
+        if flags & MAP_ANONYMOUS == 0 {
+            // file mappings are private copies; writes never reach the file
+            let GuestFile::Host(file) = self.file(fd)? else { return Err(EBADF) };
+            let mut data = vec![0; length as usize];
+            let mut filled = 0;
+            while filled < data.len() {
+                match file.read_at(&mut data[filled..], offset + filled as u64).map_err(errno)? {
+                    0 => break,
+                    count => filled += count,
+                }
+            }
+            self.store_bytes(bus, address, &data)?;
+        }
+        Ok(address)
+    }
+
+    fn munmap(&mut self, bus: &mut Bus, address: u64, length: u64) -> SyscallResult {
+        let length = length.next_multiple_of(PAGE_SIZE);
+        self.zero(bus, address, length)?;
+        // only the lowest mapping can be handed out again; anything else stays reserved
+        if address == self.mmap_bottom {
+            self.mmap_bottom += length;
+        }
+        Ok(0)
+    }
+
+    fn clock_gettime(&mut self, bus: &mut Bus, clock: u64, buffer: u64, elapsed: Duration) -> SyscallResult {
+        let time = match clock {
+            CLOCK_REALTIME if self.deterministic.is_none() => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            _ => elapsed,
+        };
+        let mut timespec = [0; 16];
+        timespec[..8].copy_from_slice(&time.as_secs().to_le_bytes());
+        timespec[8..].copy_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+        self.store_bytes(bus, buffer, &timespec)?;
+        Ok(0)
+    }
+
+    fn getrandom(&mut self, bus: &mut Bus, buffer: u64, length: u64) -> SyscallResult {
+        let mut data = vec![0; length.min(MAX_TRANSFER) as usize];
+        match &mut self.deterministic {
+            Some(deterministic) => deterministic.random.fill(&mut data),
+            None => File::open("/dev/urandom").and_then(|mut random| random.read_exact(&mut data)).map_err(errno)?,
+        }
+        self.store_bytes(bus, buffer, &data)?;
+        Ok(data.len() as u64)
+    }
+
+    fn uname(&mut self, bus: &mut Bus, buffer: u64) -> SyscallResult {
+        // six fixed-size fields: sysname, nodename, release, version, machine, domainname
+        let fields = ["Linux", "yare", "6.1.0", "#1", "riscv64", ""];
+        let mut utsname = [0; 6 * 65];
+        for (i, field) in fields.iter().enumerate() {
+            utsname[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+        }
+        self.store_bytes(bus, buffer, &utsname)?;
+        Ok(0)
+    }
+}
+
+/// `struct stat` as laid out by the generic Linux ABI on 64-bit targets.
+struct Stat {
+    device: u64,
+    inode: u64,
+    mode: u64,
+    links: u64,
+    user: u64,
+    group: u64,
+    special_device: u64,
+    size: u64,
+    block_size: u64,
+    blocks: u64,
+    times: [(i64, i64); 3],
+}
+
+impl Stat {
+    fn from(metadata: &fs::Metadata) -> Stat {
+        Stat {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+            mode: metadata.mode() as u64,
+            links: metadata.nlink(),
+            user: metadata.uid() as u64,
+            group: metadata.gid() as u64,
+            special_device: metadata.rdev(),
+            size: metadata.size(),
+            block_size: metadata.blksize(),
+            blocks: metadata.blocks(),
+            times: [
+                (metadata.atime(), metadata.atime_nsec()),
+                (metadata.mtime(), metadata.mtime_nsec()),
+                (metadata.ctime(), metadata.ctime_nsec()),
+            ],
+        }
+    }
+
+    /// What the standard streams look like: a character device.
+    fn terminal() -> Stat {
+        Stat {
+            device: 0,
+            inode: 0,
+            mode: S_IFCHR | 0o620,
+            links: 1,
+            user: 0,
+            group: 0,
+            special_device: 0,
+            size: 0,
+            block_size: 1024,
+            blocks: 0,
+            times: [(0, 0); 3],
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 128] {
+        let mut bytes = [0; 128];
+        let mut put = |offset: usize, value: u64, size: usize| {
+            bytes[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+        };
+        put(0, self.device, 8);
+        put(8, self.inode, 8);
+        put(16, self.mode, 4);
+        put(20, self.links, 4);
+        put(24, self.user, 4);
+        put(28, self.group, 4);
+        put(32, self.special_device, 8);
+        put(48, self.size, 8);
+        put(56, self.block_size, 4);
+        put(64, self.blocks, 8);
+        for (i, (seconds, nanoseconds)) in self.times.iter().enumerate() {
+            put(72 + 16 * i, *seconds as u64, 8);
+            put(80 + 16 * i, *nanoseconds as u64, 8);
+        }
+        bytes
+    }
+}
+
+fn errno(error: io::Error) -> i32 {
+    error.raw_os_error().unwrap_or(EINVAL)
+}
+
+fn load(bus: &mut Bus, address: u64) -> Result<u64, i32> {
+    bus.load(address, 8).map_err(|_| EFAULT)
+}
+
+fn load_bytes(bus: &mut Bus, address: u64, length: u64) -> Result<Vec<u8>, i32> {
+    let mut data = vec![0; length as usize];
+    bus.load_bytes(address, &mut data).map_err(|_| EFAULT)?;
+    Ok(data)
+}
+
+fn load_string(bus: &mut Bus, address: u64) -> Result<String, i32> {
+    let mut bytes = Vec::new();
+    for i in 0..MAX_PATH {
+        match bus.load(address + i, 1).map_err(|_| EFAULT)? {
+            0 => return String::from_utf8(bytes).map_err(|_| EINVAL),
+            byte => bytes.push(byte as u8),
+        }
+    }
+    Err(EINVAL)
+}
+
+/// Whether a syscall depends on the host or changes it, so replaying can't just perform it again.
+fn reaches_host(number: u64, args: &[u64; 6]) -> bool {
+    match number {
+        SYS_READ | SYS_WRITE | SYS_WRITEV | SYS_OPENAT | SYS_CLOSE | SYS_LSEEK | SYS_FSTAT | SYS_NEWFSTATAT
+            | SYS_IOCTL | SYS_CLOCK_GETTIME | SYS_GETRANDOM => true,
+        SYS_MMAP => args[3] & MAP_ANONYMOUS == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::bus::Ram;
+    use crate::replay::{Journal, Position};
+
+    const MEMORY: u64 = 0x10_0000;
+
+    fn syscall(linux: &mut LinuxSyscalls, bus: &mut Bus, number: u64, args: &[u64]) -> i64 {
+        let mut registers = [0; 32];
+        registers[17] = number;
+        registers[10..10 + args.len()].copy_from_slice(args);
+        linux.handle(&mut registers, bus, Duration::ZERO);
+        registers[10] as i64
+    }
+
+    fn process() -> (LinuxSyscalls, Bus) {
+        let mut bus = Bus::new();
+        bus.map(0, MEMORY, Ram::new(MEMORY as usize));
+        (LinuxSyscalls::new(0x1_0000, MEMORY), bus)
+    }
+
+    #[test]
+    fn test_memory_management() {
+        let (mut linux, mut bus) = process();
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_BRK, &[0]), 0x1_0000);
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_BRK, &[0x1_2345]), 0x1_2345);
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_BRK, &[0x100]), 0x1_2345);
+
+        let first = syscall(&mut linux, &mut bus, SYS_MMAP, &[0, 0x1800, 3, 0x22, u64::MAX, 0]);
+        assert_eq!(first, (MEMORY - 0x2000) as i64);
+        let second = syscall(&mut linux, &mut bus, SYS_MMAP, &[0, 0x1000, 3, 0x22, u64::MAX, 0]);
+        assert_eq!(second, (MEMORY - 0x3000) as i64);
+        bus.store64(second as u64, 0xFF).unwrap();
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_MUNMAP, &[second as u64, 0x1000]), 0);
+        // the freed mapping comes back zeroed
+        let third = syscall(&mut linux, &mut bus, SYS_MMAP, &[0, 0x1000, 3, 0x22, u64::MAX, 0]);
+        assert_eq!(third, second);
+        assert_eq!(bus.load64(third as u64), Ok(0));
+
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_MMAP, &[0, MEMORY, 3, 0x22, u64::MAX, 0]), -(ENOMEM as i64));
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_BRK, &[MEMORY - 0x1000]), 0x1_2345);
+    }
+
+    #[test]
+    fn test_files() {
+        let path = std::env::temp_dir().join(format!("yare-syscall-test-{}", std::process::id()));
+        let (mut linux, mut bus) = process();
+        bus.store_bytes(0x100, path.to_str().unwrap().as_bytes()).unwrap();
+        bus.store_bytes(0x200, b"hello, world").unwrap();
+
+        let fd = syscall(&mut linux, &mut bus, SYS_OPENAT, &[AT_FDCWD as u64, 0x100, O_RDWR | O_CREAT | O_TRUNC, 0o600]);
+        assert_eq!(fd, 3);
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_WRITE, &[3, 0x200, 12]), 12);
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_LSEEK, &[3, 7, 0]), 7);
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_READ, &[3, 0x300, 100]), 5);
+        assert_eq!(bus.load32(0x300), Ok(u32::from_le_bytes(*b"worl") as u64));
+
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_FSTAT, &[3, 0x400]), 0);
+        assert_eq!(bus.load64(0x400 + 48), Ok(12));
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_FSTAT, &[1, 0x400]), 0);
+        assert_eq!(bus.load32(0x400 + 16), Ok(S_IFCHR | 0o620));
+
+        let mapping = syscall(&mut linux, &mut bus, SYS_MMAP, &[0, 5, 1, 0x2, 3, 7]);
+        assert_eq!(bus.load64(mapping as u64), Ok(u64::from_le_bytes(*b"world\0\0\0")));
+
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_CLOSE, &[3]), 0);
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_CLOSE, &[3]), -(EBADF as i64));
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_READ, &[3, 0x300, 1]), -(EBADF as i64));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_OPENAT, &[AT_FDCWD as u64, 0x100, 0, 0]), -2);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let (mut linux, mut bus) = process();
+        linux.make_deterministic(Random::new(1), b"typed".to_vec());
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_GETRANDOM, &[0x100, 12]), 12);
+        let mut expected = [0; 12];
+        Random::new(1).fill(&mut expected);
+        let mut random = [0; 12];
+        bus.load_bytes(0x100, &mut random).unwrap();
+        assert_eq!(random, expected);
+        assert_ne!(random, [0; 12]);
+
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_READ, &[0, 0x200, 3]), 3);
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_READ, &[0, 0x203, 100]), 2);
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_READ, &[0, 0x205, 100]), 0);
+        assert_eq!(bus.load64(0x200), Ok(u64::from_le_bytes(*b"typed\0\0\0")));
+
+        // the wall clock starts at the epoch
+        assert_eq!(syscall(&mut linux, &mut bus, SYS_CLOCK_GETTIME, &[CLOCK_REALTIME, 0x300]), 0);
+        assert_eq!(bus.load64(0x300), Ok(0));
+    }
+
+    #[test]
+    fn test_replay() {
+        let journal = Rc::new(RefCell::new(Journal::new()));
+        let calls = |linux: &mut LinuxSyscalls, bus: &mut Bus| {
+            linux.journal = Some(journal.clone());
+            let mut results = Vec::new();
+            for (number, args) in [(SYS_GETRANDOM, [0x100, 16]), (SYS_BRK, [0x1_2000, 0]), (SYS_CLOSE, [2, 0]), (SYS_FSTAT, [2, 0x200])] {
+                journal.borrow_mut().step(|| 0);
+                results.push(syscall(linux, bus, number, &args));
+            }
+            let mut memory = vec![0; 0x300];
+            bus.load_bytes(0, &mut memory).unwrap();
+            (results, memory)
+        };
+        let (mut linux, mut bus) = process();
+        let (results, memory) = calls(&mut linux, &mut bus);
+        assert_eq!(results[1..], [0x1_2000, 0, -(EBADF as i64)]);
+
+        // the replay gets the same randomness and still has stderr, but brk runs again
+        journal.borrow_mut().seek(Position::default());
+        let (mut replayed, mut replayed_bus) = process();
+        assert_eq!(calls(&mut replayed, &mut replayed_bus), (results, memory));
+        assert!(replayed.file(2).is_ok());
+        assert_eq!(replayed.program_break, 0x1_2000);
+    }
+
+    #[test]
+    fn test_exit() {
+        let (mut linux, mut bus) = process();
+        assert_eq!(syscall(&mut linux, &mut bus, 1234, &[]), -(ENOSYS as i64));
+        assert_eq!(linux.exit_code, None);
+        syscall(&mut linux, &mut bus, SYS_EXIT_GROUP, &[3]);
+        assert_eq!(linux.exit_code, Some(3));
+    }
+}
@@ -0,0 +1,177 @@
+﻿use std::fmt;
+
+use crate::mmu::AccessType;
+
+/// Privilege levels, numbered the way they are encoded in `mstatus.MPP`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Decodes a privilege field; unsupported encodings fall back to user mode.
+    pub fn from_bits(bits: u64) -> Privilege {
+        match bits & 3 {
+            1 => Privilege::Supervisor,
+            3 => Privilege::Machine,
+            _ => Privilege::User,
+        }
+    }
+}
+
+/// Synchronous exception along with the value reported in `mtval`/`stval`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAccessFault(u64),
+    IllegalInstruction(u64),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+}
+
+impl Exception {
+    pub fn cause(&self) -> u64 {
+        match self {
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromMMode => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        match *self {
+            Exception::InstructionAccessFault(value)
+            | Exception::IllegalInstruction(value)
+            | Exception::Breakpoint(value)
+            | Exception::LoadAddressMisaligned(value)
+            | Exception::LoadAccessFault(value)
+            | Exception::StoreAddressMisaligned(value)
+            | Exception::StoreAccessFault(value)
+            | Exception::InstructionPageFault(value)
+            | Exception::LoadPageFault(value)
+            | Exception::StorePageFault(value) => value,
+            Exception::EnvironmentCallFromUMode | Exception::EnvironmentCallFromSMode | Exception::EnvironmentCallFromMMode => 0,
+        }
+    }
+}
+
+/// Interrupt sources, valued with their bit position in `mip`/`mie` and their cause code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+    MachineExternal = 11,
+}
+
+impl Interrupt {
+    /// Interrupts in the order they are taken when several are pending at once.
+    pub const PRIORITY: [Interrupt; 6] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+    ];
+
+    pub fn cause(&self) -> u64 { *self as u64 }
+    pub fn mask(&self) -> u64 { 1 << (*self as u64) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    Exception(Exception),
+    Interrupt(Interrupt),
+}
+
+impl Trap {
+    /// Value written to `mcause`/`scause`, with the top bit marking interrupts.
+    pub fn cause(&self) -> u64 {
+        match self {
+            Trap::Exception(exception) => exception.cause(),
+            Trap::Interrupt(interrupt) => interrupt.cause() | (1 << 63),
+        }
+    }
+}
+
+/// How a step ended, when the guest can go on running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// An instruction executed.
+    Retired,
+    /// The guest's trap handler took an exception or interrupt.
+    Trapped(Trap),
+    /// The guest exited with this code, through a syscall or the host-target interface.
+    Exited(i32),
+    /// The hart waits for an interrupt that can't arrive, as none are enabled in `mie`.
+    Halted,
+}
+
+/// An exception the guest has no handler for. The hart is left as it was before the instruction,
+/// so an embedder can report it, fix things up and carry on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    IllegalInstruction { pc: u64, encoding: u32 },
+    /// A misaligned, unmapped or unpermitted access, or a page fault.
+    MemoryFault { pc: u64, address: u64, access: AccessType },
+    Breakpoint { pc: u64 },
+    /// Any other exception, such as an environment call.
+    Unhandled { pc: u64, exception: Exception },
+}
+
+impl ExecError {
+    pub fn new(pc: u64, exception: Exception) -> ExecError {
+        let memory_fault = |address, access| ExecError::MemoryFault { pc, address, access };
+        match exception {
+            Exception::IllegalInstruction(encoding) => ExecError::IllegalInstruction { pc, encoding: encoding as u32 },
+            Exception::Breakpoint(_) => ExecError::Breakpoint { pc },
+            Exception::InstructionAccessFault(address) | Exception::InstructionPageFault(address) => memory_fault(address, AccessType::Instruction),
+            Exception::LoadAddressMisaligned(address) | Exception::LoadAccessFault(address) | Exception::LoadPageFault(address) => memory_fault(address, AccessType::Load),
+            Exception::StoreAddressMisaligned(address) | Exception::StoreAccessFault(address) | Exception::StorePageFault(address) => memory_fault(address, AccessType::Store),
+            _ => ExecError::Unhandled { pc, exception },
+        }
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::IllegalInstruction { pc, encoding } => write!(f, "illegal instruction 0x{:08x} at 0x{:x}", encoding, pc),
+            ExecError::MemoryFault { pc, address, access } => {
+                let access = match access {
+                    AccessType::Instruction => "fetching",
+                    AccessType::Load => "loading from",
+                    AccessType::Store => "storing to",
+                };
+                write!(f, "memory fault {} 0x{:x} at 0x{:x}", access, address, pc)
+            }
+            ExecError::Breakpoint { pc } => write!(f, "breakpoint at 0x{:x}", pc),
+            ExecError::Unhandled { pc, exception } => write!(f, "unhandled {:?} at 0x{:x}", exception, pc),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
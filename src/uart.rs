@@ -0,0 +1,359 @@
+﻿use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use anyhow::{bail, Result};
+
+use crate::bus::{Device, InterruptLines};
+use crate::replay::SharedJournal;
+use crate::snapshot::{Reader, Writer};
+
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
+/// PLIC source the UART interrupt is wired to, as on QEMU's virt board.
+pub const UART_IRQ: u32 = 10;
+
+const RBR_THR_DLL: u64 = 0;
+const IER_DLM: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+const IER_TRANSMITTER_EMPTY: u8 = 1 << 1;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_TRANSMITTER_EMPTY: u8 = 0x02;
+const IIR_RECEIVED_DATA: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RECEIVER: u8 = 1 << 1;
+
+const LCR_DIVISOR_LATCH: u8 = 1 << 7;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TRANSMITTER_HOLDING_EMPTY: u8 = 1 << 5;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+/// Carrier detect, data set ready and clear to send: the line always looks connected.
+const MSR_CONNECTED: u8 = 0xB0;
+
+const FIFO_SIZE: usize = 16;
+
+/// An NS16550A serial port. Transmitted bytes go straight out, so the transmitter is always empty.
+pub struct Uart {
+    input: ConsoleInput,
+    output: Box<dyn Write>,
+    receiver: VecDeque<u8>,
+    interrupt_enable: u8,
+    fifo_control: u8,
+    line_control: u8,
+    modem_control: u8,
+    scratch: u8,
+    divisor: u16,
+    /// The "transmitter holding register empty" interrupt is edge-like: raised when the register empties
+    /// (or the interrupt gets enabled) and acknowledged by reading IIR or writing THR.
+    transmitter_interrupt: bool,
+}
+
+/// Where a console device gets bytes from the host, going through a journal when the run is recorded.
+pub struct ConsoleInput {
+    receiver: Receiver<u8>,
+    journal: Option<SharedJournal>,
+}
+
+impl ConsoleInput {
+    pub fn new(receiver: Receiver<u8>, journal: Option<SharedJournal>) -> ConsoleInput {
+        ConsoleInput { receiver, journal }
+    }
+
+    /// The next byte, if one has arrived.
+    pub fn poll(&self) -> Option<u8> {
+        let poll = || self.receiver.try_recv().ok();
+        match &self.journal {
+            Some(journal) => journal.borrow_mut().input(poll),
+            None => poll(),
+        }
+    }
+
+    /// Whether the run is being replayed, when output already went out the first time through.
+    pub fn replaying(&self) -> bool {
+        self.journal.as_ref().is_some_and(|journal| journal.borrow().replaying())
+    }
+}
+
+impl From<Receiver<u8>> for ConsoleInput {
+    fn from(receiver: Receiver<u8>) -> ConsoleInput {
+        ConsoleInput::new(receiver, None)
+    }
+}
+
+/// Reads the host's stdin on a background thread, so console devices can poll it without blocking.
+pub fn spawn_stdin_reader() -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut buffer = [0; 256];
+        while let Ok(count @ 1..) = stdin.read(&mut buffer) {
+            if buffer[..count].iter().any(|byte| sender.send(*byte).is_err()) {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Input that has been read already, all of it available to a console device from the start.
+pub fn queued_input(bytes: &[u8]) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    for byte in bytes {
+        sender.send(*byte).unwrap();
+    }
+    receiver
+}
+
+impl Uart {
+    pub fn new(input: impl Into<ConsoleInput>, output: impl Write + 'static) -> Uart {
+        Uart {
+            input: input.into(),
+            output: Box::new(output),
+            receiver: VecDeque::with_capacity(FIFO_SIZE),
+            interrupt_enable: 0,
+            fifo_control: 0,
+            line_control: 0,
+            modem_control: 0,
+            scratch: 0,
+            divisor: 0,
+            transmitter_interrupt: false,
+        }
+    }
+
+    fn poll_input(&mut self) {
+        while self.receiver.len() < FIFO_SIZE {
+            match self.input.poll() {
+                Some(byte) => self.receiver.push_back(byte),
+                None => break,
+            }
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.modem_control & MCR_LOOPBACK != 0 {
+            if self.receiver.len() < FIFO_SIZE {
+                self.receiver.push_back(byte);
+            }
+        } else if !self.input.replaying() {
+            // the guest has no way to learn about a broken stdout, so the byte is dropped
+            let _ = self.output.write_all(&[byte]).and_then(|_| self.output.flush());
+        }
+        self.transmitter_interrupt = true;
+    }
+
+    fn interrupt_identification(&self) -> u8 {
+        let fifo = if self.fifo_control & FCR_FIFO_ENABLE != 0 { IIR_FIFO_ENABLED } else { 0 };
+        let source = if self.interrupt_enable & IER_RECEIVED_DATA != 0 && !self.receiver.is_empty() {
+            IIR_RECEIVED_DATA
+        } else if self.interrupt_enable & IER_TRANSMITTER_EMPTY != 0 && self.transmitter_interrupt {
+            IIR_TRANSMITTER_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        };
+        fifo | source
+    }
+
+    fn modem_status(&self) -> u8 {
+        if self.modem_control & MCR_LOOPBACK == 0 {
+            return MSR_CONNECTED;
+        }
+        // loopback feeds RTS, DTR, OUT1 and OUT2 back into CTS, DSR, RI and DCD
+        let mcr = self.modem_control;
+        ((mcr & 0b0010) << 3) | ((mcr & 0b0001) << 5) | ((mcr & 0b1100) << 4)
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u64, _size: u64) -> Option<u64> {
+        let divisor_latch = self.line_control & LCR_DIVISOR_LATCH != 0;
+        let value = match offset {
+            RBR_THR_DLL if divisor_latch => self.divisor as u8,
+            RBR_THR_DLL => {
+                let byte = self.receiver.pop_front().unwrap_or(0);
+                self.poll_input();
+                byte
+            }
+            IER_DLM if divisor_latch => (self.divisor >> 8) as u8,
+            IER_DLM => self.interrupt_enable,
+            IIR_FCR => {
+                let identification = self.interrupt_identification();
+                if identification & 0x0F == IIR_TRANSMITTER_EMPTY {
+                    self.transmitter_interrupt = false;
+                }
+                identification
+            }
+            LCR => self.line_control,
+            MCR => self.modem_control,
+            LSR => {
+                self.poll_input();
+                let data_ready = if self.receiver.is_empty() { 0 } else { LSR_DATA_READY };
+                data_ready | LSR_TRANSMITTER_HOLDING_EMPTY | LSR_TRANSMITTER_EMPTY
+            }
+            MSR => self.modem_status(),
+            SCR => self.scratch,
+            _ => 0,
+        };
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, _size: u64, value: u64) -> Option<()> {
+        let value = value as u8;
+        let divisor_latch = self.line_control & LCR_DIVISOR_LATCH != 0;
+        match offset {
+            RBR_THR_DLL if divisor_latch => self.divisor = (self.divisor & 0xFF00) | value as u16,
+            RBR_THR_DLL => self.transmit(value),
+            IER_DLM if divisor_latch => self.divisor = (self.divisor & 0x00FF) | ((value as u16) << 8),
+            IER_DLM => {
+                let enabling_transmitter = value & !self.interrupt_enable & IER_TRANSMITTER_EMPTY != 0;
+                self.interrupt_enable = value & 0x0F;
+                if enabling_transmitter {
+                    self.transmitter_interrupt = true;
+                }
+            }
+            IIR_FCR => {
+                if value & FCR_CLEAR_RECEIVER != 0 {
+                    self.receiver.clear();
+                }
+                self.fifo_control = value & !0b110;
+            }
+            LCR => self.line_control = value,
+            MCR => self.modem_control = value & 0x1F,
+            SCR => self.scratch = value,
+            _ => (),
+        }
+        Some(())
+    }
+
+    fn tick(&mut self, interrupts: &mut InterruptLines) {
+        self.poll_input();
+        let pending = self.interrupt_identification() & IIR_NO_INTERRUPT == 0;
+        interrupts.set_source(UART_IRQ, pending);
+    }
+
+    fn save(&self, snapshot: &mut Writer) {
+        snapshot.u8(self.receiver.len() as u8);
+        snapshot.bytes(&self.receiver.iter().copied().collect::<Vec<_>>());
+        for register in [self.interrupt_enable, self.fifo_control, self.line_control, self.modem_control, self.scratch] {
+            snapshot.u8(register);
+        }
+        snapshot.u32(self.divisor as u32);
+        snapshot.bool(self.transmitter_interrupt);
+    }
+
+    fn restore(&mut self, snapshot: &mut Reader) -> Result<()> {
+        let received = snapshot.u8()? as usize;
+        if received > FIFO_SIZE {
+            bail!("{} bytes in a {}-byte receive FIFO", received, FIFO_SIZE);
+        }
+        self.receiver = snapshot.bytes(received)?.iter().copied().collect();
+        for register in [&mut self.interrupt_enable, &mut self.fifo_control, &mut self.line_control, &mut self.modem_control, &mut self.scratch] {
+            *register = snapshot.u8()?;
+        }
+        self.divisor = snapshot.u32()? as u16;
+        self.transmitter_interrupt = snapshot.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::mpsc::Sender;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn uart() -> (Uart, Sender<u8>, Output) {
+        let (sender, receiver) = mpsc::channel();
+        let output = Output::default();
+        (Uart::new(receiver, output.clone()), sender, output)
+    }
+
+    #[test]
+    fn test_polling() {
+        let (mut uart, input, output) = uart();
+        assert_eq!(uart.read(LSR, 1), Some(0x60));
+        for byte in b"hi\n" {
+            uart.write(RBR_THR_DLL, 1, *byte as u64);
+        }
+        assert_eq!(*output.0.borrow(), b"hi\n");
+
+        input.send(b'x').unwrap();
+        input.send(b'y').unwrap();
+        assert_eq!(uart.read(LSR, 1), Some(0x61));
+        assert_eq!(uart.read(RBR_THR_DLL, 1), Some(b'x' as u64));
+        assert_eq!(uart.read(RBR_THR_DLL, 1), Some(b'y' as u64));
+        assert_eq!(uart.read(LSR, 1), Some(0x60));
+
+        // the divisor latch shadows the data and interrupt enable registers
+        uart.write(LCR, 1, 0x83);
+        uart.write(RBR_THR_DLL, 1, 0x34);
+        uart.write(IER_DLM, 1, 0x12);
+        uart.write(LCR, 1, 0x03);
+        assert_eq!(uart.divisor, 0x1234);
+        assert_eq!(uart.read(IER_DLM, 1), Some(0));
+        assert_eq!(output.0.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let (mut uart, input, _) = uart();
+        let mut interrupts = InterruptLines::default();
+        uart.write(IIR_FCR, 1, 0x07);
+        uart.write(IER_DLM, 1, (IER_RECEIVED_DATA | IER_TRANSMITTER_EMPTY) as u64);
+
+        uart.tick(&mut interrupts);
+        assert_ne!(interrupts.sources & (1 << UART_IRQ), 0);
+        assert_eq!(uart.read(IIR_FCR, 1), Some(0xC2));
+        // reading IIR acknowledged the transmitter interrupt
+        assert_eq!(uart.read(IIR_FCR, 1), Some(0xC1));
+        uart.tick(&mut interrupts);
+        assert_eq!(interrupts.sources & (1 << UART_IRQ), 0);
+
+        input.send(b'a').unwrap();
+        uart.tick(&mut interrupts);
+        assert_ne!(interrupts.sources & (1 << UART_IRQ), 0);
+        assert_eq!(uart.read(IIR_FCR, 1), Some(0xC4));
+        assert_eq!(uart.read(RBR_THR_DLL, 1), Some(b'a' as u64));
+        uart.tick(&mut interrupts);
+        assert_eq!(interrupts.sources & (1 << UART_IRQ), 0);
+    }
+
+    #[test]
+    fn test_loopback() {
+        let (mut uart, _, output) = uart();
+        uart.write(MCR, 1, 0x1A);
+        assert_eq!(uart.read(MSR, 1), Some(0x90));
+        uart.write(RBR_THR_DLL, 1, 0x55);
+        assert_eq!(uart.read(RBR_THR_DLL, 1), Some(0x55));
+        assert!(output.0.borrow().is_empty());
+    }
+}
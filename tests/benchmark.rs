@@ -0,0 +1,73 @@
+﻿mod common;
+
+use std::fs;
+
+use common::{run, temp_path};
+
+/// Sieve of Eratosthenes over 64 KiB, repeated, then exit through tohost.
+const SIEVE: &str = "
+    .text
+_start:
+    li s0, 10               # rounds
+round:
+    la a0, flags
+    li a1, 65536
+    mv t0, zero
+1:  add t1, a0, t0          # clear
+    sb zero, 0(t1)
+    addi t0, t0, 1
+    blt t0, a1, 1b
+    li t0, 2
+2:  add t1, a0, t0
+    lbu t2, 0(t1)
+    bnez t2, 4f
+    add t3, t0, t0
+3:  bge t3, a1, 4f
+    add t1, a0, t3
+    li t2, 1
+    sb t2, 0(t1)
+    add t3, t3, t0
+    j 3b
+4:  addi t0, t0, 1
+    blt t0, a1, 2b
+    addi s0, s0, -1
+    bnez s0, round
+    la t0, tohost
+    li t1, 1
+    sd t1, 0(t0)
+5:  j 5b
+    .data
+    .align 3
+tohost: .dword 0
+fromhost: .dword 0
+flags: .zero 65536
+";
+
+/// Runs the program with `--stats`, returning the instruction count and MIPS it printed.
+fn measure(program: &str, extra: &[&str]) -> (u64, f64) {
+    let args: Vec<&str> = extra.iter().copied().chain(["--stats", program]).collect();
+    let output = run(&args);
+    assert!(output.status.success());
+    let stats = String::from_utf8(output.stderr).unwrap();
+    let words: Vec<&str> = stats.split_whitespace().collect();
+    (words[0].parse().unwrap(), words[4].parse().unwrap())
+}
+
+/// Speed with and without the decode cache. Run with
+/// `cargo test --release --test benchmark -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn benchmark_decode_cache() {
+    let source = temp_path("sieve.s");
+    let program = temp_path("sieve");
+    fs::write(&source, SIEVE).unwrap();
+    assert!(run(&["asm", "-o", program.to_str().unwrap(), source.to_str().unwrap()]).status.success());
+    let program_path = program.to_str().unwrap();
+
+    let (uncached_count, uncached) = measure(program_path, &["--no-decode-cache"]);
+    let (cached_count, cached) = measure(program_path, &[]);
+    fs::remove_file(&source).unwrap();
+    fs::remove_file(&program).unwrap();
+    assert_eq!(cached_count, uncached_count);
+    println!("{} instructions: {:.2} MIPS without the decode cache, {:.2} MIPS with it", cached_count, uncached, cached);
+}
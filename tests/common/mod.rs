@@ -0,0 +1,102 @@
+﻿//! Helpers shared by the integration tests, which drive the emulator binary.
+
+// every test crate compiles its own copy and uses only some of the helpers
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+pub fn emulator() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_untitled1"))
+}
+
+pub fn run(args: &[&str]) -> Output {
+    emulator().args(args).output().expect("can't run the emulator")
+}
+
+/// A scratch file path unique to this test process.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("yare-{}-{}", std::process::id(), name))
+}
+
+/// Writes a statically linked RISC-V executable with a single segment loaded at `base`, plus a symbol
+/// table so the emulator can find symbols like `tohost`.
+pub fn write_elf(path: &Path, base: u64, code: &[u8], symbols: &[(&str, u64)]) {
+    const CODE_OFFSET: usize = 0x1000;
+
+    let mut strings = vec![0u8];
+    let mut symbol_table = vec![0u8; 24];
+    for (name, value) in symbols {
+        let name_offset = strings.len() as u32;
+        strings.extend_from_slice(name.as_bytes());
+        strings.push(0);
+        symbol_table.extend_from_slice(&name_offset.to_le_bytes());
+        symbol_table.push(0x10); // global, no type
+        symbol_table.push(0);
+        symbol_table.extend_from_slice(&0xFFF1u16.to_le_bytes()); // absolute
+        symbol_table.extend_from_slice(&value.to_le_bytes());
+        symbol_table.extend_from_slice(&0u64.to_le_bytes());
+    }
+    let section_names = b"\0.text\0.symtab\0.strtab\0.shstrtab\0";
+
+    let mut file = vec![0u8; CODE_OFFSET];
+    file.extend_from_slice(code);
+    let symbol_table_offset = file.len().next_multiple_of(8);
+    file.resize(symbol_table_offset, 0);
+    file.extend_from_slice(&symbol_table);
+    let strings_offset = file.len();
+    file.extend_from_slice(&strings);
+    let section_names_offset = file.len();
+    file.extend_from_slice(section_names);
+    let section_headers_offset = file.len().next_multiple_of(8);
+    file.resize(section_headers_offset, 0);
+
+    // name, type, flags, address, offset, size, link, info, alignment, entry size
+    #[allow(clippy::type_complexity)]
+    let sections: [(u32, u32, u64, u64, usize, usize, u32, u32, u64, u64); 5] = [
+        (0, 0, 0, 0, 0, 0, 0, 0, 0, 0),
+        (1, 1, 0x6, base, CODE_OFFSET, code.len(), 0, 0, 4, 0),
+        (7, 2, 0, 0, symbol_table_offset, symbol_table.len(), 3, 1, 8, 24),
+        (15, 3, 0, 0, strings_offset, strings.len(), 0, 0, 1, 0),
+        (23, 3, 0, 0, section_names_offset, section_names.len(), 0, 0, 1, 0),
+    ];
+    for (name, kind, flags, address, offset, size, link, info, alignment, entry_size) in sections {
+        file.extend_from_slice(&name.to_le_bytes());
+        file.extend_from_slice(&kind.to_le_bytes());
+        file.extend_from_slice(&flags.to_le_bytes());
+        file.extend_from_slice(&address.to_le_bytes());
+        file.extend_from_slice(&(offset as u64).to_le_bytes());
+        file.extend_from_slice(&(size as u64).to_le_bytes());
+        file.extend_from_slice(&link.to_le_bytes());
+        file.extend_from_slice(&info.to_le_bytes());
+        file.extend_from_slice(&alignment.to_le_bytes());
+        file.extend_from_slice(&entry_size.to_le_bytes());
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    header.extend_from_slice(&2u16.to_le_bytes()); // executable
+    header.extend_from_slice(&243u16.to_le_bytes()); // RISC-V
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&base.to_le_bytes());
+    header.extend_from_slice(&64u64.to_le_bytes());
+    header.extend_from_slice(&(section_headers_offset as u64).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    for half in [64u16, 56, 1, 64, sections.len() as u16, 4] {
+        header.extend_from_slice(&half.to_le_bytes());
+    }
+    // the one PT_LOAD program header, readable, writable and executable
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&7u32.to_le_bytes());
+    for word in [CODE_OFFSET as u64, base, base, code.len() as u64, code.len() as u64, 0x1000] {
+        header.extend_from_slice(&word.to_le_bytes());
+    }
+    file[..header.len()].copy_from_slice(&header);
+
+    fs::write(path, file).expect("can't write the test program");
+}
+
+pub fn words(instructions: &[u32]) -> Vec<u8> {
+    instructions.iter().flat_map(|instruction| instruction.to_le_bytes()).collect()
+}
@@ -0,0 +1,40 @@
+﻿mod common;
+
+use std::fs;
+
+use common::{run, temp_path, words, write_elf};
+
+/// Where user programs usually get linked, well below the bare machine's RAM.
+const USER_BASE: u64 = 0x1_0000;
+
+#[test]
+fn test_deterministic() {
+    let program = temp_path("deterministic");
+    let code = words(&[
+        0xff010113, // addi sp, sp, -16
+        0x00010513, // mv a0, sp
+        0x00800593, // li a1, 8
+        0x00000613, // li a2, 0
+        0x11600893, // li a7, 278
+        0x00000073, // ecall                          getrandom(sp, 8, 0)
+        0x00013503, // ld a0, 0(sp)
+        0xc01025f3, // rdtime a1
+        0x07f57513, // andi a0, a0, 127
+        0x05d00893, // li a7, 93
+        0x00000073, // ecall                          exit(a0)
+    ]);
+    write_elf(&program, USER_BASE, &code, &[]);
+    let program = program.to_str().unwrap();
+
+    let first = run(&["--seed", "7", "--clock-rate", "1000000", "--log-commits", program]);
+    let second = run(&["--seed", "7", "--clock-rate", "1000000", "--log-commits", program]);
+    let reseeded = run(&["--seed", "8", "--clock-rate", "1000000", "--log-commits", program]);
+    fs::remove_file(program).unwrap();
+
+    assert_eq!(first.status.code(), second.status.code());
+    assert_eq!(first.stderr, second.stderr);
+    assert_ne!(first.stderr, reseeded.stderr);
+    // seven instructions in at a million per second, with the timer counting at 10 MHz
+    let log = String::from_utf8(first.stderr).unwrap();
+    assert!(log.contains("(0xc01025f3) x11 0x0000000000000046\n"), "{}", log);
+}
@@ -0,0 +1,36 @@
+﻿mod common;
+
+use std::fs;
+
+use common::{run, temp_path, words, write_elf};
+
+#[test]
+fn test_disasm() {
+    let path = temp_path("disasm");
+    let code = words(&[
+        0x00500513, // li a0, 5
+        0x008000ef, // jal f
+        0x0000006f, // j .
+        0x00150513, // f: addi a0, a0, 1
+        0x00008067, // ret
+    ]);
+    write_elf(&path, 0x8000_0000, &code, &[("_start", 0x8000_0000), ("f", 0x8000_000c)]);
+    let output = run(&["disasm", path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+
+    let listing = String::from_utf8(output.stdout).unwrap();
+    let expected = "
+Disassembly of section .text:
+
+0000000080000000 <_start>:
+80000000:\t00500513  li a0, 5
+80000004:\t008000ef  jal 0x8000000c <f>
+80000008:\t0000006f  j 0x80000008 <_start+0x8>
+
+000000008000000c <f>:
+8000000c:\t00150513  addi a0, a0, 1
+80000010:\t00008067  ret
+";
+    assert_eq!(listing, expected);
+}
@@ -0,0 +1,43 @@
+﻿mod common;
+
+use std::fs;
+
+use common::{run, temp_path, words, write_elf};
+
+const DRAM_BASE: u64 = 0x8000_0000;
+
+#[test]
+fn test_lockstep() {
+    let program = temp_path("lockstep");
+    let code = words(&[
+        0x00a00513, // li a0, 10
+        0x00000593, // li a1, 0
+        0x00a585b3, // 1: add a1, a1, a0
+        0xfff50513, // addi a0, a0, -1
+        0xfe051ce3, // bnez a0, 1b
+        0x00001317, // auipc t1, 1
+        0x00100293, // li t0, 1
+        0xfe533623, // sd t0, -20(t1)                 tohost = 1, exit code 0
+        0x0000006f, // j .
+    ]);
+    write_elf(&program, DRAM_BASE, &code, &[("tohost", DRAM_BASE + 0x1000)]);
+    let program = program.to_str().unwrap();
+
+    let trace = temp_path("lockstep.log");
+    let trace_path = trace.to_str().unwrap();
+    assert!(run(&["--log", trace_path, program]).status.success());
+    let output = run(&["--lockstep", trace_path, program]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "all 35 instructions in the trace matched\n");
+
+    let recorded = fs::read_to_string(&trace).unwrap();
+    assert!(recorded.contains("x11 0x0000000000000037"));
+    fs::write(&trace, recorded.replace("x11 0x0000000000000037", "x11 0x0000000000000038")).unwrap();
+    let output = run(&["--lockstep", trace_path, program]);
+    fs::remove_file(&trace).unwrap();
+    fs::remove_file(program).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let report = String::from_utf8(output.stderr).unwrap();
+    assert!(report.starts_with("divergence after 29 matching instructions"), "{}", report);
+    assert!(report.contains("different register writes"));
+}
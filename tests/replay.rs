@@ -0,0 +1,42 @@
+﻿mod common;
+
+use std::fs;
+
+use common::{run, temp_path, words, write_elf};
+
+const USER_BASE: u64 = 0x1_0000;
+
+#[test]
+fn test_record_and_replay() {
+    let program = temp_path("replay");
+    let journal = temp_path("replay.journal");
+    let code = words(&[
+        0xff010113, // addi sp, sp, -16
+        0x00010513, // mv a0, sp
+        0x00800593, // li a1, 8
+        0x00000613, // li a2, 0
+        0x11600893, // li a7, 278
+        0x00000073, // ecall                          getrandom(sp, 8, 0)
+        0x00013503, // ld a0, 0(sp)
+        0xc01025f3, // rdtime a1
+        0x00b54533, // xor a0, a0, a1
+        0x07f57513, // andi a0, a0, 127
+        0x05d00893, // li a7, 93
+        0x00000073, // ecall                          exit(a0)
+    ]);
+    write_elf(&program, USER_BASE, &code, &[]);
+    let (program, journal) = (program.to_str().unwrap(), journal.to_str().unwrap());
+
+    let recorded = run(&["--record", journal, "--log-commits", program]);
+    let replayed = run(&["--replay", journal, "--log-commits", program]);
+    let not_a_journal = run(&["--replay", program, program]);
+    fs::remove_file(program).unwrap();
+    fs::remove_file(journal).unwrap();
+
+    // the random number and the time come out the same
+    assert_eq!(recorded.status.code(), replayed.status.code());
+    assert_eq!(String::from_utf8(recorded.stderr).unwrap(), String::from_utf8(replayed.stderr).unwrap());
+    assert_eq!(not_a_journal.status.code(), Some(1));
+    let error = String::from_utf8(not_a_journal.stderr).unwrap();
+    assert!(error.contains("can't replay") && error.contains("not a journal"), "{}", error);
+}
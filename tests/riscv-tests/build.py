@@ -0,0 +1,1584 @@
+#!/usr/bin/env python3
+"""Builds the riscv-tests binaries in this directory.
+
+Every test follows the layout of the upstream riscv-tests ISA suite: the "p" environment from
+riscv_test.h (physical memory, one hart, `tohost` for the result) and test cases written after the
+macros in test_macros.h, one numbered case at a time. A case that goes wrong stops the test and
+reports its number through `tohost`, so a failing test tells which case failed.
+
+Expected values aren't written by hand. They come from the reference model below, which follows the
+ISA manual and computes floating point results exactly with fractions before rounding them.
+
+Needs Python 3 and llvm-mc. The binaries are checked in, so this only has to run again after
+changing a test:
+
+    python3 tests/riscv-tests/build.py
+"""
+
+import math
+import os
+import re
+import struct
+import subprocess
+import sys
+import tempfile
+from fractions import Fraction
+
+DIRECTORY = os.path.dirname(os.path.abspath(__file__))
+LLVM_MC = os.environ.get("LLVM_MC", "llvm-mc")
+LLVM_READELF = os.environ.get("LLVM_READELF", "llvm-readelf")
+LLVM_OBJCOPY = os.environ.get("LLVM_OBJCOPY", "llvm-objcopy")
+DRAM_BASE = 0x8000_0000
+
+MASK = (1 << 64) - 1
+
+
+def signed(value, bits=64):
+    value &= (1 << bits) - 1
+    return value - (1 << bits) if value >> (bits - 1) else value
+
+
+def sext32(value):
+    return signed(value, 32) & MASK
+
+
+# --- integer reference model ---------------------------------------------------------------------
+
+def divide(a, b, bits, is_signed):
+    """Quotient and remainder the way RISC-V defines them, division by zero and overflow included."""
+    a &= (1 << bits) - 1
+    b &= (1 << bits) - 1
+    if is_signed:
+        a, b = signed(a, bits), signed(b, bits)
+    if b == 0:
+        return -1, a
+    if is_signed and a == -(1 << (bits - 1)) and b == -1:
+        return a, 0
+    quotient = abs(a) // abs(b)
+    if (a < 0) != (b < 0):
+        quotient = -quotient
+    return quotient, a - quotient * b
+
+
+def word(operation):
+    return lambda a, b: sext32(operation(a & 0xFFFF_FFFF, b & 0xFFFF_FFFF))
+
+
+OPERATIONS = {
+    "add": lambda a, b: a + b,
+    "sub": lambda a, b: a - b,
+    "sll": lambda a, b: a << (b & 63),
+    "srl": lambda a, b: (a & MASK) >> (b & 63),
+    "sra": lambda a, b: signed(a) >> (b & 63),
+    "slt": lambda a, b: int(signed(a) < signed(b)),
+    "sltu": lambda a, b: int((a & MASK) < (b & MASK)),
+    "and": lambda a, b: a & b,
+    "or": lambda a, b: a | b,
+    "xor": lambda a, b: a ^ b,
+    "addw": word(lambda a, b: a + b),
+    "subw": word(lambda a, b: a - b),
+    "sllw": word(lambda a, b: a << (b & 31)),
+    "srlw": word(lambda a, b: a >> (b & 31)),
+    "sraw": word(lambda a, b: signed(a, 32) >> (b & 31)),
+    "mul": lambda a, b: a * b,
+    "mulh": lambda a, b: (signed(a) * signed(b)) >> 64,
+    "mulhsu": lambda a, b: (signed(a) * (b & MASK)) >> 64,
+    "mulhu": lambda a, b: ((a & MASK) * (b & MASK)) >> 64,
+    "mulw": word(lambda a, b: a * b),
+    "div": lambda a, b: divide(a, b, 64, True)[0],
+    "divu": lambda a, b: divide(a, b, 64, False)[0],
+    "rem": lambda a, b: divide(a, b, 64, True)[1],
+    "remu": lambda a, b: divide(a, b, 64, False)[1],
+    "divw": word(lambda a, b: divide(a, b, 32, True)[0]),
+    "divuw": word(lambda a, b: divide(a, b, 32, False)[0]),
+    "remw": word(lambda a, b: divide(a, b, 32, True)[1]),
+    "remuw": word(lambda a, b: divide(a, b, 32, False)[1]),
+}
+# the immediate forms compute the same, with the immediate as the second operand
+for _name in ["add", "slt", "and", "or", "xor", "sll", "srl", "sra"]:
+    OPERATIONS[_name + "i"] = OPERATIONS[_name]
+for _name in ["add", "sll", "srl", "sra"]:
+    OPERATIONS[_name + "iw"] = OPERATIONS[_name + "w"]
+OPERATIONS["sltiu"] = OPERATIONS["sltu"]
+
+
+def compute(name, a, b):
+    return OPERATIONS[name](a & MASK, b & MASK) & MASK
+
+
+# --- floating point reference model --------------------------------------------------------------
+
+NX, UF, OF, DZ, NV = 1, 2, 4, 8, 16
+RNE, RTZ, RDN, RUP, RMM = range(5)
+ROUNDING_MODES = {"rne": RNE, "rtz": RTZ, "rdn": RDN, "rup": RUP, "rmm": RMM}
+
+
+class Format:
+    def __init__(self, suffix, exponent_bits, fraction_bits):
+        self.suffix = suffix
+        self.exponent_bits = exponent_bits
+        self.fraction_bits = fraction_bits
+        self.width = 1 + exponent_bits + fraction_bits
+        self.bias = (1 << (exponent_bits - 1)) - 1
+        self.min_exponent = 1 - self.bias
+        self.max_exponent = self.bias
+        self.exponent_mask = (1 << exponent_bits) - 1
+        self.canonical_nan = (self.exponent_mask << fraction_bits) | (1 << (fraction_bits - 1))
+
+    def bits(self, value):
+        """The bits of a Python float (or bits already), rounded to this format."""
+        if isinstance(value, int):
+            return value
+        if self.width == 32:
+            return struct.unpack("<I", struct.pack("<f", value))[0]
+        return struct.unpack("<Q", struct.pack("<d", value))[0]
+
+    def infinity(self, sign):
+        return (sign << (self.width - 1)) | (self.exponent_mask << self.fraction_bits)
+
+    def largest(self, sign):
+        return self.infinity(sign) - 1
+
+    def unpack(self, bits):
+        """("nan", signaling), ("inf", sign) or ("num", sign, magnitude)."""
+        sign = bits >> (self.width - 1) & 1
+        exponent = bits >> self.fraction_bits & self.exponent_mask
+        fraction = bits & ((1 << self.fraction_bits) - 1)
+        if exponent == self.exponent_mask:
+            if fraction:
+                return ("nan", not fraction >> (self.fraction_bits - 1))
+            return ("inf", sign)
+        if exponent == 0:
+            return ("num", sign, Fraction(fraction) * power(self.min_exponent - self.fraction_bits))
+        significand = fraction | (1 << self.fraction_bits)
+        return ("num", sign, Fraction(significand) * power(exponent - self.bias - self.fraction_bits))
+
+    def round(self, sign, magnitude, mode):
+        """Bits and flags for `magnitude` rounded to this format. Tininess is detected after rounding."""
+        if magnitude == 0:
+            return sign << (self.width - 1), 0
+        exponent = magnitude.numerator.bit_length() - magnitude.denominator.bit_length()
+        while power(exponent) > magnitude:
+            exponent -= 1
+        while power(exponent + 1) <= magnitude:
+            exponent += 1
+
+        def quantize(exponent):
+            scaled = magnitude / power(exponent - self.fraction_bits)
+            kept = math.floor(scaled)
+            remainder = scaled - kept
+            up = {
+                RNE: remainder > Fraction(1, 2) or (remainder == Fraction(1, 2) and kept & 1),
+                RTZ: False,
+                RDN: remainder > 0 and sign,
+                RUP: remainder > 0 and not sign,
+                RMM: remainder >= Fraction(1, 2),
+            }[mode]
+            return kept + bool(up), remainder != 0
+
+        kept, inexact = quantize(exponent)
+        tiny = kept * power(exponent - self.fraction_bits) < power(self.min_exponent)
+        if exponent < self.min_exponent:
+            exponent = self.min_exponent
+            kept, inexact = quantize(exponent)
+        if kept == 1 << (self.fraction_bits + 1):
+            kept >>= 1
+            exponent += 1
+        flags = NX if inexact else 0
+        if tiny and inexact:
+            flags |= UF
+        if exponent > self.max_exponent:
+            to_infinity = mode in (RNE, RMM) or (mode == RDN and sign) or (mode == RUP and not sign)
+            return self.infinity(sign) if to_infinity else self.largest(sign), OF | NX
+        if kept >> self.fraction_bits:
+            biased = exponent + self.bias
+            kept -= 1 << self.fraction_bits
+        else:
+            biased = 0
+        return (sign << (self.width - 1)) | (biased << self.fraction_bits) | kept, flags
+
+
+SINGLE = Format("s", 8, 23)
+DOUBLE = Format("d", 11, 52)
+
+
+def power(exponent):
+    return Fraction(2) ** exponent
+
+
+def signaling(*values):
+    return any(value[0] == "nan" and value[1] for value in values)
+
+
+def has_nan(*values):
+    return any(value[0] == "nan" for value in values)
+
+
+def exact_sum(f, a, b, mode):
+    """a + b for unpacked values that aren't NaN."""
+    if a[0] == "inf" and b[0] == "inf" and a[1] != b[1]:
+        return f.canonical_nan, NV
+    if a[0] == "inf":
+        return f.infinity(a[1]), 0
+    if b[0] == "inf":
+        return f.infinity(b[1]), 0
+    total = (-a[2] if a[1] else a[2]) + (-b[2] if b[1] else b[2])
+    if total == 0:
+        sign = a[1] if a[2] == 0 and b[2] == 0 and a[1] == b[1] else int(mode == RDN)
+        return sign << (f.width - 1), 0
+    return f.round(int(total < 0), abs(total), mode)
+
+
+def negate(value):
+    return value if value[0] == "nan" else (value[0], value[1] ^ 1) + value[2:]
+
+
+def fp_add(f, a, b, mode):
+    a, b = f.unpack(a), f.unpack(b)
+    if has_nan(a, b):
+        return f.canonical_nan, NV if signaling(a, b) else 0
+    return exact_sum(f, a, b, mode)
+
+
+def fp_sub(f, a, b, mode):
+    return fp_add(f, a, b ^ (1 << (f.width - 1)), mode)
+
+
+def product(a, b):
+    """The exact product of two unpacked values that aren't NaN, or None for infinity times zero."""
+    sign = a[1] ^ b[1]
+    if a[0] == "inf" or b[0] == "inf":
+        if (a[0] == "num" and a[2] == 0) or (b[0] == "num" and b[2] == 0):
+            return None
+        return ("inf", sign)
+    return ("num", sign, a[2] * b[2])
+
+
+def fp_mul(f, a, b, mode):
+    a, b = f.unpack(a), f.unpack(b)
+    if has_nan(a, b):
+        return f.canonical_nan, NV if signaling(a, b) else 0
+    result = product(a, b)
+    if result is None:
+        return f.canonical_nan, NV
+    if result[0] == "inf":
+        return f.infinity(result[1]), 0
+    return f.round(result[1], result[2], mode)
+
+
+def fp_fma(f, a, b, c, mode, negate_product=False, negate_addend=False):
+    a, b, c = f.unpack(a), f.unpack(b), f.unpack(c)
+    if signaling(a, b, c):
+        return f.canonical_nan, NV
+    if has_nan(a, b):
+        return f.canonical_nan, 0
+    result = product(a, b)
+    if result is None:
+        return f.canonical_nan, NV
+    if has_nan(c):
+        return f.canonical_nan, 0
+    if negate_product:
+        result = negate(result)
+    if negate_addend:
+        c = negate(c)
+    return exact_sum(f, result, c, mode)
+
+
+def fp_div(f, a, b, mode):
+    a, b = f.unpack(a), f.unpack(b)
+    if has_nan(a, b):
+        return f.canonical_nan, NV if signaling(a, b) else 0
+    sign = a[1] ^ b[1]
+    if a[0] == "inf":
+        return (f.canonical_nan, NV) if b[0] == "inf" else (f.infinity(sign), 0)
+    if b[0] == "inf":
+        return sign << (f.width - 1), 0
+    if b[2] == 0:
+        return (f.canonical_nan, NV) if a[2] == 0 else (f.infinity(sign), DZ)
+    return f.round(sign, a[2] / b[2], mode)
+
+
+def fp_sqrt(f, a, mode):
+    a = f.unpack(a)
+    if a[0] == "nan":
+        return f.canonical_nan, NV if a[1] else 0
+    if a[0] == "inf":
+        return (f.canonical_nan, NV) if a[1] else (f.infinity(0), 0)
+    if a[2] == 0:
+        return a[1] << (f.width - 1), 0
+    if a[1]:
+        return f.canonical_nan, NV
+    # enough bits below the result's precision that one more bit stands in for everything below
+    scale = 1200
+    scaled = a[2] * power(2 * scale)
+    root = math.isqrt(scaled.numerator // scaled.denominator)
+    exact = scaled.denominator == 1 and root * root == scaled.numerator
+    magnitude = Fraction(root, 1 << scale) if exact else Fraction(2 * root + 1, 1 << (scale + 1))
+    return f.round(0, magnitude, mode)
+
+
+def fp_min_max(f, a, b, maximum):
+    ua, ub = f.unpack(a), f.unpack(b)
+    flags = NV if signaling(ua, ub) else 0
+    if ua[0] == "nan" and ub[0] == "nan":
+        return f.canonical_nan, flags
+    if ua[0] == "nan":
+        return b, flags
+    if ub[0] == "nan":
+        return a, flags
+    key_a, key_b = order_key(ua), order_key(ub)
+    if key_a == key_b:
+        # only the zeros compare equal with different bits, and -0 is the smaller one
+        pick_a = (ua[1] == 0) == maximum
+    else:
+        pick_a = (key_a > key_b) == maximum
+    return (a if pick_a else b), flags
+
+
+def order_key(value):
+    if value[0] == "inf":
+        return Fraction(-1 if value[1] else 1) * 10 ** 400
+    return -value[2] if value[1] else value[2]
+
+
+def fp_compare(f, name, a, b):
+    ua, ub = f.unpack(a), f.unpack(b)
+    if has_nan(ua, ub):
+        quiet = name == "feq"
+        return 0, NV if not quiet or signaling(ua, ub) else 0
+    key_a, key_b = order_key(ua), order_key(ub)
+    return int({"feq": key_a == key_b, "flt": key_a < key_b, "fle": key_a <= key_b}[name]), 0
+
+
+def fp_class(f, a):
+    value = f.unpack(a)
+    if value[0] == "nan":
+        return 1 << 8 if value[1] else 1 << 9
+    if value[0] == "inf":
+        return 1 << 0 if value[1] else 1 << 7
+    sign = value[1]
+    exponent = a >> f.fraction_bits & f.exponent_mask
+    if value[2] == 0:
+        return 1 << 3 if sign else 1 << 4
+    if exponent == 0:
+        return 1 << 2 if sign else 1 << 5
+    return 1 << 1 if sign else 1 << 6
+
+
+def round_to_integer(magnitude, sign, mode):
+    kept = math.floor(magnitude)
+    remainder = magnitude - kept
+    up = {
+        RNE: remainder > Fraction(1, 2) or (remainder == Fraction(1, 2) and kept & 1),
+        RTZ: False,
+        RDN: remainder > 0 and sign,
+        RUP: remainder > 0 and not sign,
+        RMM: remainder >= Fraction(1, 2),
+    }[mode]
+    kept += bool(up)
+    return -kept if sign else kept, remainder != 0
+
+
+def fp_to_int(f, a, bits, is_signed, mode):
+    value = f.unpack(a)
+    low, high = (-(1 << (bits - 1)), (1 << (bits - 1)) - 1) if is_signed else (0, (1 << bits) - 1)
+    if value[0] == "nan":
+        result, flags = high, NV
+    elif value[0] == "inf":
+        result, flags = (low if value[1] else high), NV
+    else:
+        result, inexact = round_to_integer(value[2], value[1], mode)
+        if result < low or result > high:
+            result, flags = (low if value[1] else high), NV
+        else:
+            flags = NX if inexact else 0
+    return (sext32(result) if bits == 32 else result & MASK), flags
+
+
+def int_to_fp(f, value, bits, is_signed, mode):
+    value &= (1 << bits) - 1
+    if is_signed:
+        value = signed(value, bits)
+    return f.round(int(value < 0), Fraction(abs(value)), mode)
+
+
+def fp_convert(to, source, a, mode):
+    value = source.unpack(a)
+    if value[0] == "nan":
+        return to.canonical_nan, NV if value[1] else 0
+    if value[0] == "inf":
+        return to.infinity(value[1]), 0
+    return to.round(value[1], value[2], mode)
+
+
+# --- assembling and linking ----------------------------------------------------------------------
+
+class Test:
+    """One test program: the "p" environment around numbered test cases."""
+
+    def __init__(self, name, mode="U", floating_point=False, compressed=False):
+        self.name = name
+        self.mode = mode
+        self.floating_point = floating_point
+        self.compressed = compressed
+        self.lines = []
+        self.data = []
+        self.mtvec_handler = None
+        self.stvec_handler = None
+        self.extra_init = []
+        self.number = 1
+
+    def code(self, *lines):
+        self.lines.extend(lines)
+
+    def data_lines(self, *lines):
+        self.data.extend(lines)
+
+    def next(self):
+        self.number += 1
+        return self.number
+
+    def begin(self, number):
+        self.code(f"test_{number}:", f"li gp, {number}")
+
+    def check(self, register, correct):
+        self.code(f"li t2, {signed(correct)}")
+        self.fail_unless("beq", register, "t2")
+
+    def case(self, number, register, correct, *code):
+        """TEST_CASE: runs `code`, then checks `register` holds `correct`."""
+        self.begin(number)
+        self.code(*code)
+        self.check(register, correct)
+
+    def fail_unless(self, branch, a, b):
+        # branches only reach 4 KiB, so jump to the failure code from here
+        self.code(f"{branch} {a}, {b}, 9f", "j fail", "9:")
+
+    def source(self):
+        lines = [
+            ".text",
+            "_start:",
+            "j reset_vector",
+            ".balign 4",
+            "trap_vector:",
+            "csrr t5, mcause",
+            "li t6, 8",
+            "beq t5, t6, write_tohost",
+            "li t6, 9",
+            "beq t5, t6, write_tohost",
+            "li t6, 11",
+            "beq t5, t6, write_tohost",
+        ]
+        if self.mtvec_handler:
+            lines += ["j mtvec_handler"]
+        lines += [
+            "handle_exception:",
+            "other_exception:",
+            "ori gp, gp, 1337",
+            "write_tohost:",
+            "sw gp, tohost, t5",
+            "sw zero, tohost + 4, t5",
+            "j write_tohost",
+            "reset_vector:",
+        ]
+        lines += [f"li x{register}, 0" for register in range(1, 32)]
+        lines += [
+            "csrr a0, mhartid",
+            "1: bnez a0, 1b",
+            # no translation, every access allowed and no delegation, skipping whatever isn't there
+            "la t0, 1f", "csrw mtvec, t0", "csrwi satp, 0", ".balign 4", "1:",
+            "la t0, 1f", "csrw mtvec, t0", "li t0, -1", "csrw pmpaddr0, t0", "li t0, 0x1f",
+            "csrw pmpcfg0, t0", ".balign 4", "1:",
+            "csrwi mie, 0", "la t0, 1f", "csrw mtvec, t0", "csrwi medeleg, 0", "csrwi mideleg, 0",
+            ".balign 4", "1:",
+            "li gp, 0",
+            "la t0, trap_vector",
+            "csrw mtvec, t0",
+        ]
+        if self.stvec_handler:
+            lines += [
+                "la t0, stvec_handler",
+                "csrw stvec, t0",
+                # misaligned fetch, breakpoint, user ecall and the page faults
+                f"li t0, {(1 << 0) | (1 << 3) | (1 << 8) | (1 << 12) | (1 << 13) | (1 << 15)}",
+                "csrw medeleg, t0",
+            ]
+        lines += ["csrwi mstatus, 0"]
+        if self.mode == "M":
+            lines += ["li a0, 0x1800", "csrs mstatus, a0"]
+        elif self.mode == "S":
+            lines += ["li a0, 0x800", "csrs mstatus, a0", "li a0, 0x22", "csrs mideleg, a0"]
+        if self.floating_point:
+            lines += ["li a0, 0x2000", "csrs mstatus, a0", "csrwi fcsr, 0"]
+        lines += self.extra_init
+        lines += ["la t0, 1f", "csrw mepc, t0", "csrr a0, mhartid", "mret", "1:"]
+        lines += self.lines
+        lines += [
+            "bne x0, gp, pass",
+            "fail:",
+            "fence",
+            "1: beqz gp, 1b",
+            "sll gp, gp, 1",
+            "ori gp, gp, 1",
+            "li a7, 93",
+            "addi a0, gp, 0",
+            "ecall",
+            "pass:",
+            "fence",
+            "li gp, 1",
+            "li a7, 93",
+            "li a0, 0",
+            "ecall",
+        ]
+        for handler, name in [(self.mtvec_handler, "mtvec_handler"), (self.stvec_handler, "stvec_handler")]:
+            if handler:
+                lines += [".balign 4", f"{name}:"] + handler
+        lines += [
+            ".balign 4096",
+            "tohost: .dword 0",
+            ".balign 64",
+            "fromhost: .dword 0",
+            ".balign 64",
+            "begin_signature:",
+        ]
+        lines += self.data
+        lines += [".balign 16", "end_signature:"]
+        return "\n".join(lines) + "\n"
+
+    def build(self, directory):
+        attributes = "+m,+a,+f,+d,-relax" + (",+c" if self.compressed else ",-c")
+        with tempfile.TemporaryDirectory() as scratch:
+            source = os.path.join(scratch, "test.S")
+            objects = os.path.join(scratch, "test.o")
+            image = os.path.join(scratch, "test.bin")
+            with open(source, "w") as file:
+                file.write(self.source())
+            subprocess.run([LLVM_MC, "-triple=riscv64", f"-mattr={attributes}", "-filetype=obj", source,
+                            "-o", objects], check=True)
+            relocations = subprocess.run([LLVM_READELF, "-r", objects], check=True, capture_output=True,
+                                         text=True).stdout
+            if "R_RISCV" in relocations:
+                sys.exit(f"{self.name}: the code refers to something outside itself:\n{relocations}")
+            symbols = {}
+            listing = subprocess.run([LLVM_READELF, "-s", objects], check=True, capture_output=True,
+                                     text=True).stdout
+            for line in listing.splitlines():
+                fields = line.split()
+                if len(fields) == 8 and fields[7] in ("tohost", "fromhost", "begin_signature", "end_signature"):
+                    symbols[fields[7]] = DRAM_BASE + int(fields[1], 16)
+            subprocess.run([LLVM_OBJCOPY, "-O", "binary", "--only-section=.text", objects, image], check=True)
+            with open(image, "rb") as file:
+                code = file.read()
+        write_elf(os.path.join(directory, self.name), DRAM_BASE, code, sorted(symbols.items()))
+
+
+def write_elf(path, base, code, symbols):
+    """A statically linked executable with one segment at `base` and a symbol table, like the Rust
+    tests' `write_elf`."""
+    code_offset = 0x1000
+    strings = b"\0"
+    symbol_table = bytes(24)
+    for name, value in symbols:
+        symbol_table += struct.pack("<IBBHQQ", len(strings), 0x10, 0, 0xFFF1, value, 0)
+        strings += name.encode() + b"\0"
+    section_names = b"\0.text\0.symtab\0.strtab\0.shstrtab\0"
+
+    file = bytearray(code_offset) + code
+    symbol_table_offset = -(-len(file) // 8) * 8
+    file += bytes(symbol_table_offset - len(file)) + symbol_table
+    strings_offset = len(file)
+    file += strings
+    section_names_offset = len(file)
+    file += section_names
+    section_headers_offset = -(-len(file) // 8) * 8
+    file += bytes(section_headers_offset - len(file))
+    sections = [
+        (0, 0, 0, 0, 0, 0, 0, 0, 0, 0),
+        (1, 1, 0x7, base, code_offset, len(code), 0, 0, 4, 0),
+        (7, 2, 0, 0, symbol_table_offset, len(symbol_table), 3, 1, 8, 24),
+        (15, 3, 0, 0, strings_offset, len(strings), 0, 0, 1, 0),
+        (23, 3, 0, 0, section_names_offset, len(section_names), 0, 0, 1, 0),
+    ]
+    for section in sections:
+        file += struct.pack("<IIQQQQIIQQ", *section)
+    header = b"\x7fELF\x02\x01\x01" + bytes(9)
+    header += struct.pack("<HHIQQQIHHHHHH", 2, 243, 1, base, 64, section_headers_offset, 0, 64, 56, 1, 64,
+                          len(sections), 4)
+    header += struct.pack("<IIQQQQQQ", 1, 7, code_offset, base, base, len(code), len(code), 0x1000)
+    file[:len(header)] = header
+    with open(path, "wb") as output:
+        output.write(file)
+
+
+# --- test_macros.h -------------------------------------------------------------------------------
+
+def nops(count):
+    return ["nop"] * count
+
+
+def bypass_loop(t, *body):
+    """Runs `body` twice, the way the bypass tests make sure values are forwarded on every path."""
+    t.code("li tp, 0", "1:", *body, "addi tp, tp, 1", "li t0, 2", "bne tp, t0, 1b")
+
+
+def immediate(value):
+    return signed(value, 12)
+
+
+def imm_op(t, number, name, value, imm):
+    t.case(number, "a4", compute(name, value, imm), f"li ra, {signed(value)}", f"{name} a4, ra, {immediate(imm)}")
+
+
+def imm_tests(t, name, cases, bypass):
+    """The TEST_IMM_* cases for one instruction."""
+    for value, imm in cases:
+        imm_op(t, t.next(), name, value, imm)
+    value, imm = bypass
+    result = compute(name, value, imm)
+    imm = immediate(imm)
+    t.case(t.next(), "ra", result, f"li ra, {signed(value)}", f"{name} ra, ra, {imm}")
+    for count in range(3):
+        t.begin(t.next())
+        bypass_loop(t, f"li ra, {signed(value)}", f"{name} a4, ra, {imm}", *nops(count), "addi t1, a4, 0")
+        t.check("t1", result)
+    for count in range(3):
+        t.begin(t.next())
+        bypass_loop(t, f"li ra, {signed(value)}", *nops(count), f"{name} a4, ra, {imm}")
+        t.check("a4", result)
+    t.case(t.next(), "ra", compute(name, 0, imm), f"{name} ra, x0, {imm}")
+    t.case(t.next(), "x0", 0, f"li ra, {signed(value)}", f"{name} x0, ra, {imm}")
+
+
+def rr_tests(t, name, cases, bypass):
+    """The TEST_RR_* cases for one instruction."""
+    for a, b in cases:
+        t.case(t.next(), "a4", compute(name, a, b), f"li ra, {signed(a)}", f"li sp, {signed(b)}",
+               f"{name} a4, ra, sp")
+    a, b = bypass
+    result = compute(name, a, b)
+    load_a, load_b = f"li ra, {signed(a)}", f"li sp, {signed(b)}"
+    t.case(t.next(), "ra", result, load_a, load_b, f"{name} ra, ra, sp")
+    t.case(t.next(), "sp", result, load_a, load_b, f"{name} sp, ra, sp")
+    t.case(t.next(), "ra", compute(name, a, a), load_a, f"{name} ra, ra, ra")
+    for count in range(3):
+        t.begin(t.next())
+        bypass_loop(t, load_a, load_b, f"{name} a4, ra, sp", *nops(count), "addi t1, a4, 0")
+        t.check("t1", result)
+    for first, second in [(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (2, 0)]:
+        for order in [(load_a, load_b), (load_b, load_a)]:
+            t.begin(t.next())
+            bypass_loop(t, order[0], *nops(first), order[1], *nops(second), f"{name} a4, ra, sp")
+            t.check("a4", result)
+    t.case(t.next(), "sp", compute(name, 0, a), load_a, f"{name} sp, x0, ra")
+    t.case(t.next(), "sp", compute(name, a, 0), load_a, f"{name} sp, ra, x0")
+    t.case(t.next(), "ra", compute(name, 0, 0), f"{name} ra, x0, x0")
+    t.case(t.next(), "x0", 0, load_a, load_b, f"{name} x0, ra, sp")
+
+
+# operands worth trying for any two-operand instruction
+PAIRS = [
+    (0, 0), (1, 1), (3, 7), (0, -0x8000), (-0x8000_0000, 0), (-0x8000_0000, -0x8000), (0, 0x7FFF),
+    (0x7FFF_FFFF, 0), (0x7FFF_FFFF, 0x7FFF), (-0x8000_0000, 0x7FFF), (0x7FFF_FFFF, -0x8000), (0, -1),
+    (-1, 1), (-1, -1), (1, 0x7FFF_FFFF), (0x7FFF_FFFF_FFFF_FFFF, 1), (-0x8000_0000_0000_0000, -1),
+    (-0x8000_0000_0000_0000, 1), (0xFFFF_FFFF, 0xFFFF_FFFF), (0x00FF_00FF_00FF_00FF, 0x0F0F_0F0F_0F0F_0F0F),
+    (-0xFF_00FF_00FF_0100, -0x0F0F_0F0F_0F0F_0F10), (20, 6), (-20, 6), (20, -6), (-20, -6),
+    (0x1234_5678_9ABC_DEF0, 0xFEDC_BA98), (-0x8000_0000, -1), (0x8000_0000, 0x7FFF_FFFF),
+]
+# values and amounts for shifts, the amounts including bits the instruction ignores
+SHIFTED = [1, -0x8000_0000_0000_0000, 0x2121_2121, -1, 0x7FFF_FFFF_FFFF_FFFF, -0x8000_0000, 0x8000_0000]
+AMOUNTS = [0, 1, 7, 14, 20, 31, 32, 33, 50, 63, -1, -31, -63, 0xFC0]
+IMMEDIATES = [0, 1, 3, 0x7FF, -0x800, -1, 0x555, -0x556, 20, -20]
+
+
+def integer_tests():
+    tests = []
+    for name in ["add", "sub", "and", "or", "xor", "slt", "sltu", "addw", "subw"]:
+        t = Test(f"rv64ui-p-{name}")
+        rr_tests(t, name, PAIRS, (13, 11))
+        tests.append(t)
+    for name in ["sll", "srl", "sra", "sllw", "srlw", "sraw"]:
+        t = Test(f"rv64ui-p-{name}")
+        rr_tests(t, name, [(value, amount) for value in SHIFTED for amount in AMOUNTS], (-0x8000_0000, 7))
+        tests.append(t)
+    for name in ["addi", "andi", "ori", "xori", "slti", "sltiu", "addiw"]:
+        t = Test(f"rv64ui-p-{name}")
+        imm_tests(t, name, [(a, imm) for a in [pair[0] for pair in PAIRS[:18]] for imm in IMMEDIATES[:4]]
+                  + [(a, imm) for a in [0, -1, 0x7FFF_FFFF] for imm in IMMEDIATES[4:]], (13, 11))
+        tests.append(t)
+    for name, amounts in [("slli", range(64)), ("srli", range(64)), ("srai", range(64)),
+                          ("slliw", range(32)), ("srliw", range(32)), ("sraiw", range(32))]:
+        t = Test(f"rv64ui-p-{name}")
+        imm_tests(t, name, [(value, amount) for value in SHIFTED for amount in amounts if amount % 3 == 0
+                            or amount in (1, 31, 32, 63)], (-0x8000_0000, 7))
+        tests.append(t)
+    for name in ["mul", "mulh", "mulhsu", "mulhu", "mulw", "div", "divu", "rem", "remu", "divw", "divuw",
+                 "remw", "remuw"]:
+        t = Test(f"rv64um-p-{name}")
+        rr_tests(t, name, PAIRS + [(a, 0) for a in [1, -1, 0x8000_0000, -0x8000_0000_0000_0000]],
+                 (-20, 6))
+        tests.append(t)
+
+    t = Test("rv64ui-p-lui")
+    for upper in [0, 0xFFFFF, 0x7FFFF, 0x80000, 0x12345]:
+        t.case(t.next(), "ra", sext32(upper << 12), f"lui ra, {upper}")
+    t.case(t.next(), "ra", (signed(0x80000 << 12, 32) >> 12) & MASK, "lui ra, 0x80000", "sra ra, ra, 12")
+    t.case(t.next(), "x0", 0, "lui x0, 0x80000")
+    tests.append(t)
+
+    t = Test("rv64ui-p-auipc")
+    # the distance from a label to the auipc after it, wherever the code ends up
+    t.case(t.next(), "a0", 10000, ".balign 4", "lla a0, 1f + 10000", "jal a1, 1f", "1: sub a0, a0, a1")
+    t.case(t.next(), "a0", (-10000) & MASK, ".balign 4", "lla a0, 1f - 10000", "jal a1, 1f", "1: sub a0, a0, a1")
+    t.case(t.next(), "a0", sext32(0x80000 << 12), "auipc a0, 0x80000", "auipc a1, 0", "sub a0, a0, a1",
+           "addi a0, a0, 4")
+    tests.append(t)
+
+    tests.append(branch_test("beq", lambda a, b: a == b))
+    tests.append(branch_test("bne", lambda a, b: a != b))
+    tests.append(branch_test("blt", lambda a, b: signed(a) < signed(b)))
+    tests.append(branch_test("bge", lambda a, b: signed(a) >= signed(b)))
+    tests.append(branch_test("bltu", lambda a, b: (a & MASK) < (b & MASK)))
+    tests.append(branch_test("bgeu", lambda a, b: (a & MASK) >= (b & MASK)))
+    tests += [jal_test(), jalr_test(), simple_test(), fence_i_test()]
+    tests += memory_tests()
+    return tests
+
+
+def branch_test(name, taken):
+    t = Test(f"rv64ui-p-{name}")
+    values = [0, 1, -1, 2, -2, 0x7FFF_FFFF_FFFF_FFFF, -0x8000_0000_0000_0000, 0xFFFF_FFFF, 0x8000_0000]
+    for a in values:
+        for b in values[:5] + values[5:7]:
+            t.begin(t.next())
+            t.code(f"li ra, {signed(a)}", f"li sp, {signed(b)}")
+            if taken(a & MASK, b & MASK):
+                # TEST_BR2_OP_TAKEN
+                t.code(f"{name} ra, sp, 2f", "j fail", "1: j 3f", f"2: {name} ra, sp, 1b", "j fail", "3:")
+            else:
+                # TEST_BR2_OP_NOTTAKEN
+                t.code(f"{name} ra, sp, 1f", "j 2f", "1: j fail", f"2: {name} ra, sp, 1b", "3:")
+    # a branch that isn't taken, however late its operands arrive
+    a, b = next(pair for pair in [(1, 0), (0, 1), (0, 0)] if not taken(*pair))
+    for first, second in [(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (2, 0)]:
+        t.begin(t.next())
+        bypass_loop(t, f"li ra, {a}", *nops(first), f"li sp, {b}", *nops(second), f"{name} ra, sp, 8f")
+        t.code("j 9f", "8: j fail", "9:")
+    # the instructions after a taken branch don't run
+    a, b = (0, 0) if taken(0, 0) else (0, 1)
+    t.case(t.next(), "ra", 3, "li ra, 1", f"li t3, {a}", f"li t4, {b}", f"{name} t3, t4, 1f", "addi ra, ra, 1",
+           "addi ra, ra, 1", "addi ra, ra, 1", "addi ra, ra, 1", "1: addi ra, ra, 1", "addi ra, ra, 1")
+    return t
+
+
+def jal_test():
+    t = Test("rv64ui-p-jal")
+    t.begin(t.next())
+    t.code("li ra, 0", "jal tp, 1f", "linkaddr_2:", "nop", "nop", "j fail", "1:", "la sp, linkaddr_2")
+    t.fail_unless("beq", "sp", "tp")
+    t.case(t.next(), "a0", 3, "li a0, 1", "jal x0, 1f", "addi a0, a0, 1", "addi a0, a0, 1", "addi a0, a0, 1",
+           "addi a0, a0, 1", "1: addi a0, a0, 1", "addi a0, a0, 1")
+    # a jump far enough that it needs most of the offset
+    t.case(t.next(), "a0", 5, "li a0, 5", "jal zero, 1f", "li a0, 0", ".skip 0x4000, 0", "1:")
+    return t
+
+
+def jalr_test():
+    t = Test("rv64ui-p-jalr")
+    t.begin(t.next())
+    t.code("li t0, 0", "la t1, 1f", "jalr t0, t1, 0", "linkaddr_2:", "j fail", "1:", "la t1, linkaddr_2")
+    t.fail_unless("beq", "t0", "t1")
+    t.begin(t.next())
+    t.code("la t0, 1f", "jalr t0, t0, 0", "linkaddr_3:", "j fail", "1:", "la t1, linkaddr_3")
+    t.fail_unless("beq", "t0", "t1")
+    for count in range(3):
+        t.begin(t.next())
+        bypass_loop(t, "la t1, 8f", *nops(count), "jalr t6, t1, 0", "j fail", "8:")
+    t.case(t.next(), "ra", 4, "li ra, 1", "la t0, 1f", "jalr t0, -4(t0)", "addi ra, ra, 1", "addi ra, ra, 1",
+           "addi ra, ra, 1", "1: addi ra, ra, 1", "addi ra, ra, 1")
+    # the lowest bit of the target is dropped
+    t.case(t.next(), "a0", 7, "li a0, 7", "la t0, 1f", "jalr zero, 1(t0)", "li a0, 0", "1:")
+    return t
+
+
+def simple_test():
+    t = Test("rv64ui-p-simple")
+    t.code("j pass")
+    return t
+
+
+def fence_i_test():
+    t = Test("rv64ui-p-fence_i")
+    # copies two instructions over the ones at 2f, which run before and after fence.i
+    t.data_lines(".balign 4", "insn:", "addi a3, a3, 333")
+    t.case(t.next(), "a3", 111, "li a3, 111", "la a0, 2f", "jal 2f", "j 3f", "2: addi a3, a3, 0", "ret", "3:")
+    t.case(t.next(), "a3", 555, "la a0, insn", "lwu a1, 0(a0)", "la a0, 2f", "sw a1, 0(a0)", "fence.i",
+           "li a3, 222", "jal 2f", "j 3f", "2: addi a3, a3, 0", "ret", "3:")
+    return t
+
+
+def memory_tests():
+    data = [0x00FF00FF_00FF00FF, 0xFF00FF00_FF00FF00, 0x0FF00FF0_0FF00FF0, 0xF00FF00F_F00FF00F]
+    memory = b"".join(struct.pack("<Q", value) for value in data)
+    tests = []
+    for name, size, is_signed in [("lb", 1, True), ("lbu", 1, False), ("lh", 2, True), ("lhu", 2, False),
+                                  ("lw", 4, True), ("lwu", 4, False), ("ld", 8, True)]:
+        t = Test(f"rv64ui-p-{name}")
+        t.data_lines(".balign 16", "tdat:", *[f".dword {value:#x}" for value in data])
+
+        def loaded(offset):
+            value = int.from_bytes(memory[offset:offset + size], "little")
+            return signed(value, size * 8) & MASK if is_signed else value
+
+        for offset in range(0, 32, size * 2 if size < 8 else size):
+            t.case(t.next(), "a4", loaded(offset), "la sp, tdat", f"{name} a4, {offset}(sp)")
+        t.case(t.next(), "a4", loaded(size), "la sp, tdat + 16", f"{name} a4, {size - 16}(sp)")
+        t.case(t.next(), "t0", loaded(2 * size), "la ra, tdat", "addi ra, ra, -32", f"{name} t0, {32 + 2 * size}(ra)")
+        t.case(t.next(), "t0", loaded(size), "la ra, tdat", "addi ra, ra, -3", f"{name} t0, {3 + size}(ra)")
+        for count in range(3):
+            t.begin(t.next())
+            bypass_loop(t, f"la ra, tdat + {size}", f"{name} a4, {size}(ra)", *nops(count), "addi t1, a4, 0")
+            t.check("t1", loaded(2 * size))
+        for count in range(3):
+            t.begin(t.next())
+            bypass_loop(t, f"la ra, tdat + {size}", *nops(count), f"{name} a4, {size}(ra)")
+            t.check("a4", loaded(2 * size))
+        # the loaded value replaces a register it was computed from
+        t.case(t.next(), "t0", loaded(0), "la t0, tdat", f"{name} t0, 0(t0)")
+        t.case(t.next(), "t0", 2, "la t0, tdat", f"{name} t1, 0(t0)", "li t1, 2", "mv t0, t1")
+        tests.append(t)
+
+    for name, size in [("sb", 1), ("sh", 2), ("sw", 4), ("sd", 8)]:
+        t = Test(f"rv64ui-p-{name}")
+        t.data_lines(".balign 16", "tdat:", *[".dword 0xefefefefefefefef"] * 8, "tdat2:",
+                     *[".dword 0xefefefefefefefef"] * 2)
+        load = {1: "lb", 2: "lh", 4: "lw", 8: "ld"}[size]
+        values = [0xAA, -0x56, 0xAA00_00AA, 0x7FFF_FFFF_FFFF_FFFF, -1, 0x0AA0, -0x1000_0000_0000_0000, 0x1234_5678]
+        for index, value in enumerate(values):
+            stored = signed(value, size * 8) & MASK
+            offset = index * size
+            t.case(t.next(), "a4", stored, "la sp, tdat", f"li ra, {signed(value)}", f"{name} ra, {offset}(sp)",
+                   f"{load} a4, {offset}(sp)")
+        # the bytes around a store stay as they were
+        t.case(t.next(), "a4", 0xEFEF_EFEF_EFEF_EFEF, "la sp, tdat2 + 8", "li ra, 0", f"{name} ra, 0(sp)",
+               "ld a4, -8(sp)")
+        t.case(t.next(), "a4", (0xEFEF_EFEF_EFEF_EFEF >> (size * 8) << (size * 8)) & MASK, "la sp, tdat2 + 8",
+               "li ra, 0", f"{name} ra, 0(sp)", "ld a4, 0(sp)")
+        t.case(t.next(), "a4", signed(0x1234_5678_9ABC_DEF0, size * 8) & MASK, "la ra, tdat", "addi ra, ra, -32",
+               "li a0, 0x123456789abcdef0", f"{name} a0, 32(ra)", f"{load} a4, 32(ra)")
+        t.case(t.next(), "a4", signed(0x5A5A_5A5A_5A5A_5A5A, size * 8) & MASK, "la ra, tdat + 16",
+               "addi ra, ra, -3", "li a0, 0x5a5a5a5a5a5a5a5a", f"{name} a0, {3 + size}(ra)", "la a5, tdat",
+               f"{load} a4, {16 + size}(a5)")
+        for first, second in [(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (2, 0)]:
+            for order in [("li ra, -0x3fcd", "la sp, tdat"), ("la sp, tdat", "li ra, -0x3fcd")]:
+                t.begin(t.next())
+                bypass_loop(t, order[0], *nops(first), order[1], *nops(second), f"{name} ra, {2 * size}(sp)",
+                            f"{load} a4, {2 * size}(sp)")
+                t.check("a4", signed(-0x3FCD, size * 8) & MASK)
+        tests.append(t)
+
+    # loads and stores that aren't aligned, which this emulator handles in hardware
+    t = Test("rv64ui-p-ma_data")
+    t.data_lines(".balign 16", "tdat:", *[f".byte {byte}" for byte in range(64)])
+    memory = bytes(range(64))
+    for load, size, is_signed in [("lh", 2, True), ("lhu", 2, False), ("lw", 4, True), ("lwu", 4, False),
+                                  ("ld", 8, True)]:
+        for offset in range(1, 8):
+            if offset % size == 0:
+                continue
+            value = int.from_bytes(memory[offset:offset + size], "little")
+            t.case(t.next(), "a4", signed(value, size * 8) & MASK if is_signed else value, "la s0, tdat",
+                   f"{load} a4, {offset}(s0)")
+    for store, load, size in [("sh", "lhu", 2), ("sw", "lwu", 4), ("sd", "ld", 8)]:
+        for offset in range(1, 8):
+            if offset % size == 0:
+                continue
+            value = 0x8877_6655_4433_2211 & ((1 << (size * 8)) - 1)
+            t.case(t.next(), "a4", value, "la s0, tdat + 32", f"li a1, {value}", f"{store} a1, {offset}(s0)",
+                   f"{load} a4, {offset}(s0)")
+    tests.append(t)
+    return tests
+
+
+def atomic_tests():
+    tests = []
+    operations = {
+        "swap": lambda old, value, bits: value,
+        "add": lambda old, value, bits: old + value,
+        "and": lambda old, value, bits: old & value,
+        "or": lambda old, value, bits: old | value,
+        "xor": lambda old, value, bits: old ^ value,
+        "max": lambda old, value, bits: max(signed(old, bits), signed(value, bits)),
+        "maxu": lambda old, value, bits: max(old, value),
+        "min": lambda old, value, bits: min(signed(old, bits), signed(value, bits)),
+        "minu": lambda old, value, bits: min(old, value),
+    }
+    for name, operation in operations.items():
+        for suffix, bits in [("d", 64), ("w", 32)]:
+            t = Test(f"rv64ua-p-amo{name}_{suffix}")
+            t.data_lines(".balign 8", "amo_operand:", ".dword 0")
+            mask = (1 << bits) - 1
+            extend = (lambda value: signed(value, 32) & MASK) if bits == 32 else (lambda value: value & MASK)
+            load = "lw" if bits == 32 else "ld"
+            # each case without an initial value carries on from what the one before left in memory
+            memory = None
+            for initial, value in [(-0x8000_0000, -0x800), (None, -0x8000_0000), (None, 1), (0x7FFF_FFFF, -1),
+                                   (None, 0x1234_5678_8765_4321), (0, 0)]:
+                code = ["la a3, amo_operand"]
+                if initial is not None:
+                    memory = initial & mask
+                    code += [f"li a0, {signed(initial)}", f"s{suffix} a0, 0(a3)"]
+                code += [f"li a1, {signed(value)}", f"amo{name}.{suffix} a4, a1, 0(a3)"]
+                t.case(t.next(), "a4", extend(memory), *code)
+                memory = operation(memory, value & mask, bits) & mask
+                t.case(t.next(), "a5", extend(memory), f"{load} a5, 0(a3)")
+            # the destination can be the same register as the value
+            t.case(t.next(), "a1", 7, "la a3, amo_operand", "li a0, 7", f"s{suffix} a0, 0(a3)",
+                   "li a1, 3", f"amo{name}.{suffix} a1, a1, 0(a3)")
+            tests.append(t)
+
+    t = Test("rv64ua-p-lrsc")
+    t.data_lines(".balign 8", "foo:", ".dword 0", "bar:", ".dword 0", "counter:", ".dword 0")
+    # a store conditional without a reservation fails and stores nothing
+    t.case(t.next(), "a4", 0, "la a0, foo", "li a5, 0xdeadbeef", "sc.w a4, a5, (a0)", "sltu a4, x0, a4", "addi a4, a4, -1")
+    t.case(t.next(), "a4", 0, "lw a4, foo")
+    # one that follows a load reserved succeeds, once
+    t.case(t.next(), "a4", 0, "la a0, foo", "lr.w a1, (a0)", "li a5, -2", "sc.w a4, a5, (a0)")
+    t.case(t.next(), "a4", (-2) & MASK, "lw a4, foo")
+    t.case(t.next(), "a4", 1, "la a0, foo", "sc.w a4, a5, (a0)", "sltu a4, x0, a4")
+    t.case(t.next(), "a1", (-2) & MASK, "la a0, foo", "lr.w a1, (a0)")
+    t.case(t.next(), "a4", 0, "la a0, foo", "li a5, 5", "lr.d a1, (a0)", "sc.d a4, a5, (a0)")
+    t.case(t.next(), "a4", 5, "ld a4, foo")
+    # the usual increment loop
+    t.case(t.next(), "a4", 1024, "la a0, counter", "li a1, 1024", "1: lr.d a2, (a0)", "addi a2, a2, 1",
+           "sc.d a3, a2, (a0)", "bnez a3, 1b", "addi a1, a1, -1", "bnez a1, 1b", "ld a4, counter")
+    tests.append(t)
+    return tests
+
+
+INF = float("inf")
+QNAN = float("nan")
+SNAN = {32: 0x7F80_0001, 64: 0x7FF0_0000_0000_0001}
+
+
+def special(f, value):
+    """Bits for a Python float, bits as they are, or a name for one of the values floats can't spell."""
+    if value == "snan":
+        return SNAN[f.width]
+    if value == "-snan":
+        return SNAN[f.width] | (1 << (f.width - 1))
+    if value == "max":
+        return f.largest(0)
+    if value == "min":
+        return 1 << f.fraction_bits
+    if value == "tiny":
+        return 1
+    if value == "-tiny":
+        return (1 << (f.width - 1)) | 1
+    return f.bits(value)
+
+
+def fp_result(f, bits):
+    """How a result reaches an integer register: `fmv.x.w` sign-extends."""
+    return sext32(bits) if f.width == 32 else bits
+
+
+class FloatTest(Test):
+    def __init__(self, name, f):
+        super().__init__(name, floating_point=True)
+        self.f = f
+
+    def fp_case(self, code, inputs, result, flags):
+        """TEST_FP_OP*: loads `inputs` into f0 to f2, runs `code`, which leaves its result in a0, then
+        checks a0 and the accrued exception flags."""
+        f = self.f
+        number = self.next()
+        load = "flw" if f.width == 32 else "fld"
+        inputs = [special(f, value) for value in inputs] + [0] * (3 - len(inputs))
+        self.begin(number)
+        self.code(f"la a0, test_{number}_data", *[f"{load} f{index}, {8 * index}(a0)" for index in range(3)],
+                  "ld a3, 24(a0)", *code, "fsflags a1, x0", f"li a2, {flags}")
+        self.fail_unless("beq", "a0", "a3")
+        self.fail_unless("beq", "a1", "a2")
+        self.data_lines(".balign 8", f"test_{number}_data:", *[f".dword {value:#x}" for value in inputs],
+                        f".dword {result & MASK:#x}")
+
+    def operation(self, name, inputs, mode=None):
+        """A floating point result of `name`, an instruction without its format suffix."""
+        f = self.f
+        bits = [special(f, value) for value in inputs]
+        rounding = RNE if mode is None else ROUNDING_MODES[mode]
+        result, flags = {
+            "fadd": lambda: fp_add(f, *bits, rounding),
+            "fsub": lambda: fp_sub(f, *bits, rounding),
+            "fmul": lambda: fp_mul(f, *bits, rounding),
+            "fdiv": lambda: fp_div(f, *bits, rounding),
+            "fsqrt": lambda: fp_sqrt(f, *bits, rounding),
+            "fmadd": lambda: fp_fma(f, *bits, rounding),
+            "fmsub": lambda: fp_fma(f, *bits, rounding, negate_addend=True),
+            "fnmsub": lambda: fp_fma(f, *bits, rounding, negate_product=True),
+            "fnmadd": lambda: fp_fma(f, *bits, rounding, negate_product=True, negate_addend=True),
+            "fmin": lambda: fp_min_max(f, *bits, False),
+            "fmax": lambda: fp_min_max(f, *bits, True),
+        }[name]()
+        operands = ", ".join(f"f{index}" for index in range(len(inputs)))
+        suffix = f", {mode}" if mode else ""
+        self.fp_case([f"{name}.{f.suffix} f3, {operands}{suffix}", f"fmv.x.{f.suffix} a0, f3"], inputs,
+                     fp_result(f, result), flags)
+
+    def compare(self, name, a, b):
+        f = self.f
+        result, flags = fp_compare(f, name, special(f, a), special(f, b))
+        self.fp_case([f"{name}.{f.suffix} a0, f0, f1"], [a, b], result, flags)
+
+    def classify(self, value):
+        f = self.f
+        self.fp_case([f"fclass.{f.suffix} a0, f0"], [value], fp_class(f, special(f, value)), 0)
+
+    def to_integer(self, kind, value, mode="rtz"):
+        f = self.f
+        bits, is_signed = {"w": (32, True), "wu": (32, False), "l": (64, True), "lu": (64, False)}[kind]
+        result, flags = fp_to_int(f, special(f, value), bits, is_signed, ROUNDING_MODES[mode])
+        self.fp_case([f"fcvt.{kind}.{f.suffix} a0, f0, {mode}"], [value], result, flags)
+
+    def from_integer(self, kind, value, mode=None):
+        """TEST_INT_FP_OP: converts an integer register."""
+        f = self.f
+        bits, is_signed = {"w": (32, True), "wu": (32, False), "l": (64, True), "lu": (64, False)}[kind]
+        result, flags = int_to_fp(f, value, bits, is_signed, RNE if mode is None else ROUNDING_MODES[mode])
+        number = self.next()
+        suffix = f", {mode}" if mode else ""
+        self.case(number, "a0", fp_result(f, result), f"li a0, {signed(value)}",
+                  f"fcvt.{f.suffix}.{kind} f0, a0{suffix}", "fsflags x0", f"fmv.x.{f.suffix} a0, f0")
+
+
+def float_tests(f):
+    prefix = "rv64uf" if f.width == 32 else "rv64ud"
+    tests = []
+
+    t = FloatTest(f"{prefix}-p-fadd", f)
+    for name in ["fadd", "fsub", "fmul"]:
+        for a, b in [(2.5, 1.0), (-1235.1, 1.1), (3.14159265, 0.00000001), (-1235.1, -1.1), (INF, INF),
+                     (INF, -INF), (INF, 0.0), ("max", "max"), ("min", 0.5), ("min", 0.3), (1.0, -1.0),
+                     (1.0, 1.0), ("snan", 1.0), (QNAN, 1.0), (-0.0, -0.0), (0.0, -0.0), ("tiny", 1e-10)]:
+            t.operation(name, [a, b])
+        for mode in ROUNDING_MODES:
+            for a, b in [(1.0, 3e-8), (-1.0, -3e-8), (1.0, 1e-25), (-1.0, 1.0), ("max", "max")]:
+                t.operation(name, [a, b], mode)
+    tests.append(t)
+
+    t = FloatTest(f"{prefix}-p-fdiv", f)
+    for a, b in [(3.14159265, 2.71828182), (-1234, 1235.1), (3.14159265, 1.0), (1.0, 0.0), (-1.0, 0.0),
+                 (0.0, 0.0), (INF, INF), (-1.0, INF), (INF, -2.0), ("snan", 1.0), ("max", 0.5), ("min", 3.0),
+                 (1.0, 3.0)]:
+        t.operation("fdiv", [a, b])
+    for mode in ROUNDING_MODES:
+        t.operation("fdiv", [1.0, 3.0], mode)
+        t.operation("fdiv", [-2.0, 3.0], mode)
+    for value in [3.14159265, 10000, -1.0, 171.0, -0.0, INF, -INF, 2.0, "tiny", "min", "max", QNAN, "snan",
+                  1e-30]:
+        t.operation("fsqrt", [value])
+    for mode in ROUNDING_MODES:
+        t.operation("fsqrt", [2.0], mode)
+    tests.append(t)
+
+    t = FloatTest(f"{prefix}-p-fmadd", f)
+    for name in ["fmadd", "fnmadd", "fmsub", "fnmsub"]:
+        for inputs in [(1.0, 2.5, 1.0), (-1.0, -1235.1, 1.1), (2.0, -5.0, -2.0), (INF, 0.0, QNAN), (0.0, INF, 1.0),
+                       (1.0, 1.0, -1.0), (1.0, -1.0, 1.0), (INF, 1.0, -INF), (3.0, 1e-8, 1.0),
+                       ("max", 2.0, -1.0), ("snan", 1.0, 1.0), (1.0, 1.0, QNAN)]:
+            t.operation(name, list(inputs))
+        for mode in ROUNDING_MODES:
+            t.operation(name, [1.0, 1.0, -1.0], mode)
+            t.operation(name, [3.0, 1e-8, 1.0], mode)
+    tests.append(t)
+
+    t = FloatTest(f"{prefix}-p-fmin", f)
+    for name in ["fmin", "fmax"]:
+        for a, b in [(2.5, 1.0), (-1235.1, 1.1), (1.1, -1235.1), (QNAN, -1235.1), (3.14159265, 0.00000001),
+                     (-1.0, -2.0), ("snan", 1.0), (1.0, "snan"), (QNAN, QNAN), ("snan", QNAN), (-0.0, 0.0),
+                     (0.0, -0.0), (INF, -INF)]:
+            t.operation(name, [a, b])
+    tests.append(t)
+
+    t = FloatTest(f"{prefix}-p-fcmp", f)
+    for name in ["feq", "flt", "fle"]:
+        for a, b in [(-1.36, -1.36), (-1.37, -1.36), (-1.36, -1.37), (QNAN, 0.0), (0.0, QNAN), (QNAN, QNAN),
+                     ("snan", 0.0), (0.0, -0.0), (-INF, INF), ("tiny", 0.0)]:
+            t.compare(name, a, b)
+    tests.append(t)
+
+    t = FloatTest(f"{prefix}-p-fclass", f)
+    for value in [-INF, -1.0, "-tiny", -0.0, 0.0, "tiny", 1.0, INF, "snan", QNAN]:
+        t.classify(value)
+    tests.append(t)
+
+    t = FloatTest(f"{prefix}-p-fcvt", f)
+    for kind in ["w", "wu", "l", "lu"]:
+        for value in [2, -2, 0, 0x7FFF_FFFF, -0x8000_0000, 0x7FFF_FFFF_FFFF_FFFF, -0x8000_0000_0000_0000,
+                      0xFFFF_FFFF, 0x1_2345_6789, 16777217, 9007199254740993]:
+            t.from_integer(kind, value)
+    for mode in ROUNDING_MODES:
+        # 32-bit integers always fit in a double
+        if f.width == 32:
+            t.from_integer("w", 16777217, mode)
+        t.from_integer("l", -9007199254740993, mode)
+        t.from_integer("l", 0x7FFF_FFFF_FFFF_FFFF, mode)
+    if f.width == 64:
+        # between the formats, where going to single precision has to round
+        for value in [-1.5, 3.14159265, "snan", QNAN, INF, 1e-300, 1e300, "tiny", -0.0, 1.0000001, "max"]:
+            result, flags = fp_convert(SINGLE, DOUBLE, special(DOUBLE, value), RNE)
+            t.fp_case(["fcvt.s.d f3, f0", "fmv.x.w a0, f3"], [value], fp_result(SINGLE, result), flags)
+        for value in [-1.5, 3.14159265, "snan", QNAN, -INF, "tiny", -0.0, "max"]:
+            single = special(SINGLE, value)
+            result, flags = fp_convert(DOUBLE, SINGLE, single, RNE)
+            t.fp_case(["fcvt.d.s f3, f0", "fmv.x.d a0, f3"], [0xFFFF_FFFF_0000_0000 | single], result, flags)
+    tests.append(t)
+
+    t = FloatTest(f"{prefix}-p-fcvt_w", f)
+    for kind in ["w", "wu", "l", "lu"]:
+        for value in [-3e9, -1.1, -1.0, -0.9, -0.0, 0.9, 1.0, 1.1, 3e9, 2.0 ** 31, 2.0 ** 32, 2.0 ** 63,
+                      2.0 ** 64, -2.0 ** 63, -2.0 ** 31, -2.0 ** 31 - 1, -2.0 ** 32, INF, -INF, QNAN, "snan",
+                      "tiny"]:
+            t.to_integer(kind, value)
+        for mode in ROUNDING_MODES:
+            for value in [2.5, -2.5, 1.5, -1.5, 0.5, -0.5, 1.0]:
+                t.to_integer(kind, value, mode)
+    tests.append(t)
+
+    t = FloatTest(f"{prefix}-p-ldst", f)
+    size = f.width // 8
+    load, store = ("flw", "fsw") if f.width == 32 else ("fld", "fsd")
+    values = [f.bits(-1.0), f.bits(2.0), f.bits(3.0), f.bits(-4.0), 0xDEADBEEF, 0xCAFEBABE, 0xABAD1DEA, 0x1337D00D]
+    if f.width == 64:
+        values = [value | (0x5A5A_0000_0000_0000 * (index >= 4)) for index, value in enumerate(values)]
+    memory = bytearray(b"".join(value.to_bytes(size, "little") for value in values))
+    t.data_lines(".balign 16", "tdat:", *[f".byte {byte}" for byte in memory])
+    for source, target in [(1, 5), (0, 4), (3, 7), (2, 6)]:
+        memory[target * size:(target + 1) * size] = memory[source * size:(source + 1) * size]
+        read = (target * size) & ~7
+        t.case(t.next(), "a0", int.from_bytes(memory[read:read + 8], "little"), "la a1, tdat",
+               f"{load} f1, {source * size}(a1)", f"{store} f1, {target * size}(a1)", f"ld a0, {read}(a1)")
+    # the value loaded is the one moved, not something rounded
+    t.case(t.next(), "a0", fp_result(f, values[1]), "la a1, tdat", f"{load} f2, {size}(a1)",
+           f"fmv.x.{f.suffix} a0, f2")
+    tests.append(t)
+
+    t = FloatTest(f"{prefix}-p-move", f)
+    t.case(t.next(), "a1", 1, "csrwi fcsr, 1", "li a0, 0x1234", "fscsr a1, a0")
+    t.case(t.next(), "a0", 0x34, "frcsr a0")
+    t.case(t.next(), "a0", 0x14, "frflags a0")
+    t.case(t.next(), "a0", 0x01, "csrrwi a0, frm, 2")
+    t.case(t.next(), "a0", 0x54, "frcsr a0")
+    t.case(t.next(), "a0", 0x14, "csrrci a0, fflags, 4")
+    t.case(t.next(), "a0", 0x50, "frcsr a0")
+    t.case(t.next(), "a0", 2, "frrm a0")
+    t.case(t.next(), "a0", 0, "csrwi fcsr, 0", "frcsr a0")
+    sign = 1 << (f.width - 1)
+    payload = 0x1234_5678 if f.width == 32 else 0x1234_5678_9ABC_DEF0
+    for name, new_sign in [("fsgnj", lambda a, b: b), ("fsgnjn", lambda a, b: b ^ 1), ("fsgnjx", lambda a, b: a ^ b)]:
+        for a in [0, 1]:
+            for b in [0, 1]:
+                t.case(t.next(), "a0", fp_result(f, (new_sign(a, b) * sign) | payload),
+                       f"li a1, {signed(a * sign | payload, f.width)}", f"li a2, {-b}",
+                       f"fmv.{f.suffix}.x f1, a1", f"fmv.{f.suffix}.x f2, a2",
+                       f"{name}.{f.suffix} f0, f1, f2", f"fmv.x.{f.suffix} a0, f0")
+    # moves leave every bit as it was, NaNs included
+    for value in [0x7F80_0001, 0xFFC0_0000, 0x8000_0000, 0x1234_5678] if f.width == 32 else \
+            [0x7FF0_0000_0000_0001, 0xFFF8_0000_0000_0000, 0x8000_0000_0000_0000]:
+        t.case(t.next(), "a0", fp_result(f, value), f"li a1, {signed(value, f.width)}", f"fmv.{f.suffix}.x f1, a1",
+               f"fsgnj.{f.suffix} f2, f1, f1", f"fmv.x.{f.suffix} a0, f2")
+    tests.append(t)
+
+    t = FloatTest(f"{prefix}-p-recoding", f)
+    t.data_lines(".balign 8", "minf:", f".dword {f.infinity(1):#x}", "three:", f".dword {f.bits(3.0):#x}")
+    load = "flw" if f.width == 32 else "fld"
+    # infinities and zeroes made different ways compare equal
+    setup = [f"{load} f0, minf, a0", f"{load} f1, three, a0", f"fmul.{f.suffix} f1, f1, f0"]
+    t.case(t.next(), "a0", 1, *setup, f"feq.{f.suffix} a0, f0, f1")
+    t.case(t.next(), "a0", 1, *setup, f"fle.{f.suffix} a0, f0, f1")
+    t.case(t.next(), "a0", 0, *setup, f"flt.{f.suffix} a0, f0, f1")
+    setup = [f"fcvt.{f.suffix}.w f0, x0", "li a0, 1", f"fcvt.{f.suffix}.w f1, a0", f"fmul.{f.suffix} f1, f1, f0"]
+    t.case(t.next(), "a0", 1, *setup, f"feq.{f.suffix} a0, f0, f1")
+    t.case(t.next(), "a0", 1, *setup, f"fle.{f.suffix} a0, f0, f1")
+    t.case(t.next(), "a0", 0, *setup, f"flt.{f.suffix} a0, f0, f1")
+    if f.width == 64:
+        # a single precision value that isn't NaN-boxed reads as the canonical NaN
+        t.case(t.next(), "a0", 0xFFFF_FFFF_0000_0000 | SINGLE.canonical_nan, "li a1, 0x123456783f800000",
+               "fmv.d.x f0, a1", "fsgnj.s f1, f0, f0", "fmv.x.d a0, f1")
+        t.case(t.next(), "a0", sext32(SINGLE.canonical_nan), "li a1, 0x3f800000", "fmv.d.x f0, a1",
+               "fadd.s f1, f0, f0", "fmv.x.w a0, f1")
+        t.case(t.next(), "a0", 0, "li a1, 0x3f800000", "fmv.d.x f0, a1", "fadd.s f1, f0, f0", "frflags a0")
+        # while moving it keeps every bit
+        t.case(t.next(), "a0", 0x3F80_0000, "li a1, 0x123456783f800000", "fmv.d.x f0, a1", "fmv.x.w a0, f0")
+        t.case(t.next(), "a0", 0x1234_5678_3F80_0000, "li a1, 0x123456783f800000", "fmv.d.x f0, a1",
+               "fsgnj.d f1, f0, f0", "fmv.x.d a0, f1")
+        # and single precision results are NaN-boxed
+        t.case(t.next(), "a0", MASK, "li a1, -1", "fmv.w.x f0, a1", "fmv.x.d a0, f0")
+        t.case(t.next(), "a0", 0xFFFF_FFFF_4000_0000, "li a1, 1", "fcvt.s.w f0, a1", "fadd.s f0, f0, f0",
+               "fmv.x.d a0, f0")
+        t.data_lines(".balign 8", "boxed:", ".dword 0x123456783f800000")
+        t.case(t.next(), "a0", 0x1234_5678_3F80_0000, "la a1, boxed", "fld f0, 0(a1)", "fsw f0, 0(a1)",
+               "ld a0, 0(a1)")
+    tests.append(t)
+    return tests
+
+
+def compressed_test():
+    t = Test("rv64uc-p-rvc", floating_point=True, compressed=True)
+    t.data_lines(".balign 8", "data:", ".dword 0xfedcba9876543210", ".dword 0xfedcba9876543210", "fdata:",
+                 f".dword {DOUBLE.bits(1.5):#x}")
+    # a 4-byte instruction that straddles a page boundary
+    t.case(t.next(), "a1", 667, "li a1, 666", "j 1f", ".balign 4096", ".skip 4094", ".option push", ".option norvc",
+           "1: addi a1, a1, 1", ".option pop")
+    t.code("li sp, 0x1234")
+    t.case(t.next(), "a0", 0x1234 + 1020, "c.addi4spn a0, sp, 1020")
+    t.case(t.next(), "sp", 0x1234 + 496, "c.addi16sp sp, 496")
+    t.case(t.next(), "sp", 0x1234 + 496 - 512, "c.addi16sp sp, -512")
+    t.code("la a1, data")
+    t.case(t.next(), "a2", 0xFFFF_FFFF_FEDC_BA99, "c.lw a0, 4(a1)", "addi a0, a0, 1", "c.sw a0, 4(a1)", "c.lw a2, 4(a1)")
+    t.case(t.next(), "a2", 0xFEDC_BA99_7654_3211, "c.ld a0, 0(a1)", "addi a0, a0, 1", "c.sd a0, 0(a1)",
+           "c.ld a2, 0(a1)")
+    t.case(t.next(), "s0", (-15) & MASK, "ori a0, x0, 1", "c.addi a0, -16", "mv s0, a0")
+    t.case(t.next(), "s0", (-16) & MASK, "ori a5, x0, 1", "c.li a5, -16", "mv s0, a5")
+    t.case(t.next(), "s0", 0xFFFF_FFFF_8000_0000, "li a0, 0x7fffffff", "c.addiw a0, 1", "mv s0, a0")
+    t.case(t.next(), "s0", 0x1F000, "c.lui s0, 0x1f")
+    t.case(t.next(), "s0", 0xFFFF_FFFF_FFFE_0000, "c.lui s0, 0xfffe0")
+    t.case(t.next(), "s0", 0x0FFF_FFFF_FFFF_FFFF, "li s0, -1", "c.srli s0, 4")
+    t.case(t.next(), "s0", MASK, "li s0, -1", "c.srai s0, 63")
+    t.case(t.next(), "s0", (-(1 << 63) >> 33) & MASK, "li s0, 0x8000000000000000", "c.srai s0, 33")
+    t.case(t.next(), "s0", 0x1_0000_0000, "li s0, 1", "c.slli s0, 32")
+    t.case(t.next(), "s0", MASK & ~0x1F, "li s0, -1", "c.andi s0, -32")
+    t.case(t.next(), "s1", 20, "li s1, 25", "li a0, 5", "c.sub s1, a0")
+    t.case(t.next(), "s1", 0xA, "li s1, 0xf", "li a0, 5", "c.xor s1, a0")
+    t.case(t.next(), "s1", 0xF, "li s1, 0xa", "li a0, 5", "c.or s1, a0")
+    t.case(t.next(), "s1", 0x5, "li s1, 0xf", "li a0, 5", "c.and s1, a0")
+    t.case(t.next(), "s1", 0xFFFF_FFFF_8000_0000, "li s1, 0x7fffffff", "li a0, -1", "c.subw s1, a0")
+    t.case(t.next(), "s1", 0xFFFF_FFFF_8000_0000, "li s1, 0x7fffffff", "li a0, 1", "c.addw s1, a0")
+    t.case(t.next(), "a0", 3, "li a0, 1", "c.j 1f", "c.addi a0, 1", "c.addi a0, 1", "1: c.addi a0, 1", "c.addi a0, 1")
+    t.case(t.next(), "a0", 2, "li a0, 0", "c.beqz a0, 1f", "c.li a0, 5", "1: c.addi a0, 2")
+    t.case(t.next(), "a0", 6, "li a0, 1", "c.bnez a0, 1f", "c.li a0, 9", "1: c.addi a0, 5")
+    t.case(t.next(), "a0", 9, "li a0, 0", "c.bnez a0, 1f", "c.li a0, 9", "1: nop")
+    t.case(t.next(), "ra", 0, "li ra, 0", "la t0, 1f", "c.jr t0", "c.li ra, 1", "1: nop")
+    t.case(t.next(), "t1", 0, "la t0, 1f", "c.jalr t0", "2: j 3f", "1: la t1, 2b", "sub t1, t1, ra", "ret", "3:")
+    t.case(t.next(), "a0", 0x1234, "li t0, 0x1234", "c.mv a0, t0")
+    t.case(t.next(), "a0", 0x1235, "li a0, 1", "li t0, 0x1234", "c.add a0, t0")
+    t.case(t.next(), "s0", 1, "li s0, 1", "c.nop")
+    t.code("la sp, data")
+    t.case(t.next(), "a2", 0xFFFF_FFFF_FEDC_BA99, "c.lwsp a2, 4(sp)")
+    t.case(t.next(), "a2", 0x1234, "li a0, 0x1234", "c.swsp a0, 8(sp)", "c.lwsp a2, 8(sp)")
+    t.case(t.next(), "a2", 0xFEDC_BA98_0000_1234, "c.ldsp a2, 8(sp)")
+    t.case(t.next(), "a2", 0x1_0000_0001, "li a0, 0x100000001", "c.sdsp a0, 0(sp)", "c.ldsp a2, 0(sp)")
+    t.code("la a1, fdata")
+    t.case(t.next(), "a2", DOUBLE.bits(3.0), "c.fld fs0, 0(a1)", "fadd.d fs1, fs0, fs0", "c.fsd fs1, 0(a1)",
+           "c.ld a2, 0(a1)")
+    t.code("la sp, fdata")
+    t.case(t.next(), "a2", DOUBLE.bits(6.0), "c.fldsp ft0, 0(sp)", "fadd.d ft0, ft0, ft0", "c.fsdsp ft0, 0(sp)",
+           "c.ldsp a2, 0(sp)")
+    return [t]
+
+
+def trap_handler(level):
+    """A handler for traps a test expects: the cause must be the one in s11, then s10 is set and s8 gets
+    the trap value. It resumes at s9 if that's set, or after the instruction that trapped.
+
+    The environment call that finishes the test (a7 is 93) is passed on."""
+    return [
+        f"csrr t5, {level}cause",
+        "beq t5, s11, 1f",
+        "li t6, 8",
+        "bne t5, t6, fail",
+        "li t6, 93",
+        "bne a7, t6, fail",
+        "ecall",
+        "1: li s11, -1",
+        f"csrr s8, {level}tval",
+        "li s10, 1",
+        "beqz s9, 2f",
+        f"csrw {level}epc, s9",
+        f"{level}ret",
+        f"2: csrr t5, {level}epc",
+        "addi t5, t5, 4",
+        f"csrw {level}epc, t5",
+        f"{level}ret",
+    ]
+
+
+def expect_trap(t, cause, *code):
+    """Runs `code`, which must trap with `cause`, leaving the trap value in s8."""
+    t.begin(t.next())
+    t.code("li s10, 0", f"li s11, {cause}", *code)
+    t.check("s10", 1)
+
+
+# switching privilege from machine mode, and from supervisor to user mode
+MACHINE_TO_USER = ["li t0, 0x1800", "csrc mstatus, t0", "la t0, 1f", "csrw mepc, t0", "mret", "1:"]
+MACHINE_TO_SUPERVISOR = ["li t0, 0x1800", "csrc mstatus, t0", "li t0, 0x800", "csrs mstatus, t0", "la t0, 1f",
+                         "csrw mepc, t0", "mret", "1:"]
+SUPERVISOR_TO_USER = ["li t0, 0x100", "csrc sstatus, t0", "la t0, 1f", "csrw sepc, t0", "sret", "1:"]
+
+MSTATUS_TVM, MSTATUS_TW, MSTATUS_TSR = 1 << 20, 1 << 21, 1 << 22
+MISA_EXTENSIONS = sum(1 << (ord(letter) - ord("A")) for letter in "IMAFDCSU")
+
+
+def scratch_cases(t, level):
+    """csrrw, csrrs and csrrc in all their forms, on a scratch register."""
+    register = f"{level}scratch"
+    t.case(t.next(), "a0", 3, f"csrwi {register}, 3", f"csrr a0, {register}")
+    t.case(t.next(), "a0", 3, f"csrrci a0, {register}, 1")
+    t.case(t.next(), "a0", 2, f"csrr a0, {register}")
+    t.case(t.next(), "a0", 2, f"csrrsi a0, {register}, 4")
+    t.case(t.next(), "a0", 6, f"csrr a0, {register}")
+    t.case(t.next(), "a0", 6, f"csrrwi a0, {register}, 2")
+    t.case(t.next(), "a0", 2, f"csrr a0, {register}")
+    t.case(t.next(), "a1", MASK, "li a0, -1", f"csrw {register}, a0", f"csrr a1, {register}")
+    t.case(t.next(), "a0", MASK, f"csrrs a0, {register}, x0", f"csrrc a0, {register}, x0")
+    t.case(t.next(), "a0", 0xFFF, "li a0, 0xf0f0", f"csrrc x0, {register}, a0", "li a0, 0xff",
+           f"csrrw a1, {register}, a0", "srli a0, a1, 52", "li t0, 0xf0f0", "and a1, a1, t0", "or a0, a0, a1")
+
+
+def machine_tests():
+    tests = []
+
+    t = Test("rv64mi-p-csr", mode="M")
+    t.mtvec_handler = trap_handler("m")
+    scratch_cases(t, "m")
+    # sstatus is a view of mstatus
+    t.case(t.next(), "a1", 2, "li a0, 2", "csrs sstatus, a0", "csrr a1, mstatus", "andi a1, a1, 2")
+    t.case(t.next(), "a1", 0, "li a0, 2", "csrc mstatus, a0", "csrr a1, sstatus", "andi a1, a1, 2")
+    t.case(t.next(), "a0", 0, "csrr a0, sstatus", "li t0, 0x1800", "and a0, a0, t0")
+    t.case(t.next(), "a0", 2, "csrr a0, sstatus", "srli a0, a0, 32", "andi a0, a0, 3")
+    t.case(t.next(), "a0", 2, "csrr a0, misa", "srli a0, a0, 62")
+    t.case(t.next(), "a0", MISA_EXTENSIONS, "csrr a0, misa", f"li t0, {MISA_EXTENSIONS}", "and a0, a0, t0")
+    t.case(t.next(), "a0", 0, "csrr a0, mhartid")
+    # counters count
+    t.case(t.next(), "a0", 2, "rdinstret a1", "nop", "rdinstret a0", "sub a0, a0, a1")
+    t.case(t.next(), "a1", 100, "li a0, 100", "csrw minstret, a0", "csrr a1, minstret")
+    t.case(t.next(), "a0", 1, "rdcycle a1", "nop", "rdcycle a0", "sltu a0, a1, a0")
+    expect_trap(t, 2, "csrw mvendorid, x0")
+    expect_trap(t, 2, "csrrsi a0, mimpid, 1")
+    t.code(*MACHINE_TO_USER)
+    expect_trap(t, 2, "csrr a0, mscratch")
+    expect_trap(t, 2, "csrr a0, sscratch")
+    expect_trap(t, 2, "rdcycle a0")
+    expect_trap(t, 2, "csrr a0, mstatus")
+    tests.append(t)
+
+    t = Test("rv64mi-p-mcsr", mode="M")
+    t.case(t.next(), "a0", 2, "csrr a0, misa", "srli a0, a0, 62")
+    t.case(t.next(), "a0", 0, "csrr a0, mhartid")
+    t.case(t.next(), "a0", 0xA, "csrr a0, mstatus", "srli a0, a0, 32", "andi a0, a0, 0xf")
+    # these only have to be readable
+    t.case(t.next(), "a0", 0, "csrr a0, mimpid", "csrr a0, marchid", "csrr a0, mvendorid", "csrr a0, mconfigptr",
+           "li a0, 0")
+    tests.append(t)
+
+    t = Test("rv64mi-p-illegal", mode="M")
+    t.mtvec_handler = trap_handler("m")
+    expect_trap(t, 2, ".word 0")
+    t.check("s8", 0)
+    expect_trap(t, 2, ".word 0x77")
+    # the trap value is either zero or the instruction
+    t.code("beqz s8, 8f", "li t2, 0x77")
+    t.fail_unless("beq", "s8", "t2")
+    t.code("8:")
+    t.code(f"li t0, {MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR}", "csrs mstatus, t0", *MACHINE_TO_SUPERVISOR)
+    expect_trap(t, 2, "wfi")
+    expect_trap(t, 2, "sfence.vma")
+    expect_trap(t, 2, "csrr a0, satp")
+    expect_trap(t, 2, "csrw satp, x0")
+    expect_trap(t, 2, "sret")
+    expect_trap(t, 2, "mret")
+    expect_trap(t, 2, "csrr a0, mstatus")
+    tests.append(t)
+
+    t = Test("rv64mi-p-ma_addr", mode="M")
+    t.mtvec_handler = trap_handler("m")
+    ma_addr_cases(t)
+    tests.append(t)
+
+    tests.append(ma_fetch_test("rv64mi-p-ma_fetch", "M"))
+
+    t = Test("rv64mi-p-sbreak", mode="M")
+    t.mtvec_handler = trap_handler("m")
+    expect_trap(t, 3, "la s7, 1f", "1: ebreak")
+    # the trap value is either zero or the address of the ebreak
+    t.code("beqz s8, 8f")
+    t.fail_unless("beq", "s8", "s7")
+    t.code("8:")
+    # a compressed ebreak, with something after it to make up the four bytes the handler skips
+    expect_trap(t, 3, ".hword 0x9002", ".hword 0x0001")
+    tests.append(t)
+
+    t = Test("rv64mi-p-scall", mode="M")
+    # the environment call reaches the end of the test, which reports test 1: a pass
+    t.code(*MACHINE_TO_USER, "li gp, 1", "ecall", "j fail")
+    tests.append(t)
+
+    t = Test("rv64mi-p-access", mode="M")
+    t.mtvec_handler = trap_handler("m")
+    unmapped = 1 << 40
+    expect_trap(t, 1, f"li t0, {unmapped}", "la s9, 1f", "jalr t0", "j fail", "1: li s9, 0")
+    t.code(f"li t2, {unmapped}")
+    t.fail_unless("beq", "s8", "t2")
+    expect_trap(t, 5, f"li t0, {unmapped + 8}", "ld a0, 0(t0)")
+    t.code(f"li t2, {unmapped + 8}")
+    t.fail_unless("beq", "s8", "t2")
+    expect_trap(t, 7, f"li t0, {unmapped + 16}", "sw a0, 0(t0)")
+    t.code(f"li t2, {unmapped + 16}")
+    t.fail_unless("beq", "s8", "t2")
+    tests.append(t)
+    return tests
+
+
+def ma_addr_cases(t):
+    """Misaligned loads and stores either work or trap with the address as the trap value."""
+    t.data_lines(".balign 16", "tdat:", *[f".byte {byte}" for byte in range(32)])
+    memory = bytes(range(32))
+    for name, size, is_signed in [("lh", 2, True), ("lhu", 2, False), ("lw", 4, True), ("lwu", 4, False),
+                                  ("ld", 8, True)]:
+        for offset in range(1, size):
+            value = int.from_bytes(memory[offset:offset + size], "little")
+            value = signed(value, size * 8) & MASK if is_signed else value
+            t.begin(t.next())
+            t.code("li s10, 0", "li s11, 4", "la s0, tdat", f"{name} a4, {offset}(s0)", "beqz s10, 7f",
+                   f"addi t2, s0, {offset}")
+            t.fail_unless("beq", "s8", "t2")
+            t.code("j 8f", "7:")
+            t.check("a4", value)
+            t.code("8:")
+    for name, size in [("sh", 2), ("sw", 4), ("sd", 8)]:
+        for offset in range(1, size):
+            value = 0x8877_6655_4433_2211 & ((1 << (size * 8)) - 1)
+            t.begin(t.next())
+            t.code("li s10, 0", "li s11, 6", "la s0, tdat + 16", f"li a1, {value}", f"{name} a1, {offset}(s0)",
+                   "beqz s10, 7f", f"addi t2, s0, {offset}")
+            t.fail_unless("beq", "s8", "t2")
+            t.code("j 8f", "7:", f"lbu a4, {offset}(s0)")
+            t.check("a4", 0x11)
+            t.code(f"lbu a4, {offset + size - 1}(s0)")
+            t.check("a4", value >> (8 * (size - 1)))
+            t.code("8:")
+
+
+def ma_fetch_test(name, mode):
+    """With compressed instructions, a jump only has to be to an even address."""
+    t = Test(name, mode=mode)
+    for offset in [2, 3]:
+        t.begin(t.next())
+        # jalr drops the lowest bit, landing on the c.li in the middle of the word
+        t.code("li a0, 0", "la t0, 1f", f"jalr t1, {offset}(t0)", "2: j fail", ".balign 4", "1: .hword 0x0001",
+               ".hword 0x4515", "j 3f", "3:", "la t2, 2b")
+        t.fail_unless("beq", "t1", "t2")
+        t.check("a0", 5)
+    # a 4-byte instruction that starts half way through a word
+    t.case(t.next(), "a0", 7, "la t0, 1f", "jalr zero, 2(t0)", ".balign 4", "1: .hword 0x0001", "li a0, 7",
+           ".hword 0x0001")
+    return t
+
+
+def supervisor_tests():
+    tests = []
+
+    t = Test("rv64si-p-csr", mode="S")
+    t.mtvec_handler = trap_handler("m")
+    t.stvec_handler = trap_handler("s")
+    scratch_cases(t, "s")
+    t.case(t.next(), "a1", 2, "li a0, 2", "csrs sstatus, a0", "csrr a1, sstatus", "andi a1, a1, 2", "csrc sstatus, a0")
+    t.case(t.next(), "a1", 1 << 18, "li a0, 1 << 18", "csrs sstatus, a0", "csrr a1, sstatus", "and a1, a1, a0",
+           "csrc sstatus, a0")
+    t.case(t.next(), "a0", 0, "csrr a0, sstatus", "li t0, 0x1800", "and a0, a0, t0")
+    t.case(t.next(), "a0", 0, "csrr a0, stvec", "la t0, stvec_handler", "sub a0, a0, t0")
+    expect_trap(t, 2, "csrr a0, mscratch")
+    expect_trap(t, 2, "csrr a0, mstatus")
+    expect_trap(t, 2, "rdcycle a0")
+    t.code(*SUPERVISOR_TO_USER)
+    expect_trap(t, 2, "csrr a0, sscratch")
+    expect_trap(t, 2, "csrr a0, sstatus")
+    tests.append(t)
+
+    t = Test("rv64si-p-scall", mode="S")
+    t.stvec_handler = trap_handler("s")
+    t.code(*SUPERVISOR_TO_USER)
+    expect_trap(t, 8, "ecall")
+    t.check("s8", 0)
+    tests.append(t)
+
+    t = Test("rv64si-p-sbreak", mode="S")
+    t.stvec_handler = trap_handler("s")
+    expect_trap(t, 3, "ebreak")
+    t.code(*SUPERVISOR_TO_USER)
+    expect_trap(t, 3, "ebreak")
+    tests.append(t)
+
+    tests.append(ma_fetch_test("rv64si-p-ma_fetch", "S"))
+
+    t = Test("rv64si-p-wfi", mode="S")
+    # a pending interrupt wakes wfi up even when it's masked
+    t.case(t.next(), "a0", 2, "csrci sstatus, 2", "li a0, 2", "csrs sie, a0", "csrs sip, a0", "wfi", "csrr a0, sip",
+           "andi a0, a0, 2")
+    tests.append(t)
+
+    t = Test("rv64si-p-dirty", mode="M")
+    # a gigapage mapping the first gigabyte of virtual memory onto DRAM, accessed but not dirty
+    pte = (DRAM_BASE >> 12 << 10) | 0x1 | 0x2 | 0x4 | 0x8 | 0x10 | 0x40
+    t.data_lines(".balign 4096", "page_table_1:", f".dword {pte:#x}", "dummy:", ".dword 0")
+    t.code("la a1, page_table_1", "srli a1, a1, 12", "li a0, 8 << 60", "or a1, a1, a0", "csrw satp, a1",
+           "sfence.vma", "la a2, dummy", f"li t0, {DRAM_BASE}", "sub a2, a2, t0",
+           # loads and stores go through the page table as if in supervisor mode
+           "li a1, 0x1800", "csrc mstatus, a1", "li a1, 0x800 | (1 << 17)", "csrs mstatus, a1")
+    # a user page without SUM faults, and the fault leaves the dirty bit alone
+    t.begin(2)
+    t.code("li t2, 1", "sw t2, 0(a2)")
+    # with SUM it works, setting the dirty bit by itself or after a fault
+    t.begin(3)
+    t.code("li a1, 0x800 | (1 << 18)", "csrs mstatus, a1", "lw t0, 0(a2)")
+    t.fail_unless("beq", "t0", "x0")
+    t.code("sw t2, 0(a2)", "lw t0, 0(a2)")
+    t.fail_unless("beq", "t0", "t2")
+    t.code("li t0, 1 << 17", "csrc mstatus, t0")
+    t.code("ld t0, page_table_1", "andi t0, t0, 0xc0", "li t1, 0xc0")
+    t.fail_unless("beq", "t0", "t1")
+    t.mtvec_handler = [
+        "csrr t5, mcause",
+        "li t6, 15",
+        "bne t5, t6, fail",
+        "ld t5, page_table_1",
+        "andi t6, t5, 0x80",
+        "bnez t6, fail",
+        "li t6, 2",
+        "bne gp, t6, 1f",
+        "csrr t5, mepc",
+        "addi t5, t5, 4",
+        "csrw mepc, t5",
+        "mret",
+        "1: li t6, 3",
+        "bne gp, t6, fail",
+        "ori t5, t5, 0x80",
+        "sd t5, page_table_1, t6",
+        "sfence.vma",
+        "mret",
+    ]
+    tests.append(t)
+    return tests
+
+
+def main():
+    tests = integer_tests() + atomic_tests() + float_tests(SINGLE) + float_tests(DOUBLE) + compressed_test()
+    tests += machine_tests() + supervisor_tests()
+    names = set()
+    for test in tests:
+        assert test.name not in names, test.name
+        names.add(test.name)
+    pattern = re.compile(sys.argv[1]) if len(sys.argv) > 1 else None
+    for name in os.listdir(DIRECTORY):
+        if name.startswith("rv64") and name not in names:
+            os.remove(os.path.join(DIRECTORY, name))
+    for test in tests:
+        if pattern is None or pattern.search(test.name):
+            test.build(DIRECTORY)
+    print(f"built {len(tests)} tests in {DIRECTORY}")
+
+
+if __name__ == "__main__":
+    main()
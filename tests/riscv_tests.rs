@@ -0,0 +1,99 @@
+﻿mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use common::{run, temp_path, words, write_elf};
+
+/// Where the riscv-tests binaries live, named like `rv64ui-p-add` as riscv-tests' `make isa` produces.
+const TESTS_DIRECTORY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/riscv-tests");
+const SUITES: [&str; 8] = ["rv64ui", "rv64um", "rv64ua", "rv64uf", "rv64ud", "rv64uc", "rv64mi", "rv64si"];
+/// Every test in the suite finishes in well under this many instructions.
+const MAX_INSTRUCTIONS: &str = "10000000";
+
+const DRAM_BASE: u64 = 0x8000_0000;
+
+#[test]
+fn riscv_tests() {
+    let mut tests: Vec<_> = fs::read_dir(TESTS_DIRECTORY).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            // skip the .dump disassembly files riscv-tests builds alongside
+            SUITES.iter().any(|suite| name.starts_with(&format!("{}-", suite))) && path.extension().is_none()
+        })
+        .collect();
+    tests.sort();
+    assert!(!tests.is_empty(), "no riscv-tests binaries in {}, see the README there", TESTS_DIRECTORY);
+
+    run_suite(&tests, &["--no-jit"]);
+    // with the feature the JIT is on unless it's turned off
+    #[cfg(feature = "jit")]
+    run_suite(&tests, &[]);
+}
+
+/// Runs every test with `options`, reporting each one, and fails if any of them did.
+fn run_suite(tests: &[PathBuf], options: &[&str]) {
+    let mut failures = Vec::new();
+    for test in tests {
+        let name = test.file_name().unwrap().to_string_lossy();
+        let mut arguments = vec!["--max-instructions", MAX_INSTRUCTIONS];
+        arguments.extend_from_slice(options);
+        arguments.push(test.to_str().unwrap());
+        let output = run(&arguments);
+        if output.status.success() {
+            println!("PASS {} {}", name, options.join(" "));
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            println!("FAIL {} {} ({}) {}", name, options.join(" "), output.status, stderr.trim());
+            failures.push(name.into_owned());
+        }
+    }
+    println!("{} of {} riscv-tests passed {}", tests.len() - failures.len(), tests.len(), options.join(" "));
+    assert!(failures.is_empty(), "failed {}: {}", options.join(" "), failures.join(", "));
+}
+
+/// A riscv-tests style program: prints `A` through the HTIF console, then reports `exit_code`.
+fn htif_program(path: &Path, exit_code: u32) {
+    let code = words(&[
+        0x00001317, // auipc t1, 1                   t1 = tohost
+        0x00100293, // li t0, 1
+        0x03829293, // slli t0, t0, 56               console device
+        0x00100393, // li t2, 1
+        0x03039393, // slli t2, t2, 48               putchar
+        0x0072e2b3, // or t0, t0, t2
+        0x0412e293, // ori t0, t0, 65                'A'
+        0x00533023, // sd t0, 0(t1)
+        0x04033023, // sd zero, 64(t1)               acknowledge fromhost
+        0x00000293 | (((exit_code << 1) | 1) << 20), // li t0, (exit_code << 1) | 1
+        0x00533023, // sd t0, 0(t1)
+        0x0000006f, // j .
+    ]);
+    write_elf(path, DRAM_BASE, &code, &[("tohost", DRAM_BASE + 0x1000), ("fromhost", DRAM_BASE + 0x1040)]);
+}
+
+#[test]
+fn test_htif() {
+    let path = temp_path("htif-pass");
+    htif_program(&path, 0);
+    let output = run(&[path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"A");
+
+    let path = temp_path("htif-fail");
+    htif_program(&path, 7);
+    let output = run(&[path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(7));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("FAILED"));
+}
+
+#[test]
+fn test_instruction_limit() {
+    let path = temp_path("htif-loop");
+    write_elf(&path, DRAM_BASE, &words(&[0x0000006f]), &[("tohost", DRAM_BASE + 0x1000)]);
+    let output = run(&["--max-instructions", "1000", path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(124));
+}
@@ -0,0 +1,46 @@
+﻿mod common;
+
+use std::fs;
+
+use common::{run, temp_path, words, write_elf};
+
+const DRAM_BASE: u64 = 0x8000_0000;
+
+#[test]
+fn test_snapshot() {
+    let program = temp_path("snapshot");
+    let code = words(&[
+        0x00a00513, // li a0, 10
+        0x00000593, // li a1, 0
+        0x00a585b3, // 1: add a1, a1, a0
+        0xfff50513, // addi a0, a0, -1
+        0xfe051ce3, // bnez a0, 1b
+        0x00159593, // slli a1, a1, 1
+        0x00158593, // addi a1, a1, 1
+        0x00001317, // auipc t1, 1
+        0xfeb33223, // sd a1, -28(t1)                 tohost = 111, exit code 55
+        0x0000006f, // j .
+    ]);
+    write_elf(&program, DRAM_BASE, &code, &[("tohost", DRAM_BASE + 0x1000)]);
+    let program = program.to_str().unwrap();
+    let snapshot = temp_path("snapshot.snap");
+    let snapshot_path = snapshot.to_str().unwrap();
+
+    let output = run(&["--max-instructions", "12", "--save-snapshot", snapshot_path, program]);
+    assert_eq!(output.status.code(), Some(124));
+    // 64 MiB of RAM with one page in use
+    assert!(fs::metadata(&snapshot).unwrap().len() < 8192);
+
+    let output = run(&["--restore", snapshot_path, "--log-commits", program]);
+    assert_eq!(output.status.code(), Some(55));
+    // the loop picks up where it stopped, counting a0 down from 7
+    let log = String::from_utf8(output.stderr).unwrap();
+    let first = log.lines().find(|line| line.starts_with("core   0: 3 ")).unwrap();
+    assert_eq!(first, "core   0: 3 0x000000008000000c (0xfff50513) x10 0x0000000000000006");
+
+    let output = run(&["--restore", snapshot_path, "--memory", "32M", program]);
+    fs::remove_file(&snapshot).unwrap();
+    fs::remove_file(program).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("can't restore"));
+}